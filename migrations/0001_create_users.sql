@@ -0,0 +1,11 @@
+CREATE TABLE users (
+    id SERIAL PRIMARY KEY,
+    email VARCHAR(50) UNIQUE NOT NULL,
+    display_name VARCHAR(32),
+    avatar_url VARCHAR(256),
+    public_key BYTEA NOT NULL,
+    pass BYTEA NOT NULL,
+    salt BYTEA NOT NULL,
+    failed_attempts INT NOT NULL DEFAULT 0,
+    locked_until TIMESTAMPTZ
+)
\ No newline at end of file
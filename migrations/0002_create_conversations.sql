@@ -0,0 +1,9 @@
+CREATE TABLE conversations (
+    id SERIAL PRIMARY KEY,
+    name VARCHAR(50) NOT NULL,
+    direct_key VARCHAR(101) UNIQUE,
+    public BOOLEAN NOT NULL DEFAULT FALSE,
+    last_seq INT NOT NULL DEFAULT 0,
+    retention_seconds INT,
+    timestamp BYTEA
+)
\ No newline at end of file
@@ -0,0 +1,9 @@
+CREATE TABLE participants (
+    id SERIAL PRIMARY KEY,
+    display_name VARCHAR(32),
+    role VARCHAR(16) NOT NULL DEFAULT 'member',
+    last_read_message_id INT,
+    identity INT references users(id) NOT NULL,
+    conversation INT references conversations(id) NOT NULL,
+    UNIQUE (identity, conversation)
+)
\ No newline at end of file
@@ -0,0 +1,9 @@
+CREATE TABLE attachments (
+    id SERIAL PRIMARY KEY,
+    media_type BYTEA NOT NULL,
+    size INT NOT NULL,
+    data BYTEA NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    owner INT references users(id) NOT NULL,
+    conversation INT references conversations(id) NOT NULL
+)
\ No newline at end of file
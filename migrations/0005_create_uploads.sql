@@ -0,0 +1,11 @@
+CREATE TABLE uploads (
+    id SERIAL PRIMARY KEY,
+    media_type BYTEA NOT NULL,
+    size INT NOT NULL,
+    received INT NOT NULL DEFAULT 0,
+    next_index INT NOT NULL DEFAULT 0,
+    data BYTEA NOT NULL DEFAULT '',
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    owner INT references users(id) NOT NULL,
+    conversation INT references conversations(id) NOT NULL
+)
\ No newline at end of file
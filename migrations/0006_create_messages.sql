@@ -0,0 +1,22 @@
+CREATE TABLE messages (
+    id SERIAL PRIMARY KEY,
+    seq INT NOT NULL,
+    data BYTEA NOT NULL,
+    media_type BYTEA,
+    timestamp TIMESTAMPTZ,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    edited_at TIMESTAMPTZ,
+    signature BYTEA,
+    idempotency_key VARCHAR(64),
+    search TSVECTOR,
+    parent_id INT,
+    attachment_id INT references attachments(id),
+    deleted_at TIMESTAMPTZ,
+    deleted_by INT references users(id),
+    sender INT references participants(id) NOT NULL,
+    conversation INT references conversations(id) NOT NULL,
+    UNIQUE (sender, idempotency_key),
+    UNIQUE (conversation, seq)
+);
+
+CREATE INDEX messages_search ON messages USING GIN (search)
\ No newline at end of file
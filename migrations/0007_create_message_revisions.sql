@@ -0,0 +1,8 @@
+CREATE TABLE message_revisions (
+    id SERIAL PRIMARY KEY,
+    message INT references messages(id) NOT NULL,
+    data BYTEA NOT NULL,
+    media_type BYTEA,
+    signature BYTEA,
+    edited_at TIMESTAMPTZ NOT NULL
+)
\ No newline at end of file
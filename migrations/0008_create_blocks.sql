@@ -0,0 +1,6 @@
+CREATE TABLE blocks (
+    id SERIAL PRIMARY KEY,
+    blocker INT references users(id) NOT NULL,
+    blocked INT references users(id) NOT NULL,
+    UNIQUE (blocker, blocked)
+)
\ No newline at end of file
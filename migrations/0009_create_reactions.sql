@@ -0,0 +1,7 @@
+CREATE TABLE reactions (
+    id SERIAL PRIMARY KEY,
+    emoji VARCHAR(32) NOT NULL,
+    message INT references messages(id) NOT NULL,
+    identity INT references users(id) NOT NULL,
+    UNIQUE (message, identity, emoji)
+)
\ No newline at end of file
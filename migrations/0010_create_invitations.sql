@@ -0,0 +1,9 @@
+CREATE TABLE invitations (
+    id SERIAL PRIMARY KEY,
+    status VARCHAR(16) NOT NULL DEFAULT 'pending',
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    conversation INT references conversations(id) NOT NULL,
+    inviter INT references users(id) NOT NULL,
+    invitee INT references users(id) NOT NULL,
+    UNIQUE (conversation, invitee)
+)
\ No newline at end of file
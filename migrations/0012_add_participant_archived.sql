@@ -0,0 +1 @@
+ALTER TABLE participants ADD COLUMN archived BOOLEAN NOT NULL DEFAULT FALSE
\ No newline at end of file
@@ -0,0 +1,6 @@
+CREATE TABLE public_key_history (
+    id SERIAL PRIMARY KEY,
+    identity INT references users(id) NOT NULL,
+    public_key BYTEA NOT NULL,
+    replaced_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+)
\ No newline at end of file
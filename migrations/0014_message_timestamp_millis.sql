@@ -0,0 +1 @@
+ALTER TABLE messages ALTER COLUMN timestamp TYPE BIGINT USING (EXTRACT(EPOCH FROM timestamp) * 1000)::BIGINT
\ No newline at end of file
@@ -0,0 +1,2 @@
+ALTER TABLE messages ADD COLUMN data_encoding VARCHAR(16);
+ALTER TABLE message_revisions ADD COLUMN data_encoding VARCHAR(16)
\ No newline at end of file
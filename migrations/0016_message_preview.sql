@@ -0,0 +1 @@
+ALTER TABLE messages ADD COLUMN preview TEXT
\ No newline at end of file
@@ -0,0 +1 @@
+ALTER TABLE message_revisions ADD COLUMN timestamp BIGINT
\ No newline at end of file
@@ -0,0 +1,8 @@
+CREATE TABLE upload_chunks (
+    id SERIAL PRIMARY KEY,
+    upload INT references uploads(id) NOT NULL,
+    position INT NOT NULL,
+    data BYTEA NOT NULL,
+    UNIQUE (upload, position)
+);
+ALTER TABLE uploads DROP COLUMN data
\ No newline at end of file
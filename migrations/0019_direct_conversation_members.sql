@@ -0,0 +1,2 @@
+ALTER TABLE conversations DROP CONSTRAINT conversations_direct_key_key;
+CREATE INDEX conversations_direct_key ON conversations (direct_key)
\ No newline at end of file
@@ -0,0 +1,12 @@
+CREATE TABLE users (
+    id INTEGER PRIMARY KEY,
+    email VARCHAR(50) UNIQUE NOT NULL,
+    display_name VARCHAR(32),
+    avatar_url VARCHAR(256),
+    public_key BLOB NOT NULL,
+    pass BLOB NOT NULL,
+    salt BLOB NOT NULL,
+    failed_attempts INTEGER NOT NULL DEFAULT 0,
+    -- Times are kept as milliseconds since the epoch
+    locked_until INTEGER
+)
\ No newline at end of file
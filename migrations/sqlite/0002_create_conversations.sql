@@ -0,0 +1,11 @@
+CREATE TABLE conversations (
+    id INTEGER PRIMARY KEY,
+    name VARCHAR(50) NOT NULL,
+    direct_key VARCHAR(101),
+    public BOOLEAN NOT NULL DEFAULT FALSE,
+    last_seq INTEGER NOT NULL DEFAULT 0,
+    retention_seconds INTEGER,
+    timestamp BLOB
+);
+
+CREATE INDEX conversations_direct_key ON conversations (direct_key)
\ No newline at end of file
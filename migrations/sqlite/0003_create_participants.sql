@@ -0,0 +1,10 @@
+CREATE TABLE participants (
+    id INTEGER PRIMARY KEY,
+    display_name VARCHAR(32),
+    role VARCHAR(16) NOT NULL DEFAULT 'member',
+    last_read_message_id INTEGER,
+    archived BOOLEAN NOT NULL DEFAULT FALSE,
+    identity INTEGER references users(id) NOT NULL,
+    conversation INTEGER references conversations(id) NOT NULL,
+    UNIQUE (identity, conversation)
+)
\ No newline at end of file
@@ -0,0 +1,9 @@
+CREATE TABLE attachments (
+    id INTEGER PRIMARY KEY,
+    media_type BLOB NOT NULL,
+    size INTEGER NOT NULL,
+    data BLOB NOT NULL,
+    created_at INTEGER NOT NULL,
+    owner INTEGER references users(id) NOT NULL,
+    conversation INTEGER references conversations(id) NOT NULL
+)
\ No newline at end of file
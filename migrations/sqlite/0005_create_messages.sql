@@ -0,0 +1,21 @@
+CREATE TABLE messages (
+    id INTEGER PRIMARY KEY,
+    seq INTEGER NOT NULL,
+    data BLOB NOT NULL,
+    data_encoding VARCHAR(16),
+    preview TEXT,
+    media_type BLOB,
+    timestamp INTEGER,
+    created_at INTEGER NOT NULL,
+    edited_at INTEGER,
+    signature BLOB,
+    idempotency_key VARCHAR(64),
+    parent_id INTEGER,
+    attachment_id INTEGER references attachments(id),
+    deleted_at INTEGER,
+    deleted_by INTEGER references users(id),
+    sender INTEGER references participants(id) NOT NULL,
+    conversation INTEGER references conversations(id) NOT NULL,
+    UNIQUE (sender, idempotency_key),
+    UNIQUE (conversation, seq)
+)
\ No newline at end of file
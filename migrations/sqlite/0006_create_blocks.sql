@@ -0,0 +1,6 @@
+CREATE TABLE blocks (
+    id INTEGER PRIMARY KEY,
+    blocker INTEGER references users(id) NOT NULL,
+    blocked INTEGER references users(id) NOT NULL,
+    UNIQUE (blocker, blocked)
+)
\ No newline at end of file
@@ -0,0 +1,7 @@
+CREATE TABLE reactions (
+    id INTEGER PRIMARY KEY,
+    emoji VARCHAR(32) NOT NULL,
+    message INTEGER references messages(id) NOT NULL,
+    identity INTEGER references users(id) NOT NULL,
+    UNIQUE (message, identity, emoji)
+)
\ No newline at end of file
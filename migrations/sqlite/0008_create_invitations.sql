@@ -0,0 +1,9 @@
+CREATE TABLE invitations (
+    id INTEGER PRIMARY KEY,
+    status VARCHAR(16) NOT NULL DEFAULT 'pending',
+    created_at INTEGER NOT NULL,
+    conversation INTEGER references conversations(id) NOT NULL,
+    inviter INTEGER references users(id) NOT NULL,
+    invitee INTEGER references users(id) NOT NULL,
+    UNIQUE (conversation, invitee)
+)
\ No newline at end of file
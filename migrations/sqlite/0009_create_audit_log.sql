@@ -0,0 +1,8 @@
+CREATE TABLE audit_log (
+    id INTEGER PRIMARY KEY,
+    actor VARCHAR(50) NOT NULL,
+    action VARCHAR(32) NOT NULL,
+    target VARCHAR(64),
+    outcome VARCHAR(16) NOT NULL,
+    created_at INTEGER NOT NULL
+)
\ No newline at end of file
@@ -3,21 +3,152 @@ pub mod response;
 
 use std::convert::TryFrom;
 use std::error::Error;
+use std::fmt;
+use std::io::Error as ioErr;
+use std::io::ErrorKind as ioErrKind;
+use std::str::FromStr;
 use base64;
+use chrono::{DateTime, TimeZone, Utc};
+use serde::Deserialize;
 use serde_json::Value;
+use zeroize::{Zeroize, Zeroizing};
+
+/// The bytes every PNG image starts with
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+/// The bytes every JPEG image starts with
+const JPEG_SIGNATURE: &[u8] = b"\xff\xd8\xff";
+/// The longest media type (in bytes) that can be given
+const MAX_MEDIA_TYPE_LENGTH: usize = 127;
 
 trait ApiObject: Sized {
     fn from_json(data: &Value) -> Result<Self, Box<dyn Error>>;
 }
 
+/// JSON parsed from a request, whose passwords are scrubbed once it's dropped (however parsing it turns out)
+#[derive(Deserialize)]
+#[serde(transparent)]
+pub struct ScrubbedValue(pub Value);
+
+impl Drop for ScrubbedValue {
+    fn drop(&mut self) {
+        scrub_passwords(&mut self.0);
+    }
+}
+
+/// Scrub every password and new password from JSON, wherever they're nested
+pub fn scrub_passwords(value: &mut Value) {
+    match value {
+        Value::Object(fields) => {
+            for (key, field) in fields.iter_mut() {
+                match field {
+                    Value::String(s) if key == "password" || key == "newPassword" => s.zeroize(),
+                    _ => scrub_passwords(field),
+                }
+            }
+        },
+        Value::Array(items) => items.iter_mut().for_each(scrub_passwords),
+        _ => (),
+    }
+}
+
+/// Parse an RFC 3339 timestamp, converting it to UTC
+pub fn parse_timestamp(data: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(data)
+        .ok()
+        .map(|t| t.with_timezone(&Utc))
+}
+
+/// Convert a timestamp in milliseconds since the Unix epoch to a date and time, if it isn't negative or out of range
+pub fn from_epoch_millis(millis: i64) -> Option<DateTime<Utc>> {
+    match millis >= 0 {
+        true => Utc.timestamp_millis_opt(millis).single(),
+        false => None,
+    }
+}
+
+/// Decode a base64 field, which is how binary data is sent in JSON
+fn decode_bytes(value: &Value, field: &str, object: &str) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+    match value.as_str() {
+        Some(d) => base64::decode(d)
+            .map(Some)
+            .map_err(|_| ioErr::new(ioErrKind::InvalidInput, format!("Invalid '{}' field for '{}'", field, object)).into()),
+        None => Ok(None),
+    }
+}
+
+/// Check whether a media type is a short string in the form 'type/subtype'
+pub fn is_media_type(media_type: &[u8]) -> bool {
+    let is_name = |part: &[u8]| !part.is_empty()
+        && part.iter().all(|c| c.is_ascii_alphanumeric() || b"!#$&-^_.+".contains(c));
+
+    media_type.len() <= MAX_MEDIA_TYPE_LENGTH
+        && match media_type.iter().position(|&c| c == b'/') {
+            Some(i) => is_name(&media_type[..i]) && is_name(&media_type[i + 1..]),
+            None => false,
+        }
+}
+
+/// Check whether a media type is for text, ignoring case
+pub fn is_text(media_type: &[u8]) -> bool {
+    media_type.len() >= 5 && media_type[..5].eq_ignore_ascii_case(b"text/")
+}
+
+/// Check that a message's data looks like its declared media type, giving the reason if it doesn't
+///
+/// Only a few common types are checked; anything else (including application/octet-stream) is accepted.
+pub fn check_content(media_type: &[u8], data: &[u8]) -> Result<(), &'static str> {
+    let is = |expected: &[u8]| media_type.eq_ignore_ascii_case(expected);
+
+    if is_text(media_type) {
+        return match std::str::from_utf8(data) {
+            Ok(_) => Ok(()),
+            Err(_) => Err("text is not valid UTF-8"),
+        };
+    }
+
+    if is(b"image/png") && !data.starts_with(PNG_SIGNATURE) {
+        return Err("data is not a PNG image");
+    }
+
+    if is(b"image/jpeg") && !data.starts_with(JPEG_SIGNATURE) {
+        return Err("data is not a JPEG image");
+    }
+
+    Ok(())
+}
+
 /// A target representing a user on the server
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Default)]
 pub struct User {
     pub id: Option<i32>,
     pub email: Option<String>,
     pub name: Option<String>,
-    pub password: Option<String>,
+    pub password: Option<Zeroizing<String>>,
+    pub new_password: Option<Zeroizing<String>>,
     pub public_key: Option<Vec<u8>>,
+    pub new_public_key: Option<Vec<u8>>,
+    pub previous_keys: Option<Vec<PreviousKey>>,
+    pub display_name: Option<String>,
+    pub avatar_url: Option<String>,
+    pub last_read_message_id: Option<i32>,
+    pub status: Option<u8>,
+}
+
+/// A public key that a user used until they replaced it, kept so that older messages can still be verified
+#[derive(Clone, Debug, PartialEq)]
+pub struct PreviousKey {
+    pub public_key: Vec<u8>,
+    pub replaced_at: DateTime<Utc>,
+}
+
+impl User {
+    /// Create a user object identified only by email
+    pub fn from_email(email: String) -> User {
+        User{
+            email: Some(email),
+            ..Default::default()
+        }
+    }
 }
 
 impl ApiObject for User {
@@ -37,65 +168,301 @@ impl ApiObject for User {
                 None => None,
             },
             password: match data["password"].as_str() {
+                Some(d) => Some(Zeroizing::new(String::from(d))),
+                None => None,
+            },
+            new_password: match data["newPassword"].as_str() {
+                Some(d) => Some(Zeroizing::new(String::from(d))),
+                None => None,
+            },
+            public_key: decode_bytes(&data["publicKey"], "public_key", "user")?,
+            new_public_key: decode_bytes(&data["newPublicKey"], "new_public_key", "user")?,
+            previous_keys: None,
+            display_name: match data["displayName"].as_str() {
                 Some(d) => Some(String::from(d)),
                 None => None,
             },
-            public_key: match data["publicKey"].as_str() {
-                Some(d) => Some(base64::decode(d)?),
+            avatar_url: match data["avatarUrl"].as_str() {
+                Some(d) => Some(String::from(d)),
                 None => None,
             },
+            last_read_message_id: None,
+            status: None,
         })
     }
 }
 
 /// A target representing a message on the server
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct Message {
     pub id: Option<i32>,
+    pub seq: Option<i32>,
+    pub conversation: Option<i32>,
     pub data: Option<Vec<u8>>,
     pub media_type: Option<Vec<u8>>,
-    pub timestamp: Option<Vec<u8>>,
+    /// When the sender says they sent the message, in milliseconds since the Unix epoch
+    pub timestamp: Option<i64>,
+    pub created_at: Option<DateTime<Utc>>,
+    pub edited_at: Option<DateTime<Utc>>,
     pub signature: Option<Vec<u8>>,
     pub sender: Option<String>,
+    pub idempotency_key: Option<String>,
+    pub parent_id: Option<i32>,
+    pub attachment: Option<Attachment>,
+    pub reactions: Option<Vec<Reaction>>,
+    pub deleted: Option<bool>,
+    pub status: Option<u8>,
+    /// Why the message couldn't be stored, when its status isn't a success
+    pub error: Option<String>,
+}
+
+impl Message {
+    /// Replace a deleted message with a tombstone, which keeps its place in the conversation
+    /// (and its reactions) but none of its content
+    pub fn redact(self) -> Message {
+        match self.deleted {
+            Some(true) => Message{
+                id: self.id,
+                seq: self.seq,
+                conversation: self.conversation,
+                created_at: self.created_at,
+                sender: self.sender,
+                parent_id: self.parent_id,
+                reactions: self.reactions,
+                deleted: Some(true),
+                ..Default::default()
+            },
+            _ => self,
+        }
+    }
 }
 
 impl ApiObject for Message {
     /// Create a message object from JSON
     fn from_json(data: &Value) -> Result<Message, Box<dyn Error>> {
+        let media_type = decode_bytes(&data["mediaType"], "media_type", "message")?;
+
+        // Text messages can still be sent as plain strings, as clients did before binary fields were base64
+        let message_data = match (&data["data"], &data["text"]) {
+            (Value::Null, Value::Null) => None,
+            (Value::Null, text) => match (text.as_str(), media_type.as_deref().map(is_text)) {
+                (Some(t), Some(true)) => Some(t.as_bytes().to_vec()),
+                _ => return Err(Box::new(ioErr::new(ioErrKind::InvalidInput, "Invalid 'text' field for 'message'"))),
+            },
+            (d, Value::Null) => decode_bytes(d, "data", "message")?,
+            _ => return Err(Box::new(ioErr::new(ioErrKind::InvalidInput, "Cannot have both 'data' and 'text' fields for 'message'"))),
+        };
+
         Ok(Message{
             id: match data["id"].as_i64() {
                 Some(d) => Some(i32::try_from(d)?),
                 None => None,
             },
-            data: match data["data"].as_str() {
-                Some(d) => Some(base64::decode(d)?),
+            seq: match data["seq"].as_i64() {
+                Some(d) => Some(i32::try_from(d)?),
                 None => None,
             },
-            media_type: match data["mediaType"].as_str() {
-                Some(d) => Some(base64::decode(d)?),
+            conversation: match data["conversation"].as_i64() {
+                Some(d) => Some(i32::try_from(d)?),
                 None => None,
             },
-            timestamp: match data["timestamp"].as_str() {
-                Some(d) => Some(base64::decode(d)?),
+            data: message_data,
+            media_type,
+            timestamp: match &data["timestamp"] {
+                Value::Null => None,
+                t => Some(t.as_i64()
+                    .filter(|&t| from_epoch_millis(t).is_some())
+                    .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Invalid 'timestamp' field for 'message'"))?),
+            },
+            created_at: None,
+            edited_at: None,
+            signature: decode_bytes(&data["signature"], "signature", "message")?,
+            sender: match data["sender"].as_str() {
+                Some(d) => Some(String::from(d)),
+                None => None,
+            },
+            idempotency_key: match data["idempotencyKey"].as_str() {
+                Some(d) => Some(String::from(d)),
+                None => None,
+            },
+            parent_id: match data["parentId"].as_i64() {
+                Some(d) => Some(i32::try_from(d)?),
+                None => None,
+            },
+            attachment: match data["attachment"].is_object() {
+                true => Some(Attachment::from_json(&data["attachment"])?),
+                false => None,
+            },
+            reactions: None,
+            deleted: None,
+            status: None,
+            error: None,
+        })
+    }
+}
+
+/// A target representing an emoji reaction to a message
+#[derive(Clone, Debug)]
+pub struct Reaction {
+    pub message: Option<i32>,
+    pub emoji: Option<String>,
+    pub sender: Option<String>,
+}
+
+impl ApiObject for Reaction {
+    /// Create a reaction object from JSON
+    fn from_json(data: &Value) -> Result<Reaction, Box<dyn Error>> {
+        Ok(Reaction{
+            message: match data["message"].as_i64() {
+                Some(d) => Some(i32::try_from(d)?),
                 None => None,
             },
-            signature: match data["signature"].as_str() {
-                Some(d) => Some(base64::decode(d)?),
+            emoji: match data["emoji"].as_str() {
+                Some(d) => Some(String::from(d)),
                 None => None,
             },
             sender: match data["sender"].as_str() {
                 Some(d) => Some(String::from(d)),
                 None => None,
-            }
+            },
         })
     }
 }
 
-/// A target representing a conversation on the server
+/// A target representing a file uploaded to a conversation, which messages can refer to
+#[derive(Clone, Debug, Default)]
+pub struct Attachment {
+    pub id: Option<i32>,
+    pub conversation: Option<i32>,
+    pub media_type: Option<Vec<u8>>,
+    pub size: Option<i32>,
+    pub data: Option<Vec<u8>>,
+    pub upload: Option<i32>,
+    pub sha256: Option<Vec<u8>>,
+}
+
+impl ApiObject for Attachment {
+    /// Create an attachment object from JSON
+    fn from_json(data: &Value) -> Result<Attachment, Box<dyn Error>> {
+        Ok(Attachment{
+            id: match data["id"].as_i64() {
+                Some(d) => Some(i32::try_from(d)?),
+                None => None,
+            },
+            conversation: match data["conversation"].as_i64() {
+                Some(d) => Some(i32::try_from(d)?),
+                None => None,
+            },
+            media_type: decode_bytes(&data["mediaType"], "media_type", "attachment")?,
+            size: None,
+            data: decode_bytes(&data["data"], "data", "attachment")?,
+            upload: match data["upload"].as_i64() {
+                Some(d) => Some(i32::try_from(d)?),
+                None => None,
+            },
+            sha256: decode_bytes(&data["sha256"], "sha256", "attachment")?,
+        })
+    }
+}
+
+/// A target representing an attachment being uploaded in chunks
+#[derive(Clone, Debug, Default)]
+pub struct Upload {
+    pub id: Option<i32>,
+    pub conversation: Option<i32>,
+    pub media_type: Option<Vec<u8>>,
+    pub size: Option<i32>,
+    pub received: Option<i32>,
+    pub index: Option<i32>,
+    pub data: Option<Vec<u8>>,
+}
+
+impl ApiObject for Upload {
+    /// Create an upload object from JSON
+    fn from_json(data: &Value) -> Result<Upload, Box<dyn Error>> {
+        Ok(Upload{
+            id: match data["id"].as_i64() {
+                Some(d) => Some(i32::try_from(d)?),
+                None => None,
+            },
+            conversation: match data["conversation"].as_i64() {
+                Some(d) => Some(i32::try_from(d)?),
+                None => None,
+            },
+            media_type: decode_bytes(&data["mediaType"], "media_type", "upload")?,
+            size: match data["size"].as_i64() {
+                Some(d) => Some(i32::try_from(d)?),
+                None => None,
+            },
+            received: None,
+            index: match data["index"].as_i64() {
+                Some(d) => Some(i32::try_from(d)?),
+                None => None,
+            },
+            data: decode_bytes(&data["data"], "data", "upload")?,
+        })
+    }
+}
+
+/// A target representing an invitation to join a conversation
 #[derive(Clone, Debug)]
+pub struct Invitation {
+    pub id: Option<i32>,
+    pub conversation: Option<i32>,
+    pub inviter: Option<String>,
+    pub status: Option<String>,
+}
+
+impl ApiObject for Invitation {
+    /// Create an invitation object from JSON
+    fn from_json(data: &Value) -> Result<Invitation, Box<dyn Error>> {
+        Ok(Invitation{
+            id: match data["id"].as_i64() {
+                Some(d) => Some(i32::try_from(d)?),
+                None => None,
+            },
+            conversation: match data["conversation"].as_i64() {
+                Some(d) => Some(i32::try_from(d)?),
+                None => None,
+            },
+            inviter: match data["inviter"].as_str() {
+                Some(d) => Some(String::from(d)),
+                None => None,
+            },
+            status: match data["status"].as_str() {
+                Some(d) => Some(String::from(d)),
+                None => None,
+            },
+        })
+    }
+}
+
+/// A target representing a conversation on the server, identified by its id
+/// (the name is only a display label and need not be unique)
+#[derive(Clone, Debug, Default)]
 pub struct Conversation {
     pub id: Option<i32>,
     pub name: Option<String>,
+    pub timestamp: Option<DateTime<Utc>>,
+    pub direct: Option<bool>,
+    pub public: Option<bool>,
+    pub last_read_message_id: Option<i32>,
+    pub unread_count: Option<i64>,
+    pub last_sender: Option<String>,
+    pub last_media_type: Option<Vec<u8>>,
+    pub last_preview: Option<String>,
+    pub retention_seconds: Option<i32>,
+    pub role: Option<String>,
+    pub archived: Option<bool>,
+}
+
+impl Conversation {
+    /// Create a key identifying the direct conversation between two users
+    pub fn direct_key(first: &str, second: &str) -> String {
+        let mut emails = [first, second];
+        emails.sort_unstable();
+        emails.join(" ")
+    }
 }
 
 impl ApiObject for Conversation {
@@ -110,14 +477,65 @@ impl ApiObject for Conversation {
                 Some(d) => Some(String::from(d)),
                 None => None,
             },
+            timestamp: match data["timestamp"].as_str() {
+                Some(d) => Some(parse_timestamp(d)
+                    .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Invalid 'timestamp' field for 'conversation'"))?),
+                None => None,
+            },
+            direct: data["direct"].as_bool(),
+            public: data["public"].as_bool(),
+            last_read_message_id: match data["lastReadMessageId"].as_i64() {
+                Some(d) => Some(i32::try_from(d)?),
+                None => None,
+            },
+            unread_count: None,
+            last_sender: None,
+            last_media_type: None,
+            last_preview: None,
+            retention_seconds: match data["retentionSeconds"].as_i64() {
+                Some(d) if d >= 0 => Some(i32::try_from(d)?),
+                Some(_) => return Err(Box::new(ioErr::new(ioErrKind::InvalidInput, "Invalid 'retention_seconds' field for 'conversation'"))),
+                None => None,
+            },
+            role: None,
+            archived: data["archived"].as_bool(),
+        })
+    }
+}
+
+/// A position in a paginated list, given by a sort key and a tie-breaking id
+#[derive(Clone, Debug, PartialEq)]
+pub struct Cursor {
+    pub key: i32,
+    pub id: i32,
+}
+
+impl fmt::Display for Cursor {
+    /// Format a cursor as an opaque string for clients
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.key, self.id)
+    }
+}
+
+impl FromStr for Cursor {
+    type Err = Box<dyn Error>;
+
+    /// Parse a cursor previously handed out to a client
+    fn from_str(data: &str) -> Result<Cursor, Box<dyn Error>> {
+        let (key, id) = data.split_once(':')
+            .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Malformed cursor"))?;
+
+        Ok(Cursor{
+            key: key.parse()?,
+            id: id.parse()?,
         })
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::api::{User, Message, Conversation};
-    use crate::api::ApiObject;
+    use crate::api::{User, Message, Reaction, Invitation, Conversation, Cursor, Attachment, Upload};
+    use crate::api::{ApiObject, check_content, from_epoch_millis, is_text, parse_timestamp, scrub_passwords};
     use serde_json::json;
 
     #[test]
@@ -128,7 +546,11 @@ mod tests {
                 "email": "1@example.com",
                 "name": "Example User",
                 "password": "pass",
+                "newPassword": "new pass",
                 "publicKey": "a2V5",
+                "newPublicKey": "bmV3IGtleQ==",
+                "displayName": "Example",
+                "avatarUrl": "https://example.com/avatar.png",
             }),
             json!({}),
         ];
@@ -141,14 +563,124 @@ mod tests {
         assert_eq!(users[0].id, Some(1));
         assert_eq!(users[0].email, Some(String::from("1@example.com")));
         assert_eq!(users[0].name, Some(String::from("Example User")));
-        assert_eq!(users[0].password, Some(String::from("pass")));
+        assert_eq!(users[0].password.as_deref(), Some(&String::from("pass")));
+        assert_eq!(users[0].new_password.as_deref(), Some(&String::from("new pass")));
         assert_eq!(users[0].public_key, Some(String::from("key").into_bytes()));
+        assert_eq!(users[0].new_public_key, Some(String::from("new key").into_bytes()));
+        assert_eq!(users[0].display_name, Some(String::from("Example")));
+        assert_eq!(users[0].avatar_url, Some(String::from("https://example.com/avatar.png")));
 
         assert_eq!(users[1].id, None);
         assert_eq!(users[1].email, None);
         assert_eq!(users[1].name, None);
         assert_eq!(users[1].password, None);
+        assert_eq!(users[1].new_password, None);
         assert_eq!(users[1].public_key, None);
+        assert_eq!(users[1].new_public_key, None);
+        assert_eq!(users[1].display_name, None);
+        assert_eq!(users[1].avatar_url, None);
+    }
+
+    #[test]
+    fn test_is_text() {
+        assert!(is_text(b"text/plain"));
+        assert!(is_text(b"Text/Markdown"));
+        assert!(!is_text(b"text"));
+        assert!(!is_text(b"application/octet-stream"));
+    }
+
+    #[test]
+    fn test_check_content() {
+        assert_eq!(check_content(b"text/plain", "hello wörld".as_bytes()), Ok(()));
+        assert_eq!(check_content(b"text/plain", "héllo wörld 👋".as_bytes()), Ok(()));
+        assert_eq!(check_content(b"text/plain", b""), Ok(()));
+        assert_eq!(check_content(b"TEXT/plain", &[0x68, 0x65, 0xff, 0x6c]), Err("text is not valid UTF-8"));
+        assert_eq!(check_content(b"TEXT/Markdown", b"# hello"), Ok(()));
+        assert!(check_content(b"text/plain", &[0x68, 0x69, 0xc3]).is_err());
+        assert!(check_content(b"text/plain", &[0xff, 0xfe]).is_err());
+
+        let png = b"\x89PNG\r\n\x1a\n\x00\x00\x00\x0dIHDR";
+        let jpeg = b"\xff\xd8\xff\xe0\x00\x10JFIF";
+        let executable = b"MZ\x90\x00\x03\x00\x00\x00";
+
+        assert_eq!(check_content(b"image/png", png), Ok(()));
+        assert_eq!(check_content(b"image/jpeg", jpeg), Ok(()));
+        assert_eq!(check_content(b"image/png", executable), Err("data is not a PNG image"));
+        assert_eq!(check_content(b"image/jpeg", png), Err("data is not a JPEG image"));
+        // A truncated signature doesn't count
+        assert!(check_content(b"image/png", &png[..4]).is_err());
+        assert!(check_content(b"image/jpeg", b"").is_err());
+
+        // Anything else is left alone
+        assert_eq!(check_content(b"application/octet-stream", executable), Ok(()));
+        assert_eq!(check_content(b"image/gif", executable), Ok(()));
+    }
+
+    #[test]
+    fn test_message_redact() {
+        let message = |deleted| Message{
+            id: Some(3),
+            seq: Some(2),
+            conversation: Some(1),
+            data: Some(b"hello".to_vec()),
+            media_type: Some(b"text/plain".to_vec()),
+            signature: Some(b"signature".to_vec()),
+            sender: Some(String::from("1@example.com")),
+            parent_id: Some(1),
+            reactions: Some(Vec::new()),
+            deleted: Some(deleted),
+            ..Default::default()
+        };
+
+        // Other members see who sent a deleted message and where it was, but not what it said
+        let tombstone = message(true).redact();
+        assert_eq!(tombstone.id, Some(3));
+        assert_eq!(tombstone.seq, Some(2));
+        assert_eq!(tombstone.sender, Some(String::from("1@example.com")));
+        assert_eq!(tombstone.parent_id, Some(1));
+        assert!(tombstone.reactions.is_some());
+        assert_eq!(tombstone.deleted, Some(true));
+        assert_eq!(tombstone.data, None);
+        assert_eq!(tombstone.media_type, None);
+        assert_eq!(tombstone.signature, None);
+
+        let kept = message(false).redact();
+        assert_eq!(kept.data, Some(b"hello".to_vec()));
+        assert_eq!(kept.deleted, Some(false));
+    }
+
+    #[test]
+    fn test_message_invalid_timestamp() {
+        // Timestamps are whole, non-negative milliseconds since the Unix epoch
+        let invalid = [
+            json!({"timestamp": "dGltZXN0YW1w"}),
+            json!({"timestamp": "1609459200000"}),
+            json!({"timestamp": "2021-01-01T00:00:00Z"}),
+            json!({"timestamp": -1}),
+            json!({"timestamp": 1609459200000.5}),
+            json!({"timestamp": i64::MAX}),
+        ];
+
+        for message in invalid.iter() {
+            assert!(Message::from_json(message).is_err(), "{}", message);
+        }
+    }
+
+    #[test]
+    fn test_scrub_passwords() {
+        let mut batch = json!([
+            {"function": "VERIFY USERS", "users": [{"email": "me@example.com", "password": "k2uEa77H"}]},
+            {"function": "UPDATE USERS", "users": [{"password": "k2uEa77H", "newPassword": "9poyvjJN"}]},
+            {"function": "READ MESSAGES", "limit": 10},
+        ]);
+        scrub_passwords(&mut batch);
+
+        // Only passwords are scrubbed, wherever they are
+        assert_eq!(batch, json!([
+            {"function": "VERIFY USERS", "users": [{"email": "me@example.com", "password": ""}]},
+            {"function": "UPDATE USERS", "users": [{"password": "", "newPassword": ""}]},
+            {"function": "READ MESSAGES", "limit": 10},
+        ]));
     }
 
     #[test]
@@ -156,11 +688,15 @@ mod tests {
         let json = [
             json!({
                 "id": 1,
+                "seq": 4,
+                "conversation": 2,
                 "data": "ZGF0YQ==",
                 "mediaType": "dGV4dC9wbGFpbg==",
-                "timestamp": "dGltZXN0YW1w",
+                "timestamp": 1609459200123i64,
                 "signature": "c2lnbmF0dXJl",
                 "sender": "1@example.com",
+                "idempotencyKey": "9b2c6f1e",
+                "parentId": 3,
             }),
             json!({}),
         ];
@@ -171,18 +707,188 @@ mod tests {
         ];
 
         assert_eq!(messages[0].id, Some(1));
+        assert_eq!(messages[0].seq, Some(4));
+        assert_eq!(messages[0].conversation, Some(2));
         assert_eq!(messages[0].data, Some(String::from("data").into_bytes()));
         assert_eq!(messages[0].media_type, Some(String::from("text/plain").into_bytes()));
-        assert_eq!(messages[0].timestamp, Some(String::from("timestamp").into_bytes()));
+        assert_eq!(messages[0].timestamp, Some(1609459200123));
+        assert_eq!(messages[0].timestamp.and_then(from_epoch_millis), parse_timestamp("2021-01-01T00:00:00.123Z"));
         assert_eq!(messages[0].signature, Some(String::from("signature").into_bytes()));
         assert_eq!(messages[0].sender, Some(String::from("1@example.com")));
+        assert_eq!(messages[0].idempotency_key, Some(String::from("9b2c6f1e")));
+        assert_eq!(messages[0].parent_id, Some(3));
 
         assert_eq!(messages[1].id, None);
+        assert_eq!(messages[1].seq, None);
+        assert_eq!(messages[1].conversation, None);
+        assert_eq!(messages[1].parent_id, None);
         assert_eq!(messages[1].data, None);
         assert_eq!(messages[1].media_type, None);
         assert_eq!(messages[1].timestamp, None);
         assert_eq!(messages[1].signature, None);
         assert_eq!(messages[1].sender, None);
+        assert_eq!(messages[1].idempotency_key, None);
+    }
+
+    #[test]
+    fn test_message_text() {
+        let message = Message::from_json(&json!({"text": "héllo\u{0}wörld", "mediaType": "dGV4dC9wbGFpbg=="})).unwrap();
+        assert_eq!(message.data, Some("héllo\u{0}wörld".as_bytes().to_vec()));
+
+        let message = Message::from_json(&json!({"text": "hello", "mediaType": "VEVYVC9tYXJrZG93bg=="})).unwrap();
+        assert_eq!(message.data, Some(b"hello".to_vec()));
+
+        let invalid = [
+            (json!({"text": "hello"}), "Invalid 'text' field for 'message'"),
+            (json!({"text": "hello", "mediaType": "aW1hZ2UvcG5n"}), "Invalid 'text' field for 'message'"),
+            (json!({"text": 5, "mediaType": "dGV4dC9wbGFpbg=="}), "Invalid 'text' field for 'message'"),
+            (json!({"text": "hello", "data": "aGVsbG8=", "mediaType": "dGV4dC9wbGFpbg=="}), "Cannot have both 'data' and 'text' fields for 'message'"),
+        ];
+
+        for (json, expected) in invalid.iter() {
+            assert_eq!(Message::from_json(json).unwrap_err().to_string(), *expected);
+        }
+    }
+
+    #[test]
+    fn test_message_invalid_base64() {
+        let invalid = [
+            (json!({"data": "ZGF0YQ="}), "Invalid 'data' field for 'message'"),
+            (json!({"data": "not base64!"}), "Invalid 'data' field for 'message'"),
+            (json!({"mediaType": "dGV4dC9wbGFpbg=="}), ""),
+            (json!({"signature": "c2lnbmF0dXJl*"}), "Invalid 'signature' field for 'message'"),
+        ];
+
+        for (json, expected) in invalid.iter() {
+            match Message::from_json(json) {
+                Ok(_) => assert!(expected.is_empty(), "{}", json),
+                Err(e) => assert_eq!(e.to_string(), *expected),
+            }
+        }
+    }
+
+    #[test]
+    fn test_reaction_from_json() {
+        let json = [
+            json!({
+                "message": 1,
+                "emoji": "👍",
+                "sender": "1@example.com",
+            }),
+            json!({}),
+        ];
+
+        let reactions = [
+            Reaction::from_json(&json[0]).unwrap(),
+            Reaction::from_json(&json[1]).unwrap(),
+        ];
+
+        assert_eq!(reactions[0].message, Some(1));
+        assert_eq!(reactions[0].emoji, Some(String::from("👍")));
+        assert_eq!(reactions[0].sender, Some(String::from("1@example.com")));
+
+        assert_eq!(reactions[1].message, None);
+        assert_eq!(reactions[1].emoji, None);
+        assert_eq!(reactions[1].sender, None);
+    }
+
+    #[test]
+    fn test_invitation_from_json() {
+        let json = [
+            json!({
+                "id": 1,
+                "conversation": 2,
+                "inviter": "1@example.com",
+                "status": "accepted",
+            }),
+            json!({}),
+        ];
+
+        let invitations = [
+            Invitation::from_json(&json[0]).unwrap(),
+            Invitation::from_json(&json[1]).unwrap(),
+        ];
+
+        assert_eq!(invitations[0].id, Some(1));
+        assert_eq!(invitations[0].conversation, Some(2));
+        assert_eq!(invitations[0].inviter, Some(String::from("1@example.com")));
+        assert_eq!(invitations[0].status, Some(String::from("accepted")));
+
+        assert_eq!(invitations[1].id, None);
+        assert_eq!(invitations[1].conversation, None);
+        assert_eq!(invitations[1].inviter, None);
+        assert_eq!(invitations[1].status, None);
+    }
+
+    #[test]
+    fn test_attachment_from_json() {
+        let json = [
+            json!({
+                "id": 1,
+                "conversation": 2,
+                "mediaType": "aW1hZ2UvcG5n",
+                "data": "ZGF0YQ==",
+            }),
+            json!({}),
+        ];
+
+        let attachments = [
+            Attachment::from_json(&json[0]).unwrap(),
+            Attachment::from_json(&json[1]).unwrap(),
+        ];
+
+        assert_eq!(attachments[0].id, Some(1));
+        assert_eq!(attachments[0].conversation, Some(2));
+        assert_eq!(attachments[0].media_type, Some(String::from("image/png").into_bytes()));
+        assert_eq!(attachments[0].data, Some(String::from("data").into_bytes()));
+
+        assert_eq!(attachments[1].id, None);
+        assert_eq!(attachments[1].conversation, None);
+        assert_eq!(attachments[1].media_type, None);
+        assert_eq!(attachments[1].data, None);
+
+        // Finished uploads become attachments
+        let finished = Attachment::from_json(&json!({"upload": 4, "sha256": "ZGF0YQ=="})).unwrap();
+        assert_eq!(finished.upload, Some(4));
+        assert_eq!(finished.sha256, Some(String::from("data").into_bytes()));
+
+        // Messages refer to attachments by id
+        let message = Message::from_json(&json!({"attachment": {"id": 3}})).unwrap();
+        assert_eq!(message.attachment.unwrap().id, Some(3));
+    }
+
+    #[test]
+    fn test_upload_from_json() {
+        let json = [
+            json!({
+                "id": 1,
+                "conversation": 2,
+                "mediaType": "aW1hZ2UvcG5n",
+                "size": 4,
+                "index": 0,
+                "data": "ZGF0YQ==",
+            }),
+            json!({}),
+        ];
+
+        let uploads = [
+            Upload::from_json(&json[0]).unwrap(),
+            Upload::from_json(&json[1]).unwrap(),
+        ];
+
+        assert_eq!(uploads[0].id, Some(1));
+        assert_eq!(uploads[0].conversation, Some(2));
+        assert_eq!(uploads[0].media_type, Some(String::from("image/png").into_bytes()));
+        assert_eq!(uploads[0].size, Some(4));
+        assert_eq!(uploads[0].index, Some(0));
+        assert_eq!(uploads[0].data, Some(String::from("data").into_bytes()));
+
+        assert_eq!(uploads[1].id, None);
+        assert_eq!(uploads[1].conversation, None);
+        assert_eq!(uploads[1].media_type, None);
+        assert_eq!(uploads[1].size, None);
+        assert_eq!(uploads[1].index, None);
+        assert_eq!(uploads[1].data, None);
     }
 
     #[test]
@@ -191,6 +897,11 @@ mod tests {
             json!({
                 "id": 1,
                 "name": "Example Conversation",
+                "direct": true,
+                "public": false,
+                "lastReadMessageId": 5,
+                "retentionSeconds": 86400,
+                "archived": true,
             }),
             json!({}),
         ];
@@ -200,10 +911,46 @@ mod tests {
             Conversation::from_json(&json[1]).unwrap(),
         ];
 
+        assert!(Conversation::from_json(&json!({"retentionSeconds": -1})).is_err());
+
         assert_eq!(conversations[0].id, Some(1));
         assert_eq!(conversations[0].name, Some(String::from("Example Conversation")));
+        assert_eq!(conversations[0].direct, Some(true));
+        assert_eq!(conversations[0].public, Some(false));
+        assert_eq!(conversations[0].last_read_message_id, Some(5));
+        assert_eq!(conversations[0].retention_seconds, Some(86400));
+        assert_eq!(conversations[0].archived, Some(true));
 
         assert_eq!(conversations[1].id, None);
         assert_eq!(conversations[1].name, None);
+        assert_eq!(conversations[1].direct, None);
+        assert_eq!(conversations[1].public, None);
+        assert_eq!(conversations[1].last_read_message_id, None);
+        assert_eq!(conversations[1].retention_seconds, None);
+        assert_eq!(conversations[1].archived, None);
+    }
+
+    #[test]
+    fn test_direct_key() {
+        assert_eq!(
+            Conversation::direct_key("1@example.com", "2@example.com"),
+            Conversation::direct_key("2@example.com", "1@example.com"),
+        );
+        assert_ne!(
+            Conversation::direct_key("1@example.com", "2@example.com"),
+            Conversation::direct_key("1@example.com", "3@example.com"),
+        );
+    }
+
+    #[test]
+    fn test_cursor() {
+        let cursor = Cursor{ key: 42, id: 7 };
+        let parsed: Cursor = cursor.to_string().parse().unwrap();
+
+        assert_eq!(cursor.to_string(), "42:7");
+        assert_eq!(parsed, cursor);
+
+        assert!("42".parse::<Cursor>().is_err());
+        assert!("a:b".parse::<Cursor>().is_err());
     }
 }
\ No newline at end of file
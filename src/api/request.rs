@@ -1,14 +1,431 @@
 use crate::api;
-use crate::auth::{Login, Password};
-use crate::api::ApiObject;
-use crate::api::response::Response;
+use crate::audit;
+use crate::database;
+use crate::encoding;
+use crate::settings::{self, MediaAllowlist, MessageConfig};
+use crate::storage::{MessageQuery, NewConversation, NewMessage, NewMessages, NewUser, PgStorage, RetryStorage, Storage, StoredMessageId};
+#[cfg(feature = "sqlite")]
+use crate::storage::SqliteStorage;
+use crate::auth::{Lockout, Login, Password, PasswordPolicy, LOOKUPS};
+use crate::auth::signature;
+use crate::api::{ApiObject, ScrubbedValue};
+use crate::api::response::{self, Response, STATUS_BUSY, STATUS_CONFLICT, STATUS_FAILURE, STATUS_NOT_FOUND, STATUS_SUCCESS, STATUS_TIMED_OUT};
 
+use std::collections::HashMap;
 use std::error::Error;
+use std::future::Future;
 use std::io::Error as ioErr;
 use std::io::ErrorKind as ioErrKind;
-use api::{Conversation, Message, User};
+use std::str;
+use std::time::Instant;
+use api::{Attachment, Conversation, Invitation, Message, PreviousKey, Reaction, Upload, User};
+use async_std::channel::{self, Sender};
+use async_std::stream::StreamExt;
+use async_std::task;
+use chrono::{DateTime, Duration, SecondsFormat, Utc};
+use log::error;
+use serde::Deserialize;
 use serde_json::Value;
+use sha2::{Digest, Sha256};
 use sqlx::PgPool;
+#[cfg(feature = "sqlite")]
+use sqlx::SqlitePool;
+use zeroize::Zeroizing;
+
+/// The number of results returned per page when a request doesn't specify one
+const DEFAULT_PAGE_SIZE: i64 = 50;
+/// The largest number of results that can be returned per page, unless configured otherwise
+const DEFAULT_MAX_PAGE_SIZE: i64 = 200;
+/// The largest number of requests that can be sent together in a batch, unless configured otherwise
+const DEFAULT_MAX_BATCH_SIZE: usize = 20;
+/// The longest idempotency key a client can attach to a message
+const MAX_IDEMPOTENCY_KEY_LENGTH: usize = 64;
+/// The largest number of participants (including the creator) a conversation can start with
+const DEFAULT_MAX_PARTICIPANTS: usize = 256;
+/// The role of a participant who manages a conversation
+pub(crate) const ROLE_ADMIN: &str = "admin";
+/// The role of any other participant in a conversation
+pub(crate) const ROLE_MEMBER: &str = "member";
+/// The longest display name a user can have
+const MAX_DISPLAY_NAME_LENGTH: usize = 32;
+/// The longest avatar URL a user can have
+const MAX_AVATAR_URL_LENGTH: usize = 256;
+/// The longest conversation name (in characters) that fits in the conversations table
+const MAX_CONVERSATION_NAME_LENGTH: usize = 50;
+/// The status of an invitation that the invitee accepted
+const INVITATION_ACCEPTED: &str = "accepted";
+/// The status of an invitation that the invitee declined
+const INVITATION_DECLINED: &str = "declined";
+/// The longest emoji (in bytes) that can be used as a reaction
+const MAX_EMOJI_LENGTH: usize = 32;
+/// The largest attachment (in bytes) that can be uploaded, unless configured otherwise
+const DEFAULT_MAX_ATTACHMENT_SIZE: usize = 1048576;
+/// The largest attachment (in bytes) that can be uploaded in chunks, unless configured otherwise
+const DEFAULT_MAX_UPLOAD_SIZE: usize = 104857600;
+/// The number of seconds a message's timestamp can be ahead of the server's clock if none is configured
+const DEFAULT_MAX_CLOCK_SKEW: i64 = 300;
+/// The number of earlier versions kept for each edited message, unless configured otherwise
+const DEFAULT_MAX_REVISIONS: i64 = 10;
+/// The number of seconds a request can take before it's abandoned, unless configured otherwise
+const DEFAULT_REQUEST_TIMEOUT: u64 = 30;
+/// The number of seconds a streamed read or chunked upload can take before it's abandoned, unless configured otherwise
+const DEFAULT_LONG_REQUEST_TIMEOUT: u64 = 300;
+
+/// Lowercase and deduplicate invited emails, leaving out the creator (who is added separately)
+///
+/// Returns the remaining invitees and the emails that were collapsed or left out
+fn normalize_invitees(emails: Vec<String>, creator: &str) -> (Vec<String>, Vec<String>) {
+    let creator = creator.to_lowercase();
+    let mut invitees: Vec<String> = Vec::new();
+    let mut duplicates: Vec<String> = Vec::new();
+
+    for email in emails {
+        let normalized = email.to_lowercase();
+
+        match normalized == creator || invitees.contains(&normalized) {
+            true => duplicates.push(email),
+            false => invitees.push(normalized),
+        }
+    }
+
+    (invitees, duplicates)
+}
+
+/// Check that a conversation with the creator and invitees doesn't exceed the participant limit
+///
+/// Conversations with no invitees are allowed, so users can keep notes to themselves.
+fn check_participant_count(invitees: usize, max: usize) -> Result<(), Box<dyn Error>> {
+    match invitees + 1 > max {
+        true => Err(Box::new(ioErr::new(ioErrKind::InvalidInput, format!("Too many participants (maximum {})", max)))),
+        false => Ok(()),
+    }
+}
+
+/// Salt and hash a batch of passwords in order, scrubbing the plaintexts once they're done
+///
+/// Errors are returned as strings so the hashing can run on another thread.
+fn hash_passwords(passwords: Vec<Zeroizing<String>>) -> Result<Vec<Password>, String> {
+    passwords
+        .iter()
+        .map(|p| Password::hash(p, None).map_err(|e| e.to_string()))
+        .collect()
+}
+
+/// Match the users a batch created back to the emails that were sent, giving each one's id if it was created
+///
+/// An email that was already registered, or that appears earlier in the same batch, wasn't created.
+fn match_created_users(emails: &[String], created: Vec<(i32, String)>) -> Vec<Option<i32>> {
+    let mut created: HashMap<String, i32> = created
+        .into_iter()
+        .map(|(id, email)| (email, id))
+        .collect();
+
+    emails
+        .iter()
+        .map(|email| created.remove(email))
+        .collect()
+}
+
+/// Check that a role is one a participant can hold
+fn check_role(role: &str) -> Result<(), Box<dyn Error>> {
+    match role == ROLE_ADMIN || role == ROLE_MEMBER {
+        true => Ok(()),
+        false => Err(Box::new(ioErr::new(ioErrKind::InvalidInput, "Invalid 'role' field"))),
+    }
+}
+
+/// Check that a user's profile fields are within their length limits
+fn check_profile(user: &User) -> Result<(), Box<dyn Error>> {
+    if user.display_name.as_ref().map_or(false, |n| n.chars().count() > MAX_DISPLAY_NAME_LENGTH) {
+        return Err(Box::new(ioErr::new(ioErrKind::InvalidInput, "Invalid 'display_name' field for 'user'")));
+    }
+
+    if user.avatar_url.as_ref().map_or(false, |u| u.len() > MAX_AVATAR_URL_LENGTH) {
+        return Err(Box::new(ioErr::new(ioErrKind::InvalidInput, "Invalid 'avatar_url' field for 'user'")));
+    }
+
+    Ok(())
+}
+
+/// Check that a conversation name fits in the database, so an overlong name fails cleanly instead of
+/// with a database error
+///
+/// Names arrive as JSON strings, so they are always valid UTF-8 by this point.
+fn check_conversation_name(name: &str) -> Result<(), Box<dyn Error>> {
+    match name.chars().count() > MAX_CONVERSATION_NAME_LENGTH {
+        true => Err(Box::new(ioErr::new(ioErrKind::InvalidInput, format!("Invalid 'name' field for 'conversation' (maximum {} characters)", MAX_CONVERSATION_NAME_LENGTH)))),
+        false => Ok(()),
+    }
+}
+
+/// Check that a media type is a short string in the form 'type/subtype'
+fn check_media_type(media_type: &[u8]) -> Result<(), Box<dyn Error>> {
+    match api::is_media_type(media_type) {
+        true => Ok(()),
+        false => Err(Box::new(ioErr::new(ioErrKind::InvalidInput, "Invalid 'media_type' field for 'message'"))),
+    }
+}
+
+/// Check that a message's media type is one the server is configured to accept
+fn check_allowed_media_type(media_type: &[u8], allowlist: &MediaAllowlist) -> Result<(), Box<dyn Error>> {
+    match allowlist.allows(media_type) {
+        true => Ok(()),
+        false => Err(Box::new(ioErr::new(ioErrKind::InvalidInput, "Invalid 'media_type' field for 'message' (media type is not allowed)"))),
+    }
+}
+
+/// Check that an update or delete by id matched something, passing on the number of rows it affected
+fn check_affected(rows_affected: u64, missing: &str) -> Result<u64, Box<dyn Error>> {
+    match rows_affected {
+        0 => Err(Box::new(ioErr::new(ioErrKind::NotFound, missing))),
+        n => Ok(n),
+    }
+}
+
+/// Check that a message's timestamp (in milliseconds since the Unix epoch) is valid and isn't further ahead of the
+/// server's clock than the allowed skew, returning it as a date and time
+fn check_timestamp(timestamp: i64, now: DateTime<Utc>, max_skew: Duration) -> Result<DateTime<Utc>, Box<dyn Error>> {
+    let timestamp = api::from_epoch_millis(timestamp)
+        .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Invalid 'timestamp' field for 'message'"))?;
+
+    match timestamp > now + max_skew {
+        true => Err(Box::new(ioErr::new(ioErrKind::InvalidInput, "Invalid 'timestamp' field for 'message' (too far in the future)"))),
+        false => Ok(timestamp),
+    }
+}
+
+/// Check that an attachment was uploaded to the conversation a message is being sent to
+///
+/// The attachment's conversation is None if the attachment doesn't exist.
+fn check_attachment(attachment_conversation: Option<i32>, conversation_id: i32) -> Result<(), Box<dyn Error>> {
+    match attachment_conversation == Some(conversation_id) {
+        true => Ok(()),
+        false => Err(Box::new(ioErr::new(ioErrKind::InvalidInput, "Invalid 'attachment' field for 'message'"))),
+    }
+}
+
+/// Check that an attachment isn't larger than the size limit
+fn check_attachment_size(size: usize, max: usize) -> Result<(), Box<dyn Error>> {
+    match size > max {
+        true => Err(Box::new(ioErr::new(ioErrKind::InvalidInput, format!("Attachment is too large (maximum {} bytes)", max)))),
+        false => Ok(()),
+    }
+}
+
+/// Check that a chunk of an upload is the next one expected and doesn't overrun the declared size
+fn check_chunk(next_index: i32, received: i32, size: i32, index: i32, chunk_size: usize) -> Result<(), Box<dyn Error>> {
+    if index != next_index {
+        return Err(Box::new(ioErr::new(ioErrKind::InvalidInput, format!("Expected chunk {} of upload", next_index))));
+    }
+
+    if received as usize + chunk_size > size as usize {
+        return Err(Box::new(ioErr::new(ioErrKind::InvalidInput, "Upload is larger than its declared size")));
+    }
+
+    Ok(())
+}
+
+/// Check that an upload was received in full and matches the checksum the client gave
+fn check_upload(size: i32, data: &[u8], sha256: &[u8]) -> Result<(), Box<dyn Error>> {
+    if data.len() != size as usize {
+        return Err(Box::new(ioErr::new(ioErrKind::InvalidInput, "Upload is incomplete")));
+    }
+
+    if Sha256::digest(data).as_slice() != sha256 {
+        return Err(Box::new(ioErr::new(ioErrKind::InvalidInput, "Invalid 'sha256' field for 'attachment'")));
+    }
+
+    Ok(())
+}
+
+/// Check that a user can see a message's earlier versions, which only its sender and the
+/// conversation's admins can
+fn check_revision_access(is_sender: bool, is_admin: bool) -> Result<(), Box<dyn Error>> {
+    match is_sender || is_admin {
+        true => Ok(()),
+        false => Err(Box::new(ioErr::new(ioErrKind::PermissionDenied, "Not the sender of message or an admin of conversation"))),
+    }
+}
+
+/// Check that a message's data matches its media type, naming the message by its position in the request
+fn check_message_content(index: usize, message: &Message) -> Result<(), Box<dyn Error>> {
+    match (&message.media_type, &message.data) {
+        (Some(media_type), Some(data)) => api::check_content(media_type, data)
+            .map_err(|reason| ioErr::new(ioErrKind::InvalidInput, format!("Invalid 'data' field for message {} ({})", index, reason)).into()),
+        _ => Ok(()),
+    }
+}
+
+/// Check that a reply is in the same conversation as the message it replies to
+///
+/// The parent's conversation is None if the parent message doesn't exist.
+fn check_parent(parent_conversation: Option<i32>, conversation_id: i32) -> Result<(), Box<dyn Error>> {
+    match parent_conversation == Some(conversation_id) {
+        true => Ok(()),
+        false => Err(Box::new(ioErr::new(ioErrKind::InvalidInput, "Invalid 'parent_id' field for 'message'"))),
+    }
+}
+
+/// Check that a read pointer is moving forward (or staying put), given the sequence numbers of
+/// the message currently read up to and the message being read up to
+fn check_read_pointer(current_seq: Option<i32>, seq: i32) -> Result<(), Box<dyn Error>> {
+    match current_seq.map_or(false, |c| c > seq) {
+        true => Err(Box::new(ioErr::new(ioErrKind::InvalidInput, "Read pointer can't move backward"))),
+        false => Ok(()),
+    }
+}
+
+/// Get the text of a message that should be indexed for searching
+///
+/// Only plain text messages are indexed, so encrypted or binary data never matches a search.
+fn searchable_text<'a>(media_type: &[u8], data: &'a [u8]) -> Option<&'a str> {
+    match api::is_text(media_type) {
+        true => str::from_utf8(data).ok(),
+        false => None,
+    }
+}
+
+/// Get a short preview of a text message, which may have been cut off partway through a character
+fn preview_text(media_type: &[u8], data: &[u8], length: usize) -> Option<String> {
+    if !api::is_text(media_type) {
+        return None;
+    }
+
+    let text = match str::from_utf8(data) {
+        Ok(t) => t,
+        // Only drop a character that was cut off at the end, not invalid data
+        Err(e) if e.error_len().is_none() => str::from_utf8(&data[..e.valid_up_to()]).ok()?,
+        Err(_) => return None,
+    };
+
+    Some(text.chars().take(length).collect())
+}
+
+/// Check that a message can be stored in a conversation, without storing it
+///
+/// The message's data is left as it was sent, without a preview, so it's still to be prepared for storage.
+async fn validate_message(message: Message, conversation_id: i32, public_key: Option<&[u8]>, max_skew: Duration, storage: &dyn Storage) -> Result<NewMessage, Box<dyn Error>> {
+    let data = message.data
+        .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'data' field for 'message'"))?;
+    let media_type = message.media_type
+        .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'media_type' field for 'message'"))?;
+    let timestamp = message.timestamp
+        .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'timestamp' field for 'message'"))?;
+    let signature = message.signature
+        .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'signature' field for 'message'"))?;
+    let idempotency_key = message.idempotency_key;
+
+    check_media_type(&media_type)?;
+    check_allowed_media_type(&media_type, settings::media_allowlist())?;
+
+    // Text has to be valid UTF-8 whether or not other content is validated
+    if api::is_text(&media_type) {
+        api::check_content(&media_type, &data)
+            .map_err(|reason| ioErr::new(ioErrKind::InvalidInput, format!("Invalid 'data' field for 'message' ({})", reason)))?;
+    }
+
+    if let Some(key) = &idempotency_key {
+        if key.is_empty() || key.len() > MAX_IDEMPOTENCY_KEY_LENGTH {
+            return Err(Box::new(ioErr::new(ioErrKind::InvalidInput, "Invalid 'idempotency_key' field for 'message'")));
+        }
+    }
+
+    let sent_at = check_timestamp(timestamp, Utc::now(), max_skew)?;
+
+    let attachment_id = match &message.attachment {
+        Some(attachment) => {
+            let id = attachment.id
+                .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'id' field for 'attachment'"))?;
+            check_attachment(storage.attachment_conversation(id).await?, conversation_id)?;
+            Some(id)
+        },
+        None => None,
+    };
+
+    if let Some(public_key) = public_key {
+        let sent_at = sent_at.to_rfc3339_opts(SecondsFormat::Millis, true);
+        let signed = signature::signed_bytes(&data, &media_type, sent_at.as_bytes(), conversation_id);
+        signature::verify(public_key, &signature, &signed)?;
+    }
+
+    if let Some(parent_id) = message.parent_id {
+        check_parent(storage.message_conversation(parent_id).await?, conversation_id)?;
+    }
+
+    let search = searchable_text(&media_type, &data).map(String::from);
+
+    Ok(NewMessage{
+        data,
+        data_encoding: None,
+        preview: None,
+        media_type,
+        timestamp,
+        signature,
+        idempotency_key,
+        search,
+        parent_id: message.parent_id,
+        attachment_id,
+    })
+}
+
+/// Cut rows fetched with one extra row down to a page, returning whether another page exists
+fn truncate_page<T>(rows: &mut Vec<T>, limit: i64) -> bool {
+    let has_more = rows.len() as i64 > limit;
+    rows.truncate(limit.max(0) as usize);
+    has_more
+}
+
+/// Parse a list of objects sent in a request, which can be left out but has to be a list of objects if it's given
+fn parse_list<T: ApiObject>(value: Option<&Value>, field: &str) -> Result<Option<Vec<T>>, Box<dyn Error>> {
+    match value {
+        None | Some(Value::Null) => Ok(None),
+        Some(Value::Array(items)) => items
+            .iter()
+            .map(|item| match item {
+                // Errors that aren't already described (such as numbers out of range) are put down to the item
+                Value::Object(_) => T::from_json(item).map_err(|e| -> Box<dyn Error> {
+                    match e.is::<ioErr>() {
+                        true => e,
+                        false => Box::new(ioErr::new(ioErrKind::InvalidInput, format!("Invalid item in '{}' list ({})", field, e))),
+                    }
+                }),
+                _ => Err(Box::new(ioErr::new(ioErrKind::InvalidInput, format!("Invalid item in '{}' list (must be an object)", field)))),
+            })
+            .collect::<Result<Vec<T>, Box<dyn Error>>>()
+            .map(Some),
+        Some(_) => Err(Box::new(ioErr::new(ioErrKind::InvalidInput, format!("Invalid '{}' field (must be a list)", field)))),
+    }
+}
+
+/// Check a user's password the way logging in does, so it can't be guessed any faster by re-entering it elsewhere
+///
+/// Unknown users are checked against a dummy hash, locked accounts are checked anyway, and every check writes once (a
+/// failure for an unknown user updates nothing), so neither the answer nor how long it takes gives away whether the
+/// account exists or is locked. The plaintext is scrubbed as soon as it's been checked.
+async fn check_password(email: &str, password: Zeroizing<String>, storage: &dyn Storage) -> Result<(), Box<dyn Error>> {
+    let lockout = Lockout::from_env()?;
+    let stored = storage.get_user_by_email(email).await?;
+
+    let is_valid = match &stored {
+        Some(stored) => stored.password.is_valid(&password)? && !lockout.is_locked(stored.locked_until, Utc::now()),
+        None => {
+            Password::check_dummy(&password);
+            false
+        },
+    };
+    drop(password);
+
+    // Count failures in a row, starting again after a success
+    match is_valid {
+        true => storage.reset_failed_logins(email).await?,
+        false => {
+            storage.add_failed_login(email, &lockout).await?;
+            return Err(Box::new(ioErr::new(ioErrKind::PermissionDenied, "Invalid password")));
+        },
+    };
+
+    Ok(())
+}
 
 /// An action that a request wants to take
 #[derive(Debug, PartialEq)]
@@ -26,6 +443,43 @@ pub enum Target {
     Conversations,
     Messages,
     Users,
+    Blocks,
+    Reactions,
+    Invitations,
+    Participants,
+    Attachments,
+    Uploads,
+    Revisions,
+    Unread,
+}
+
+/// The structure of a request as sent by a client, before its contents are interpreted
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+struct RequestData {
+    function: String,
+    /// Users can carry passwords, which are scrubbed along with the rest of the request
+    users: Option<ScrubbedValue>,
+    messages: Option<Value>,
+    conversations: Option<Value>,
+    reactions: Option<Value>,
+    invitations: Option<Value>,
+    attachments: Option<Value>,
+    uploads: Option<Value>,
+    cursor: Option<String>,
+    limit: Option<i64>,
+    offset: Option<i64>,
+    after_id: Option<i32>,
+    before_id: Option<i32>,
+    parent_id: Option<i32>,
+    exclude_self: Option<bool>,
+    purge: Option<bool>,
+    include_archived: Option<bool>,
+    stream: Option<bool>,
+    role: Option<String>,
+    since: Option<String>,
+    until: Option<String>,
+    query: Option<String>,
 }
 
 /// A request sent by a client
@@ -36,6 +490,204 @@ pub struct Request {
     users: Option<Vec<api::User>>,
     messages: Option<Vec<api::Message>>,
     conversations: Option<Vec<api::Conversation>>,
+    reactions: Option<Vec<api::Reaction>>,
+    invitations: Option<Vec<api::Invitation>>,
+    attachments: Option<Vec<api::Attachment>>,
+    uploads: Option<Vec<api::Upload>>,
+    cursor: Option<api::Cursor>,
+    limit: Option<i64>,
+    offset: Option<i64>,
+    after_id: Option<i32>,
+    before_id: Option<i32>,
+    parent_id: Option<i32>,
+    exclude_self: bool,
+    purge: bool,
+    include_archived: bool,
+    stream: bool,
+    role: Option<String>,
+    since: Option<DateTime<Utc>>,
+    until: Option<DateTime<Utc>>,
+    query: Option<String>,
+    message_config: Option<MessageConfig>,
+}
+
+/// Builds a request field by field, for callers that don't start from JSON
+///
+/// Each setter takes either a value or an `Option`, so fields can be left out or passed on as they are.
+pub struct RequestBuilder {
+    request: Request,
+}
+
+impl RequestBuilder {
+    pub fn users(mut self, users: impl Into<Option<Vec<api::User>>>) -> Self {
+        self.request.users = users.into();
+        self
+    }
+
+    pub fn messages(mut self, messages: impl Into<Option<Vec<api::Message>>>) -> Self {
+        self.request.messages = messages.into();
+        self
+    }
+
+    pub fn conversations(mut self, conversations: impl Into<Option<Vec<api::Conversation>>>) -> Self {
+        self.request.conversations = conversations.into();
+        self
+    }
+
+    pub fn reactions(mut self, reactions: impl Into<Option<Vec<api::Reaction>>>) -> Self {
+        self.request.reactions = reactions.into();
+        self
+    }
+
+    pub fn invitations(mut self, invitations: impl Into<Option<Vec<api::Invitation>>>) -> Self {
+        self.request.invitations = invitations.into();
+        self
+    }
+
+    pub fn attachments(mut self, attachments: impl Into<Option<Vec<api::Attachment>>>) -> Self {
+        self.request.attachments = attachments.into();
+        self
+    }
+
+    pub fn uploads(mut self, uploads: impl Into<Option<Vec<api::Upload>>>) -> Self {
+        self.request.uploads = uploads.into();
+        self
+    }
+
+    pub fn cursor(mut self, cursor: impl Into<Option<api::Cursor>>) -> Self {
+        self.request.cursor = cursor.into();
+        self
+    }
+
+    pub fn limit(mut self, limit: impl Into<Option<i64>>) -> Self {
+        self.request.limit = limit.into();
+        self
+    }
+
+    pub fn offset(mut self, offset: impl Into<Option<i64>>) -> Self {
+        self.request.offset = offset.into();
+        self
+    }
+
+    pub fn after_id(mut self, after_id: impl Into<Option<i32>>) -> Self {
+        self.request.after_id = after_id.into();
+        self
+    }
+
+    pub fn before_id(mut self, before_id: impl Into<Option<i32>>) -> Self {
+        self.request.before_id = before_id.into();
+        self
+    }
+
+    pub fn parent_id(mut self, parent_id: impl Into<Option<i32>>) -> Self {
+        self.request.parent_id = parent_id.into();
+        self
+    }
+
+    pub fn since(mut self, since: impl Into<Option<DateTime<Utc>>>) -> Self {
+        self.request.since = since.into();
+        self
+    }
+
+    pub fn until(mut self, until: impl Into<Option<DateTime<Utc>>>) -> Self {
+        self.request.until = until.into();
+        self
+    }
+
+    pub fn role(mut self, role: impl Into<Option<String>>) -> Self {
+        self.request.role = role.into();
+        self
+    }
+
+    pub fn query(mut self, query: impl Into<Option<String>>) -> Self {
+        self.request.query = query.into();
+        self
+    }
+
+    pub fn exclude_self(mut self, exclude_self: bool) -> Self {
+        self.request.exclude_self = exclude_self;
+        self
+    }
+
+    pub fn purge(mut self, purge: bool) -> Self {
+        self.request.purge = purge;
+        self
+    }
+
+    pub fn include_archived(mut self, include_archived: bool) -> Self {
+        self.request.include_archived = include_archived;
+        self
+    }
+
+    pub fn stream(mut self, stream: bool) -> Self {
+        self.request.stream = stream;
+        self
+    }
+
+    /// Use these message settings rather than reading them from the environment
+    pub fn message_config(mut self, message_config: impl Into<Option<MessageConfig>>) -> Self {
+        self.request.message_config = message_config.into();
+        self
+    }
+
+    /// Finish building the request
+    pub fn build(self) -> Request {
+        self.request
+    }
+}
+
+/// Give up on a request that takes longer than `limit`, e.g. because it's waiting on a stuck database connection
+///
+/// The request's future is dropped, so anything it left unfinished (such as a transaction) is rolled back.
+async fn with_timeout<T, F>(limit: std::time::Duration, future: F) -> Result<T, Box<dyn Error>>
+where
+    F: Future<Output = Result<T, Box<dyn Error>>>,
+{
+    match async_std::future::timeout(limit, future).await {
+        Ok(result) => result,
+        Err(_) => Err(Box::new(ioErr::new(ioErrKind::TimedOut, "Request timed out"))),
+    }
+}
+
+/// Record a handled request in the audit log if it's security-sensitive
+///
+/// A failure to audit is logged rather than failing a request that has already been handled.
+async fn record_audit(action: Option<&'static str>, actor: Option<String>, target: Option<String>, status: u8, db_pool: &PgPool) {
+    if let Some(entry) = audit::entry_for(action, actor, target, status) {
+        if let Err(e) = audit::record(&entry, db_pool).await {
+            error!("Could not record audit entry: {}", e);
+        }
+    }
+}
+
+/// Record a handled request in the audit log of a SQLite database if it's security-sensitive
+#[cfg(feature = "sqlite")]
+async fn record_sqlite_audit(action: Option<&'static str>, actor: Option<String>, target: Option<String>, status: u8, db_pool: &SqlitePool) {
+    if let Some(entry) = audit::entry_for(action, actor, target, status) {
+        if let Err(e) = audit::record_sqlite(&entry, db_pool).await {
+            error!("Could not record audit entry: {}", e);
+        }
+    }
+}
+
+/// Turn an error into one that can be held across an await
+///
+/// `Box<dyn Error>` isn't `Send`, so holding one across an await stops a handler from running on a task that moves
+/// between threads. Database errors and invalid signatures are kept as they are, and anything else keeps its kind and
+/// message, so the error is still described to the client the same way.
+fn into_send(error: Box<dyn Error>) -> Box<dyn Error + Send + Sync> {
+    let error = match error.downcast::<sqlx::Error>() {
+        Ok(e) => return e,
+        Err(e) => e,
+    };
+
+    let error = match error.downcast::<signature::InvalidSignature>() {
+        Ok(e) => return e,
+        Err(e) => e,
+    };
+
+    let kind = error.downcast_ref::<ioErr>().map_or(ioErrKind::Other, |e| e.kind());
+    Box::new(ioErr::new(kind, error.to_string()))
 }
 
 impl Request {
@@ -52,381 +704,4172 @@ impl Request {
         Ok((split_func[0].to_owned(), split_func[1].to_owned()))
     }
 
+    /// Get the number of results to return per page
+    fn page_size(&self) -> Result<i64, Box<dyn Error>> {
+        let max = settings::get_value("MAX_PAGE_SIZE", DEFAULT_MAX_PAGE_SIZE)?;
+
+        Ok(self.limit
+            .unwrap_or(DEFAULT_PAGE_SIZE)
+            .clamp(1, max.max(1)))
+    }
+
     /// Create a request object from JSON
     pub fn from_json(data: &str) -> Result<Self, Box<dyn Error>> {
-        let data: Value = serde_json::from_str(data)?;
-
-        let (operation, target) = Request::split_function(data["function"].as_str()
-            .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Invalid request function"))?)?;
-
-        let request = Self{
-            operation: match operation.to_uppercase().as_ref() {
-                "VERIFY" => Operation::Verify,
-                "CREATE" => Operation::Create,
-                "READ" => Operation::Read,
-                "UPDATE" => Operation::Update,
-                "DELETE" => Operation::Delete,
-                _ => return Err(Box::new(ioErr::new(ioErrKind::InvalidInput, "Unknown request"))),
-            },
-            target: match target.to_uppercase().as_ref() {
-                "CONVERSATIONS" => Target::Conversations,
-                "MESSAGES" => Target::Messages,
-                "USERS" => Target::Users,
-                _ => return Err(Box::new(ioErr::new(ioErrKind::InvalidInput, "Unknown target"))),
-            },
-            users: match data["users"].as_array() {
-                Some(d) => {
-                    let users = d
-                        .iter()
-                        .flat_map(|item| api::User::from_json(item))
-                        .collect();
-                    Some(users)
-                },
+        let data: RequestData = serde_json::from_str(data)
+            .map_err(|e| ioErr::new(ioErrKind::InvalidInput, format!("Malformed request: {}", e)))?;
+
+        let (operation, target) = Request::split_function(&data.function)?;
+
+        let operation = match operation.to_uppercase().as_ref() {
+            "VERIFY" => Operation::Verify,
+            "CREATE" => Operation::Create,
+            "READ" => Operation::Read,
+            "UPDATE" => Operation::Update,
+            "DELETE" => Operation::Delete,
+            _ => return Err(Box::new(ioErr::new(ioErrKind::InvalidInput, "Unknown request"))),
+        };
+        let target = match target.to_uppercase().as_ref() {
+            "CONVERSATIONS" => Target::Conversations,
+            "MESSAGES" => Target::Messages,
+            "USERS" => Target::Users,
+            "BLOCKS" => Target::Blocks,
+            "REACTIONS" => Target::Reactions,
+            "INVITATIONS" => Target::Invitations,
+            "PARTICIPANTS" => Target::Participants,
+            "ATTACHMENTS" => Target::Attachments,
+            "UPLOADS" => Target::Uploads,
+            "REVISIONS" => Target::Revisions,
+            "UNREAD" => Target::Unread,
+            _ => return Err(Box::new(ioErr::new(ioErrKind::InvalidInput, "Unknown target"))),
+        };
+
+        let request = Request::builder(operation, target)
+            .users(parse_list::<api::User>(data.users.as_ref().map(|u| &u.0), "users")?)
+            .messages(parse_list::<api::Message>(data.messages.as_ref(), "messages")?)
+            .conversations(parse_list::<api::Conversation>(data.conversations.as_ref(), "conversations")?)
+            .reactions(parse_list::<api::Reaction>(data.reactions.as_ref(), "reactions")?)
+            .invitations(parse_list::<api::Invitation>(data.invitations.as_ref(), "invitations")?)
+            .attachments(parse_list::<api::Attachment>(data.attachments.as_ref(), "attachments")?)
+            .uploads(parse_list::<api::Upload>(data.uploads.as_ref(), "uploads")?)
+            .cursor(match data.cursor {
+                Some(d) => Some(d.parse::<api::Cursor>()?),
                 None => None,
-            },
-            messages: match data["messages"].as_array() {
-                Some(d) => {
-                    let messages = d
-                        .iter()
-                        .flat_map(|item| api::Message::from_json(item))
-                        .collect();
-                    Some(messages)
-                },
+            })
+            .limit(data.limit)
+            .offset(data.offset)
+            .after_id(data.after_id)
+            .before_id(data.before_id)
+            .parent_id(data.parent_id)
+            .exclude_self(data.exclude_self.unwrap_or(false))
+            .purge(data.purge.unwrap_or(false))
+            .include_archived(data.include_archived.unwrap_or(false))
+            .stream(data.stream.unwrap_or(false))
+            .role(data.role)
+            .since(match data.since {
+                Some(d) => Some(api::parse_timestamp(&d)
+                    .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Invalid 'since' timestamp"))?),
                 None => None,
-            },
-            conversations: match data["conversations"].as_array() {
-                Some(d) => {
-                    let conversations = d
-                        .iter()
-                        .flat_map(|item| api::Conversation::from_json(item))
-                        .collect();
-                    Some(conversations)
-                },
+            })
+            .until(match data.until {
+                Some(d) => Some(api::parse_timestamp(&d)
+                    .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Invalid 'until' timestamp"))?),
                 None => None,
-            },
-        };
+            })
+            .query(data.query)
+            .build();
 
         Ok(request)
     }
 
-    /// Authenticate a user for the duration of the session
-    pub async fn verify_users(self, login: &mut Login, db_pool: &PgPool) -> Result<Response, Box<dyn Error>> {
-        // Read remote data
-        let users = self.users
-            .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'users' list"))?;
-        let user = users[0].clone();
-
-        let email = user.email
-            .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'email' field for 'user'"))?;
-        let remote_pass = user.password
-            .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'password' field for 'user'"))?;
+    /// Create a list of requests from a JSON array of requests, which is rejected as a whole if any request is invalid
+    pub fn batch_from_json(data: &str) -> Result<Vec<Self>, Box<dyn Error>> {
+        let data: Vec<ScrubbedValue> = serde_json::from_str(data)
+            .map_err(|e| ioErr::new(ioErrKind::InvalidInput, format!("Malformed batch: {}", e)))?;
 
-        // Read local data
-        let stream = sqlx::query_file!("src/sql/verify-user.sql", email)
-            .fetch_one(db_pool)
-            .await?;
+        let max_batch_size = settings::get_value("MAX_BATCH_SIZE", DEFAULT_MAX_BATCH_SIZE)?;
 
-        let local_pass = Password{
-            hash: stream.pass,
-            salt: stream.salt
+        match data.len() {
+            0 => return Err(Box::new(ioErr::new(ioErrKind::InvalidInput, "Empty batch"))),
+            n if n > max_batch_size => return Err(Box::new(ioErr::new(ioErrKind::InvalidInput, format!("Batch has more than {} requests", max_batch_size)))),
+            _ => (),
         };
 
-        // Validate password
-        match local_pass.is_valid(&remote_pass)? {
-            true => login.authenticate(email),
-            false => return Err(Box::new(ioErr::new(ioErrKind::PermissionDenied, "Invalid password"))),
-        };
+        data
+            .iter()
+            .enumerate()
+            .map(|(i, item)| -> Result<Self, Box<dyn Error>> {
+                // Each request is parsed again from its own copy, which is scrubbed too
+                let request = Request::from_json(&Zeroizing::new(item.0.to_string()))
+                    .map_err(|e| ioErr::new(ioErrKind::InvalidInput, format!("Invalid request {} in batch: {}", i, e)))?;
 
-        Ok(Response{
-            status: 1,
-            conversations: None,
-            messages: None,
-            users: None,
-        })
+                // Each request in a batch gets exactly one response
+                match request.stream {
+                    true => Err(Box::new(ioErr::new(ioErrKind::InvalidInput, format!("Invalid request {} in batch: Streamed requests can't be batched", i)))),
+                    false => Ok(request),
+                }
+            })
+            .collect()
     }
 
-    /// Add users to the database
-    pub async fn create_users(self, db_pool: &PgPool) -> Result<Response, Box<dyn Error>> {
-        // Authenticate user
-        let users = self.users
-            .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'users' list"))?;
+    /// Start building a request for an operation and target, without going through JSON
+    pub fn builder(operation: Operation, target: Target) -> RequestBuilder {
+        RequestBuilder{
+            request: Request{
+                operation,
+                target,
+                users: None,
+                messages: None,
+                conversations: None,
+                reactions: None,
+                invitations: None,
+                attachments: None,
+                uploads: None,
+                cursor: None,
+                limit: None,
+                offset: None,
+                after_id: None,
+                before_id: None,
+                parent_id: None,
+                exclude_self: false,
+                purge: false,
+                include_archived: false,
+                stream: false,
+                role: None,
+                since: None,
+                until: None,
+                query: None,
+                message_config: None,
+            },
+        }
+    }
 
-        for user in users {
-            // Unpack request
-            let email = user.email
-                .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'email' field for 'user'"))?;
-            let password = user.password
-                .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'password' field for 'user'"))?;
-            let public_key = user.public_key
-                .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'public_key' field for 'user'"))?;
+    /// Handle a request, recording it in the audit log if it's security-sensitive
+    pub async fn handle(self, login: &mut Login, db_pool: &PgPool) -> Result<Response, Box<dyn Error>> {
+        self.handle_routed(login, db_pool, db_pool).await
+    }
 
-            // Salt and hash password
-            let password = Password::hash(&password, Option::None)?;
+    /// Handle a request using `db_pool`, which may be a read-only replica, while recording it in the audit log on the
+    /// `primary` database
+    pub async fn handle_routed(self, login: &mut Login, db_pool: &PgPool, primary: &PgPool) -> Result<Response, Box<dyn Error>> {
+        // Work out what to audit before the request is used up
+        let (action, actor, target) = self.audit_subject(login);
+        let limit = self.timeout()?;
 
-            // Store user data
-            sqlx::query_file!("src/sql/create-user.sql",
-                    email,
-                    public_key,
-                    password.hash,
-                    password.salt)
-                .execute(db_pool)
-                .await?;
+        let result = with_timeout(limit, self.dispatch(login, db_pool)).await
+            .map_err(into_send);
+
+        let status = match &result {
+            Ok(response) => response.status,
+            Err(e) => response::status_of(e.as_ref()),
         };
 
-        Ok(Response{
-            status: 1,
-            conversations: None,
-            messages: None,
-            users: None,
-        })
+        record_audit(action, actor, target, status, primary).await;
+        result.map_err(|e| -> Box<dyn Error> { e })
     }
 
-    /// Add user's conversations to the database
-    pub async fn create_conversations(self, login: &Login, db_pool: &PgPool) -> Result<Response, Box<dyn Error>> {
-        // Authenticate user
-        if login.is_authenticated == false {
-            return Err(Box::new(ioErr::new(ioErrKind::PermissionDenied, "Not authenticated")));
-        }
+    /// Handle a request whose responses are passed to `send` as they're produced, recording it in the audit log on the
+    /// `primary` database if it's security-sensitive (only reading messages can be streamed so far)
+    pub async fn handle_streamed(self, login: &Login, db_pool: &PgPool, primary: &PgPool, send: &Sender<Response>) -> Result<(), Box<dyn Error>> {
+        let (action, actor, target) = self.audit_subject(login);
+        let limit = self.timeout()?;
 
-        // Unpack request
-        let users = self.users
-            .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'users' list"))?;
-        let conversations = self.conversations
-            .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'conversations' list"))?;
+        let result: Result<(), Box<dyn Error>> = match (&self.operation, &self.target) {
+            (Operation::Read, Target::Messages) => with_timeout(limit, self.stream_messages(login, &RetryStorage::new(PgStorage::new(db_pool)), send)).await,
+            _ => Err(Box::new(ioErr::new(ioErrKind::InvalidInput, "Only reading a conversation's messages can be streamed"))),
+        };
+        let result = result.map_err(into_send);
 
-        let conversation = conversations[0].clone();
+        let status = match &result {
+            Ok(()) => STATUS_SUCCESS,
+            Err(e) => response::status_of(e.as_ref()),
+        };
 
-        let name = conversation.name
-            .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'name' field for 'conversation'"))?;
+        record_audit(action, actor, target, status, primary).await;
+        result.map_err(|e| -> Box<dyn Error> { e })
+    }
 
-        // Create conversation
-        sqlx::query_file!("src/sql/create-conversation-1.sql", name)
-            .execute(db_pool)
-            .await?;
+    /// Get how long a request can take before it's abandoned
+    ///
+    /// Streamed reads and chunked uploads are expected to take a while, so they have a separate, longer limit.
+    pub fn timeout(&self) -> Result<std::time::Duration, Box<dyn Error>> {
+        let is_long = self.stream || match (&self.operation, &self.target) {
+            (_, Target::Uploads) => true,
+            (Operation::Create, Target::Attachments) => self.attachments.as_ref().and_then(|a| a.first()).and_then(|a| a.upload).is_some(),
+            _ => false,
+        };
 
-        // Add creator user
-        sqlx::query_file!("src/sql/create-conversation-2.sql", login.email, name)
-            .execute(db_pool)
-            .await?;
+        let seconds = match is_long {
+            true => settings::get_value("LONG_REQUEST_TIMEOUT", DEFAULT_LONG_REQUEST_TIMEOUT)?,
+            false => settings::get_value("REQUEST_TIMEOUT", DEFAULT_REQUEST_TIMEOUT)?,
+        };
 
-        // Add remaining users
-        for user in users.clone() {
-            let email = user.email
-                .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'email' field for 'user'"))?;
+        Ok(std::time::Duration::from_secs(seconds.max(1)))
+    }
 
-            sqlx::query_file!("src/sql/create-conversation-2.sql", email, name)
-                .execute(db_pool)
-                .await?;
-        };
+    /// Check whether a request asks for its responses to be streamed
+    pub fn is_streamed(&self) -> bool {
+        self.stream
+    }
 
-        Ok(Response{
-            status: 1,
-            conversations: None,
-            messages: None,
-            users: None,
-        })
+    /// Check whether a request only reads, so it can be sent to a replica
+    pub fn is_read(&self) -> bool {
+        self.operation == Operation::Read
     }
 
-    /// Add messages from a conversation to the database
-    pub async fn create_messages(self, login: &Login, db_pool: &PgPool) -> Result<Response, Box<dyn Error>> {
-        // Authenticate user
-        if login.is_authenticated == false {
-            return Err(Box::new(ioErr::new(ioErrKind::PermissionDenied, "Not authenticated")));
+    /// Check whether a request changes anything, after which its connection should read from the primary for a while
+    pub fn is_write(&self) -> bool {
+        matches!(self.operation, Operation::Create | Operation::Update | Operation::Delete)
+    }
+
+    /// Get the sensitive action a request takes (if any), who is taking it and what it's taken on, for the audit log
+    fn audit_subject(&self, login: &Login) -> (Option<&'static str>, Option<String>, Option<String>) {
+        let first_user = self.users.as_ref().and_then(|u| u.first());
+        let first_email = first_user.and_then(|u| u.email.clone());
+
+        match (&self.operation, &self.target) {
+            (Operation::Verify, Target::Users) => (Some(audit::ACTION_LOGIN), first_email.clone(), first_email),
+            (Operation::Update, Target::Users) if first_user.map_or(false, |u| u.new_password.is_some()) => {
+                (Some(audit::ACTION_CHANGE_PASSWORD), login.email().ok().map(String::from), login.email().ok().map(String::from))
+            },
+            (Operation::Update, Target::Users) if first_user.map_or(false, |u| u.new_public_key.is_some()) => {
+                (Some(audit::ACTION_ROTATE_KEY), login.email().ok().map(String::from), login.email().ok().map(String::from))
+            },
+            _ => (None, login.email().ok().map(String::from), Some(self.function())),
         }
+    }
 
-        // Unpack request
-        let messages = self.messages
-            .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'messages' list"))?;
-        let conversations = self.conversations
-            .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'conversations' list"))?;
-        let conversation = &conversations[0];
-        let conversation_id = conversation.id
-            .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'id' field for 'conversation'"))?;
+    /// Handle a batch of requests in order, returning a response for each
+    ///
+    /// The requests are independent, so one that fails is answered with a failure response and the rest still run. Like
+    /// single requests, they're audited on the `primary` database.
+    pub async fn handle_batch(requests: Vec<Request>, login: &mut Login, db_pool: &PgPool, primary: &PgPool) -> Vec<Response> {
+        let mut responses = Vec::new();
 
-        for message in messages {
-            let data = message.data
-                .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'data' field for 'message'"))?;
-            let media_type = message.media_type
-                .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'media_type' field for 'message'"))?;
-            let timestamp = message.timestamp
-                .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'timestamp' field for 'message'"))?;
-            let signature = message.signature
-                .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'signature' field for 'message'"))?;
+        for request in requests {
+            let response = match request.handle_routed(login, db_pool, primary).await {
+                Ok(response) => response,
+                Err(e) => {
+                    error!("{}", e);
+                    Response::from_error(e.as_ref())
+                },
+            };
 
-            // Store user data
-            sqlx::query_file!("src/sql/create-message.sql",
-                    login.email,
-                    conversation_id,
-                    data,
-                    media_type,
-                    timestamp,
-                    signature)
-                .execute(db_pool)
-                .await?;
+            responses.push(response);
+        }
+
+        responses
+    }
+
+    /// Handle a request with a SQLite database, recording it in the audit log if it's security-sensitive
+    ///
+    /// Only the handlers that take a storage can use SQLite, so any other request is refused.
+    #[cfg(feature = "sqlite")]
+    pub async fn handle_sqlite(self, login: &mut Login, db_pool: &SqlitePool) -> Result<Response, Box<dyn Error>> {
+        let (action, actor, target) = self.audit_subject(login);
+        let limit = self.timeout()?;
+
+        let result = with_timeout(limit, self.dispatch_storage(login, &RetryStorage::new(SqliteStorage::new(db_pool)))).await
+            .map_err(into_send);
+
+        let status = match &result {
+            Ok(response) => response.status,
+            Err(e) => response::status_of(e.as_ref()),
+        };
+
+        record_sqlite_audit(action, actor, target, status, db_pool).await;
+        result.map_err(|e| -> Box<dyn Error> { e })
+    }
+
+    /// Handle a streamed request with a SQLite database, like `handle_streamed`
+    #[cfg(feature = "sqlite")]
+    pub async fn handle_sqlite_streamed(self, login: &Login, db_pool: &SqlitePool, send: &Sender<Response>) -> Result<(), Box<dyn Error>> {
+        let (action, actor, target) = self.audit_subject(login);
+        let limit = self.timeout()?;
+
+        let result: Result<(), Box<dyn Error>> = match (&self.operation, &self.target) {
+            (Operation::Read, Target::Messages) => with_timeout(limit, self.stream_messages(login, &RetryStorage::new(SqliteStorage::new(db_pool)), send)).await,
+            _ => Err(Box::new(ioErr::new(ioErrKind::InvalidInput, "Only reading a conversation's messages can be streamed"))),
+        };
+        let result = result.map_err(into_send);
+
+        let status = match &result {
+            Ok(()) => STATUS_SUCCESS,
+            Err(e) => response::status_of(e.as_ref()),
         };
-        
+
+        record_sqlite_audit(action, actor, target, status, db_pool).await;
+        result.map_err(|e| -> Box<dyn Error> { e })
+    }
+
+    /// Handle a batch of requests in order with a SQLite database, like `handle_batch`
+    #[cfg(feature = "sqlite")]
+    pub async fn handle_sqlite_batch(requests: Vec<Request>, login: &mut Login, db_pool: &SqlitePool) -> Vec<Response> {
+        let mut responses = Vec::new();
+
+        for request in requests {
+            let response = match request.handle_sqlite(login, db_pool).await {
+                Ok(response) => response,
+                Err(e) => {
+                    error!("{}", e);
+                    Response::from_error(e.as_ref())
+                },
+            };
+
+            responses.push(response);
+        }
+
+        responses
+    }
+
+    /// Get the name of the function a request calls, e.g. 'CREATE USERS'
+    pub fn function(&self) -> String {
+        format!("{:?} {:?}", self.operation, self.target).to_uppercase()
+    }
+
+    /// Route a request to the handler for its operation and target
+    async fn dispatch(self, login: &mut Login, db_pool: &PgPool) -> Result<Response, Box<dyn Error>> {
+        match (&self.operation, &self.target) {
+            (Operation::Verify, Target::Users) => self.verify_users(login, &RetryStorage::new(PgStorage::new(db_pool))).await,
+            (Operation::Create, Target::Conversations) => self.create_conversations(login, &RetryStorage::new(PgStorage::new(db_pool))).await,
+            (Operation::Create, Target::Messages) => self.create_messages(login, &RetryStorage::new(PgStorage::new(db_pool))).await,
+            (Operation::Create, Target::Users) => self.create_users(&RetryStorage::new(PgStorage::new(db_pool))).await,
+            (Operation::Create, Target::Blocks) => self.create_blocks(login, db_pool).await,
+            (Operation::Create, Target::Reactions) => self.create_reactions(login, db_pool).await,
+            (Operation::Read, Target::Conversations) => match self.conversations.as_ref().and_then(|c| c.first()).and_then(|c| c.public) {
+                Some(true) => self.read_public_conversations(login, db_pool).await,
+                _ => self.read_conversations(login, db_pool).await,
+            },
+            (Operation::Update, Target::Users) => match self.users.as_ref().and_then(|u| u.first()).map(|u| (u.new_password.is_some(), u.new_public_key.is_some())) {
+                Some((true, true)) => Err(Box::new(ioErr::new(ioErrKind::InvalidInput, "Password and public key must be changed separately"))),
+                Some((true, false)) => self.change_password(login, db_pool).await,
+                Some((false, true)) => self.rotate_public_key(login, db_pool).await,
+                _ => self.update_users(login, db_pool).await,
+            },
+            (Operation::Update, Target::Conversations) => self.update_conversations(login, db_pool).await,
+            (Operation::Delete, Target::Conversations) => self.delete_conversations(login, db_pool).await,
+            (Operation::Create, Target::Participants) => self.create_participants(login, &RetryStorage::new(PgStorage::new(db_pool))).await,
+            (Operation::Read, Target::Messages) => match (&self.query, &self.messages, &self.conversations) {
+                (Some(_), _, _) => self.search_messages(login, db_pool).await,
+                (None, Some(_), _) => self.read_message_by_id(login, db_pool).await,
+                (None, None, Some(_)) => self.read_messages(login, &RetryStorage::new(PgStorage::new(db_pool))).await,
+                (None, None, None) => self.read_inbox(login, db_pool).await,
+            },
+            (Operation::Read, Target::Users) => match (&self.conversations, &self.users) {
+                (Some(_), _) => self.read_users(login, db_pool).await,
+                (None, Some(_)) => self.read_user_by_email(login, db_pool).await,
+                (None, None) => self.read_self(login, db_pool).await,
+            },
+            (Operation::Delete, Target::Blocks) => self.delete_blocks(login, db_pool).await,
+            (Operation::Update, Target::Messages) => self.update_messages(login, db_pool).await,
+            (Operation::Read, Target::Revisions) => self.read_revisions(login, db_pool).await,
+            (Operation::Delete, Target::Messages) => match self.purge {
+                true => self.purge_messages(login, db_pool).await,
+                false => self.delete_messages(login, db_pool).await,
+            },
+            (Operation::Delete, Target::Reactions) => self.delete_reactions(login, db_pool).await,
+            (Operation::Read, Target::Invitations) => self.read_invitations(login, db_pool).await,
+            (Operation::Read, Target::Unread) => self.read_unread_counts(login, db_pool).await,
+            (Operation::Update, Target::Invitations) => self.update_invitations(login, db_pool).await,
+            (Operation::Create, Target::Attachments) => match self.attachments.as_ref().and_then(|a| a.first()).and_then(|a| a.upload) {
+                Some(_) => self.finish_uploads(login, db_pool).await,
+                None => self.create_attachments(login, db_pool).await,
+            },
+            (Operation::Read, Target::Attachments) => self.read_attachments(login, db_pool).await,
+            (Operation::Create, Target::Uploads) => self.create_uploads(login, db_pool).await,
+            (Operation::Read, Target::Uploads) => self.read_uploads(login, db_pool).await,
+            (Operation::Update, Target::Uploads) => self.update_uploads(login, db_pool).await,
+            (Operation::Verify, _) => Err(Box::new(ioErr::new(ioErrKind::InvalidInput, "Only users can be verified"))),
+            _ => Err(Box::new(ioErr::new(ioErrKind::InvalidInput, "Unsupported operation"))),
+        }
+    }
+
+    /// Route a request to the handler for its operation and target, for a database that only the handlers taking a
+    /// storage can use
+    #[cfg(feature = "sqlite")]
+    async fn dispatch_storage(self, login: &mut Login, storage: &dyn Storage) -> Result<Response, Box<dyn Error>> {
+        match (&self.operation, &self.target) {
+            (Operation::Verify, Target::Users) => self.verify_users(login, storage).await,
+            (Operation::Create, Target::Conversations) => self.create_conversations(login, storage).await,
+            (Operation::Create, Target::Messages) => self.create_messages(login, storage).await,
+            (Operation::Create, Target::Users) => self.create_users(storage).await,
+            (Operation::Create, Target::Participants) => self.create_participants(login, storage).await,
+            (Operation::Read, Target::Messages) if self.query.is_none() && self.messages.is_none() && self.conversations.is_some() => {
+                self.read_messages(login, storage).await
+            },
+            (Operation::Verify, _) => Err(Box::new(ioErr::new(ioErrKind::InvalidInput, "Only users can be verified"))),
+            _ => Err(Box::new(ioErr::new(ioErrKind::InvalidInput, format!("{} is not supported with SQLite", self.function())))),
+        }
+    }
+
+    /// Authenticate a user for the duration of the session
+    pub async fn verify_users(self, login: &mut Login, storage: &dyn Storage) -> Result<Response, Box<dyn Error>> {
+        // Read remote data
+        let users = self.users
+            .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'users' list"))?;
+        let user = users.into_iter().next()
+            .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Empty 'users' list"))?;
+
+        let email = user.email
+            .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'email' field for 'user'"))?;
+        let remote_pass = user.password
+            .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'password' field for 'user'"))?;
+
+        // Logging in again starts from scratch, so a failed attempt doesn't leave the connection as the previous user
+        login.logout();
+
+        // Validate password
+        check_password(&email, remote_pass, storage).await?;
+        login.authenticate(email)?;
+
         Ok(Response{
-            status: 1,
-            conversations: None,
-            messages: None,
-            users: None,
+            status: STATUS_SUCCESS,
+            ..Default::default()
         })
     }
 
-    /// Read a user's messages from the database
-    pub async fn read_conversations(self, login: &Login, db_pool: &PgPool) -> Result<Response, Box<dyn Error>> {
+    /// Add users to the database
+    pub async fn create_users(self, storage: &dyn Storage) -> Result<Response, Box<dyn Error>> {
         // Authenticate user
-        if login.is_authenticated == false {
-            return Err(Box::new(ioErr::new(ioErrKind::PermissionDenied, "Not authenticated")));
-        }
+        let users = self.users
+            .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'users' list"))?;
+        let policy = PasswordPolicy::from_env()?;
 
-        // Read from database
-        let stream = sqlx::query_file!("src/sql/read-conversation.sql", login.email)
-            .fetch_all(db_pool)
-            .await?;
+        // Check every user before hashing anything, reporting bad users by status rather than failing the batch
+        let mut checked: Vec<(Option<String>, Result<(), u8>)> = Vec::new();
+        let mut valid: Vec<(String, Vec<u8>, Option<String>, Option<String>)> = Vec::new();
+        let mut passwords: Vec<Zeroizing<String>> = Vec::new();
 
-        // Format response
-        let conversations: Vec<Conversation> = stream
-            .iter()
-            .map(|c| Conversation{
-                id: Some(c.id),
-                name: Some(c.name.to_owned())
+        for user in users {
+            let email = user.email.clone();
+
+            let result = check_profile(&user).and_then(|()| {
+                // Unpack request
+                let email = user.email
+                    .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'email' field for 'user'"))?;
+                let password = user.password
+                    .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'password' field for 'user'"))?;
+                let public_key = user.public_key
+                    .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'public_key' field for 'user'"))?;
+
+                policy.check(&password)?;
+
+                valid.push((email, public_key, user.display_name, user.avatar_url));
+                passwords.push(password);
+                Ok(())
+            });
+
+            checked.push((email, result.map_err(|e| response::status_of(e.as_ref()))));
+        };
+
+        // Store every valid user in one step, skipping emails that are already registered
+        let ids = match valid.is_empty() {
+            true => Vec::new(),
+            false => {
+                // Salt and hash passwords away from the async executor, since argon2 is slow on purpose
+                let hashed = task::spawn_blocking(move || hash_passwords(passwords)).await
+                    .map_err(|e| ioErr::new(ioErrKind::Other, e))?;
+
+                let emails: Vec<String> = valid.iter().map(|(email, ..)| email.clone()).collect();
+                let new_users: Vec<NewUser> = valid
+                    .into_iter()
+                    .zip(hashed)
+                    .map(|((email, public_key, display_name, avatar_url), password)| NewUser{
+                        email,
+                        public_key,
+                        password,
+                        display_name,
+                        avatar_url,
+                    })
+                    .collect();
+
+                let created = storage.insert_users(&new_users).await?;
+                match_created_users(&emails, created)
+            },
+        };
+
+        // Report whether each user was created, in the order they were sent
+        let mut ids = ids.into_iter();
+        let results: Vec<User> = checked
+            .into_iter()
+            .map(|(email, result)| {
+                let (id, status) = match result {
+                    Ok(()) => match ids.next().flatten() {
+                        Some(id) => (Some(id), STATUS_SUCCESS),
+                        // The email was already registered
+                        None => (None, STATUS_CONFLICT),
+                    },
+                    Err(status) => (None, status),
+                };
+
+                User{
+                    id,
+                    email,
+                    status: Some(status),
+                    ..Default::default()
+                }
             })
             .collect();
 
-        let response = Response{
-            status: 1,
-            conversations: Some(conversations),
-            messages: None,
-            users: None,
-        };
+        Ok(Response{
+            status: STATUS_SUCCESS,
+            users: Some(results),
+            ..Default::default()
+        })
+    }
 
-        Ok(response)
+    /// Change the current user's password, which needs their current password as well as the new one
+    pub async fn change_password(self, login: &Login, db_pool: &PgPool) -> Result<Response, Box<dyn Error>> {
+        // Authenticate user
+        let email = login.email()?;
+
+        // Unpack request
+        let users = self.users
+            .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'users' list"))?;
+        let user = users.into_iter().next()
+            .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Empty 'users' list"))?;
+
+        let current = user.password
+            .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'password' field for 'user'"))?;
+        let new = user.new_password
+            .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'new_password' field for 'user'"))?;
+        PasswordPolicy::from_env()?.check(&new)?;
+
+        // Check the current password through the same lockout as logging in, then hash the new one, scrubbing it
+        // afterward
+        check_password(email, current, &RetryStorage::new(PgStorage::new(db_pool))).await?;
+        let hashed = Password::hash(&new, None)?;
+        drop(new);
+
+        sqlx::query_file!("src/sql/update-password.sql",
+                email,
+                hashed.hash,
+                hashed.salt)
+            .execute(db_pool)
+            .await?;
+
+        Ok(Response{
+            status: STATUS_SUCCESS,
+            ..Default::default()
+        })
     }
 
-    /// Read messages in a conversation from the database
-    pub async fn read_messages(self, login: &Login, db_pool: &PgPool) -> Result<Response, Box<dyn Error>> {
+    /// Replace the authenticated user's public key, keeping the old one so older messages can still be verified
+    pub async fn rotate_public_key(self, login: &Login, db_pool: &PgPool) -> Result<Response, Box<dyn Error>> {
         // Authenticate user
-        if login.is_authenticated == false {
-            return Err(Box::new(ioErr::new(ioErrKind::PermissionDenied, "Not authenticated")));
+        let email = login.email()?;
+
+        // Unpack request
+        let users = self.users
+            .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'users' list"))?;
+        let user = users.into_iter().next()
+            .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Empty 'users' list"))?;
+
+        // The current password is asked for again, so a hijacked session can't swap in its own key
+        let password = user.password
+            .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'password' field for 'user'"))?;
+        let new_public_key = user.new_public_key
+            .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'new_public_key' field for 'user'"))?;
+
+        if !signature::is_public_key(&new_public_key) {
+            return Err(Box::new(ioErr::new(ioErrKind::InvalidInput, "Invalid 'new_public_key' field for 'user'")));
         }
 
+        // Check the password through the same lockout as logging in
+        check_password(email, password, &RetryStorage::new(PgStorage::new(db_pool))).await?;
+
+        // Record the old key and replace it together, so a key is never lost
+        let mut tx = db_pool.begin().await?;
+
+        sqlx::query_file!("src/sql/create-key-history.sql", email)
+            .execute(&mut tx)
+            .await?;
+
+        sqlx::query_file!("src/sql/update-public-key.sql", email, new_public_key)
+            .execute(&mut tx)
+            .await?;
+
+        tx.commit().await?;
+
+        Ok(Response{
+            status: STATUS_SUCCESS,
+            ..Default::default()
+        })
+    }
+
+    /// Update the current user's profile
+    pub async fn update_users(self, login: &Login, db_pool: &PgPool) -> Result<Response, Box<dyn Error>> {
+        // Authenticate user
+        let email = login.email()?;
+
+        // Unpack request
+        let users = self.users
+            .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'users' list"))?;
+        let user = users.first()
+            .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Empty 'users' list"))?;
+
+        check_profile(user)?;
+
+        // Store user data
+        let affected = sqlx::query_file!("src/sql/update-user.sql",
+                email,
+                user.display_name,
+                user.avatar_url)
+            .execute(db_pool)
+            .await?
+            .rows_affected();
+
+        Ok(Response{
+            status: STATUS_SUCCESS,
+            affected: Some(affected),
+            ..Default::default()
+        })
+    }
+
+    /// Add user's conversations to the database
+    pub async fn create_conversations(self, login: &Login, storage: &dyn Storage) -> Result<Response, Box<dyn Error>> {
+        // Authenticate user
+        let creator = login.email()?;
+
         // Unpack request
+        let users = self.users
+            .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'users' list"))?;
         let conversations = self.conversations
             .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'conversations' list"))?;
-        let conversation = &conversations[0];
 
-        let conversation_id = conversation.id
-            .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'id' field for 'conversation'"))?;
+        let conversation = conversations[0].clone();
 
-        // Read from database
-        let stream = sqlx::query_file!("src/sql/read-message.sql",
-                login.email,
-                conversation_id)
-            .fetch_all(db_pool)
-            .await?;
+        let name = conversation.name
+            .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'name' field for 'conversation'"))?;
+        check_conversation_name(&name)?;
+        let direct = conversation.direct.unwrap_or(false);
+        let public = conversation.public.unwrap_or(false);
 
-        // Format response
-        let messages: Vec<Message> = stream
-            .iter()
-            .map(|m| Message{
-                id: None,
-                data: Some(m.data.to_owned()),
-                media_type: m.media_type.to_owned(),
-                timestamp: m.timestamp.to_owned(),
-                signature: m.signature.to_owned(),
-                sender: Some(m.email.to_owned()),
-            })
-            .collect();
+        if direct && public {
+            return Err(Box::new(ioErr::new(ioErrKind::InvalidInput, "Direct conversations cannot be public")));
+        }
 
-        let response = Response{
-            status: 1,
-            conversations: None,
-            messages: Some(messages),
-            users: None,
+        let invitees = users
+            .into_iter()
+            .map(|user| user.email
+                .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'email' field for 'user'")))
+            .collect::<Result<Vec<String>, ioErr>>()?;
+        let (mut invitees, duplicates) = normalize_invitees(invitees, creator);
+
+        // Check the size of the conversation before looking anyone up (leaving out unknown users only makes it smaller)
+        let max_participants = settings::get_value("MAX_PARTICIPANTS", DEFAULT_MAX_PARTICIPANTS)?;
+        check_participant_count(invitees.len(), max_participants)?;
+
+        // Check that all invited users exist
+        let existing = storage.existing_users(&invitees).await?;
+
+        let (known, unknown): (Vec<String>, Vec<String>) = invitees
+            .into_iter()
+            .partition(|email| existing.contains(email));
+
+        let skipped = match unknown.is_empty() {
+            true => None,
+            // Leave out unknown users if configured to, otherwise report them and fail
+            false if !direct && settings::is_enabled("SKIP_UNKNOWN_INVITEES") => Some(unknown),
+            false => {
+                return Ok(Response{
+                    status: STATUS_NOT_FOUND,
+                    users: Some(unknown
+                        .into_iter()
+                        .map(User::from_email)
+                        .collect()),
+                    ..Default::default()
+                });
+            },
         };
+        invitees = known;
 
-        Ok(response)
+        // Direct conversations are identified by their two members
+        let direct_key = match direct {
+            true => {
+                let other = match invitees.as_slice() {
+                    [other] => other,
+                    _ => return Err(Box::new(ioErr::new(ioErrKind::InvalidInput, "Direct conversations need exactly one other user"))),
+                };
+
+                Some(Conversation::direct_key(&creator.to_lowercase(), other))
+            },
+            false => None,
+        };
+
+        // Add remaining users, or invite them if they must accept first
+        let (members, invitees) = match settings::is_enabled("AUTO_JOIN_CONVERSATIONS") {
+            true => (invitees, Vec::new()),
+            false => (Vec::new(), invitees),
+        };
+
+        // Create conversation, unless a direct conversation between the same users exists
+        let stored = storage.insert_conversation(&NewConversation{
+            name,
+            direct_key,
+            public,
+            creator: String::from(creator),
+            members,
+            invitees,
+        }).await?;
+
+        if !stored.created {
+            return Ok(Response{
+                status: STATUS_SUCCESS,
+                conversations: Some(vec![Conversation{
+                    id: Some(stored.id),
+                    name: Some(stored.name),
+                    direct: Some(true),
+                    public: Some(false),
+                    ..Default::default()
+                }]),
+                created: Some(false),
+                ..Default::default()
+            });
+        }
+
+        Ok(Response{
+            status: STATUS_SUCCESS,
+            conversations: Some(vec![Conversation{
+                id: Some(stored.id),
+                name: Some(stored.name),
+                direct: Some(direct),
+                public: Some(public),
+                ..Default::default()
+            }]),
+            // Report any invited users that were left out
+            users: skipped.map(|emails| emails
+                .into_iter()
+                .map(User::from_email)
+                .collect()),
+            created: Some(true),
+            duplicates: match duplicates.is_empty() {
+                true => None,
+                false => Some(duplicates),
+            },
+            ..Default::default()
+        })
     }
 
-    /// Read users in a conversation from the database
-    pub async fn read_users(self, login: &Login, db_pool: &PgPool) -> Result<Response, Box<dyn Error>> {
+    /// Update the settings of conversations the user manages, or how far the user has read in them
+    pub async fn update_conversations(self, login: &Login, db_pool: &PgPool) -> Result<Response, Box<dyn Error>> {
         // Authenticate user
-        if login.is_authenticated == false {
-            return Err(Box::new(ioErr::new(ioErrKind::PermissionDenied, "Not authenticated")));
-        }
+        let email = login.email()?;
 
         // Unpack request
         let conversations = self.conversations
             .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'conversations' list"))?;
-        let conversation = &conversations[0];
 
-        let conversation_id = conversation.id
-            .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'id' field for 'conversation'"))?;
+        let mut tx = db_pool.begin().await?;
+        let mut affected = 0;
 
-        // Read from database
-        let stream = sqlx::query_file!("src/sql/read-user.sql",
-                login.email,
-                conversation_id)
-            .fetch_all(db_pool)
-            .await?;
+        for conversation in conversations {
+            let conversation_id = conversation.id
+                .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'id' field for 'conversation'"))?;
 
-        // Format response
-        let users: Vec<User> = stream
-            .iter()
-            .map(|u| User{
-                id: None,
-                email: Some(u.email.to_owned()),
-                name: None,
-                password: None,
-                public_key: Some(u.public_key.to_owned()),
-            })
-            .collect();
+            // Any member can move their own read pointer, which only ever touches the user's own row
+            if let Some(message_id) = conversation.last_read_message_id {
+                let participant = sqlx::query_file!("src/sql/read-read-pointer.sql",
+                        email,
+                        conversation_id)
+                    .fetch_optional(&mut tx)
+                    .await?
+                    .ok_or_else(|| ioErr::new(ioErrKind::PermissionDenied, "Not a member of conversation"))?;
 
-        let response = Response{
-            status: 1,
-            conversations: None,
-            messages: None,
-            users: Some(users),
-        };
+                let seq = sqlx::query_file!("src/sql/read-message-seq.sql",
+                        message_id,
+                        conversation_id)
+                    .fetch_optional(&mut tx)
+                    .await?
+                    .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Invalid 'last_read_message_id' field for 'conversation'"))?
+                    .seq;
 
-        Ok(response)
-    }
-}
+                check_read_pointer(participant.seq, seq)?;
 
-#[cfg(test)]
-mod tests {
-    use crate::api::request::{Request, Operation, Target};
-    use serde_json::json;
+                affected += sqlx::query_file!("src/sql/update-read-pointer.sql",
+                        participant.id,
+                        message_id)
+                    .execute(&mut tx)
+                    .await?
+                    .rows_affected();
+            }
 
-    #[test]
-    fn test_request_from_json() {
-        let json = [
-            json!({"function": "CREATE USERS"}).to_string(),
-            json!({"function": "READ MESSAGES"}).to_string(),
-            json!({"function": "UPDATE CONVERSATIONS"}).to_string(),
-            json!({"function": "DELETE MESSAGES"}).to_string(),
-            json!({"function": "VERIFY USERS"}).to_string(),
-        ];
+            // Any member can archive a conversation for themselves, which hides it from their list without leaving it
+            if let Some(archived) = conversation.archived {
+                let rows = sqlx::query_file!("src/sql/update-archived.sql",
+                        email,
+                        conversation_id,
+                        archived)
+                    .execute(&mut tx)
+                    .await?
+                    .rows_affected();
 
-        let requests: Vec<Request> = json
-            .iter()
-            .map(|req| Request::from_json(&req).unwrap())
-            .collect();
+                if rows == 0 {
+                    return Err(Box::new(ioErr::new(ioErrKind::PermissionDenied, "Not a member of conversation")));
+                }
 
-        assert_eq!(requests[0].operation, Operation::Create);
-        assert_eq!(requests[0].target, Target::Users);
+                affected += rows;
+            }
 
-        assert_eq!(requests[1].operation, Operation::Read);
-        assert_eq!(requests[1].target, Target::Messages);
+            if conversation.name.is_none() && conversation.public.is_none() && conversation.retention_seconds.is_none() {
+                continue;
+            }
 
-        assert_eq!(requests[2].operation, Operation::Update);
-        assert_eq!(requests[2].target, Target::Conversations);
+            if let Some(name) = &conversation.name {
+                check_conversation_name(name)?;
+            }
 
-        assert_eq!(requests[3].operation, Operation::Delete);
-        assert_eq!(requests[3].target, Target::Messages);
+            // Only admins can change a conversation
+            if !database::is_admin(email, conversation_id, db_pool).await? {
+                return Err(Box::new(ioErr::new(ioErrKind::PermissionDenied, "Not an admin of conversation")));
+            }
 
-        assert_eq!(requests[4].operation, Operation::Verify);
-        assert_eq!(requests[4].target, Target::Users);
+            let rows = sqlx::query_file!("src/sql/update-conversation.sql",
+                    conversation_id,
+                    conversation.name,
+                    conversation.public,
+                    conversation.retention_seconds)
+                .execute(&mut tx)
+                .await?
+                .rows_affected();
+
+            affected += check_affected(rows, "Conversation does not exist")?;
+        };
+
+        tx.commit().await?;
+
+        Ok(Response{
+            status: STATUS_SUCCESS,
+            affected: Some(affected),
+            ..Default::default()
+        })
     }
-}
\ No newline at end of file
+
+    /// Delete conversations along with everything in them, which only admins of the conversation can do
+    pub async fn delete_conversations(self, login: &Login, db_pool: &PgPool) -> Result<Response, Box<dyn Error>> {
+        // Authenticate user
+        let email = login.email()?;
+
+        // Unpack request
+        let conversations = self.conversations
+            .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'conversations' list"))?;
+
+        // Delete everything in a single transaction so a failure leaves no half-deleted conversation
+        let mut tx = db_pool.begin().await?;
+        let mut affected = 0;
+
+        for conversation in conversations {
+            let conversation_id = conversation.id
+                .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'id' field for 'conversation'"))?;
+
+            // The admin's row stays locked until the deletes commit, so they can't be demoted in between
+            let admin = sqlx::query_file!("src/sql/read-admin-for-update.sql", email, conversation_id)
+                .fetch_optional(&mut tx)
+                .await?;
+
+            if admin.is_none() {
+                return Err(Box::new(ioErr::new(ioErrKind::PermissionDenied, "Not an admin of conversation")));
+            }
+
+            // Remove rows that refer to the conversation before the conversation itself
+            sqlx::query_file!("src/sql/delete-conversation-reactions.sql", conversation_id)
+                .execute(&mut tx)
+                .await?;
+
+            sqlx::query_file!("src/sql/delete-conversation-revisions.sql", conversation_id)
+                .execute(&mut tx)
+                .await?;
+
+            sqlx::query_file!("src/sql/delete-conversation-messages.sql", conversation_id)
+                .execute(&mut tx)
+                .await?;
+
+            sqlx::query_file!("src/sql/delete-conversation-attachments.sql", conversation_id)
+                .execute(&mut tx)
+                .await?;
+
+            sqlx::query_file!("src/sql/delete-conversation-upload-chunks.sql", conversation_id)
+                .execute(&mut tx)
+                .await?;
+
+            sqlx::query_file!("src/sql/delete-conversation-uploads.sql", conversation_id)
+                .execute(&mut tx)
+                .await?;
+
+            sqlx::query_file!("src/sql/delete-conversation-invitations.sql", conversation_id)
+                .execute(&mut tx)
+                .await?;
+
+            sqlx::query_file!("src/sql/delete-conversation-participants.sql", conversation_id)
+                .execute(&mut tx)
+                .await?;
+
+            let rows = sqlx::query_file!("src/sql/delete-conversation.sql", conversation_id)
+                .execute(&mut tx)
+                .await?
+                .rows_affected();
+
+            affected += check_affected(rows, "Conversation does not exist")?;
+        }
+
+        tx.commit().await?;
+
+        Ok(Response{
+            status: STATUS_SUCCESS,
+            affected: Some(affected),
+            ..Default::default()
+        })
+    }
+
+    /// Add the user as a participant of public conversations
+    pub async fn create_participants(self, login: &Login, storage: &dyn Storage) -> Result<Response, Box<dyn Error>> {
+        // Authenticate user
+        let email = login.email()?;
+
+        // Unpack request
+        let conversations = self.conversations
+            .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'conversations' list"))?;
+
+        let mut conversation_ids = Vec::new();
+
+        for conversation in conversations {
+            let conversation_id = conversation.id
+                .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'id' field for 'conversation'"))?;
+
+            // Private conversations can only be joined by invitation
+            let public = storage.is_public(conversation_id).await?
+                .ok_or_else(|| ioErr::new(ioErrKind::NotFound, "Conversation does not exist"))?;
+
+            if !public {
+                return Err(Box::new(ioErr::new(ioErrKind::PermissionDenied, "Conversation is private")));
+            }
+
+            conversation_ids.push(conversation_id);
+        };
+
+        // Join them all at once, so a request that fails partway joins none of them
+        storage.add_member(email, &conversation_ids, ROLE_MEMBER).await?;
+
+        Ok(Response{
+            status: STATUS_SUCCESS,
+            ..Default::default()
+        })
+    }
+
+    /// Add messages from a conversation to the database
+    pub async fn create_messages(self, login: &Login, storage: &dyn Storage) -> Result<Response, Box<dyn Error>> {
+        // Authenticate user
+        let email = login.email()?;
+
+        // Unpack request
+        let messages = self.messages
+            .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'messages' list"))?;
+        let conversations = self.conversations
+            .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'conversations' list"))?;
+        let conversation = &conversations[0];
+        let conversation_id = conversation.id
+            .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'id' field for 'conversation'"))?;
+
+        // Check membership
+        if !storage.conversation_exists(conversation_id).await? {
+            return Err(Box::new(ioErr::new(ioErrKind::NotFound, "Conversation does not exist")));
+        }
+
+        if !storage.is_member(email, conversation_id).await? {
+            return Err(Box::new(ioErr::new(ioErrKind::PermissionDenied, "Not a member of conversation")));
+        }
+
+        if storage.is_blocked(email, conversation_id).await? {
+            return Err(Box::new(ioErr::new(ioErrKind::PermissionDenied, "Blocked by a member of conversation")));
+        }
+
+        // Look up the sender's public key if signatures are checked
+        let public_key = match settings::is_enabled("VERIFY_SIGNATURES") {
+            true => Some(storage.get_public_key(email).await?
+                .ok_or_else(|| ioErr::new(ioErrKind::NotFound, "User does not exist"))?),
+            false => None,
+        };
+
+        let max_skew = Duration::seconds(settings::get_value("MAX_CLOCK_SKEW", DEFAULT_MAX_CLOCK_SKEW)?);
+        let config = self.message_config.map_or_else(MessageConfig::from_env, Ok)?;
+
+        // Content can't be checked when it's encrypted, so this is left to deployments to turn on
+        let validate_content = settings::is_enabled("VALIDATE_CONTENT");
+
+        // Check every message before anything is stored, so invalid messages are reported without storing half a batch
+        let mut checked: Vec<(Option<String>, Result<(), (u8, String)>)> = Vec::new();
+        let mut valid: Vec<NewMessage> = Vec::new();
+
+        for (index, message) in messages.into_iter().enumerate() {
+            let idempotency_key = message.idempotency_key.clone();
+
+            let rejected = match validate_content {
+                true => check_message_content(index, &message).err()
+                    .map(|e| (response::status_of(e.as_ref()), response::describe(e.as_ref()))),
+                false => None,
+            };
+
+            // Only the message is at fault when it's rejected, but nothing can be stored once the server fails
+            let result = match rejected {
+                Some(rejected) => Err(rejected),
+                None => match validate_message(message, conversation_id, public_key.as_deref(), max_skew, storage).await {
+                    Ok(message) => {
+                        valid.push(message);
+                        Ok(())
+                    },
+                    Err(e) => match response::status_of(e.as_ref()) {
+                        STATUS_FAILURE | STATUS_BUSY | STATUS_TIMED_OUT => return Err(e),
+                        status => Err((status, response::describe(e.as_ref()))),
+                    },
+                },
+            };
+
+            checked.push((idempotency_key, result));
+        }
+
+        // Previews are kept alongside the data, so conversations can be listed without reading (or decompressing) every
+        // latest message, and large data may be compressed for storage, with its encoding kept so reads can undo it
+        let messages = valid.into_iter()
+            .map(|m| {
+                let preview = preview_text(&m.media_type, &m.data, config.preview_length);
+                let (data, data_encoding) = encoding::compress_data(m.data, config.compression_threshold)?;
+
+                Ok(NewMessage{
+                    data,
+                    data_encoding: data_encoding.map(String::from),
+                    preview,
+                    ..m
+                })
+            })
+            .collect::<Result<Vec<NewMessage>, Box<dyn Error>>>()?;
+
+        // Store all valid messages in one step
+        let ordered = storage.insert_messages(&NewMessages{
+            sender: String::from(email),
+            conversation_id,
+            connection: login.connection(),
+            messages,
+        }).await?;
+
+        // Report the id of each message, and the idempotency keys of any that were already stored
+        let mut stored: Vec<Message> = Vec::new();
+        let mut already_stored: Vec<String> = Vec::new();
+        let mut ordered = ordered.into_iter();
+
+        for (idempotency_key, result) in checked {
+            // A message that can't be stored is reported alongside the others rather than failing the request
+            match result {
+                Ok(()) => {
+                    let StoredMessageId{ id, seq, duplicate } = ordered.next()
+                        .ok_or_else(|| ioErr::new(ioErrKind::Other, "Message was not stored"))?;
+
+                    if duplicate {
+                        already_stored.push(idempotency_key.clone().unwrap_or_default());
+                    }
+
+                    stored.push(Message{
+                        id: Some(id),
+                        seq: Some(seq),
+                        conversation: Some(conversation_id),
+                        idempotency_key,
+                        status: Some(STATUS_SUCCESS),
+                        ..Default::default()
+                    });
+                },
+                Err((status, error)) => stored.push(Message{
+                    conversation: Some(conversation_id),
+                    idempotency_key,
+                    status: Some(status),
+                    error: Some(error),
+                    ..Default::default()
+                }),
+            }
+        };
+
+        Ok(Response{
+            status: STATUS_SUCCESS,
+            messages: Some(stored),
+            already_stored: Some(already_stored),
+            ..Default::default()
+        })
+    }
+
+    /// Block users from sending messages to the current user
+    pub async fn create_blocks(self, login: &Login, db_pool: &PgPool) -> Result<Response, Box<dyn Error>> {
+        // Authenticate user
+        let blocker = login.email()?;
+
+        // Unpack request
+        let users = self.users
+            .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'users' list"))?;
+
+        let emails = users.into_iter()
+            .map(|user| user.email.ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'email' field for 'user'")))
+            .collect::<Result<Vec<String>, ioErr>>()?;
+
+        if emails.iter().any(|email| email.to_lowercase() == blocker.to_lowercase()) {
+            return Err(Box::new(ioErr::new(ioErrKind::InvalidInput, "Cannot block yourself")));
+        }
+
+        for email in emails {
+            let blocked = sqlx::query_file!("src/sql/create-block.sql", blocker, email)
+                .fetch_one(db_pool)
+                .await?;
+
+            if !blocked.exists {
+                return Err(Box::new(ioErr::new(ioErrKind::NotFound, "User does not exist")));
+            }
+        };
+
+        Ok(Response{
+            status: STATUS_SUCCESS,
+            ..Default::default()
+        })
+    }
+
+    /// Unblock users previously blocked by the current user
+    pub async fn delete_blocks(self, login: &Login, db_pool: &PgPool) -> Result<Response, Box<dyn Error>> {
+        // Authenticate user
+        let blocker = login.email()?;
+
+        // Unpack request
+        let users = self.users
+            .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'users' list"))?;
+
+        let mut affected = 0;
+
+        for user in users {
+            let email = user.email
+                .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'email' field for 'user'"))?;
+
+            affected += sqlx::query_file!("src/sql/delete-block.sql", blocker, email)
+                .execute(db_pool)
+                .await?
+                .rows_affected();
+        };
+
+        Ok(Response{
+            status: STATUS_SUCCESS,
+            affected: Some(affected),
+            ..Default::default()
+        })
+    }
+
+    /// Add reactions to messages in the user's conversations
+    pub async fn create_reactions(self, login: &Login, db_pool: &PgPool) -> Result<Response, Box<dyn Error>> {
+        // Authenticate user
+        let email = login.email()?;
+
+        // Unpack request
+        let reactions = self.reactions
+            .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'reactions' list"))?;
+
+        for reaction in reactions {
+            let message_id = reaction.message
+                .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'message' field for 'reaction'"))?;
+            let emoji = reaction.emoji
+                .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'emoji' field for 'reaction'"))?;
+
+            if emoji.is_empty() || emoji.len() > MAX_EMOJI_LENGTH {
+                return Err(Box::new(ioErr::new(ioErrKind::InvalidInput, "Invalid 'emoji' field for 'reaction'")));
+            }
+
+            // Check membership
+            let conversation_id = database::message_conversation(message_id, db_pool).await?
+                .ok_or_else(|| ioErr::new(ioErrKind::NotFound, "Message does not exist"))?;
+
+            if !database::is_member(email, conversation_id, db_pool).await? {
+                return Err(Box::new(ioErr::new(ioErrKind::PermissionDenied, "Not a member of conversation")));
+            }
+
+            // Store reaction, ignoring duplicates
+            sqlx::query_file!("src/sql/create-reaction.sql", email, message_id, emoji)
+                .execute(db_pool)
+                .await?;
+        };
+
+        Ok(Response{
+            status: STATUS_SUCCESS,
+            ..Default::default()
+        })
+    }
+
+    /// Remove the user's reactions from messages
+    pub async fn delete_reactions(self, login: &Login, db_pool: &PgPool) -> Result<Response, Box<dyn Error>> {
+        // Authenticate user
+        let email = login.email()?;
+
+        // Unpack request
+        let reactions = self.reactions
+            .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'reactions' list"))?;
+
+        let mut affected = 0;
+
+        for reaction in reactions {
+            let message_id = reaction.message
+                .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'message' field for 'reaction'"))?;
+            let emoji = reaction.emoji
+                .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'emoji' field for 'reaction'"))?;
+
+            let rows = sqlx::query_file!("src/sql/delete-reaction.sql", email, message_id, emoji)
+                .execute(db_pool)
+                .await?
+                .rows_affected();
+
+            affected += check_affected(rows, "Reaction does not exist")?;
+        };
+
+        Ok(Response{
+            status: STATUS_SUCCESS,
+            affected: Some(affected),
+            ..Default::default()
+        })
+    }
+
+    /// Edit messages the user sent, keeping the version being replaced as a revision
+    pub async fn update_messages(self, login: &Login, db_pool: &PgPool) -> Result<Response, Box<dyn Error>> {
+        // Authenticate user
+        let email = login.email()?;
+
+        // Unpack request
+        let messages = self.messages
+            .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'messages' list"))?;
+
+        // Look up the sender's public key if signatures are checked
+        let public_key = match settings::is_enabled("VERIFY_SIGNATURES") {
+            true => Some(database::retry(|| sqlx::query_file!("src/sql/read-public-key.sql", email)
+                    .fetch_one(db_pool))
+                .await?
+                .public_key),
+            false => None,
+        };
+
+        let max_skew = Duration::seconds(settings::get_value("MAX_CLOCK_SKEW", DEFAULT_MAX_CLOCK_SKEW)?);
+        let config = self.message_config.map_or_else(MessageConfig::from_env, Ok)?;
+        let max_revisions = settings::get_value("MAX_REVISIONS", DEFAULT_MAX_REVISIONS)?.max(0);
+
+        let mut tx = db_pool.begin().await?;
+        let mut affected = 0;
+
+        for message in messages {
+            let message_id = message.id
+                .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'id' field for 'message'"))?;
+            let data = message.data
+                .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'data' field for 'message'"))?;
+            let media_type = message.media_type
+                .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'media_type' field for 'message'"))?;
+            let timestamp = message.timestamp
+                .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'timestamp' field for 'message'"))?;
+            let signature = message.signature
+                .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'signature' field for 'message'"))?;
+
+            check_media_type(&media_type)?;
+            check_allowed_media_type(&media_type, settings::media_allowlist())?;
+            if api::is_text(&media_type) {
+                api::check_content(&media_type, &data)
+                    .map_err(|reason| ioErr::new(ioErrKind::InvalidInput, format!("Invalid 'data' field for 'message' ({})", reason)))?;
+            }
+            let sent_at = check_timestamp(timestamp, Utc::now(), max_skew)?;
+
+            // Only the sender can edit a message, and deleted messages stay deleted
+            let existing = sqlx::query_file!("src/sql/read-message-for-update.sql", email, message_id)
+                .fetch_optional(&mut tx)
+                .await?
+                .ok_or_else(|| ioErr::new(ioErrKind::NotFound, "Message does not exist"))?;
+
+            if let Some(public_key) = &public_key {
+                let sent_at = sent_at.to_rfc3339_opts(SecondsFormat::Millis, true);
+                let signed = signature::signed_bytes(&data, &media_type, sent_at.as_bytes(), existing.conversation);
+                signature::verify(public_key, &signature, &signed)?;
+            }
+
+            let search = searchable_text(&media_type, &data);
+            let preview = preview_text(&media_type, &data, config.preview_length);
+            let (data, data_encoding) = encoding::compress_data(data, config.compression_threshold)?;
+
+            // Keep the current version before overwriting it, dropping the oldest beyond the limit
+            sqlx::query_file!("src/sql/create-revision.sql", message_id)
+                .execute(&mut tx)
+                .await?;
+
+            affected += sqlx::query_file!("src/sql/update-message.sql",
+                    message_id,
+                    data,
+                    media_type,
+                    timestamp,
+                    signature,
+                    search,
+                    data_encoding,
+                    preview)
+                .execute(&mut tx)
+                .await?
+                .rows_affected();
+
+            sqlx::query_file!("src/sql/trim-revisions.sql", message_id, max_revisions)
+                .execute(&mut tx)
+                .await?;
+        };
+
+        tx.commit().await?;
+
+        Ok(Response{
+            status: STATUS_SUCCESS,
+            affected: Some(affected),
+            ..Default::default()
+        })
+    }
+
+    /// Read the earlier versions of an edited message, oldest first
+    pub async fn read_revisions(self, login: &Login, db_pool: &PgPool) -> Result<Response, Box<dyn Error>> {
+        // Authenticate user
+        let email = login.email()?;
+
+        // Unpack request
+        let messages = self.messages
+            .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'messages' list"))?;
+        let message_id = messages.first()
+            .and_then(|m| m.id)
+            .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'id' field for 'message'"))?;
+
+        // Check permissions
+        let conversation_id = database::message_conversation(message_id, db_pool).await?
+            .ok_or_else(|| ioErr::new(ioErrKind::NotFound, "Message does not exist"))?;
+
+        check_revision_access(
+            database::is_sender(email, message_id, db_pool).await?,
+            database::is_admin(email, conversation_id, db_pool).await?,
+        )?;
+
+        // Read from database
+        let stream = database::retry(|| sqlx::query_file!("src/sql/read-revision.sql", message_id)
+                .fetch_all(db_pool))
+            .await?;
+
+        // Format response
+        let revisions = stream
+            .into_iter()
+            .map(|r| -> Result<Message, Box<dyn Error>> {
+                Ok(Message{
+                    id: Some(message_id),
+                    conversation: Some(r.conversation),
+                    data: Some(encoding::decompress_data(r.data, r.data_encoding.as_deref())?),
+                    media_type: r.media_type,
+                    timestamp: r.timestamp,
+                    signature: r.signature,
+                    edited_at: Some(r.edited_at),
+                    ..Default::default()
+                })
+            })
+            .collect::<Result<Vec<Message>, _>>()?;
+
+        Ok(Response{
+            status: STATUS_SUCCESS,
+            messages: Some(revisions),
+            ..Default::default()
+        })
+    }
+
+    /// Delete messages sent by the user (or in conversations they administer)
+    ///
+    /// Deleted messages are kept as tombstones so replies and reactions still have something to refer to,
+    /// unless `HARD_DELETE_MESSAGES` is set, in which case their content is removed straight away and they
+    /// are left out of reads.
+    pub async fn delete_messages(self, login: &Login, db_pool: &PgPool) -> Result<Response, Box<dyn Error>> {
+        // Authenticate user
+        let email = login.email()?;
+
+        // Unpack request
+        let messages = self.messages
+            .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'messages' list"))?;
+        let hard_delete = settings::is_enabled("HARD_DELETE_MESSAGES");
+
+        let mut tx = db_pool.begin().await?;
+        let mut affected = 0;
+
+        for message in messages {
+            let message_id = message.id
+                .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'id' field for 'message'"))?;
+
+            let rows = sqlx::query_file!("src/sql/delete-message.sql", email, message_id)
+                .execute(&mut tx)
+                .await?
+                .rows_affected();
+
+            affected += check_affected(rows, "Message does not exist")?;
+
+            if hard_delete {
+                // The attachment has to be cleared first, since purging the message forgets which it was
+                sqlx::query_file!("src/sql/purge-message-attachment.sql", message_id)
+                    .execute(&mut tx)
+                    .await?;
+
+                sqlx::query_file!("src/sql/purge-message.sql", message_id)
+                    .execute(&mut tx)
+                    .await?;
+
+                sqlx::query_file!("src/sql/delete-revisions.sql", message_id)
+                    .execute(&mut tx)
+                    .await?;
+            }
+        };
+
+        tx.commit().await?;
+
+        Ok(Response{
+            status: STATUS_SUCCESS,
+            affected: Some(affected),
+            ..Default::default()
+        })
+    }
+
+    /// Remove the content of messages for good, which only admins of their conversation can do
+    pub async fn purge_messages(self, login: &Login, db_pool: &PgPool) -> Result<Response, Box<dyn Error>> {
+        // Authenticate user
+        let email = login.email()?;
+
+        // Unpack request
+        let messages = self.messages
+            .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'messages' list"))?;
+
+        let mut tx = db_pool.begin().await?;
+        let mut affected = 0;
+
+        for message in messages {
+            let message_id = message.id
+                .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'id' field for 'message'"))?;
+
+            let conversation_id = database::message_conversation(message_id, db_pool).await?
+                .ok_or_else(|| ioErr::new(ioErrKind::NotFound, "Message does not exist"))?;
+
+            if !database::is_admin(email, conversation_id, db_pool).await? {
+                return Err(Box::new(ioErr::new(ioErrKind::PermissionDenied, "Not an admin of conversation")));
+            }
+
+            // Messages that weren't deleted yet are deleted by the admin first
+            sqlx::query_file!("src/sql/delete-message.sql", email, message_id)
+                .execute(&mut tx)
+                .await?;
+
+            // The attachment has to be cleared first, since purging the message forgets which it was
+            sqlx::query_file!("src/sql/purge-message-attachment.sql", message_id)
+                .execute(&mut tx)
+                .await?;
+
+            affected += sqlx::query_file!("src/sql/purge-message.sql", message_id)
+                .execute(&mut tx)
+                .await?
+                .rows_affected();
+
+            sqlx::query_file!("src/sql/delete-revisions.sql", message_id)
+                .execute(&mut tx)
+                .await?;
+        };
+
+        tx.commit().await?;
+
+        Ok(Response{
+            status: STATUS_SUCCESS,
+            affected: Some(affected),
+            ..Default::default()
+        })
+    }
+
+    /// Read the user's pending invitations to conversations
+    pub async fn read_invitations(self, login: &Login, db_pool: &PgPool) -> Result<Response, Box<dyn Error>> {
+        // Authenticate user
+        let email = login.email()?;
+
+        // Read from database
+        let stream = database::retry(|| sqlx::query_file!("src/sql/read-invitation.sql", email)
+                .fetch_all(db_pool))
+            .await?;
+
+        // Format response
+        let invitations: Vec<Invitation> = stream
+            .into_iter()
+            .map(|i| Invitation{
+                id: Some(i.id),
+                conversation: Some(i.conversation),
+                inviter: Some(i.inviter),
+                status: Some(i.status),
+            })
+            .collect();
+
+        let response = Response{
+            status: STATUS_SUCCESS,
+            invitations: Some(invitations),
+            ..Default::default()
+        };
+
+        Ok(response)
+    }
+
+    /// Accept or decline the user's pending invitations to conversations
+    pub async fn update_invitations(self, login: &Login, db_pool: &PgPool) -> Result<Response, Box<dyn Error>> {
+        // Authenticate user
+        let email = login.email()?;
+
+        // Unpack request
+        let invitations = self.invitations
+            .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'invitations' list"))?;
+
+        let mut tx = db_pool.begin().await?;
+        let mut affected = 0;
+
+        for invitation in invitations {
+            let id = invitation.id
+                .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'id' field for 'invitation'"))?;
+            let status = invitation.status
+                .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'status' field for 'invitation'"))?;
+
+            if status != INVITATION_ACCEPTED && status != INVITATION_DECLINED {
+                return Err(Box::new(ioErr::new(ioErrKind::InvalidInput, "Invalid 'status' field for 'invitation'")));
+            }
+
+            // Only the invitee can answer a pending invitation
+            let conversation_id = sqlx::query_file!("src/sql/update-invitation.sql", email, id, status)
+                .fetch_optional(&mut tx)
+                .await?
+                .ok_or_else(|| ioErr::new(ioErrKind::NotFound, "Invitation does not exist"))?
+                .conversation;
+            affected += 1;
+
+            if status == INVITATION_ACCEPTED {
+                sqlx::query_file!("src/sql/create-conversation-2.sql", email, conversation_id, ROLE_MEMBER)
+                    .execute(&mut tx)
+                    .await?;
+            }
+        };
+
+        tx.commit().await?;
+
+        Ok(Response{
+            status: STATUS_SUCCESS,
+            affected: Some(affected),
+            ..Default::default()
+        })
+    }
+
+    /// Read a page of a user's conversations from the database, most recently active first
+    pub async fn read_conversations(self, login: &Login, db_pool: &PgPool) -> Result<Response, Box<dyn Error>> {
+        // Authenticate user
+        let email = login.email()?;
+
+        // Unpack request
+        let limit = self.page_size()?;
+        let config = self.message_config.map_or_else(MessageConfig::from_env, Ok)?;
+        let (after_key, after_id) = match &self.cursor {
+            Some(c) => (Some(c.key), Some(c.id)),
+            None => (None, None),
+        };
+
+        if let Some(role) = &self.role {
+            check_role(role)?;
+        }
+
+        // Read from database, fetching one extra row to tell if another page exists
+        // (messages from other participants after the user's read pointer count as unread,
+        // so every message from others is unread until the pointer is first set),
+        // along with the preview stored with each conversation's latest message (or enough of the message to preview it,
+        // for messages stored before previews were),
+        // keeping only conversations where the user holds 'role' if it is given,
+        // and leaving out conversations the user archived unless asked for them
+        let mut stream = database::retry(|| sqlx::query_file!("src/sql/read-conversation.sql",
+                    email,
+                    after_key,
+                    after_id,
+                    limit + 1,
+                    (config.preview_length * 4) as i32,
+                    self.role,
+                    self.include_archived)
+                .fetch_all(db_pool))
+            .await?;
+
+        let has_more = truncate_page(&mut stream, limit);
+        let cursor = match has_more {
+            true => stream.last().map(|c| api::Cursor{
+                key: c.activity,
+                id: c.id,
+            }),
+            false => None,
+        };
+
+        // Format response
+        let conversations: Vec<Conversation> = stream
+            .iter()
+            .map(|c| Conversation{
+                id: Some(c.id),
+                name: Some(c.name.to_owned()),
+                timestamp: c.timestamp.to_owned(),
+                direct: Some(c.direct),
+                public: Some(c.public),
+                last_read_message_id: c.last_read_message_id,
+                unread_count: Some(c.unread_count),
+                last_sender: c.last_sender.to_owned(),
+                last_media_type: c.last_media_type.to_owned(),
+                // Previews are cut to the current length, in case it's shorter than when the message was stored
+                last_preview: match (&c.last_preview, &c.last_media_type, &c.last_data) {
+                    (Some(preview), _, _) => Some(preview.chars().take(config.preview_length).collect()),
+                    (None, Some(media_type), Some(data)) => preview_text(media_type, data, config.preview_length),
+                    _ => None,
+                },
+                retention_seconds: c.retention_seconds,
+                role: Some(c.role.to_owned()),
+                archived: Some(c.archived),
+            })
+            .collect();
+
+        let response = Response{
+            status: STATUS_SUCCESS,
+            conversations: Some(conversations),
+            cursor,
+            has_more: Some(has_more),
+            ..Default::default()
+        };
+
+        Ok(response)
+    }
+
+    /// Read a page of public conversations, optionally searching by name
+    pub async fn read_public_conversations(self, login: &Login, db_pool: &PgPool) -> Result<Response, Box<dyn Error>> {
+        // Authenticate user
+        login.email()?;
+
+        // Unpack request
+        let limit = self.page_size()?;
+        let after_id = self.cursor.as_ref().map(|c| c.id);
+        let search = self.conversations
+            .as_ref()
+            .and_then(|c| c.first())
+            .and_then(|c| c.name.as_deref())
+            .unwrap_or("");
+
+        // Read from database, fetching one extra row to tell if another page exists
+        let mut stream = database::retry(|| sqlx::query_file!("src/sql/read-public-conversation.sql",
+                    search,
+                    after_id,
+                    limit + 1)
+                .fetch_all(db_pool))
+            .await?;
+
+        let has_more = truncate_page(&mut stream, limit);
+        let cursor = match has_more {
+            true => stream.last().map(|c| api::Cursor{
+                key: c.id,
+                id: c.id,
+            }),
+            false => None,
+        };
+
+        // Format response
+        let conversations: Vec<Conversation> = stream
+            .into_iter()
+            .map(|c| Conversation{
+                id: Some(c.id),
+                name: Some(c.name),
+                direct: Some(false),
+                public: Some(true),
+                ..Default::default()
+            })
+            .collect();
+
+        let response = Response{
+            status: STATUS_SUCCESS,
+            conversations: Some(conversations),
+            cursor,
+            has_more: Some(has_more),
+            ..Default::default()
+        };
+
+        Ok(response)
+    }
+
+    /// Count the unread messages in each of a user's conversations, without reading any of them
+    pub async fn read_unread_counts(self, login: &Login, db_pool: &PgPool) -> Result<Response, Box<dyn Error>> {
+        // Authenticate user
+        let email = login.email()?;
+
+        // Read from database, counting the same messages as a conversation's 'unreadCount'
+        // (those from other participants after the user's read pointer)
+        let unread: HashMap<i32, i64> = database::retry(|| sqlx::query_file!("src/sql/read-unread-counts.sql", email)
+                .fetch_all(db_pool))
+            .await?
+            .into_iter()
+            .map(|c| (c.id, c.unread))
+            .collect();
+
+        // Format response
+        let response = Response{
+            status: STATUS_SUCCESS,
+            unread: Some(unread),
+            ..Default::default()
+        };
+
+        Ok(response)
+    }
+
+    /// Read messages in a conversation from the database
+    pub async fn read_messages(self, login: &Login, storage: &dyn Storage) -> Result<Response, Box<dyn Error>> {
+        let (sender, page) = channel::unbounded();
+        self.read_message_chunks(login, false, storage, &sender).await?;
+
+        Ok(page.try_recv().unwrap_or_default())
+    }
+
+    /// Read the most recent messages from all of the user's conversations at once, newest first
+    pub async fn read_inbox(self, login: &Login, db_pool: &PgPool) -> Result<Response, Box<dyn Error>> {
+        // Authenticate user
+        let email = login.email()?;
+
+        let limit = self.page_size()?;
+        let offset = self.offset.unwrap_or(0).max(0);
+
+        // Read from database, fetching one extra row to tell if another page exists,
+        // where joining on the user's own participants keeps out conversations they aren't in,
+        // and only messages older than 'before_id' are kept if it is given
+        let mut stream = database::retry(|| sqlx::query_file!("src/sql/read-inbox.sql",
+                    email,
+                    limit + 1,
+                    self.before_id,
+                    offset)
+                .fetch_all(db_pool))
+            .await?;
+
+        let has_more = truncate_page(&mut stream, limit);
+
+        // The last message read is where the next page continues from
+        let next_id = match has_more {
+            true => stream.last().map(|m| m.id),
+            false => None,
+        };
+
+        // Format response
+        let messages = stream
+            .into_iter()
+            .map(|m| -> Result<Message, Box<dyn Error>> {
+                Ok(Message{
+                    id: Some(m.id),
+                    seq: Some(m.seq),
+                    conversation: Some(m.conversation),
+                    data: Some(encoding::decompress_data(m.data, m.data_encoding.as_deref())?),
+                    media_type: m.media_type,
+                    timestamp: m.timestamp,
+                    created_at: Some(m.created_at),
+                    edited_at: m.edited_at,
+                    signature: m.signature,
+                    sender: Some(m.email),
+                    parent_id: m.parent_id,
+                    attachment: m.attachment_id.map(|id| Attachment{
+                        id: Some(id),
+                        conversation: Some(m.conversation),
+                        media_type: m.attachment_media_type,
+                        size: m.attachment_size,
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                })
+            })
+            .collect::<Result<Vec<Message>, _>>()?;
+
+        let response = Response{
+            status: STATUS_SUCCESS,
+            messages: Some(messages),
+            has_more: Some(has_more),
+            next_id,
+            ..Default::default()
+        };
+
+        Ok(response)
+    }
+
+    /// Read every message from a conversation, passing them to `send` in chunks of up to a page each as they're read,
+    /// so a long conversation never has to be held in memory all at once
+    pub async fn stream_messages(self, login: &Login, storage: &dyn Storage, send: &Sender<Response>) -> Result<(), Box<dyn Error>> {
+        if self.query.is_some() || self.messages.is_some() {
+            return Err(Box::new(ioErr::new(ioErrKind::InvalidInput, "Only reading a conversation's messages can be streamed")));
+        }
+
+        self.read_message_chunks(login, true, storage, send).await
+    }
+
+    /// Read messages from a conversation a page at a time, passing each page to `send`, and stopping after the first
+    /// page unless reading them all
+    async fn read_message_chunks(self, login: &Login, all: bool, storage: &dyn Storage, send: &Sender<Response>) -> Result<(), Box<dyn Error>> {
+        // Authenticate user
+        let email = login.email()?;
+
+        // Unpack request
+        let conversations = self.conversations
+            .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'conversations' list"))?;
+        let conversation = &conversations[0];
+
+        let conversation_id = conversation.id
+            .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'id' field for 'conversation'"))?;
+
+        // Check membership
+        if !storage.is_member(email, conversation_id).await? {
+            return Err(Box::new(ioErr::new(ioErrKind::PermissionDenied, "Not a member of conversation")));
+        }
+
+        let limit = self.page_size()?;
+        let offset = self.offset.unwrap_or(0).max(0);
+
+        // Read from database in sequence order, fetching one extra row to tell if another page exists
+        // (newest first, or oldest first when syncing messages after a given id),
+        // keeping messages the server received from 'since' (inclusive) until 'until' (exclusive),
+        // and only replies to 'parent_id' if it is given, leaving out the user's own messages if asked
+        let mut rows = storage.list_messages(email, conversation_id, &MessageQuery{
+            limit: match all {
+                true => i64::MAX,
+                false => limit + 1,
+            },
+            offset,
+            after_id: self.after_id,
+            before_id: self.before_id,
+            since: self.since,
+            until: self.until,
+            parent_id: self.parent_id,
+            exclude_self: self.exclude_self,
+            include_deleted: !settings::is_enabled("HARD_DELETE_MESSAGES"),
+        });
+
+        // Pages stop being read once there's nowhere left to send them
+        let closed = |_: channel::SendError<Response>| ioErr::new(ioErrKind::BrokenPipe, "Connection closed while messages were being sent");
+
+        // Each page is only sent once the row after it has been read (or there are none left), to tell if another
+        // page follows
+        let mut page = Vec::new();
+        let mut next = rows.next().await.transpose()?;
+        let mut sent = false;
+
+        while let Some(row) = next {
+            page.push(row);
+            next = rows.next().await.transpose()?;
+
+            let has_more = next.is_some();
+
+            if (page.len() as i64) < limit && has_more {
+                continue;
+            }
+
+            // The last message read is where the next page continues from
+            let next_id = match has_more {
+                true => page.last().map(|m| m.id),
+                false => None,
+            };
+
+            // Format response
+            let messages = page
+                .drain(..)
+                .map(|m| -> Result<Message, Box<dyn Error>> {
+                    let message = Message{
+                        id: Some(m.id),
+                        seq: Some(m.seq),
+                        conversation: Some(conversation_id),
+                        data: Some(encoding::decompress_data(m.data, m.data_encoding.as_deref())?),
+                        media_type: m.media_type,
+                        timestamp: m.timestamp,
+                        created_at: Some(m.created_at),
+                        edited_at: m.edited_at,
+                        signature: m.signature,
+                        sender: Some(m.sender),
+                        idempotency_key: None,
+                        parent_id: m.parent_id,
+                        attachment: m.attachment_id.map(|id| Attachment{
+                            id: Some(id),
+                            conversation: Some(conversation_id),
+                            media_type: m.attachment_media_type,
+                            size: m.attachment_size,
+                            ..Default::default()
+                        }),
+                        // Reactions are read with the messages, so a page never needs a second connection
+                        reactions: Some(m.reactions.into_iter()
+                            .map(|(emoji, sender)| Reaction{
+                                message: Some(m.id),
+                                emoji: Some(emoji),
+                                sender: Some(sender),
+                            })
+                            .collect()),
+                        deleted: Some(m.deleted),
+                        status: None,
+                        error: None,
+                    };
+
+                    Ok(message.redact())
+                })
+                .collect::<Result<Vec<Message>, _>>()?;
+
+            send.send(Response{
+                status: STATUS_SUCCESS,
+                messages: Some(messages),
+                has_more: Some(has_more),
+                next_id,
+                ..Default::default()
+            }).await.map_err(closed)?;
+            sent = true;
+
+            if !all {
+                break;
+            }
+        }
+
+        // A conversation without any (matching) messages still gets an answer
+        if !sent {
+            send.send(Response{
+                status: STATUS_SUCCESS,
+                messages: Some(Vec::new()),
+                has_more: Some(false),
+                ..Default::default()
+            }).await.map_err(closed)?;
+        }
+
+        Ok(())
+    }
+
+    /// Look up a user by email so that a conversation can be started with them
+    pub async fn read_user_by_email(self, login: &Login, db_pool: &PgPool) -> Result<Response, Box<dyn Error>> {
+        // Authenticate user
+        let requester = login.email()?;
+
+        // Limit each user's lookups (across all their connections) to slow down enumeration of accounts
+        if !LOOKUPS.check(&requester.to_lowercase(), Instant::now()) {
+            return Err(Box::new(ioErr::new(ioErrKind::PermissionDenied, "Too many lookups")));
+        }
+
+        // Unpack request
+        let users = self.users
+            .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'users' list"))?;
+        let user = users.first()
+            .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Empty 'users' list"))?;
+
+        let email = user.email.as_deref()
+            .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'email' field for 'user'"))?;
+
+        // Read from database
+        let stream = database::retry(|| sqlx::query_file!("src/sql/read-user-by-email.sql", email)
+                .fetch_optional(db_pool))
+            .await?
+            .ok_or_else(|| ioErr::new(ioErrKind::NotFound, "User does not exist"))?;
+
+        // Older keys (oldest first) let clients verify messages signed before the user replaced their key
+        let previous_keys = database::retry(|| sqlx::query_file!("src/sql/read-key-history.sql", email)
+                .fetch_all(db_pool))
+            .await?
+            .into_iter()
+            .map(|k| PreviousKey{
+                public_key: k.public_key,
+                replaced_at: k.replaced_at,
+            })
+            .collect();
+
+        // Format response
+        let response = Response{
+            status: STATUS_SUCCESS,
+            users: Some(vec![User{
+                id: Some(stream.id),
+                email: Some(stream.email),
+                public_key: Some(stream.public_key),
+                previous_keys: Some(previous_keys),
+                display_name: stream.display_name,
+                avatar_url: stream.avatar_url,
+                ..Default::default()
+            }]),
+            ..Default::default()
+        };
+
+        Ok(response)
+    }
+
+    /// Read the authenticated user's own profile, e.g. to show it straight after logging in
+    pub async fn read_self(self, login: &Login, db_pool: &PgPool) -> Result<Response, Box<dyn Error>> {
+        // Authenticate user
+        let email = login.email()?;
+
+        // Read from database (never the password or its salt)
+        let stream = database::retry(|| sqlx::query_file!("src/sql/read-self.sql", email)
+                .fetch_optional(db_pool))
+            .await?
+            .ok_or_else(|| ioErr::new(ioErrKind::NotFound, "User does not exist"))?;
+
+        let previous_keys = database::retry(|| sqlx::query_file!("src/sql/read-key-history.sql", email)
+                .fetch_all(db_pool))
+            .await?
+            .into_iter()
+            .map(|k| PreviousKey{
+                public_key: k.public_key,
+                replaced_at: k.replaced_at,
+            })
+            .collect();
+
+        // Format response
+        let response = Response{
+            status: STATUS_SUCCESS,
+            users: Some(vec![User{
+                id: Some(stream.id),
+                email: Some(stream.email),
+                public_key: Some(stream.public_key),
+                previous_keys: Some(previous_keys),
+                display_name: stream.display_name,
+                avatar_url: stream.avatar_url,
+                ..Default::default()
+            }]),
+            ..Default::default()
+        };
+
+        Ok(response)
+    }
+
+    /// Upload attachments to conversations, so that messages can refer to them
+    pub async fn create_attachments(self, login: &Login, db_pool: &PgPool) -> Result<Response, Box<dyn Error>> {
+        // Authenticate user
+        let email = login.email()?;
+
+        // Unpack request
+        let attachments = self.attachments
+            .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'attachments' list"))?;
+        let max_size = settings::get_value("MAX_ATTACHMENT_SIZE", DEFAULT_MAX_ATTACHMENT_SIZE)?;
+
+        let mut stored: Vec<Attachment> = Vec::new();
+
+        for attachment in attachments {
+            let conversation_id = attachment.conversation
+                .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'conversation' field for 'attachment'"))?;
+            let media_type = attachment.media_type
+                .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'media_type' field for 'attachment'"))?;
+            let data = attachment.data
+                .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'data' field for 'attachment'"))?;
+
+            check_media_type(&media_type)?;
+            check_attachment_size(data.len(), max_size)?;
+
+            // Only members can upload to a conversation
+            if !database::is_member(email, conversation_id, db_pool).await? {
+                return Err(Box::new(ioErr::new(ioErrKind::PermissionDenied, "Not a member of conversation")));
+            }
+
+            let size = data.len() as i32;
+            let id = sqlx::query_file!("src/sql/create-attachment.sql",
+                    email,
+                    conversation_id,
+                    media_type,
+                    size,
+                    data)
+                .fetch_one(db_pool)
+                .await?
+                .id;
+
+            stored.push(Attachment{
+                id: Some(id),
+                conversation: Some(conversation_id),
+                media_type: Some(media_type),
+                size: Some(size),
+                ..Default::default()
+            });
+        };
+
+        Ok(Response{
+            status: STATUS_SUCCESS,
+            attachments: Some(stored),
+            ..Default::default()
+        })
+    }
+
+    /// Start uploading attachments in chunks, for attachments too large to send in one request
+    pub async fn create_uploads(self, login: &Login, db_pool: &PgPool) -> Result<Response, Box<dyn Error>> {
+        // Authenticate user
+        let email = login.email()?;
+
+        // Unpack request
+        let uploads = self.uploads
+            .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'uploads' list"))?;
+        let max_size = settings::get_value("MAX_UPLOAD_SIZE", DEFAULT_MAX_UPLOAD_SIZE)?;
+
+        let mut started: Vec<Upload> = Vec::new();
+
+        for upload in uploads {
+            let conversation_id = upload.conversation
+                .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'conversation' field for 'upload'"))?;
+            let media_type = upload.media_type
+                .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'media_type' field for 'upload'"))?;
+            let size = upload.size
+                .filter(|s| *s >= 0)
+                .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'size' field for 'upload'"))?;
+
+            check_media_type(&media_type)?;
+            check_attachment_size(size as usize, max_size)?;
+
+            // Only members can upload to a conversation
+            if !database::is_member(email, conversation_id, db_pool).await? {
+                return Err(Box::new(ioErr::new(ioErrKind::PermissionDenied, "Not a member of conversation")));
+            }
+
+            let id = sqlx::query_file!("src/sql/create-upload.sql",
+                    email,
+                    conversation_id,
+                    media_type,
+                    size)
+                .fetch_one(db_pool)
+                .await?
+                .id;
+
+            started.push(Upload{
+                id: Some(id),
+                conversation: Some(conversation_id),
+                media_type: Some(media_type),
+                size: Some(size),
+                received: Some(0),
+                index: Some(0),
+                data: None,
+            });
+        };
+
+        Ok(Response{
+            status: STATUS_SUCCESS,
+            uploads: Some(started),
+            ..Default::default()
+        })
+    }
+
+    /// Read how much of the user's uploads has been received, so they can be resumed
+    pub async fn read_uploads(self, login: &Login, db_pool: &PgPool) -> Result<Response, Box<dyn Error>> {
+        // Authenticate user
+        let email = login.email()?;
+
+        // Unpack request
+        let uploads = self.uploads
+            .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'uploads' list"))?;
+
+        let mut found: Vec<Upload> = Vec::new();
+
+        for upload in uploads {
+            let id = upload.id
+                .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'id' field for 'upload'"))?;
+
+            let u = database::retry(|| sqlx::query_file!("src/sql/read-upload.sql", email, id)
+                    .fetch_optional(db_pool))
+                .await?
+                .ok_or_else(|| ioErr::new(ioErrKind::NotFound, "Upload does not exist"))?;
+
+            found.push(Upload{
+                id: Some(u.id),
+                conversation: Some(u.conversation),
+                media_type: Some(u.media_type),
+                size: Some(u.size),
+                received: Some(u.received),
+                index: Some(u.next_index),
+                data: None,
+            });
+        };
+
+        Ok(Response{
+            status: STATUS_SUCCESS,
+            uploads: Some(found),
+            ..Default::default()
+        })
+    }
+
+    /// Add the next chunk to each of the user's uploads
+    pub async fn update_uploads(self, login: &Login, db_pool: &PgPool) -> Result<Response, Box<dyn Error>> {
+        // Authenticate user
+        let email = login.email()?;
+
+        // Unpack request
+        let uploads = self.uploads
+            .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'uploads' list"))?;
+
+        let mut tx = db_pool.begin().await?;
+        let mut updated: Vec<Upload> = Vec::new();
+
+        for upload in uploads {
+            let id = upload.id
+                .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'id' field for 'upload'"))?;
+            let index = upload.index
+                .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'index' field for 'upload'"))?;
+            let data = upload.data
+                .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'data' field for 'upload'"))?;
+
+            // Lock the upload so chunks are added one at a time, in order
+            let u = sqlx::query_file!("src/sql/read-upload.sql", email, id)
+                .fetch_optional(&mut tx)
+                .await?
+                .ok_or_else(|| ioErr::new(ioErrKind::NotFound, "Upload does not exist"))?;
+
+            check_chunk(u.next_index, u.received, u.size, index, data.len())?;
+
+            // Chunks are kept apart until the upload is finished, rather than rewriting everything received so far
+            let chunk_size = data.len() as i32;
+            sqlx::query_file!("src/sql/create-upload-chunk.sql", id, index, data)
+                .execute(&mut tx)
+                .await?;
+
+            sqlx::query_file!("src/sql/update-upload.sql", id, chunk_size)
+                .execute(&mut tx)
+                .await?;
+
+            updated.push(Upload{
+                id: Some(id),
+                conversation: Some(u.conversation),
+                size: Some(u.size),
+                received: Some(u.received + chunk_size),
+                index: Some(u.next_index + 1),
+                ..Default::default()
+            });
+        };
+
+        tx.commit().await?;
+
+        Ok(Response{
+            status: STATUS_SUCCESS,
+            uploads: Some(updated),
+            ..Default::default()
+        })
+    }
+
+    /// Turn finished uploads into attachments once their size and checksum have been checked
+    pub async fn finish_uploads(self, login: &Login, db_pool: &PgPool) -> Result<Response, Box<dyn Error>> {
+        // Authenticate user
+        let email = login.email()?;
+
+        // Unpack request
+        let attachments = self.attachments
+            .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'attachments' list"))?;
+
+        let mut tx = db_pool.begin().await?;
+        let mut stored: Vec<Attachment> = Vec::new();
+
+        for attachment in attachments {
+            let upload_id = attachment.upload
+                .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'upload' field for 'attachment'"))?;
+            let sha256 = attachment.sha256
+                .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'sha256' field for 'attachment'"))?;
+
+            let u = sqlx::query_file!("src/sql/read-upload.sql", email, upload_id)
+                .fetch_optional(&mut tx)
+                .await?
+                .ok_or_else(|| ioErr::new(ioErrKind::NotFound, "Upload does not exist"))?;
+            let data: Vec<u8> = sqlx::query_file!("src/sql/read-upload-data.sql", upload_id)
+                .fetch_all(&mut tx)
+                .await?
+                .into_iter()
+                .flat_map(|c| c.data)
+                .collect();
+
+            check_upload(u.size, &data, &sha256)?;
+
+            let id = sqlx::query_file!("src/sql/create-attachment.sql",
+                    email,
+                    u.conversation,
+                    u.media_type,
+                    u.size,
+                    data)
+                .fetch_one(&mut tx)
+                .await?
+                .id;
+
+            sqlx::query_file!("src/sql/delete-upload-chunks.sql", upload_id)
+                .execute(&mut tx)
+                .await?;
+
+            sqlx::query_file!("src/sql/delete-upload.sql", upload_id)
+                .execute(&mut tx)
+                .await?;
+
+            stored.push(Attachment{
+                id: Some(id),
+                conversation: Some(u.conversation),
+                media_type: Some(u.media_type),
+                size: Some(u.size),
+                ..Default::default()
+            });
+        };
+
+        tx.commit().await?;
+
+        Ok(Response{
+            status: STATUS_SUCCESS,
+            attachments: Some(stored),
+            ..Default::default()
+        })
+    }
+
+    /// Download an attachment from a conversation the user is in
+    pub async fn read_attachments(self, login: &Login, db_pool: &PgPool) -> Result<Response, Box<dyn Error>> {
+        // Authenticate user
+        let email = login.email()?;
+
+        // Unpack request
+        let attachments = self.attachments
+            .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'attachments' list"))?;
+        let attachment_id = attachments.first()
+            .and_then(|a| a.id)
+            .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'id' field for 'attachment'"))?;
+
+        // Read from database, treating attachments outside the user's conversations as missing
+        let a = database::retry(|| sqlx::query_file!("src/sql/read-attachment.sql",
+                    email,
+                    attachment_id)
+                .fetch_optional(db_pool))
+            .await?
+            .ok_or_else(|| ioErr::new(ioErrKind::NotFound, "Attachment does not exist"))?;
+
+        Ok(Response{
+            status: STATUS_SUCCESS,
+            attachments: Some(vec![Attachment{
+                id: Some(a.id),
+                conversation: Some(a.conversation),
+                media_type: Some(a.media_type),
+                size: Some(a.size),
+                data: Some(a.data),
+                ..Default::default()
+            }]),
+            ..Default::default()
+        })
+    }
+
+    /// Read a single message by id from a conversation the user is in
+    pub async fn read_message_by_id(self, login: &Login, db_pool: &PgPool) -> Result<Response, Box<dyn Error>> {
+        // Authenticate user
+        let email = login.email()?;
+
+        // Unpack request
+        let messages = self.messages
+            .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'messages' list"))?;
+        let message_id = messages.first()
+            .and_then(|m| m.id)
+            .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'id' field for 'message'"))?;
+
+        // Read from database, treating messages outside the user's conversations as missing
+        let m = database::retry(|| sqlx::query_file!("src/sql/read-message-by-id.sql",
+                    email,
+                    message_id,
+                    !settings::is_enabled("HARD_DELETE_MESSAGES"))
+                .fetch_optional(db_pool))
+            .await?
+            .ok_or_else(|| ioErr::new(ioErrKind::NotFound, "Message does not exist"))?;
+
+        // Format response
+        let message = Message{
+            id: Some(m.id),
+            seq: Some(m.seq),
+            conversation: Some(m.conversation),
+            data: Some(encoding::decompress_data(m.data, m.data_encoding.as_deref())?),
+            media_type: m.media_type,
+            timestamp: m.timestamp,
+            created_at: Some(m.created_at),
+            edited_at: m.edited_at,
+            signature: m.signature,
+            sender: Some(m.email),
+            parent_id: m.parent_id,
+            attachment: m.attachment_id.map(|id| Attachment{
+                id: Some(id),
+                conversation: Some(m.conversation),
+                media_type: m.attachment_media_type,
+                size: m.attachment_size,
+                ..Default::default()
+            }),
+            deleted: Some(m.deleted),
+            ..Default::default()
+        };
+
+        Ok(Response{
+            status: STATUS_SUCCESS,
+            messages: Some(vec![message.redact()]),
+            ..Default::default()
+        })
+    }
+
+    /// Search messages in the user's conversations, most relevant first
+    pub async fn search_messages(self, login: &Login, db_pool: &PgPool) -> Result<Response, Box<dyn Error>> {
+        // Authenticate user
+        let email = login.email()?;
+
+        // Unpack request
+        let query = self.query.as_deref()
+            .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'query' field"))?;
+        let conversation_id = self.conversations
+            .as_ref()
+            .and_then(|c| c.first())
+            .and_then(|c| c.id);
+        let limit = self.page_size()?;
+        let offset = self.offset.unwrap_or(0).max(0);
+
+        // Read from database, restricted to the user's conversations
+        let mut stream = database::retry(|| sqlx::query_file!("src/sql/search-message.sql",
+                    email,
+                    query,
+                    conversation_id,
+                    limit + 1,
+                    offset)
+                .fetch_all(db_pool))
+            .await?;
+
+        let has_more = truncate_page(&mut stream, limit);
+
+        // Format response
+        let messages = stream
+            .into_iter()
+            .map(|m| -> Result<Message, Box<dyn Error>> {
+                Ok(Message{
+                    id: Some(m.id),
+                    seq: Some(m.seq),
+                    conversation: Some(m.conversation),
+                    data: Some(encoding::decompress_data(m.data, m.data_encoding.as_deref())?),
+                    media_type: m.media_type,
+                    timestamp: m.timestamp,
+                    created_at: Some(m.created_at),
+                    signature: m.signature,
+                    sender: Some(m.email),
+                    parent_id: m.parent_id,
+                    ..Default::default()
+                })
+            })
+            .collect::<Result<Vec<Message>, _>>()?;
+
+        let response = Response{
+            status: STATUS_SUCCESS,
+            messages: Some(messages),
+            has_more: Some(has_more),
+            ..Default::default()
+        };
+
+        Ok(response)
+    }
+
+    /// Read users in a conversation from the database
+    pub async fn read_users(self, login: &Login, db_pool: &PgPool) -> Result<Response, Box<dyn Error>> {
+        // Authenticate user
+        let email = login.email()?;
+
+        // Unpack request
+        let conversations = self.conversations
+            .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'conversations' list"))?;
+        let conversation = &conversations[0];
+
+        let conversation_id = conversation.id
+            .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'id' field for 'conversation'"))?;
+
+        // Check membership
+        if !database::is_member(email, conversation_id, db_pool).await? {
+            return Err(Box::new(ioErr::new(ioErrKind::PermissionDenied, "Not a member of conversation")));
+        }
+
+        // Read from database
+        let stream = database::retry(|| sqlx::query_file!("src/sql/read-user.sql",
+                    email,
+                    conversation_id)
+                .fetch_all(db_pool))
+            .await?;
+
+        // Format response
+        let users: Vec<User> = stream
+            .iter()
+            .map(|u| User{
+                email: Some(u.email.to_owned()),
+                name: u.name.to_owned(),
+                public_key: Some(u.public_key.to_owned()),
+                display_name: u.display_name.to_owned(),
+                avatar_url: u.avatar_url.to_owned(),
+                last_read_message_id: u.last_read_message_id,
+                ..Default::default()
+            })
+            .collect();
+
+        let response = Response{
+            status: STATUS_SUCCESS,
+            users: Some(users),
+            ..Default::default()
+        };
+
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::api::Cursor;
+    use crate::auth::{Lockout, Login, LOOKUPS};
+    use crate::auth::signature;
+    use crate::api::request::{Request, RequestBuilder, Operation, Target};
+    use crate::api::request::{DEFAULT_MAX_BATCH_SIZE, DEFAULT_MAX_PARTICIPANTS, DEFAULT_PAGE_SIZE, DEFAULT_MAX_PAGE_SIZE};
+    use crate::api::response::{self, Response};
+    use crate::api::response::{STATUS_BUSY, STATUS_CONFLICT, STATUS_FAILURE, STATUS_INVALID_INPUT, STATUS_INVALID_SIGNATURE, STATUS_NOT_FOUND, STATUS_PERMISSION_DENIED, STATUS_SUCCESS, STATUS_TIMED_OUT};
+    use crate::api::{Attachment, Conversation, Message, User};
+    use crate::database::lazy_pool;
+    use crate::settings::MediaAllowlist;
+    use crate::storage::{MemoryStorage, MessageQuery, MessageStream, NewConversation, NewMessages, NewUser, Storage, StoredConversation, StoredLogin, StoredMessageId};
+    use crate::api::request::{check_affected, check_attachment, check_allowed_media_type, check_attachment_size, check_chunk, check_conversation_name, check_message_content, check_password, check_revision_access, check_role, check_upload, hash_passwords, match_created_users, check_media_type, check_parent, check_participant_count, check_profile, check_read_pointer, check_timestamp, into_send, normalize_invitees, preview_text, searchable_text, truncate_page, with_timeout};
+    use async_trait::async_trait;
+    use chrono::{Duration, TimeZone, Utc};
+    use std::error::Error;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Instant;
+    use serde_json::json;
+    use sha2::{Digest, Sha256};
+    use std::io::Error as ioErr;
+    use std::io::ErrorKind as ioErrKind;
+    use zeroize::Zeroizing;
+    #[cfg(feature = "sqlite")]
+    use crate::database::sqlite_memory_pool;
+    #[cfg(feature = "sqlite")]
+    use crate::push;
+    #[cfg(feature = "sqlite")]
+    use crate::storage::SqliteStorage;
+
+    #[test]
+    fn test_request_from_json() {
+        let json = [
+            json!({"function": "CREATE USERS"}).to_string(),
+            json!({"function": "READ MESSAGES"}).to_string(),
+            json!({"function": "UPDATE CONVERSATIONS"}).to_string(),
+            json!({"function": "DELETE MESSAGES"}).to_string(),
+            json!({"function": "VERIFY USERS"}).to_string(),
+            json!({"function": "DELETE BLOCKS"}).to_string(),
+        ];
+
+        let requests: Vec<Request> = json
+            .iter()
+            .map(|req| Request::from_json(&req).unwrap())
+            .collect();
+
+        assert_eq!(requests[0].operation, Operation::Create);
+        assert_eq!(requests[0].target, Target::Users);
+
+        assert_eq!(requests[1].operation, Operation::Read);
+        assert_eq!(requests[1].target, Target::Messages);
+
+        assert_eq!(requests[2].operation, Operation::Update);
+        assert_eq!(requests[2].target, Target::Conversations);
+
+        assert_eq!(requests[3].operation, Operation::Delete);
+        assert_eq!(requests[3].target, Target::Messages);
+
+        assert_eq!(requests[4].operation, Operation::Verify);
+        assert_eq!(requests[4].target, Target::Users);
+
+        assert_eq!(requests[5].operation, Operation::Delete);
+        assert_eq!(requests[5].target, Target::Blocks);
+    }
+
+    #[test]
+    fn test_request_lists_from_json() {
+        // Lists can be left out (or null), but have to be lists of objects when they're given
+        let absent = Request::from_json(&json!({"function": "CREATE USERS"}).to_string()).unwrap();
+        assert!(absent.users.is_none());
+
+        let null = Request::from_json(&json!({"function": "CREATE USERS", "users": null}).to_string()).unwrap();
+        assert!(null.users.is_none());
+
+        let json = json!({"function": "CREATE USERS", "users": [{"email": "me@example.com"}, {}]}).to_string();
+        let present = Request::from_json(&json).unwrap();
+        let emails: Vec<Option<String>> = present.users.unwrap().into_iter().map(|u| u.email).collect();
+        assert_eq!(emails, vec![Some(String::from("me@example.com")), None]);
+
+        let invalid = [
+            (json!({"function": "CREATE USERS", "users": {"email": "me@example.com"}}), "Invalid 'users' field (must be a list)"),
+            (json!({"function": "CREATE MESSAGES", "messages": "[]"}), "Invalid 'messages' field (must be a list)"),
+            (json!({"function": "READ CONVERSATIONS", "conversations": [1]}), "Invalid item in 'conversations' list (must be an object)"),
+            (json!({"function": "CREATE MESSAGES", "messages": [{"timestamp": -1}]}), "Invalid 'timestamp' field for 'message'"),
+            (json!({"function": "CREATE MESSAGES", "messages": [{"id": 4294967296i64}]}), "Invalid item in 'messages' list (out of range integral type conversion attempted)"),
+        ];
+
+        for (json, message) in invalid.iter() {
+            let error = Request::from_json(&json.to_string()).err().unwrap();
+            assert_eq!(error.downcast_ref::<ioErr>().unwrap().kind(), ioErrKind::InvalidInput, "{}", json);
+            assert_eq!(error.to_string(), *message);
+        }
+    }
+
+    #[test]
+    fn test_is_read() {
+        let read = Request::builder(Operation::Read, Target::Messages).build();
+        assert!(read.is_read());
+        assert!(!read.is_write());
+
+        for operation in vec![Operation::Create, Operation::Update, Operation::Delete] {
+            let write = Request::builder(operation, Target::Messages).build();
+            assert!(!write.is_read());
+            assert!(write.is_write());
+        }
+
+        // Logging in stays on the primary without counting as a write
+        let verify = Request::builder(Operation::Verify, Target::Users).build();
+        assert!(!verify.is_read());
+        assert!(!verify.is_write());
+    }
+
+    #[test]
+    fn test_batch_from_json() {
+        let json = json!([
+            {"function": "CREATE USERS", "users": [{"email": "me@example.com"}]},
+            {"function": "READ MESSAGES", "limit": 10},
+            {"function": "DELETE BLOCKS"},
+        ]).to_string();
+
+        let requests = Request::batch_from_json(&json).unwrap();
+        assert_eq!(requests.len(), 3);
+        assert_eq!((&requests[0].operation, &requests[0].target), (&Operation::Create, &Target::Users));
+        assert_eq!((&requests[1].operation, &requests[1].target), (&Operation::Read, &Target::Messages));
+        assert_eq!(requests[1].limit, Some(10));
+        assert_eq!((&requests[2].operation, &requests[2].target), (&Operation::Delete, &Target::Blocks));
+
+        // One invalid request rejects the whole batch before any of it runs
+        let json = json!([
+            {"function": "READ MESSAGES"},
+            {"function": "READ NOTHING"},
+        ]).to_string();
+        let error = Request::batch_from_json(&json).err().unwrap();
+        assert_eq!(error.to_string(), "Invalid request 1 in batch: Unknown target");
+        assert_eq!(error.downcast_ref::<ioErr>().unwrap().kind(), ioErrKind::InvalidInput);
+
+        // Streamed requests get more than one response, so they can't be part of a batch
+        let json = json!([{"function": "READ MESSAGES", "stream": true}]).to_string();
+        let error = Request::batch_from_json(&json).err().unwrap();
+        assert_eq!(error.to_string(), "Invalid request 0 in batch: Streamed requests can't be batched");
+
+        assert_eq!(Request::batch_from_json("[]").err().unwrap().to_string(), "Empty batch");
+        assert!(Request::batch_from_json(r#"{"function": "READ MESSAGES"}"#).is_err());
+
+        let json = serde_json::Value::Array(vec![json!({"function": "READ MESSAGES"}); DEFAULT_MAX_BATCH_SIZE + 1]).to_string();
+        assert!(Request::batch_from_json(&json).is_err());
+    }
+
+    #[test]
+    fn test_request_passwords() {
+        let request = json!({"function": "UPDATE USERS", "users": [{"password": "k2uEa77H", "newPassword": "9poyvjJN"}]});
+
+        // Passwords come through whether a request is sent alone or in a batch (the JSON they're read from is scrubbed
+        // on the way, which leaves the parsed users alone)
+        let single = Request::from_json(&request.to_string()).unwrap();
+        let batch = Request::batch_from_json(&json!([request, request]).to_string()).unwrap();
+
+        for request in std::iter::once(single).chain(batch) {
+            let user = request.users.unwrap().into_iter().next().unwrap();
+            assert_eq!(user.password.as_deref().map(String::as_str), Some("k2uEa77H"));
+            assert_eq!(user.new_password.as_deref().map(String::as_str), Some("9poyvjJN"));
+        }
+    }
+
+    #[test]
+    fn test_request_validation() {
+        let valid = json!({"function": "READ USERS", "users": [{"email": "1@example.com"}]}).to_string();
+        let unknown = json!({"function": "READ USERS", "user": [{"email": "1@example.com"}]}).to_string();
+        let missing = json!({"users": [{"email": "1@example.com"}]}).to_string();
+        let mistyped = json!({"function": "READ USERS", "limit": "ten"}).to_string();
+
+        assert!(Request::from_json(&valid).is_ok());
+
+        let errors: Vec<String> = [unknown, missing, mistyped]
+            .iter()
+            .map(|req| Request::from_json(req).err().unwrap().to_string())
+            .collect();
+
+        assert!(errors[0].contains("unknown field `user`"), "{}", errors[0]);
+        assert!(errors[1].contains("missing field `function`"), "{}", errors[1]);
+        assert!(errors[2].contains("invalid type"), "{}", errors[2]);
+    }
+
+    #[test]
+    fn test_request_time_range() {
+        let valid = json!({
+            "function": "READ MESSAGES",
+            "since": "2021-01-01T00:00:00Z",
+            "until": "2021-02-01T02:00:00+02:00",
+        }).to_string();
+        let invalid = json!({"function": "READ MESSAGES", "since": "MjAyMS0wMS0wMVQwMDowMDowMFo="}).to_string();
+
+        let request = Request::from_json(&valid).unwrap();
+
+        assert_eq!(request.since, Some(Utc.ymd(2021, 1, 1).and_hms(0, 0, 0)));
+        assert_eq!(request.until, Some(Utc.ymd(2021, 2, 1).and_hms(0, 0, 0)));
+
+        let error = Request::from_json(&invalid).err().unwrap();
+        assert_eq!(error.to_string(), "Invalid 'since' timestamp");
+    }
+
+    #[test]
+    fn test_request_pagination() {
+        let json = [
+            json!({"function": "READ CONVERSATIONS"}).to_string(),
+            json!({"function": "READ CONVERSATIONS", "cursor": "12:3", "limit": 10, "offset": 20}).to_string(),
+            json!({"function": "READ CONVERSATIONS", "limit": 100000}).to_string(),
+            json!({"function": "READ CONVERSATIONS", "limit": -5}).to_string(),
+            json!({"function": "READ MESSAGES", "afterId": 30}).to_string(),
+            json!({"function": "READ MESSAGES", "beforeId": 12}).to_string(),
+            json!({"function": "READ MESSAGES", "parentId": 7}).to_string(),
+            json!({"function": "READ MESSAGES", "excludeSelf": true}).to_string(),
+            json!({"function": "READ CONVERSATIONS", "includeArchived": true}).to_string(),
+        ];
+
+        let requests: Vec<Request> = json
+            .iter()
+            .map(|req| Request::from_json(&req).unwrap())
+            .collect();
+
+        assert_eq!(requests[0].cursor, None);
+        assert_eq!(requests[0].page_size().unwrap(), DEFAULT_PAGE_SIZE);
+
+        assert_eq!(requests[1].cursor, Some(Cursor{ key: 12, id: 3 }));
+        assert_eq!(requests[1].page_size().unwrap(), 10);
+        assert_eq!(requests[1].offset, Some(20));
+
+        assert_eq!(requests[2].page_size().unwrap(), DEFAULT_MAX_PAGE_SIZE);
+        assert_eq!(requests[3].page_size().unwrap(), 1);
+        assert_eq!(requests[3].after_id, None);
+
+        assert_eq!(requests[4].after_id, Some(30));
+        assert_eq!(requests[4].before_id, None);
+
+        assert_eq!(requests[5].after_id, None);
+        assert_eq!(requests[5].before_id, Some(12));
+        assert_eq!(requests[5].parent_id, None);
+
+        assert_eq!(requests[6].parent_id, Some(7));
+        assert_eq!(requests[6].exclude_self, false);
+
+        assert_eq!(requests[7].exclude_self, true);
+        assert_eq!(requests[7].include_archived, false);
+
+        assert_eq!(requests[8].include_archived, true);
+
+        let malformed = json!({"function": "READ CONVERSATIONS", "cursor": "twelve"}).to_string();
+        assert!(Request::from_json(&malformed).is_err());
+    }
+
+    #[test]
+    fn test_normalize_invitees() {
+        let emails = vec![
+            String::from("2@example.com"),
+            String::from("Me@Example.com"),
+            String::from("3@example.com"),
+            String::from("2@EXAMPLE.com"),
+            String::from("2@example.com"),
+        ];
+
+        let (invitees, duplicates) = normalize_invitees(emails, "me@example.com");
+
+        assert_eq!(invitees, vec!["2@example.com", "3@example.com"]);
+        assert_eq!(duplicates, vec!["Me@Example.com", "2@EXAMPLE.com", "2@example.com"]);
+
+        let (invitees, duplicates) = normalize_invitees(Vec::new(), "me@example.com");
+
+        assert!(invitees.is_empty());
+        assert!(duplicates.is_empty());
+    }
+
+    #[test]
+    fn test_check_participant_count() {
+        assert!(check_participant_count(0, 1).is_ok());
+        assert!(check_participant_count(9, 10).is_ok());
+        assert!(check_participant_count(10, 10).is_err());
+        assert!(check_participant_count(1, 1).is_err());
+    }
+
+    #[test]
+    fn test_check_profile() {
+        let valid = User{
+            display_name: Some(String::from("Émilie")),
+            avatar_url: Some(String::from("https://example.com/avatar.png")),
+            ..Default::default()
+        };
+        let long_name = User{
+            display_name: Some("a".repeat(33)),
+            ..Default::default()
+        };
+        let long_url = User{
+            avatar_url: Some("a".repeat(257)),
+            ..Default::default()
+        };
+
+        assert!(check_profile(&valid).is_ok());
+        assert!(check_profile(&User::default()).is_ok());
+        assert!(check_profile(&long_name).is_err());
+        assert!(check_profile(&long_url).is_err());
+    }
+
+    #[test]
+    fn test_check_conversation_name() {
+        assert!(check_conversation_name("Example Conversation").is_ok());
+        assert!(check_conversation_name(&"a".repeat(50)).is_ok());
+        // Multibyte characters count once each, matching the column's limit
+        assert!(check_conversation_name(&"é".repeat(50)).is_ok());
+
+        let error = check_conversation_name(&"a".repeat(51)).err().unwrap();
+        assert_eq!(error.to_string(), "Invalid 'name' field for 'conversation' (maximum 50 characters)");
+    }
+
+    #[test]
+    fn test_check_message_content() {
+        let message = |media_type: &[u8], data: &[u8]| Message{
+            media_type: Some(media_type.to_vec()),
+            data: Some(data.to_vec()),
+            ..Default::default()
+        };
+
+        assert!(check_message_content(0, &message(b"text/plain", b"hello")).is_ok());
+        assert!(check_message_content(0, &Message::default()).is_ok());
+
+        let error = check_message_content(2, &message(b"image/png", b"MZ\x90\x00")).err().unwrap();
+        assert_eq!(error.to_string(), "Invalid 'data' field for message 2 (data is not a PNG image)");
+        assert_eq!(error.downcast_ref::<ioErr>().unwrap().kind(), ioErrKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_check_revision_access() {
+        assert!(check_revision_access(true, false).is_ok());
+        assert!(check_revision_access(false, true).is_ok());
+        assert!(check_revision_access(true, true).is_ok());
+
+        // Other members of the conversation can't see earlier versions
+        let error = check_revision_access(false, false).err().unwrap();
+        assert_eq!(error.downcast_ref::<ioErr>().unwrap().kind(), ioErrKind::PermissionDenied);
+    }
+
+    #[test]
+    fn test_hash_passwords() {
+        let passwords = vec![
+            Zeroizing::new(String::from("8nLpNaeJ")),
+            Zeroizing::new(String::from("9poyvjJN")),
+        ];
+
+        // Hashes come back in the same order as the passwords, each with its own salt
+        let hashed = hash_passwords(passwords).unwrap();
+        assert_eq!(hashed.len(), 2);
+        assert!(hashed[0].is_valid("8nLpNaeJ").unwrap());
+        assert!(hashed[1].is_valid("9poyvjJN").unwrap());
+        assert!(!hashed[1].is_valid("8nLpNaeJ").unwrap());
+        assert_ne!(hashed[0].salt, hashed[1].salt);
+
+        assert!(hash_passwords(Vec::new()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_check_role() {
+        assert!(check_role("admin").is_ok());
+        assert!(check_role("member").is_ok());
+        assert!(check_role("owner").is_err());
+        assert!(check_role("Admin").is_err());
+
+        let request = Request::from_json(&json!({"function": "READ CONVERSATIONS", "role": "admin"}).to_string()).unwrap();
+        assert_eq!(request.role.as_deref(), Some("admin"));
+
+        let request = Request::from_json(&json!({"function": "READ CONVERSATIONS"}).to_string()).unwrap();
+        assert_eq!(request.role, None);
+    }
+
+    #[test]
+    fn test_check_affected() {
+        assert_eq!(check_affected(1, "Reaction does not exist").unwrap(), 1);
+
+        let error = check_affected(0, "Reaction does not exist").unwrap_err();
+        assert_eq!(error.to_string(), "Reaction does not exist");
+        assert_eq!(error.downcast_ref::<ioErr>().unwrap().kind(), ioErrKind::NotFound);
+    }
+
+    #[test]
+    fn test_check_media_type() {
+        assert!(check_media_type(b"text/plain").is_ok());
+        assert!(check_media_type(b"application/vnd.echo+json").is_ok());
+
+        // Too long
+        let long = format!("text/{}", "a".repeat(123));
+        assert!(check_media_type(long.as_bytes()).is_err());
+
+        // Not in the form 'type/subtype'
+        assert!(check_media_type(b"textplain").is_err());
+        assert!(check_media_type(b"text/").is_err());
+        assert!(check_media_type(b"/plain").is_err());
+        assert!(check_media_type(b"text/plain/extra").is_err());
+        assert!(check_media_type(b"text/plain; charset=utf-8").is_err());
+    }
+
+    #[test]
+    fn test_check_allowed_media_type() {
+        // Any media type is allowed unless an allowlist is configured
+        assert!(check_allowed_media_type(b"application/x-custom", &MediaAllowlist::default()).is_ok());
+
+        let allowlist = MediaAllowlist::parse("text/plain, image/png").unwrap();
+        assert!(check_allowed_media_type(b"text/plain", &allowlist).is_ok());
+        assert!(check_allowed_media_type(b"Image/PNG", &allowlist).is_ok());
+
+        let error = check_allowed_media_type(b"image/gif", &allowlist).unwrap_err();
+        assert_eq!(error.downcast_ref::<ioErr>().unwrap().kind(), ioErrKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_check_timestamp() {
+        let now = Utc.ymd(2021, 1, 1).and_hms(12, 0, 0);
+        let skew = Duration::seconds(300);
+
+        let millis = |t: chrono::DateTime<Utc>| t.timestamp_millis();
+
+        // Past timestamps and small amounts of clock drift are fine
+        assert_eq!(check_timestamp(millis(now - Duration::days(1)), now, skew).unwrap(), now - Duration::days(1));
+        assert!(check_timestamp(millis(now + Duration::seconds(300)), now, skew).is_ok());
+        assert!(check_timestamp(0, now, skew).is_ok());
+
+        assert!(check_timestamp(millis(now + Duration::seconds(301)), now, skew).is_err());
+        assert!(check_timestamp(-1, now, skew).is_err());
+        assert!(check_timestamp(i64::MAX, now, skew).is_err());
+    }
+
+    #[test]
+    fn test_check_attachment() {
+        assert!(check_attachment(Some(3), 3).is_ok());
+
+        // Attachments from other conversations, or that don't exist, can't be used
+        assert!(check_attachment(Some(4), 3).is_err());
+        assert!(check_attachment(None, 3).is_err());
+
+        assert!(check_attachment_size(1024, 1024).is_ok());
+        assert!(check_attachment_size(1025, 1024).is_err());
+    }
+
+    #[test]
+    fn test_upload_chunks() {
+        let file = b"hello world";
+        let sha256 = Sha256::digest(file);
+        let size = file.len() as i32;
+
+        // Chunks arrive in order until the whole file is received
+        let mut received = 0;
+        for (index, chunk) in file.chunks(4).enumerate() {
+            assert!(check_chunk(index as i32, received, size, index as i32, chunk.len()).is_ok());
+            received += chunk.len() as i32;
+        }
+        assert!(check_upload(size, file, &sha256).is_ok());
+
+        // Out of order and repeated chunks are rejected
+        assert!(check_chunk(1, 4, size, 2, 4).is_err());
+        assert!(check_chunk(1, 4, size, 0, 4).is_err());
+
+        // A resumed upload carries on from the next chunk the server expects
+        assert!(check_chunk(2, 8, size, 2, 3).is_ok());
+
+        // Chunks can't go past the declared size
+        assert!(check_chunk(2, 8, size, 2, 4).is_err());
+
+        // Incomplete uploads and checksum mismatches are rejected
+        assert!(check_upload(size, &file[..8], &sha256).is_err());
+        assert!(check_upload(size, b"hello_world", &sha256).is_err());
+    }
+
+    #[test]
+    fn test_check_parent() {
+        // Replies can be to any message in the same conversation, whoever sent it
+        assert!(check_parent(Some(3), 3).is_ok());
+
+        // Replies can't cross conversations or point at missing messages
+        assert!(check_parent(Some(4), 3).is_err());
+        assert!(check_parent(None, 3).is_err());
+    }
+
+    #[test]
+    fn test_check_read_pointer() {
+        // Pointers start unset and can advance or stay put
+        assert!(check_read_pointer(None, 1).is_ok());
+        assert!(check_read_pointer(Some(3), 5).is_ok());
+        assert!(check_read_pointer(Some(5), 5).is_ok());
+
+        // Pointers never move backward
+        assert!(check_read_pointer(Some(5), 3).is_err());
+    }
+
+    #[test]
+    fn test_preview_text() {
+        assert_eq!(preview_text(b"text/plain", b"hello", 100), Some(String::from("hello")));
+        assert_eq!(preview_text(b"image/png", b"hello", 100), None);
+        assert_eq!(preview_text(b"text/plain", &[0xff, 0x00, 0x68], 100), None);
+
+        // Long messages are cut down to the preview length
+        let long = "a".repeat(150);
+        assert_eq!(preview_text(b"text/plain", long.as_bytes(), 100).unwrap().chars().count(), 100);
+        assert_eq!(preview_text(b"text/plain", long.as_bytes(), 20).unwrap().chars().count(), 20);
+
+        // A character cut off by the database is dropped
+        let text = "é".as_bytes();
+        assert_eq!(preview_text(b"text/plain", &[b'h', text[0]], 100), Some(String::from("h")));
+    }
+
+    #[test]
+    fn test_searchable_text() {
+        assert_eq!(searchable_text(b"text/plain", b"hello world"), Some("hello world"));
+        assert_eq!(searchable_text(b"text/plain", &[0xff, 0xfe, 0x00]), None);
+        assert_eq!(searchable_text(b"application/octet-stream", b"hello world"), None);
+        assert_eq!(searchable_text(b"image/png", b"hello world"), None);
+    }
+
+    #[test]
+    fn test_match_created_users() {
+        let emails: Vec<String> = ["a@example.com", "b@example.com", "a@example.com", "c@example.com"]
+            .iter()
+            .map(|e| e.to_string())
+            .collect();
+
+        // b@example.com was already registered, and the second a@example.com is a duplicate within the batch
+        let created = vec![(12, String::from("c@example.com")), (11, String::from("a@example.com"))];
+        assert_eq!(match_created_users(&emails, created), vec![Some(11), None, None, Some(12)]);
+
+        assert_eq!(match_created_users(&emails, Vec::new()), vec![None; 4]);
+    }
+
+    #[test]
+    fn test_truncate_page() {
+        // One more row than the limit means another page exists
+        let mut rows = vec![1, 2, 3, 4];
+        assert!(truncate_page(&mut rows, 3));
+        assert_eq!(rows, vec![1, 2, 3]);
+
+        let mut rows = vec![1, 2, 3];
+        assert!(!truncate_page(&mut rows, 3));
+        assert_eq!(rows, vec![1, 2, 3]);
+
+        let mut rows: Vec<i32> = Vec::new();
+        assert!(!truncate_page(&mut rows, 3));
+        assert!(rows.is_empty());
+    }
+
+    #[test]
+    fn test_request_builder() {
+        let request = Request::builder(Operation::Read, Target::Messages)
+            .limit(10)
+            .after_id(30)
+            .exclude_self(true)
+            .build();
+
+        assert_eq!(request.operation, Operation::Read);
+        assert_eq!(request.target, Target::Messages);
+        assert_eq!(request.limit, Some(10));
+        assert_eq!(request.after_id, Some(30));
+        assert_eq!(request.before_id, None);
+        assert!(request.exclude_self);
+        assert!(request.users.is_none());
+        assert_eq!(request.function(), "READ MESSAGES");
+    }
+
+    /// Handle a request that's refused before it reaches the database, returning why
+    async fn refused(request: Request, login: &mut Login) -> Box<dyn Error> {
+        request.handle(login, &lazy_pool()).await.err().unwrap()
+    }
+
+    #[async_std::test]
+    async fn test_request_builder_handle() {
+        let db_pool = lazy_pool();
+        let mut login = Login::new();
+
+        let weak_password = User{
+            email: Some(String::from("1@example.com")),
+            password: Some(Zeroizing::new(String::from("short"))),
+            public_key: Some(vec![0; 32]),
+            ..Default::default()
+        };
+        let missing_key = User{
+            public_key: None,
+            ..weak_password.clone()
+        };
+
+        // Each invalid user is reported on its own
+        let request = Request::builder(Operation::Create, Target::Users)
+            .users(vec![weak_password, missing_key])
+            .build();
+        let response = request.handle(&mut login, &db_pool).await.unwrap();
+        let users = response.users.unwrap();
+        assert_eq!(users.len(), 2);
+        assert!(users.iter().all(|u| u.status == Some(STATUS_INVALID_INPUT) && u.id.is_none()));
+        assert_eq!(users[0].email.as_deref(), Some("1@example.com"));
+
+        let request = Request::builder(Operation::Create, Target::Users).build();
+        let error = refused(request, &mut login).await;
+        assert_eq!(error.to_string(), "Missing 'users' list");
+    }
+
+    #[async_std::test]
+    async fn test_delete_conversations() {
+        let mut login = Login::new();
+
+        let request = Request::builder(Operation::Delete, Target::Conversations)
+            .conversations(vec![Conversation{
+                id: Some(1),
+                ..Default::default()
+            }])
+            .build();
+        let error = refused(request, &mut login).await;
+        assert_eq!(error.to_string(), "Not authenticated");
+
+        login.authenticate(String::from("me@example.com")).unwrap();
+
+        let request = Request::builder(Operation::Delete, Target::Conversations).build();
+        let error = refused(request, &mut login).await;
+        assert_eq!(error.to_string(), "Missing 'conversations' list");
+    }
+
+    #[async_std::test]
+    async fn test_read_user_by_email_limit() {
+        while LOOKUPS.check("limited@example.com", Instant::now()) {}
+
+        // The limit belongs to the user, so logging in again on a new connection doesn't reset it
+        let mut login = Login::new();
+        login.authenticate(String::from("Limited@example.com")).unwrap();
+
+        let request = Request::builder(Operation::Read, Target::Users)
+            .users(vec![User::from_email(String::from("you@example.com"))])
+            .build();
+        let error = refused(request, &mut login).await;
+        assert_eq!(error.to_string(), "Too many lookups");
+        assert_eq!(response::status_of(error.as_ref()), STATUS_PERMISSION_DENIED);
+    }
+
+    #[async_std::test]
+    async fn test_create_blocks() {
+        let mut login = Login::new();
+        login.authenticate(String::from("me@example.com")).unwrap();
+
+        let block = |email: &str| Request::builder(Operation::Create, Target::Blocks)
+            .users(vec![User::from_email(String::from("other@example.com")), User::from_email(String::from(email))])
+            .build();
+
+        // Users can't block themselves, whichever case their email is given in
+        let error = refused(block("me@example.com"), &mut login).await;
+        assert_eq!(error.to_string(), "Cannot block yourself");
+
+        let error = refused(block("Me@Example.com"), &mut login).await;
+        assert_eq!(Response::from_error(error.as_ref()).status, STATUS_INVALID_INPUT);
+    }
+
+    #[async_std::test]
+    async fn test_rotate_public_key() {
+        let mut login = Login::new();
+
+        let rotate = |user: User| Request::builder(Operation::Update, Target::Users)
+            .users(vec![user])
+            .build();
+        let valid = User{
+            password: Some(Zeroizing::new(String::from("k2uEa77H"))),
+            new_public_key: Some(vec![0x3d, 0x40, 0x17, 0xc3, 0xe8, 0x43, 0x89, 0x5a, 0x92, 0xb7, 0x0a, 0xa7, 0x4d, 0x1b, 0x7e, 0xbc, 0x9c, 0x98, 0x2c, 0xcf, 0x2e, 0xc4, 0x96, 0x8c, 0xc0, 0xcd, 0x55, 0xf1, 0x2a, 0xf4, 0x66, 0x0c]),
+            ..Default::default()
+        };
+
+        let error = refused(rotate(valid.clone()), &mut login).await;
+        assert_eq!(error.to_string(), "Not authenticated");
+
+        login.authenticate(String::from("me@example.com")).unwrap();
+
+        // Rotating a key needs the current password and a real key
+        let error = refused(rotate(User{ password: None, ..valid.clone() }), &mut login).await;
+        assert_eq!(error.to_string(), "Missing 'password' field for 'user'");
+
+        let error = refused(rotate(User{ new_public_key: Some(vec![0; 31]), ..valid.clone() }), &mut login).await;
+        assert_eq!(error.to_string(), "Invalid 'new_public_key' field for 'user'");
+
+        let error = refused(rotate(User{ new_password: Some(Zeroizing::new(String::from("9poyvjJN"))), ..valid }), &mut login).await;
+        assert_eq!(error.to_string(), "Password and public key must be changed separately");
+    }
+
+    #[async_std::test]
+    async fn test_handle_streamed() {
+        let db_pool = lazy_pool();
+        let mut login = Login::new();
+        let (sender, sent) = async_std::channel::unbounded();
+
+        let request = Request::from_json(&json!({"function": "READ MESSAGES", "stream": true}).to_string()).unwrap();
+        assert!(request.is_streamed());
+        let error = request.handle_streamed(&login, &db_pool, &db_pool, &sender).await.err().unwrap();
+        assert_eq!(error.to_string(), "Not authenticated");
+
+        login.authenticate(String::from("me@example.com")).unwrap();
+
+        let request = Request::builder(Operation::Read, Target::Messages)
+            .query(String::from("hello"))
+            .stream(true)
+            .build();
+        let error = request.handle_streamed(&login, &db_pool, &db_pool, &sender).await.err().unwrap();
+        assert_eq!(error.to_string(), "Only reading a conversation's messages can be streamed");
+
+        let request = Request::builder(Operation::Read, Target::Conversations)
+            .stream(true)
+            .build();
+        let error = request.handle_streamed(&login, &db_pool, &db_pool, &sender).await.err().unwrap();
+        assert_eq!(error.to_string(), "Only reading a conversation's messages can be streamed");
+
+        assert!(sent.is_empty());
+        assert!(!Request::builder(Operation::Read, Target::Messages).build().is_streamed());
+    }
+
+    #[async_std::test]
+    async fn test_handle_batch() {
+        let db_pool = lazy_pool();
+        let mut login = Login::new();
+
+        let json = json!([
+            {"function": "CREATE USERS", "users": [{"email": "1@example.com", "password": "short", "publicKey": base64::encode([0; 32])}]},
+            {"function": "READ MESSAGES"},
+            {"function": "DELETE USERS"},
+        ]).to_string();
+        let requests = Request::batch_from_json(&json).unwrap();
+
+        // A failed request is answered in its place without stopping the rest
+        let responses = Request::handle_batch(requests, &mut login, &db_pool, &db_pool).await;
+        assert_eq!(responses.len(), 3);
+        assert_eq!(responses[0].status, STATUS_SUCCESS);
+        assert_eq!(responses[0].users.as_ref().unwrap()[0].status, Some(STATUS_INVALID_INPUT));
+        assert_eq!(responses[1].status, STATUS_PERMISSION_DENIED);
+        assert_eq!(responses[1].error.as_deref(), Some("Not authenticated"));
+        assert_eq!(responses[2].status, STATUS_INVALID_INPUT);
+        assert_eq!(responses[2].error.as_deref(), Some("Unsupported operation"));
+    }
+
+    #[async_std::test]
+    async fn test_request_handle() {
+        let mut login = Login::new();
+
+        let supported = [
+            "VERIFY USERS",
+            "CREATE CONVERSATIONS",
+            "CREATE MESSAGES",
+            "CREATE USERS",
+            "CREATE BLOCKS",
+            "CREATE REACTIONS",
+            "READ CONVERSATIONS",
+            "READ MESSAGES",
+            "READ USERS",
+            "DELETE BLOCKS",
+            "DELETE REACTIONS",
+            "READ INVITATIONS",
+            "UPDATE INVITATIONS",
+            "UPDATE CONVERSATIONS",
+            "CREATE PARTICIPANTS",
+            "UPDATE USERS",
+            "CREATE ATTACHMENTS",
+            "READ ATTACHMENTS",
+            "CREATE UPLOADS",
+            "READ UPLOADS",
+            "UPDATE UPLOADS",
+            "DELETE MESSAGES",
+            "UPDATE MESSAGES",
+            "READ REVISIONS",
+        ];
+
+        // Searches, single messages and purges are requests carrying a query, a message or a flag
+        let variants = [
+            json!({"function": "READ MESSAGES", "query": "hello"}),
+            json!({"function": "READ MESSAGES", "messages": [{"id": 1}]}),
+            json!({"function": "DELETE MESSAGES", "messages": [{"id": 1}], "purge": true}),
+            json!({"function": "UPDATE USERS", "users": [{"password": "k2uEa77H", "newPassword": "9poyvjJN"}]}),
+        ];
+
+        for variant in variants.iter() {
+            let request = Request::from_json(&variant.to_string()).unwrap();
+            let error = refused(request, &mut login).await;
+            assert_eq!(error.to_string(), "Not authenticated");
+        }
+
+        for function in supported.iter() {
+            let request = Request::from_json(&json!({"function": function}).to_string()).unwrap();
+            let error = refused(request, &mut login).await;
+            assert_ne!(error.to_string(), "Unsupported operation", "{}", function);
+        }
+
+        let unsupported = [
+            "DELETE USERS",
+            "CREATE REVISIONS",
+        ];
+
+        for function in unsupported.iter() {
+            let request = Request::from_json(&json!({"function": function}).to_string()).unwrap();
+            let error = refused(request, &mut login).await;
+            assert_eq!(error.to_string(), "Unsupported operation", "{}", function);
+        }
+
+        for function in ["VERIFY MESSAGES", "VERIFY CONVERSATIONS"].iter() {
+            let request = Request::from_json(&json!({"function": function}).to_string()).unwrap();
+            let error = refused(request, &mut login).await;
+            assert_eq!(error.to_string(), "Only users can be verified", "{}", function);
+        }
+    }
+
+    /// Check logins against a storage holding 'me@example.com' with the password 'k2uEa77H'
+    async fn check_verify_users(storage: &dyn Storage) {
+        let verify = |email: &str, password: &str| Request::builder(Operation::Verify, Target::Users)
+            .users(vec![User{
+                email: Some(String::from(email)),
+                password: Some(Zeroizing::new(String::from(password))),
+                ..Default::default()
+            }])
+            .build();
+
+        // Wrong passwords and unknown users are turned away alike
+        let mut login = Login::new();
+        let wrong_password = verify("me@example.com", "9poyvjJN").verify_users(&mut login, storage).await.err().unwrap();
+        let unknown_user = verify("you@example.com", "k2uEa77H").verify_users(&mut login, storage).await.err().unwrap();
+        assert_eq!(wrong_password.to_string(), "Invalid password");
+        assert_eq!(unknown_user.to_string(), wrong_password.to_string());
+        assert_eq!(response::status_of(unknown_user.as_ref()), STATUS_PERMISSION_DENIED);
+        assert_eq!(Response::from_error(unknown_user.as_ref()).to_json(), Response::from_error(wrong_password.as_ref()).to_json());
+        assert!(!login.is_authenticated());
+        assert_eq!(storage.get_user_by_email("me@example.com").await.unwrap().unwrap().failed_attempts, 1);
+
+        // A successful login starts the count again
+        let response = verify("me@example.com", "k2uEa77H").verify_users(&mut login, storage).await.unwrap();
+        assert_eq!(response.status, STATUS_SUCCESS);
+        assert!(login.is_authenticated());
+        assert_eq!(login.email().unwrap(), "me@example.com");
+        assert_eq!(storage.get_user_by_email("me@example.com").await.unwrap().unwrap().failed_attempts, 0);
+
+        // Failing to log in as someone else logs the connection out
+        verify("you@example.com", "wrong").verify_users(&mut login, storage).await.err().unwrap();
+        assert!(!login.is_authenticated());
+        assert!(login.email().is_err());
+
+        // Locked accounts are turned away even with the right password
+        let lockout = Lockout{
+            max_attempts: 1,
+            duration: Duration::minutes(5),
+        };
+        assert_eq!(storage.add_failed_login("me@example.com", &lockout).await.unwrap(), Some(0));
+        let mut login = Login::new();
+        let locked = verify("me@example.com", "k2uEa77H").verify_users(&mut login, storage).await.err().unwrap();
+        assert_eq!(locked.to_string(), wrong_password.to_string());
+        assert_eq!(Response::from_error(locked.as_ref()).to_json(), Response::from_error(wrong_password.as_ref()).to_json());
+        assert!(!login.is_authenticated());
+
+        let error = Request::builder(Operation::Verify, Target::Users).build()
+            .verify_users(&mut login, storage).await.err().unwrap();
+        assert_eq!(error.to_string(), "Missing 'users' list");
+    }
+
+    /// Check creating conversations in a storage holding 'me@example.com' and 'you@example.com'
+    async fn check_create_conversations(storage: &dyn Storage) {
+        let mut login = Login::new();
+        login.authenticate(String::from("me@example.com")).unwrap();
+
+        let create = |emails: &[&str], direct: bool, public: bool| Request::builder(Operation::Create, Target::Conversations)
+            .users(emails
+                .iter()
+                .map(|email| User::from_email(String::from(*email)))
+                .collect::<Vec<User>>())
+            .conversations(vec![Conversation{
+                name: Some(String::from("Chat")),
+                direct: Some(direct),
+                public: Some(public),
+                ..Default::default()
+            }])
+            .build();
+
+        // Invitees are checked before anything is stored
+        let response = create(&["you@example.com", "nobody@example.com"], false, false)
+            .create_conversations(&login, storage).await.unwrap();
+        assert_eq!(response.status, STATUS_NOT_FOUND);
+        assert_eq!(response.users.unwrap()[0].email.as_deref(), Some("nobody@example.com"));
+
+        // Too many invitees are refused before any of them are looked up
+        let crowd: Vec<String> = (0..DEFAULT_MAX_PARTICIPANTS).map(|i| format!("nobody{}@example.com", i)).collect();
+        let error = create(&crowd.iter().map(String::as_str).collect::<Vec<&str>>(), false, false)
+            .create_conversations(&login, storage).await.err().unwrap();
+        assert_eq!(error.to_string(), format!("Too many participants (maximum {})", DEFAULT_MAX_PARTICIPANTS));
+
+        let error = create(&["you@example.com"], true, true)
+            .create_conversations(&login, storage).await.err().unwrap();
+        assert_eq!(error.to_string(), "Direct conversations cannot be public");
+
+        let error = create(&[], true, false)
+            .create_conversations(&login, storage).await.err().unwrap();
+        assert_eq!(error.to_string(), "Direct conversations need exactly one other user");
+
+        // Repeated invitees are reported
+        let response = create(&["You@example.com", "you@example.com"], false, false)
+            .create_conversations(&login, storage).await.unwrap();
+        assert_eq!(response.status, STATUS_SUCCESS);
+        assert_eq!(response.created, Some(true));
+        assert_eq!(response.duplicates, Some(vec![String::from("you@example.com")]));
+        assert_eq!(response.conversations.unwrap()[0].name.as_deref(), Some("Chat"));
+
+        // A direct conversation the other user has only been invited to isn't reused
+        let first = create(&["you@example.com"], true, false)
+            .create_conversations(&login, storage).await.unwrap();
+        let second = create(&["you@example.com"], true, false)
+            .create_conversations(&login, storage).await.unwrap();
+        assert_eq!(first.created, Some(true));
+        assert_eq!(second.created, Some(true));
+        assert_ne!(first.conversations.unwrap()[0].id, second.conversations.unwrap()[0].id);
+
+        let error = create(&["you@example.com"], false, false)
+            .create_conversations(&Login::new(), storage).await.err().unwrap();
+        assert_eq!(error.to_string(), "Not authenticated");
+    }
+
+    #[async_std::test]
+    async fn test_verify_users() {
+        let storage = MemoryStorage::default();
+        storage.add_user("me@example.com", "k2uEa77H");
+
+        check_verify_users(&storage).await;
+    }
+
+    /// Storage that counts the writes made to it
+    struct CountingStorage {
+        inner: MemoryStorage,
+        writes: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl Storage for CountingStorage {
+        async fn get_user_by_email(&self, email: &str) -> Result<Option<StoredLogin>, Box<dyn Error>> {
+            self.inner.get_user_by_email(email).await
+        }
+
+        async fn add_failed_login(&self, email: &str, lockout: &Lockout) -> Result<Option<i32>, Box<dyn Error>> {
+            self.writes.fetch_add(1, Ordering::SeqCst);
+            self.inner.add_failed_login(email, lockout).await
+        }
+
+        async fn reset_failed_logins(&self, email: &str) -> Result<(), Box<dyn Error>> {
+            self.writes.fetch_add(1, Ordering::SeqCst);
+            self.inner.reset_failed_logins(email).await
+        }
+
+        async fn existing_users(&self, emails: &[String]) -> Result<Vec<String>, Box<dyn Error>> {
+            self.inner.existing_users(emails).await
+        }
+
+        async fn insert_users(&self, users: &[NewUser]) -> Result<Vec<(i32, String)>, Box<dyn Error>> {
+            self.writes.fetch_add(1, Ordering::SeqCst);
+            self.inner.insert_users(users).await
+        }
+
+        async fn get_public_key(&self, email: &str) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+            self.inner.get_public_key(email).await
+        }
+
+        async fn insert_conversation(&self, conversation: &NewConversation) -> Result<StoredConversation, Box<dyn Error>> {
+            self.writes.fetch_add(1, Ordering::SeqCst);
+            self.inner.insert_conversation(conversation).await
+        }
+
+        async fn conversation_exists(&self, conversation_id: i32) -> Result<bool, Box<dyn Error>> {
+            self.inner.conversation_exists(conversation_id).await
+        }
+
+        async fn is_public(&self, conversation_id: i32) -> Result<Option<bool>, Box<dyn Error>> {
+            self.inner.is_public(conversation_id).await
+        }
+
+        async fn is_member(&self, email: &str, conversation_id: i32) -> Result<bool, Box<dyn Error>> {
+            self.inner.is_member(email, conversation_id).await
+        }
+
+        async fn is_blocked(&self, email: &str, conversation_id: i32) -> Result<bool, Box<dyn Error>> {
+            self.inner.is_blocked(email, conversation_id).await
+        }
+
+        async fn add_member(&self, email: &str, conversation_ids: &[i32], role: &str) -> Result<(), Box<dyn Error>> {
+            self.writes.fetch_add(1, Ordering::SeqCst);
+            self.inner.add_member(email, conversation_ids, role).await
+        }
+
+        async fn message_conversation(&self, message_id: i32) -> Result<Option<i32>, Box<dyn Error>> {
+            self.inner.message_conversation(message_id).await
+        }
+
+        async fn attachment_conversation(&self, attachment_id: i32) -> Result<Option<i32>, Box<dyn Error>> {
+            self.inner.attachment_conversation(attachment_id).await
+        }
+
+        async fn insert_messages(&self, messages: &NewMessages) -> Result<Vec<StoredMessageId>, Box<dyn Error>> {
+            self.writes.fetch_add(1, Ordering::SeqCst);
+            self.inner.insert_messages(messages).await
+        }
+
+        fn list_messages<'a>(&'a self, email: &str, conversation_id: i32, query: &MessageQuery) -> MessageStream<'a> {
+            self.inner.list_messages(email, conversation_id, query)
+        }
+    }
+
+    #[async_std::test]
+    async fn test_verify_users_writes() {
+        let storage = CountingStorage{
+            inner: MemoryStorage::default(),
+            writes: AtomicUsize::new(0),
+        };
+        storage.inner.add_user("me@example.com", "k2uEa77H");
+        storage.inner.add_user("locked@example.com", "k2uEa77H");
+        storage.inner.users.lock().unwrap().get_mut("locked@example.com").unwrap().locked_until = Some(Utc::now() + Duration::minutes(5));
+
+        let verify = |email: &str, password: &str| Request::builder(Operation::Verify, Target::Users)
+            .users(vec![User{
+                email: Some(String::from(email)),
+                password: Some(Zeroizing::new(String::from(password))),
+                ..Default::default()
+            }])
+            .build();
+
+        // Unknown users, wrong passwords and locked accounts cost the same write as a successful login, and are
+        // answered alike
+        let mut login = Login::new();
+        let mut errors = Vec::new();
+
+        for &(email, password) in [("you@example.com", "k2uEa77H"), ("me@example.com", "9poyvjJN"), ("locked@example.com", "k2uEa77H")].iter() {
+            storage.writes.store(0, Ordering::SeqCst);
+            let error = verify(email, password).verify_users(&mut login, &storage).await.err().unwrap();
+            assert_eq!(storage.writes.load(Ordering::SeqCst), 1, "{}", email);
+            errors.push(Response::from_error(error.as_ref()).to_json());
+        }
+
+        assert!(errors.iter().all(|e| *e == errors[0]));
+        assert!(!login.is_authenticated());
+
+        storage.writes.store(0, Ordering::SeqCst);
+        verify("me@example.com", "k2uEa77H").verify_users(&mut login, &storage).await.unwrap();
+        assert_eq!(storage.writes.load(Ordering::SeqCst), 1);
+        assert!(login.is_authenticated());
+    }
+
+    #[async_std::test]
+    async fn test_check_password() {
+        let storage = MemoryStorage::default();
+        storage.add_user("me@example.com", "k2uEa77H");
+        let password = |p: &str| Zeroizing::new(String::from(p));
+
+        // Re-entering a password counts towards the same lockout as logging in
+        let error = check_password("me@example.com", password("9poyvjJN"), &storage).await.err().unwrap();
+        assert_eq!(error.to_string(), "Invalid password");
+        assert_eq!(storage.get_user_by_email("me@example.com").await.unwrap().unwrap().failed_attempts, 1);
+
+        check_password("me@example.com", password("k2uEa77H"), &storage).await.unwrap();
+        assert_eq!(storage.get_user_by_email("me@example.com").await.unwrap().unwrap().failed_attempts, 0);
+
+        // Locked accounts are refused even with the right password
+        storage.users.lock().unwrap().get_mut("me@example.com").unwrap().locked_until = Some(Utc::now() + Duration::minutes(5));
+        let error = check_password("me@example.com", password("k2uEa77H"), &storage).await.err().unwrap();
+        assert_eq!(error.to_string(), "Invalid password");
+
+        let error = check_password("you@example.com", password("k2uEa77H"), &storage).await.err().unwrap();
+        assert_eq!(error.to_string(), "Invalid password");
+    }
+
+    /// Storage that takes `delay` to look anything up, like a database connection that's stuck
+    struct SlowStorage {
+        inner: MemoryStorage,
+        delay: std::time::Duration,
+    }
+
+    #[async_trait]
+    impl Storage for SlowStorage {
+        async fn get_user_by_email(&self, email: &str) -> Result<Option<StoredLogin>, Box<dyn Error>> {
+            async_std::task::sleep(self.delay).await;
+            self.inner.get_user_by_email(email).await
+        }
+
+        async fn add_failed_login(&self, email: &str, lockout: &Lockout) -> Result<Option<i32>, Box<dyn Error>> {
+            self.inner.add_failed_login(email, lockout).await
+        }
+
+        async fn reset_failed_logins(&self, email: &str) -> Result<(), Box<dyn Error>> {
+            self.inner.reset_failed_logins(email).await
+        }
+
+        async fn existing_users(&self, emails: &[String]) -> Result<Vec<String>, Box<dyn Error>> {
+            self.inner.existing_users(emails).await
+        }
+
+        async fn insert_users(&self, users: &[NewUser]) -> Result<Vec<(i32, String)>, Box<dyn Error>> {
+            self.inner.insert_users(users).await
+        }
+
+        async fn get_public_key(&self, email: &str) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+            self.inner.get_public_key(email).await
+        }
+
+        async fn insert_conversation(&self, conversation: &NewConversation) -> Result<StoredConversation, Box<dyn Error>> {
+            self.inner.insert_conversation(conversation).await
+        }
+
+        async fn conversation_exists(&self, conversation_id: i32) -> Result<bool, Box<dyn Error>> {
+            self.inner.conversation_exists(conversation_id).await
+        }
+
+        async fn is_public(&self, conversation_id: i32) -> Result<Option<bool>, Box<dyn Error>> {
+            self.inner.is_public(conversation_id).await
+        }
+
+        async fn is_member(&self, email: &str, conversation_id: i32) -> Result<bool, Box<dyn Error>> {
+            self.inner.is_member(email, conversation_id).await
+        }
+
+        async fn is_blocked(&self, email: &str, conversation_id: i32) -> Result<bool, Box<dyn Error>> {
+            self.inner.is_blocked(email, conversation_id).await
+        }
+
+        async fn add_member(&self, email: &str, conversation_ids: &[i32], role: &str) -> Result<(), Box<dyn Error>> {
+            self.inner.add_member(email, conversation_ids, role).await
+        }
+
+        async fn message_conversation(&self, message_id: i32) -> Result<Option<i32>, Box<dyn Error>> {
+            self.inner.message_conversation(message_id).await
+        }
+
+        async fn attachment_conversation(&self, attachment_id: i32) -> Result<Option<i32>, Box<dyn Error>> {
+            self.inner.attachment_conversation(attachment_id).await
+        }
+
+        async fn insert_messages(&self, messages: &NewMessages) -> Result<Vec<StoredMessageId>, Box<dyn Error>> {
+            self.inner.insert_messages(messages).await
+        }
+
+        fn list_messages<'a>(&'a self, email: &str, conversation_id: i32, query: &MessageQuery) -> MessageStream<'a> {
+            self.inner.list_messages(email, conversation_id, query)
+        }
+    }
+
+    #[async_std::test]
+    async fn test_with_timeout() {
+        let storage = SlowStorage{
+            inner: MemoryStorage::default(),
+            delay: std::time::Duration::from_secs(5),
+        };
+        storage.inner.add_user("me@example.com", "k2uEa77H");
+        let verify = || Request::builder(Operation::Verify, Target::Users)
+            .users(vec![User{
+                email: Some(String::from("me@example.com")),
+                password: Some(Zeroizing::new(String::from("k2uEa77H"))),
+                ..Default::default()
+            }])
+            .build();
+
+        // A request stuck waiting on storage is abandoned once its time is up
+        let mut login = Login::new();
+        let started = std::time::Instant::now();
+        let error = with_timeout(std::time::Duration::from_millis(100), verify().verify_users(&mut login, &storage)).await.err().unwrap();
+        assert!(started.elapsed() < std::time::Duration::from_secs(2));
+        assert_eq!(response::status_of(error.as_ref()), STATUS_TIMED_OUT);
+        assert_eq!(Response::from_error(error.as_ref()).error.as_deref(), Some("Request timed out"));
+        assert!(!login.is_authenticated());
+
+        // The same login can carry on with requests that finish in time
+        let storage = SlowStorage{
+            delay: std::time::Duration::from_millis(10),
+            ..storage
+        };
+        let response = with_timeout(std::time::Duration::from_secs(5), verify().verify_users(&mut login, &storage)).await.unwrap();
+        assert_eq!(response.status, STATUS_SUCCESS);
+        assert!(login.is_authenticated());
+    }
+
+    #[test]
+    fn test_into_send() {
+        let describe = |error: Box<dyn Error>| {
+            let response = Response::from_error(into_send(error).as_ref());
+            (response.status, response.error.unwrap())
+        };
+
+        // Errors are described to clients just as they were before being made Send
+        assert_eq!(describe(Box::new(ioErr::new(ioErrKind::NotFound, "Message does not exist"))), (STATUS_NOT_FOUND, String::from("Message does not exist")));
+        assert_eq!(describe(Box::new(signature::InvalidSignature)), (STATUS_INVALID_SIGNATURE, signature::InvalidSignature.to_string()));
+        assert_eq!(describe(Box::new(sqlx::Error::PoolTimedOut)), (STATUS_BUSY, String::from("Server busy")));
+        assert_eq!(describe("1.5".parse::<i32>().unwrap_err().into()), (STATUS_FAILURE, String::from("Internal error")));
+    }
+
+    #[test]
+    fn test_timeout() {
+        let seconds = |request: Request| request.timeout().unwrap().as_secs();
+
+        assert_eq!(seconds(Request::builder(Operation::Read, Target::Messages).build()), 30);
+        assert_eq!(seconds(Request::builder(Operation::Create, Target::Attachments).build()), 30);
+
+        // Streamed reads and chunked uploads get longer
+        assert_eq!(seconds(Request::builder(Operation::Read, Target::Messages).stream(true).build()), 300);
+        assert_eq!(seconds(Request::builder(Operation::Update, Target::Uploads).build()), 300);
+        assert_eq!(seconds(Request::builder(Operation::Create, Target::Attachments)
+            .attachments(vec![Attachment{
+                upload: Some(1),
+                ..Default::default()
+            }])
+            .build()), 300);
+    }
+
+    #[async_std::test]
+    async fn test_create_conversations() {
+        let storage = MemoryStorage::default();
+        storage.add_user("me@example.com", "k2uEa77H");
+        storage.add_user("you@example.com", "9poyvjJN");
+
+        check_create_conversations(&storage).await;
+
+        // Nothing was stored for the failed requests, and invitees have to accept before joining
+        {
+            let mut conversations = storage.conversations.lock().unwrap();
+            assert_eq!(conversations.len(), 3);
+            assert_eq!(conversations[0].participants, vec![(String::from("me@example.com"), "admin")]);
+            assert_eq!(conversations[0].invitees, vec![String::from("you@example.com")]);
+
+            // The other user joins the first direct conversation
+            let direct = &mut conversations[1];
+            direct.invitees.clear();
+            direct.participants.push((String::from("you@example.com"), "member"));
+        }
+
+        // Direct conversations both users are in are reused, whichever of them asks
+        for email in &["me@example.com", "you@example.com"] {
+            let mut login = Login::new();
+            login.authenticate(String::from(*email)).unwrap();
+            let other = match *email {
+                "me@example.com" => "you@example.com",
+                _ => "me@example.com",
+            };
+
+            let response = Request::builder(Operation::Create, Target::Conversations)
+                .users(vec![User::from_email(String::from(other))])
+                .conversations(vec![Conversation{
+                    name: Some(String::from("Chat")),
+                    direct: Some(true),
+                    ..Default::default()
+                }])
+                .build()
+                .create_conversations(&login, &storage)
+                .await
+                .unwrap();
+            assert_eq!(response.created, Some(false));
+            assert_eq!(response.conversations.unwrap()[0].id, Some(2));
+        }
+
+        assert_eq!(storage.conversations.lock().unwrap().len(), 3);
+    }
+
+    #[async_std::test]
+    async fn test_create_conversations_without_email() {
+        let storage = MemoryStorage::default();
+        storage.add_user("you@example.com", "9poyvjJN");
+
+        // A login can't be authenticated without an email, so a blank creator is never stored
+        let mut login = Login::new();
+        assert!(login.authenticate(String::new()).is_err());
+
+        let error = Request::builder(Operation::Create, Target::Conversations)
+            .users(vec![User::from_email(String::from("you@example.com"))])
+            .conversations(vec![Conversation{
+                name: Some(String::from("Blank")),
+                ..Default::default()
+            }])
+            .build()
+            .create_conversations(&login, &storage).await.err().unwrap();
+        assert_eq!(response::status_of(error.as_ref()), STATUS_PERMISSION_DENIED);
+        assert_eq!(error.to_string(), "Not authenticated");
+        assert!(storage.conversations.lock().unwrap().is_empty());
+    }
+
+    /// Start a private conversation between me@example.com (its admin) and you@example.com
+    async fn chat(storage: &dyn Storage) -> i32 {
+        storage.insert_conversation(&NewConversation{
+            name: String::from("Chat"),
+            direct_key: None,
+            public: false,
+            creator: String::from("me@example.com"),
+            members: vec![String::from("you@example.com")],
+            invitees: Vec::new(),
+        }).await.unwrap().id
+    }
+
+    fn text(text: &str, idempotency_key: Option<&str>) -> Message {
+        Message{
+            data: Some(text.as_bytes().to_vec()),
+            media_type: Some(b"text/plain".to_vec()),
+            timestamp: Some(Utc::now().timestamp_millis()),
+            signature: Some(vec![0; 64]),
+            idempotency_key: idempotency_key.map(String::from),
+            ..Default::default()
+        }
+    }
+
+    fn send(conversation_id: i32, messages: Vec<Message>) -> Request {
+        Request::builder(Operation::Create, Target::Messages)
+            .conversations(vec![Conversation{
+                id: Some(conversation_id),
+                ..Default::default()
+            }])
+            .messages(messages)
+            .build()
+    }
+
+    fn read(conversation_id: i32) -> RequestBuilder {
+        Request::builder(Operation::Read, Target::Messages)
+            .conversations(vec![Conversation{
+                id: Some(conversation_id),
+                ..Default::default()
+            }])
+    }
+
+    async fn check_create_users(storage: &dyn Storage) {
+        let user = |email: &str, password: Option<&str>| User{
+            email: Some(String::from(email)),
+            password: password.map(|p| Zeroizing::new(String::from(p))),
+            public_key: Some(vec![0; 32]),
+            ..Default::default()
+        };
+
+        // Bad users, emails already registered and emails repeated in the batch are reported without failing the others
+        let response = Request::builder(Operation::Create, Target::Users)
+            .users(vec![
+                user("new@example.com", Some("correct horse")),
+                user("bad@example.com", None),
+                user("new@example.com", Some("correct horse")),
+                user("me@example.com", Some("correct horse")),
+            ])
+            .build()
+            .create_users(storage).await.unwrap();
+        assert_eq!(response.status, STATUS_SUCCESS);
+
+        let users = response.users.unwrap();
+        let statuses: Vec<Option<u8>> = users.iter().map(|u| u.status).collect();
+        assert_eq!(statuses, vec![Some(STATUS_SUCCESS), Some(STATUS_INVALID_INPUT), Some(STATUS_CONFLICT), Some(STATUS_CONFLICT)]);
+        assert!(users[0].id.is_some());
+        assert!(users[1..].iter().all(|u| u.id.is_none()));
+
+        // The password is stored hashed, and the public key as it was sent
+        let stored = storage.get_user_by_email("new@example.com").await.unwrap().unwrap();
+        assert!(stored.password.is_valid("correct horse").unwrap());
+        assert_eq!(storage.get_public_key("new@example.com").await.unwrap(), Some(vec![0; 32]));
+        assert_eq!(storage.get_user_by_email("bad@example.com").await.unwrap().map(|_| ()), None);
+    }
+
+    async fn check_create_participants(storage: &dyn Storage, public: i32, private: i32) {
+        let mut login = Login::new();
+        login.authenticate(String::from("them@example.com")).unwrap();
+
+        let join = |ids: &[i32]| Request::builder(Operation::Create, Target::Participants)
+            .conversations(ids
+                .iter()
+                .map(|id| Conversation{
+                    id: Some(*id),
+                    ..Default::default()
+                })
+                .collect::<Vec<Conversation>>())
+            .build();
+
+        // Nothing is joined if any of the conversations can't be
+        let error = join(&[public, private]).create_participants(&login, storage).await.err().unwrap();
+        assert_eq!(error.to_string(), "Conversation is private");
+        assert!(!storage.is_member("them@example.com", public).await.unwrap());
+
+        let error = join(&[public, 99]).create_participants(&login, storage).await.err().unwrap();
+        assert_eq!(error.to_string(), "Conversation does not exist");
+        assert!(!storage.is_member("them@example.com", public).await.unwrap());
+
+        // Joining a conversation again leaves it as it was
+        join(&[public]).create_participants(&login, storage).await.unwrap();
+        join(&[public]).create_participants(&login, storage).await.unwrap();
+        assert!(storage.is_member("them@example.com", public).await.unwrap());
+        assert!(!storage.is_member("them@example.com", private).await.unwrap());
+    }
+
+    async fn check_create_messages(storage: &dyn Storage, conversation_id: i32) {
+        let mut login = Login::new();
+        login.authenticate(String::from("me@example.com")).unwrap();
+
+        // Invalid messages are reported alongside the ones that were stored, without using up a sequence number
+        let mut missing = text("", None);
+        missing.data = None;
+
+        let response = send(conversation_id, vec![text("Hello", Some("a")), missing, text("Again", Some("b"))])
+            .create_messages(&login, storage).await.unwrap();
+        let messages = response.messages.unwrap();
+        let statuses: Vec<Option<u8>> = messages.iter().map(|m| m.status).collect();
+        assert_eq!(statuses, vec![Some(STATUS_SUCCESS), Some(STATUS_INVALID_INPUT), Some(STATUS_SUCCESS)]);
+        assert_eq!(messages[0].seq, Some(1));
+        assert_eq!(messages[2].seq, Some(2));
+        assert_eq!(messages[1].error.as_deref(), Some("Missing 'data' field for 'message'"));
+        assert_eq!(response.already_stored, Some(Vec::new()));
+
+        // Messages already stored with the same key, by an earlier request or earlier in the batch, aren't stored again
+        let response = send(conversation_id, vec![text("Again", Some("b")), text("New", Some("c")), text("New", Some("c"))])
+            .create_messages(&login, storage).await.unwrap();
+        let messages = response.messages.unwrap();
+        assert_eq!(messages[0].id, Some(2));
+        assert_eq!(messages[1].seq, Some(3));
+        assert_eq!(messages[2].id, messages[1].id);
+        assert_eq!(response.already_stored, Some(vec![String::from("b"), String::from("c")]));
+
+        // Replies have to be to a message in the same conversation
+        let mut reply = text("Reply", None);
+        reply.parent_id = Some(99);
+        let response = send(conversation_id, vec![reply]).create_messages(&login, storage).await.unwrap();
+        assert_eq!(response.messages.unwrap()[0].status, Some(STATUS_INVALID_INPUT));
+
+        let error = send(99, vec![text("Hello", None)]).create_messages(&login, storage).await.err().unwrap();
+        assert_eq!(error.to_string(), "Conversation does not exist");
+
+        let mut outsider = Login::new();
+        outsider.authenticate(String::from("them@example.com")).unwrap();
+        let error = send(conversation_id, vec![text("Hello", None)]).create_messages(&outsider, storage).await.err().unwrap();
+        assert_eq!(error.to_string(), "Not a member of conversation");
+    }
+
+    async fn check_read_messages(storage: &dyn Storage, conversation_id: i32) {
+        let mut login = Login::new();
+        login.authenticate(String::from("me@example.com")).unwrap();
+
+        let sent: Vec<Option<i32>> = send(conversation_id, (1..=5).map(|i| text(&i.to_string(), None)).collect())
+            .create_messages(&login, storage).await.unwrap()
+            .messages.unwrap()
+            .into_iter()
+            .map(|m| m.id)
+            .collect();
+
+        // Pages are read newest first, continuing from the last message read
+        let response = read(conversation_id).limit(2).build().read_messages(&login, storage).await.unwrap();
+        let ids: Vec<Option<i32>> = response.messages.unwrap().into_iter().map(|m| m.id).collect();
+        assert_eq!(ids, vec![sent[4], sent[3]]);
+        assert_eq!(response.has_more, Some(true));
+        assert_eq!(response.next_id, sent[3]);
+
+        // Syncing after a message reads oldest first
+        let response = read(conversation_id).after_id(sent[0]).build().read_messages(&login, storage).await.unwrap();
+        let messages = response.messages.unwrap();
+        let ids: Vec<Option<i32>> = messages.iter().map(|m| m.id).collect();
+        assert_eq!(ids, sent[1..].to_vec());
+        assert_eq!(messages[0].data.as_deref(), Some(&b"2"[..]));
+        assert_eq!(messages[0].sender.as_deref(), Some("me@example.com"));
+        assert_eq!(response.has_more, Some(false));
+        assert_eq!(response.next_id, None);
+
+        let response = read(conversation_id).exclude_self(true).build().read_messages(&login, storage).await.unwrap();
+        assert!(response.messages.unwrap().is_empty());
+        assert_eq!(response.has_more, Some(false));
+
+        // Streamed reads send every page
+        let (sender, pages) = async_std::channel::unbounded();
+        read(conversation_id).limit(2).stream(true).build().stream_messages(&login, storage, &sender).await.unwrap();
+        drop(sender);
+
+        let mut sizes = Vec::new();
+        while let Ok(page) = pages.recv().await {
+            sizes.push((page.messages.unwrap().len(), page.has_more));
+        }
+        assert_eq!(sizes, vec![(2, Some(true)), (2, Some(true)), (1, Some(false))]);
+
+        let mut outsider = Login::new();
+        outsider.authenticate(String::from("them@example.com")).unwrap();
+        let error = read(conversation_id).build().read_messages(&outsider, storage).await.err().unwrap();
+        assert_eq!(error.to_string(), "Not a member of conversation");
+    }
+
+    #[async_std::test]
+    async fn test_create_users() {
+        let storage = MemoryStorage::default();
+        storage.add_user("me@example.com", "k2uEa77H");
+
+        check_create_users(&storage).await;
+        assert_eq!(storage.users.lock().unwrap().len(), 2);
+    }
+
+    #[async_std::test]
+    async fn test_create_participants() {
+        let storage = MemoryStorage::default();
+        storage.add_user("me@example.com", "k2uEa77H");
+        storage.add_user("them@example.com", "uZmS2oMK");
+
+        let mut conversation = NewConversation{
+            name: String::from("Open"),
+            direct_key: None,
+            public: true,
+            creator: String::from("me@example.com"),
+            members: Vec::new(),
+            invitees: Vec::new(),
+        };
+        let public = storage.insert_conversation(&conversation).await.unwrap().id;
+        conversation.public = false;
+        let private = storage.insert_conversation(&conversation).await.unwrap().id;
+
+        check_create_participants(&storage, public, private).await;
+        assert_eq!(storage.conversations.lock().unwrap()[0].participants.len(), 2);
+    }
+
+    #[async_std::test]
+    async fn test_create_messages() {
+        let storage = MemoryStorage::default();
+        storage.add_user("me@example.com", "k2uEa77H");
+        storage.add_user("you@example.com", "9poyvjJN");
+        storage.add_user("them@example.com", "uZmS2oMK");
+        let conversation_id = chat(&storage).await;
+
+        check_create_messages(&storage, conversation_id).await;
+        assert_eq!(storage.messages.lock().unwrap().len(), 3);
+
+        // A member who's been blocked by another member can't send anything
+        storage.blocks.lock().unwrap().push((String::from("you@example.com"), String::from("me@example.com")));
+        let mut login = Login::new();
+        login.authenticate(String::from("me@example.com")).unwrap();
+
+        let error = send(conversation_id, vec![text("Hello", None)]).create_messages(&login, &storage).await.err().unwrap();
+        assert_eq!(error.to_string(), "Blocked by a member of conversation");
+        assert_eq!(storage.messages.lock().unwrap().len(), 3);
+    }
+
+    #[async_std::test]
+    async fn test_read_messages() {
+        let storage = MemoryStorage::default();
+        storage.add_user("me@example.com", "k2uEa77H");
+        storage.add_user("you@example.com", "9poyvjJN");
+        storage.add_user("them@example.com", "uZmS2oMK");
+        let conversation_id = chat(&storage).await;
+
+        check_read_messages(&storage, conversation_id).await;
+
+        // Deleted messages are read as tombstones
+        storage.messages.lock().unwrap()[0].deleted = true;
+        let mut login = Login::new();
+        login.authenticate(String::from("you@example.com")).unwrap();
+
+        let response = read(conversation_id).build().read_messages(&login, &storage).await.unwrap();
+        let oldest = response.messages.unwrap().pop().unwrap();
+        assert_eq!(oldest.deleted, Some(true));
+        assert_eq!(oldest.data, None);
+    }
+
+    /// Add the users the checks expect to a storage
+    #[cfg(feature = "sqlite")]
+    async fn add_sqlite_users(storage: &SqliteStorage<'_>) {
+        storage.add_user("me@example.com", "k2uEa77H").await;
+        storage.add_user("you@example.com", "9poyvjJN").await;
+        storage.add_user("them@example.com", "uZmS2oMK").await;
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[async_std::test]
+    async fn test_verify_users_sqlite() {
+        let db_pool = sqlite_memory_pool().await;
+        let storage = SqliteStorage::new(&db_pool);
+        storage.add_user("me@example.com", "k2uEa77H").await;
+
+        check_verify_users(&storage).await;
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[async_std::test]
+    async fn test_create_conversations_sqlite() {
+        let db_pool = sqlite_memory_pool().await;
+        let storage = SqliteStorage::new(&db_pool);
+        add_sqlite_users(&storage).await;
+
+        check_create_conversations(&storage).await;
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[async_std::test]
+    async fn test_create_users_sqlite() {
+        let db_pool = sqlite_memory_pool().await;
+        let storage = SqliteStorage::new(&db_pool);
+        add_sqlite_users(&storage).await;
+
+        check_create_users(&storage).await;
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[async_std::test]
+    async fn test_create_participants_sqlite() {
+        let db_pool = sqlite_memory_pool().await;
+        let storage = SqliteStorage::new(&db_pool);
+        add_sqlite_users(&storage).await;
+
+        let mut conversation = NewConversation{
+            name: String::from("Open"),
+            direct_key: None,
+            public: true,
+            creator: String::from("me@example.com"),
+            members: Vec::new(),
+            invitees: Vec::new(),
+        };
+        let public = storage.insert_conversation(&conversation).await.unwrap().id;
+        conversation.public = false;
+        let private = storage.insert_conversation(&conversation).await.unwrap().id;
+
+        check_create_participants(&storage, public, private).await;
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[async_std::test]
+    async fn test_create_messages_sqlite() {
+        let db_pool = sqlite_memory_pool().await;
+        let storage = SqliteStorage::new(&db_pool);
+        add_sqlite_users(&storage).await;
+        let conversation_id = chat(&storage).await;
+
+        check_create_messages(&storage, conversation_id).await;
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[async_std::test]
+    async fn test_push_sqlite() {
+        // Users of their own, since pushes go to every connection a user is logged in on in this process
+        let db_pool = sqlite_memory_pool().await;
+        let storage = SqliteStorage::new(&db_pool);
+        storage.add_user("sender@push.example.com", "k2uEa77H").await;
+        storage.add_user("reader@push.example.com", "9poyvjJN").await;
+        let conversation_id = storage.insert_conversation(&NewConversation{
+            name: String::from("Chat"),
+            direct_key: None,
+            public: false,
+            creator: String::from("sender@push.example.com"),
+            members: vec![String::from("reader@push.example.com")],
+            invitees: Vec::new(),
+        }).await.unwrap().id;
+
+        // Other members are pushed new messages straight away, since there are no other servers to announce them to,
+        // but the connection they were sent on isn't
+        let mut login = Login::new();
+        login.authenticate(String::from("sender@push.example.com")).unwrap();
+        let sender = push::subscribe("sender@push.example.com", login.connection());
+        let reader = push::subscribe("reader@push.example.com", Login::new().connection());
+
+        send(conversation_id, vec![text("Hello", None), text("Again", None)])
+            .create_messages(&login, &storage).await.unwrap();
+
+        let pushed: serde_json::Value = serde_json::from_str(&reader.next().await).unwrap();
+        assert_eq!(pushed["event"], "message");
+        assert_eq!(pushed["conversation"], conversation_id);
+        assert_eq!(pushed["messages"].as_array().unwrap().len(), 2);
+        assert!(async_std::future::timeout(std::time::Duration::from_millis(50), sender.next()).await.is_err());
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[async_std::test]
+    async fn test_read_messages_sqlite() {
+        let db_pool = sqlite_memory_pool().await;
+        let storage = SqliteStorage::new(&db_pool);
+        add_sqlite_users(&storage).await;
+        let conversation_id = chat(&storage).await;
+
+        check_read_messages(&storage, conversation_id).await;
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[async_std::test]
+    async fn test_handle_sqlite() {
+        let db_pool = sqlite_memory_pool().await;
+        add_sqlite_users(&SqliteStorage::new(&db_pool)).await;
+
+        // Requests are audited in the SQLite database too
+        let mut login = Login::new();
+        let response = Request::builder(Operation::Verify, Target::Users)
+            .users(vec![User{
+                email: Some(String::from("me@example.com")),
+                password: Some(Zeroizing::new(String::from("k2uEa77H"))),
+                ..Default::default()
+            }])
+            .build()
+            .handle_sqlite(&mut login, &db_pool)
+            .await
+            .unwrap();
+        assert_eq!(response.status, STATUS_SUCCESS);
+
+        let audited: (String, String) = sqlx::query_as("SELECT actor, outcome FROM audit_log")
+            .fetch_one(&db_pool)
+            .await
+            .unwrap();
+        assert_eq!(audited, (String::from("me@example.com"), String::from("success")));
+
+        // Handlers that still query Postgres directly are refused
+        let error = Request::builder(Operation::Read, Target::Invitations).build()
+            .handle_sqlite(&mut login, &db_pool)
+            .await
+            .unwrap_err();
+        assert_eq!(error.to_string(), "READ INVITATIONS is not supported with SQLite");
+        assert_eq!(response::status_of(error.as_ref()), STATUS_INVALID_INPUT);
+    }
+}
@@ -1,27 +1,206 @@
 use crate::api;
+use crate::auth::signature::InvalidSignature;
 
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::Error as ioErr;
+use std::io::ErrorKind as ioErrKind;
+use base64;
 use serde_json::{Value, json};
+use sqlx::postgres::PgDatabaseError;
+
+/// The request failed for an unspecified reason
+pub const STATUS_FAILURE: u8 = 0;
+/// The request succeeded
+pub const STATUS_SUCCESS: u8 = 1;
+/// The user is not allowed to perform the request
+pub const STATUS_PERMISSION_DENIED: u8 = 2;
+/// The request referred to something that doesn't exist
+pub const STATUS_NOT_FOUND: u8 = 3;
+/// The request was malformed
+pub const STATUS_INVALID_INPUT: u8 = 4;
+/// A message's signature didn't match its sender's public key
+pub const STATUS_INVALID_SIGNATURE: u8 = 5;
+/// The request would have duplicated something that already exists
+pub const STATUS_CONFLICT: u8 = 6;
+/// The server was too busy to handle the request, so it should be tried again later
+pub const STATUS_BUSY: u8 = 7;
+/// The request took too long and was abandoned, so it may need to be tried again
+pub const STATUS_TIMED_OUT: u8 = 8;
+
+/// The Postgres error code for a broken unique constraint
+const UNIQUE_VIOLATION: &str = "23505";
+/// The Postgres error code for a broken foreign key
+const FOREIGN_KEY_VIOLATION: &str = "23503";
+
+/// What breaking each constraint means to a client
+const CONSTRAINT_ERRORS: &[(&str, &str)] = &[
+    ("users_email_key", "Email already registered"),
+    ("conversations_direct_key_key", "Direct conversation already exists"),
+    ("participants_identity_conversation_key", "User is already a participant"),
+    ("messages_sender_idempotency_key_key", "Message already sent"),
+    ("blocks_blocker_blocked_key", "User is already blocked"),
+    ("reactions_message_identity_emoji_key", "Reaction already exists"),
+    ("invitations_conversation_invitee_key", "User is already invited"),
+    ("participants_identity_fkey", "User does not exist"),
+    ("participants_conversation_fkey", "Conversation does not exist"),
+    ("messages_conversation_fkey", "Conversation does not exist"),
+    ("messages_attachment_id_fkey", "Attachment does not exist"),
+    ("attachments_conversation_fkey", "Conversation does not exist"),
+    ("uploads_conversation_fkey", "Conversation does not exist"),
+    ("message_revisions_message_fkey", "Message does not exist"),
+    ("reactions_message_fkey", "Message does not exist"),
+    ("invitations_conversation_fkey", "Conversation does not exist"),
+    ("invitations_invitee_fkey", "User does not exist"),
+    ("blocks_blocked_fkey", "User does not exist"),
+];
 
 // A server response to a client's request
+#[derive(Default)]
 pub struct Response {
     pub status: u8,
     pub users: Option<Vec<api::User>>,
     pub messages: Option<Vec<api::Message>>,
     pub conversations: Option<Vec<api::Conversation>>,
+    pub invitations: Option<Vec<api::Invitation>>,
+    pub attachments: Option<Vec<api::Attachment>>,
+    pub uploads: Option<Vec<api::Upload>>,
+    pub cursor: Option<api::Cursor>,
+    pub has_more: Option<bool>,
+    pub unread: Option<HashMap<i32, i64>>,
+    pub next_id: Option<i32>,
+    pub created: Option<bool>,
+    pub duplicates: Option<Vec<String>>,
+    pub already_stored: Option<Vec<String>>,
+    pub affected: Option<u64>,
+    pub error: Option<String>,
+}
+
+/// Describe a broken database constraint to a client without giving away the schema
+fn describe_violation(code: &str, constraint: Option<&str>) -> Option<(u8, &'static str)> {
+    let known = CONSTRAINT_ERRORS
+        .iter()
+        .find(|(name, _)| Some(*name) == constraint)
+        .map(|(_, message)| *message);
+
+    match code {
+        UNIQUE_VIOLATION => Some((STATUS_CONFLICT, known.unwrap_or("Already exists"))),
+        FOREIGN_KEY_VIOLATION => Some((STATUS_NOT_FOUND, known.unwrap_or("Does not exist"))),
+        _ => None,
+    }
+}
+
+/// Get the status and description of a database error caused by a constraint the request broke
+fn violation_of(error: &(dyn Error + 'static)) -> Option<(u8, &'static str)> {
+    match error.downcast_ref::<sqlx::Error>() {
+        Some(sqlx::Error::Database(e)) => {
+            let e = e.try_downcast_ref::<PgDatabaseError>()?;
+            describe_violation(e.code(), e.constraint())
+        },
+        _ => None,
+    }
+}
+
+/// Get the status describing an error
+pub fn status_of(error: &(dyn Error + 'static)) -> u8 {
+    if let Some((status, _)) = violation_of(error) {
+        return status;
+    }
+
+    if error.is::<InvalidSignature>() {
+        return STATUS_INVALID_SIGNATURE;
+    }
+
+    // No database connection became free in time
+    if let Some(sqlx::Error::PoolTimedOut) = error.downcast_ref::<sqlx::Error>() {
+        return STATUS_BUSY;
+    }
+
+    match error.downcast_ref::<ioErr>().map(|e| e.kind()) {
+        Some(ioErrKind::PermissionDenied) => STATUS_PERMISSION_DENIED,
+        Some(ioErrKind::NotFound) => STATUS_NOT_FOUND,
+        Some(ioErrKind::InvalidInput) => STATUS_INVALID_INPUT,
+        Some(ioErrKind::AlreadyExists) => STATUS_CONFLICT,
+        Some(ioErrKind::TimedOut) => STATUS_TIMED_OUT,
+        _ => STATUS_FAILURE,
+    }
+}
+
+/// Describe an error to a client, hiding the details (such as SQL) of anything unexpected
+pub fn describe(error: &(dyn Error + 'static)) -> String {
+    if let Some((_, message)) = violation_of(error) {
+        return String::from(message);
+    }
+
+    match status_of(error) {
+        STATUS_FAILURE => String::from("Internal error"),
+        STATUS_BUSY => String::from("Server busy"),
+        _ => error.to_string(),
+    }
 }
 
 impl Response {
+    /// Create a failure response with a status describing an error
+    pub fn from_error(error: &(dyn Error + 'static)) -> Self {
+        Response{
+            status: status_of(error),
+            error: Some(describe(error)),
+            ..Default::default()
+        }
+    }
+
+    /// Format a list of responses (answering a batch of requests) as a JSON array
+    pub fn batch_to_json(responses: &[Response]) -> String {
+        let responses: Vec<String> = responses
+            .iter()
+            .map(|r| r.to_json())
+            .collect();
+
+        format!("[{}]", responses.join(","))
+    }
+
     /// Format response as JSON
     pub fn to_json(&self) -> String {
         let users = &self.users_to_json();
         let messages = &self.messages_to_json();
         let conversations = &self.conversations_to_json();
+        let invitations = &self.invitations_to_json();
+        let attachments = &self.attachments_to_json();
+        let uploads = &self.uploads_to_json();
+        let cursor = self.cursor.as_ref().map(|c| c.to_string());
 
         json!({
             "status": &self.status,
             "users": users,
             "messages": messages,
             "conversations": conversations,
+            "invitations": invitations,
+            "attachments": attachments,
+            "uploads": uploads,
+            "cursor": cursor,
+            "hasMore": &self.has_more,
+            "unread": &self.unread,
+            "nextId": &self.next_id,
+            "created": &self.created,
+            "duplicates": &self.duplicates,
+            "alreadyStored": &self.already_stored,
+            "affected": &self.affected,
+            "error": &self.error,
+        }).to_string()
+    }
+
+    /// Format new messages in a conversation as a push, which has an `event` rather than a `status` so clients can tell
+    /// it apart from the response to a request
+    pub fn message_event_to_json(conversation: i32, messages: Vec<api::Message>) -> String {
+        let response = Response{
+            messages: Some(messages),
+            ..Default::default()
+        };
+
+        json!({
+            "event": "message",
+            "conversation": conversation,
+            "messages": response.messages_to_json(),
         }).to_string()
     }
 
@@ -32,9 +211,21 @@ impl Response {
                 Some(users
                     .iter()
                     .map(|user| json!({
+                        "id": user.id,
                         "email": user.email,
                         "name": user.name,
-                        "publicKey": user.public_key,
+                        "publicKey": encode_bytes(&user.public_key),
+                        "previousKeys": user.previous_keys.as_ref().map(|keys| keys
+                            .iter()
+                            .map(|key| json!({
+                                "publicKey": base64::encode(&key.public_key),
+                                "replacedAt": key.replaced_at,
+                            }))
+                            .collect::<Vec<Value>>()),
+                        "displayName": user.display_name,
+                        "avatarUrl": user.avatar_url,
+                        "lastReadMessageId": user.last_read_message_id,
+                        "status": user.status,
                     }))
                     .collect()
                 )
@@ -50,11 +241,30 @@ impl Response {
                 Some(messages
                     .iter()
                     .map(|message| json!({
-                        "data": message.data,
-                        "mediaType": message.media_type,
+                        "id": message.id,
+                        "seq": message.seq,
+                        "conversation": message.conversation,
+                        "data": encode_bytes(&message.data),
+                        "mediaType": encode_bytes(&message.media_type),
                         "timestamp": message.timestamp,
-                        "signature": message.signature,
+                        "createdAt": message.created_at,
+                        "editedAt": message.edited_at,
+                        "signature": encode_bytes(&message.signature),
                         "sender": message.sender,
+                        "parentId": message.parent_id,
+                        "attachment": message.attachment.as_ref().map(attachment_to_json),
+                        "idempotencyKey": message.idempotency_key,
+                        "deleted": message.deleted,
+                        "status": message.status,
+                        "error": message.error,
+                        "reactions": message.reactions.as_ref().map(|reactions| reactions
+                            .iter()
+                            .map(|reaction| json!({
+                                "emoji": reaction.emoji,
+                                "sender": reaction.sender,
+                            }))
+                            .collect::<Vec<Value>>()
+                        ),
                     }))
                     .collect()
                 )
@@ -72,6 +282,70 @@ impl Response {
                     .map(|conversation| json!({
                         "id": conversation.id,
                         "name": conversation.name,
+                        "timestamp": conversation.timestamp,
+                        "direct": conversation.direct,
+                        "public": conversation.public,
+                        "lastReadMessageId": conversation.last_read_message_id,
+                        "unreadCount": conversation.unread_count,
+                        "lastSender": conversation.last_sender,
+                        "lastMediaType": encode_bytes(&conversation.last_media_type),
+                        "lastPreview": conversation.last_preview,
+                        "retentionSeconds": conversation.retention_seconds,
+                        "role": conversation.role,
+                        "archived": conversation.archived,
+                    }))
+                    .collect()
+                )
+            },
+            None => None,
+        }
+    }
+    /// Format attachment array as JSON
+    fn attachments_to_json(&self) -> Option<Value> {
+        match &self.attachments {
+            Some(attachments) => {
+                Some(attachments
+                    .iter()
+                    .map(attachment_to_json)
+                    .collect()
+                )
+            },
+            None => None,
+        }
+    }
+
+    /// Format upload array as JSON
+    fn uploads_to_json(&self) -> Option<Value> {
+        match &self.uploads {
+            Some(uploads) => {
+                Some(uploads
+                    .iter()
+                    .map(|upload| json!({
+                        "id": upload.id,
+                        "conversation": upload.conversation,
+                        "mediaType": encode_bytes(&upload.media_type),
+                        "size": upload.size,
+                        "received": upload.received,
+                        "index": upload.index,
+                    }))
+                    .collect()
+                )
+            },
+            None => None,
+        }
+    }
+
+    /// Format invitation array as JSON
+    fn invitations_to_json(&self) -> Option<Value> {
+        match &self.invitations {
+            Some(invitations) => {
+                Some(invitations
+                    .iter()
+                    .map(|invitation| json!({
+                        "id": invitation.id,
+                        "conversation": invitation.conversation,
+                        "inviter": invitation.inviter,
+                        "status": invitation.status,
                     }))
                     .collect()
                 )
@@ -79,4 +353,329 @@ impl Response {
             None => None,
         }
     }
+}
+
+/// Encode binary data as base64, which is how it's sent in JSON
+fn encode_bytes(bytes: &Option<Vec<u8>>) -> Option<String> {
+    bytes.as_ref().map(base64::encode)
+}
+
+/// Format an attachment as JSON, leaving out its data unless it was read
+fn attachment_to_json(attachment: &api::Attachment) -> Value {
+    json!({
+        "id": attachment.id,
+        "conversation": attachment.conversation,
+        "mediaType": encode_bytes(&attachment.media_type),
+        "size": attachment.size,
+        "data": encode_bytes(&attachment.data),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::api::response::*;
+    use crate::auth::signature::InvalidSignature;
+    use chrono::TimeZone;
+    use std::error::Error;
+    use std::io::Error as ioErr;
+    use std::io::ErrorKind as ioErrKind;
+
+    #[test]
+    fn test_from_error() {
+        let errors: Vec<Box<dyn Error>> = vec![
+            Box::new(ioErr::new(ioErrKind::PermissionDenied, "Not a member of conversation")),
+            Box::new(ioErr::new(ioErrKind::NotFound, "Conversation does not exist")),
+            Box::new(ioErr::new(ioErrKind::InvalidInput, "Missing 'users' list")),
+            Box::new(ioErr::new(ioErrKind::Other, "Something else")),
+            "not an io error".into(),
+            Box::new(InvalidSignature),
+            Box::new(ioErr::new(ioErrKind::AlreadyExists, "Email already registered")),
+            Box::new(sqlx::Error::RowNotFound),
+            Box::new(sqlx::Error::PoolTimedOut),
+        ];
+
+        let responses: Vec<Response> = errors
+            .iter()
+            .map(|e| Response::from_error(e.as_ref()))
+            .collect();
+        let statuses: Vec<u8> = responses.iter().map(|r| r.status).collect();
+
+        assert_eq!(statuses[0], STATUS_PERMISSION_DENIED);
+        assert_eq!(statuses[1], STATUS_NOT_FOUND);
+        assert_eq!(statuses[2], STATUS_INVALID_INPUT);
+        assert_eq!(statuses[3], STATUS_FAILURE);
+        assert_eq!(statuses[4], STATUS_FAILURE);
+        assert_eq!(statuses[5], STATUS_INVALID_SIGNATURE);
+        assert_eq!(statuses[6], STATUS_CONFLICT);
+        assert_eq!(statuses[7], STATUS_FAILURE);
+        assert_eq!(statuses[8], STATUS_BUSY);
+
+        // Errors meant for clients are passed on, and anything unexpected is hidden
+        assert_eq!(responses[2].error.as_deref(), Some("Missing 'users' list"));
+        assert_eq!(responses[3].error.as_deref(), Some("Internal error"));
+        assert_eq!(responses[6].error.as_deref(), Some("Email already registered"));
+        assert_eq!(responses[7].error.as_deref(), Some("Internal error"));
+        assert_eq!(responses[8].error.as_deref(), Some("Server busy"));
+    }
+
+    #[test]
+    fn test_batch_to_json() {
+        let responses = [
+            Response{
+                status: STATUS_SUCCESS,
+                ..Default::default()
+            },
+            Response::from_error(&ioErr::new(ioErrKind::PermissionDenied, "Not authenticated")),
+        ];
+
+        let json: serde_json::Value = serde_json::from_str(&Response::batch_to_json(&responses)).unwrap();
+        assert_eq!(json[0]["status"], STATUS_SUCCESS);
+        assert_eq!(json[1]["status"], STATUS_PERMISSION_DENIED);
+        assert_eq!(json[1]["error"], "Not authenticated");
+        assert_eq!(json.as_array().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_describe_violation() {
+        assert_eq!(describe_violation("23505", Some("users_email_key")), Some((STATUS_CONFLICT, "Email already registered")));
+        assert_eq!(describe_violation("23503", Some("messages_conversation_fkey")), Some((STATUS_NOT_FOUND, "Conversation does not exist")));
+
+        // Constraints without their own description still get a status, but their names aren't passed on
+        assert_eq!(describe_violation("23505", Some("new_table_key")), Some((STATUS_CONFLICT, "Already exists")));
+        assert_eq!(describe_violation("23503", None), Some((STATUS_NOT_FOUND, "Does not exist")));
+
+        // Any other database error isn't the client's fault
+        assert_eq!(describe_violation("42601", None), None);
+    }
+
+    #[test]
+    fn test_unread_count_to_json() {
+        let response = Response{
+            status: STATUS_SUCCESS,
+            conversations: Some(vec![api::Conversation{
+                id: Some(1),
+                last_read_message_id: Some(4),
+                unread_count: Some(2),
+                ..Default::default()
+            }]),
+            ..Default::default()
+        };
+
+        let json: serde_json::Value = serde_json::from_str(&response.to_json()).unwrap();
+        assert_eq!(json["conversations"][0]["lastReadMessageId"], 4);
+        assert_eq!(json["conversations"][0]["unreadCount"], 2);
+    }
+
+    #[test]
+    fn test_unread_counts_to_json() {
+        let response = Response{
+            status: STATUS_SUCCESS,
+            unread: Some(vec![(3, 0), (12, 5)].into_iter().collect()),
+            ..Default::default()
+        };
+
+        // JSON objects only have string keys, so conversation IDs are sent as strings
+        let json: serde_json::Value = serde_json::from_str(&response.to_json()).unwrap();
+        assert_eq!(json["unread"], serde_json::json!({"3": 0, "12": 5}));
+        assert!(json["conversations"].is_null());
+    }
+
+    #[test]
+    fn test_previous_keys_to_json() {
+        let replaced_at = chrono::Utc.ymd(2021, 1, 1).and_hms(0, 0, 0);
+        let response = Response{
+            status: STATUS_SUCCESS,
+            users: Some(vec![
+                api::User{
+                    public_key: Some(b"new".to_vec()),
+                    previous_keys: Some(vec![api::PreviousKey{
+                        public_key: b"old".to_vec(),
+                        replaced_at,
+                    }]),
+                    ..Default::default()
+                },
+                api::User::from_email(String::from("me@example.com")),
+            ]),
+            ..Default::default()
+        };
+
+        let json: serde_json::Value = serde_json::from_str(&response.to_json()).unwrap();
+        assert_eq!(json["users"][0]["publicKey"], "bmV3");
+        assert_eq!(json["users"][0]["previousKeys"][0]["publicKey"], "b2xk");
+        assert_eq!(json["users"][0]["previousKeys"][0]["replacedAt"], serde_json::to_value(replaced_at).unwrap());
+        assert!(json["users"][1]["previousKeys"].is_null());
+    }
+
+    #[test]
+    fn test_message_results_to_json() {
+        // Each submitted message gets a result in the same position, whether or not it was stored
+        let response = Response{
+            status: STATUS_SUCCESS,
+            messages: Some(vec![
+                api::Message{
+                    id: Some(7),
+                    seq: Some(3),
+                    status: Some(STATUS_SUCCESS),
+                    ..Default::default()
+                },
+                api::Message{
+                    status: Some(status_of(&ioErr::new(ioErrKind::InvalidInput, "Invalid 'media_type' field for 'message'"))),
+                    error: Some(describe(&ioErr::new(ioErrKind::InvalidInput, "Invalid 'media_type' field for 'message'"))),
+                    ..Default::default()
+                },
+                api::Message{
+                    id: Some(8),
+                    seq: Some(4),
+                    status: Some(STATUS_SUCCESS),
+                    ..Default::default()
+                },
+            ]),
+            ..Default::default()
+        };
+
+        let json: serde_json::Value = serde_json::from_str(&response.to_json()).unwrap();
+        assert_eq!(json["messages"][0]["id"], 7);
+        assert_eq!(json["messages"][0]["seq"], 3);
+        assert_eq!(json["messages"][0]["status"], STATUS_SUCCESS);
+        assert_eq!(json["messages"][0]["error"], serde_json::Value::Null);
+        assert_eq!(json["messages"][1]["id"], serde_json::Value::Null);
+        assert_eq!(json["messages"][1]["seq"], serde_json::Value::Null);
+        assert_eq!(json["messages"][1]["status"], STATUS_INVALID_INPUT);
+        assert_eq!(json["messages"][1]["error"], "Invalid 'media_type' field for 'message'");
+        assert_eq!(json["messages"][2]["id"], 8);
+        assert_eq!(json["messages"][2]["seq"], 4);
+        assert_eq!(json["messages"][2]["status"], STATUS_SUCCESS);
+    }
+
+    #[test]
+    fn test_binary_round_trip() {
+        use crate::api::ApiObject;
+
+        // Every byte value, runs of NULs and high bytes, and some random blobs
+        let mut blobs: Vec<Vec<u8>> = vec![
+            (0..=255).collect(),
+            vec![0; 16],
+            vec![0xff, 0x00, 0xfe, 0x80, 0x00],
+            Vec::new(),
+        ];
+        for length in [1, 2, 3, 64, 1000].iter() {
+            let mut blob = vec![0u8; *length];
+            getrandom::getrandom(&mut blob).unwrap();
+            blobs.push(blob);
+        }
+
+        for blob in blobs {
+            let response = Response{
+                status: STATUS_SUCCESS,
+                messages: Some(vec![api::Message{
+                    data: Some(blob.clone()),
+                    signature: Some(blob.clone()),
+                    ..Default::default()
+                }]),
+                ..Default::default()
+            };
+
+            // What the server sends can be sent back and decodes to the same bytes
+            let json: serde_json::Value = serde_json::from_str(&response.to_json()).unwrap();
+            let message = api::Message::from_json(&json["messages"][0]).unwrap();
+
+            assert_eq!(message.data, Some(blob.clone()));
+            assert_eq!(message.signature, Some(blob));
+        }
+    }
+
+    #[test]
+    fn test_timestamp_round_trip() {
+        use crate::api::ApiObject;
+
+        let response = Response{
+            status: STATUS_SUCCESS,
+            messages: Some(vec![api::Message{
+                timestamp: Some(1609459200123),
+                ..Default::default()
+            }]),
+            ..Default::default()
+        };
+
+        // Timestamps are sent as epoch milliseconds, the same way clients send them
+        let json: serde_json::Value = serde_json::from_str(&response.to_json()).unwrap();
+        assert_eq!(json["messages"][0]["timestamp"], 1609459200123i64);
+        assert_eq!(api::Message::from_json(&json["messages"][0]).unwrap().timestamp, Some(1609459200123));
+    }
+
+    #[test]
+    fn test_duplicate_messages_to_json() {
+        let response = Response{
+            status: STATUS_SUCCESS,
+            messages: Some(vec![api::Message{
+                id: Some(7),
+                idempotency_key: Some(String::from("9b2c6f1e")),
+                ..Default::default()
+            }]),
+            already_stored: Some(vec![String::from("9b2c6f1e")]),
+            ..Default::default()
+        };
+
+        let json: serde_json::Value = serde_json::from_str(&response.to_json()).unwrap();
+        assert_eq!(json["messages"][0]["id"], 7);
+        assert_eq!(json["messages"][0]["idempotencyKey"], "9b2c6f1e");
+        assert_eq!(json["alreadyStored"][0], "9b2c6f1e");
+        assert!(json["duplicates"].is_null());
+    }
+
+    #[test]
+    fn test_message_attachment_to_json() {
+        let response = Response{
+            status: STATUS_SUCCESS,
+            messages: Some(vec![
+                api::Message{
+                    id: Some(7),
+                    attachment: Some(api::Attachment{
+                        id: Some(3),
+                        conversation: Some(2),
+                        media_type: Some(b"image/png".to_vec()),
+                        size: Some(4),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                },
+                api::Message{
+                    id: Some(8),
+                    ..Default::default()
+                },
+            ]),
+            ..Default::default()
+        };
+
+        // Messages carry the attachment they refer to, but not its contents
+        let json: serde_json::Value = serde_json::from_str(&response.to_json()).unwrap();
+        assert_eq!(json["messages"][0]["attachment"]["id"], 3);
+        assert_eq!(json["messages"][0]["attachment"]["conversation"], 2);
+        assert_eq!(json["messages"][0]["attachment"]["mediaType"], base64::encode("image/png"));
+        assert_eq!(json["messages"][0]["attachment"]["size"], 4);
+        assert!(json["messages"][0]["attachment"]["data"].is_null());
+        assert!(json["messages"][1]["attachment"].is_null());
+    }
+
+    #[test]
+    fn test_message_event_to_json() {
+        let event = Response::message_event_to_json(3, vec![api::Message{
+            id: Some(7),
+            seq: Some(2),
+            conversation: Some(3),
+            data: Some(b"hello".to_vec()),
+            sender: Some(String::from("me@example.com")),
+            ..Default::default()
+        }]);
+
+        // Pushes can't be mistaken for a response, which always has a status
+        let json: serde_json::Value = serde_json::from_str(&event).unwrap();
+        assert_eq!(json["event"], "message");
+        assert_eq!(json["conversation"], 3);
+        assert!(json.get("status").is_none());
+        assert_eq!(json["messages"][0]["id"], 7);
+        assert_eq!(json["messages"][0]["seq"], 2);
+        assert_eq!(json["messages"][0]["data"], base64::encode("hello"));
+        assert_eq!(json["messages"][0]["sender"], "me@example.com");
+    }
 }
\ No newline at end of file
@@ -0,0 +1,104 @@
+use crate::api::response::{STATUS_PERMISSION_DENIED, STATUS_SUCCESS};
+
+use std::error::Error;
+use sqlx::PgPool;
+#[cfg(feature = "sqlite")]
+use sqlx::SqlitePool;
+
+/// A user logging in
+pub const ACTION_LOGIN: &str = "login";
+/// A user changing their password
+pub const ACTION_CHANGE_PASSWORD: &str = "change_password";
+/// A user replacing their public key
+pub const ACTION_ROTATE_KEY: &str = "rotate_key";
+/// A user being refused permission to make a request
+pub const ACTION_PERMISSION_DENIED: &str = "permission_denied";
+
+/// A security-sensitive event, recorded so operators can see who did what
+///
+/// Entries only ever hold emails and request names, never passwords or message content.
+#[derive(Debug, PartialEq)]
+pub struct AuditEntry {
+    pub actor: String,
+    pub action: &'static str,
+    pub target: Option<String>,
+    pub outcome: &'static str,
+}
+
+/// Decide what to record about a handled request, given the sensitive action it took (if any) and its status
+///
+/// Requests that failed for other reasons (e.g. malformed input) and requests without a known actor aren't recorded.
+pub fn entry_for(action: Option<&'static str>, actor: Option<String>, target: Option<String>, status: u8) -> Option<AuditEntry> {
+    let actor = actor?;
+
+    let (action, outcome) = match (action, status) {
+        (Some(action), STATUS_SUCCESS) => (action, "success"),
+        (Some(action), STATUS_PERMISSION_DENIED) => (action, "denied"),
+        (None, STATUS_PERMISSION_DENIED) => (ACTION_PERMISSION_DENIED, "denied"),
+        _ => return None,
+    };
+
+    Some(AuditEntry{
+        actor,
+        action,
+        target,
+        outcome,
+    })
+}
+
+/// Add an entry to the audit log
+pub async fn record(entry: &AuditEntry, db_pool: &PgPool) -> Result<(), Box<dyn Error>> {
+    sqlx::query_file!("src/sql/create-audit-entry.sql",
+            entry.actor,
+            entry.action,
+            entry.target,
+            entry.outcome)
+        .execute(db_pool)
+        .await?;
+
+    Ok(())
+}
+
+/// Add an entry to the audit log of a SQLite database
+#[cfg(feature = "sqlite")]
+pub async fn record_sqlite(entry: &AuditEntry, db_pool: &SqlitePool) -> Result<(), Box<dyn Error>> {
+    sqlx::query(include_str!("sql/sqlite/create-audit-entry.sql"))
+        .bind(&entry.actor)
+        .bind(entry.action)
+        .bind(&entry.target)
+        .bind(entry.outcome)
+        .bind(chrono::Utc::now().timestamp_millis())
+        .execute(db_pool)
+        .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::api::response::{STATUS_INVALID_INPUT, STATUS_PERMISSION_DENIED, STATUS_SUCCESS};
+    use crate::audit::{entry_for, AuditEntry, ACTION_LOGIN, ACTION_PERMISSION_DENIED};
+
+    #[test]
+    fn test_entry_for() {
+        let actor = || Some(String::from("alice@example.com"));
+
+        assert_eq!(entry_for(Some(ACTION_LOGIN), actor(), actor(), STATUS_SUCCESS), Some(AuditEntry{
+            actor: String::from("alice@example.com"),
+            action: ACTION_LOGIN,
+            target: actor(),
+            outcome: "success",
+        }));
+        assert_eq!(entry_for(Some(ACTION_LOGIN), actor(), actor(), STATUS_PERMISSION_DENIED).unwrap().outcome, "denied");
+
+        // Any other request is only recorded when it's refused
+        let denied = entry_for(None, actor(), Some(String::from("DELETE MESSAGES")), STATUS_PERMISSION_DENIED).unwrap();
+        assert_eq!(denied.action, ACTION_PERMISSION_DENIED);
+        assert_eq!(denied.target.as_deref(), Some("DELETE MESSAGES"));
+        assert_eq!(entry_for(None, actor(), None, STATUS_SUCCESS), None);
+
+        // Malformed requests and requests from unknown users aren't recorded
+        assert_eq!(entry_for(Some(ACTION_LOGIN), actor(), actor(), STATUS_INVALID_INPUT), None);
+        assert_eq!(entry_for(None, None, None, STATUS_PERMISSION_DENIED), None);
+    }
+}
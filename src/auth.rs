@@ -1,46 +1,232 @@
+pub mod signature;
+
+use crate::settings;
+
+use std::collections::{HashMap, VecDeque};
 use std::error::Error;
+use std::io::Error as ioErr;
+use std::io::ErrorKind as ioErrKind;
 use std::str;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
 use argon2;
+use chrono::{DateTime, Utc};
 use getrandom;
+use once_cell::sync::Lazy;
+
+/// The number of user lookups allowed per user within `LOOKUP_WINDOW`
+const LOOKUP_LIMIT: usize = 20;
+/// The window over which user lookups are limited
+const LOOKUP_WINDOW: Duration = Duration::from_secs(60);
+/// The shortest password (in characters) that can be used if none is configured
+const DEFAULT_MIN_PASSWORD_LENGTH: usize = 8;
+/// The number of failed logins in a row that lock an account if none is configured (0 never locks)
+const DEFAULT_MAX_FAILED_LOGINS: i32 = 0;
+/// The number of seconds an account stays locked for if none is configured
+const DEFAULT_LOCKOUT_DURATION: i64 = 900;
+/// The number of random bytes in a new password's salt if none is configured
+const DEFAULT_SALT_LENGTH: usize = 32;
+/// The shortest salt (in bytes) Argon2 accepts
+const MIN_SALT_LENGTH: usize = 8;
+/// The longest salt (in bytes) that can be configured
+const MAX_SALT_LENGTH: usize = 64;
+
+/// The id of the next connection a login is created for
+static NEXT_CONNECTION: AtomicU64 = AtomicU64::new(0);
+/// User lookups made by each user, kept across connections so reconnecting doesn't start a fresh limit
+pub static LOOKUPS: Lazy<KeyedRateLimit> = Lazy::new(|| KeyedRateLimit::new(LOOKUP_LIMIT, LOOKUP_WINDOW));
 
 /// A user authenticated to use the current connection
+///
+/// A connection is authenticated exactly when it has an email, so the two can't disagree.
 pub struct Login {
-    pub email: Option<String>,
-    pub is_authenticated: bool,
+    email: Option<String>,
+    connection: u64,
 }
 
 impl Login {
+    /// Create an unauthenticated login for a new connection
+    pub fn new() -> Self {
+        Login{
+            email: None,
+            connection: NEXT_CONNECTION.fetch_add(1, Ordering::Relaxed),
+        }
+    }
+
+    /// Get the id of the connection, which is unique on this server
+    pub fn connection(&self) -> u64 {
+        self.connection
+    }
+
     /// Set a user as authenticated
-    pub fn authenticate(&mut self, email: String) {
+    ///
+    /// An empty email would be stored as a blank creator or sender, so it's refused and the login is left as it was.
+    pub fn authenticate(&mut self, email: String) -> Result<(), Box<dyn Error>> {
+        if email.is_empty() {
+            return Err(Box::new(ioErr::new(ioErrKind::InvalidInput, "Cannot authenticate without an email")));
+        }
+
         self.email = Some(email);
-        self.is_authenticated = true;
+        Ok(())
+    }
+
+    /// Forget the authenticated user, so the connection has to authenticate again
+    pub fn logout(&mut self) {
+        self.email = None;
+    }
+
+    /// Check whether a user has authenticated on this connection
+    pub fn is_authenticated(&self) -> bool {
+        self.email.is_some()
+    }
+
+    /// Get the authenticated user's email, failing if nobody has authenticated
+    pub fn email(&self) -> Result<&str, Box<dyn Error>> {
+        self.email.as_deref()
+            .ok_or_else(|| ioErr::new(ioErrKind::PermissionDenied, "Not authenticated").into())
+    }
+}
+
+impl Default for Login {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A limit on how many times an action can be taken within a sliding window
+pub struct RateLimit {
+    max: usize,
+    window: Duration,
+    history: VecDeque<Instant>,
+}
+
+impl RateLimit {
+    /// Create a limit of `max` actions per `window`
+    pub fn new(max: usize, window: Duration) -> Self {
+        RateLimit{
+            max,
+            window,
+            history: VecDeque::new(),
+        }
+    }
+
+    /// Record an attempt at an action, returning whether it's within the limit
+    pub fn check(&mut self, now: Instant) -> bool {
+        // Forget attempts that have left the window
+        while let Some(&oldest) = self.history.front() {
+            match now.duration_since(oldest) >= self.window {
+                true => self.history.pop_front(),
+                false => break,
+            };
+        }
+
+        if self.history.len() >= self.max {
+            return false;
+        }
+
+        self.history.push_back(now);
+        true
+    }
+
+    /// Check whether any attempts are still in the window
+    fn is_active(&self, now: Instant) -> bool {
+        self.history.back().map_or(false, |&latest| now.duration_since(latest) < self.window)
+    }
+}
+
+/// Separate limits on an action for each of many keys, such as users
+pub struct KeyedRateLimit {
+    max: usize,
+    window: Duration,
+    limits: Mutex<HashMap<String, RateLimit>>,
+}
+
+impl KeyedRateLimit {
+    /// Create a limit of `max` actions per `window` for each key
+    pub fn new(max: usize, window: Duration) -> Self {
+        KeyedRateLimit{
+            max,
+            window,
+            limits: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Record an attempt at an action for a key, returning whether it's within the key's limit
+    pub fn check(&self, key: &str, now: Instant) -> bool {
+        let mut limits = self.limits.lock().unwrap();
+
+        // Forget keys without any attempts left in the window, so every key ever seen isn't kept
+        limits.retain(|_, limit| limit.is_active(now));
+
+        limits.entry(String::from(key))
+            .or_insert_with(|| RateLimit::new(self.max, self.window))
+            .check(now)
+    }
+}
+
+/// Rules for locking an account after too many failed logins in a row
+pub struct Lockout {
+    pub max_attempts: i32,
+    pub duration: chrono::Duration,
+}
+
+impl Lockout {
+    /// Read the lockout rules from environmental variables
+    pub fn from_env() -> Result<Self, Box<dyn Error>> {
+        Ok(Lockout{
+            max_attempts: settings::get_value("MAX_FAILED_LOGINS", DEFAULT_MAX_FAILED_LOGINS)?,
+            duration: chrono::Duration::seconds(settings::get_value("LOCKOUT_DURATION", DEFAULT_LOCKOUT_DURATION)?),
+        })
+    }
+
+    /// Check if an account is locked at the given time
+    pub fn is_locked(&self, locked_until: Option<DateTime<Utc>>, now: DateTime<Utc>) -> bool {
+        locked_until.map_or(false, |until| now < until)
+    }
+
+    /// Count a failed login, returning the new number of failures in a row and when the account is
+    /// locked until (the count starts again once an account is locked)
+    pub fn fail(&self, failed_attempts: i32, now: DateTime<Utc>) -> (i32, Option<DateTime<Utc>>) {
+        let failed_attempts = failed_attempts + 1;
+
+        match self.max_attempts > 0 && failed_attempts >= self.max_attempts {
+            true => (0, Some(now + self.duration)),
+            false => (failed_attempts, None),
+        }
     }
 }
 
+/// A password no user has, checked when logging in to an account that doesn't exist
+static DUMMY_PASSWORD: Lazy<Option<Password>> = Lazy::new(|| Password::hash("", None).ok());
+
 /// A password for user accounts
 pub struct Password {
+    /// The hash as a PHC string (`$argon2id$v=19$m=...,t=...,p=...$salt$hash`), which carries the salt and the
+    /// parameters it was hashed with, so it can be checked without knowing them
     pub hash: Vec<u8>,
     pub salt: Vec<u8>,
 }
 
 impl Password {
     /// Create a password hash from a string and an (optionally provided) salt
+    ///
+    /// New salts are SALT_LENGTH random bytes.
     pub fn hash(password: &str, salt: Option<&[u8]>) -> Result<Self, Box<dyn Error>> {
         let salt = match salt {
             // Use provided salt 
             Some(s) => s.to_owned(),
             // Generate new salt
-            None => {
-                let mut salt = vec![0u8; 32];
-                getrandom::getrandom(&mut salt)?;
-                salt
-            }
+            None => generate_salt(settings::get_value("SALT_LENGTH", DEFAULT_SALT_LENGTH)?)?,
         };
 
         let hash = argon2::hash_encoded(
             password.as_bytes(),
             &salt,
-            &argon2::Config::default()
+            &argon2::Config{
+                variant: argon2::Variant::Argon2id,
+                ..argon2::Config::default()
+            }
         )?;
 
         Ok(Password{
@@ -56,30 +242,168 @@ impl Password {
 
         Ok(result)
     }
+
+    /// Take as long as checking a password against a stored hash would, without checking anything, so that logins to
+    /// accounts that don't exist can't be told apart from wrong passwords by their timing
+    pub fn check_dummy(password: &str) {
+        if let Some(dummy) = DUMMY_PASSWORD.as_ref() {
+            let _ = dummy.is_valid(password);
+        }
+    }
+}
+
+/// Check that a salt length is one Argon2 accepts, and no longer than is useful
+pub fn check_salt_length(length: usize) -> Result<(), Box<dyn Error>> {
+    match (MIN_SALT_LENGTH..=MAX_SALT_LENGTH).contains(&length) {
+        true => Ok(()),
+        false => Err(Box::new(ioErr::new(ioErrKind::InvalidInput, format!("Invalid SALT_LENGTH '{}' (must be {} to {} bytes)", length, MIN_SALT_LENGTH, MAX_SALT_LENGTH)))),
+    }
+}
+
+/// Generate a random salt of `length` bytes
+///
+/// The length is checked when the server starts, so a bad one here is the server's fault rather than the client's.
+fn generate_salt(length: usize) -> Result<Vec<u8>, Box<dyn Error>> {
+    check_salt_length(length)
+        .map_err(|e| ioErr::new(ioErrKind::Other, e.to_string()))?;
+
+    let mut salt = vec![0u8; length];
+    getrandom::getrandom(&mut salt)?;
+    Ok(salt)
+}
+
+/// Rules that new passwords have to follow
+pub struct PasswordPolicy {
+    pub min_length: usize,
+    pub require_complex: bool,
+}
+
+impl PasswordPolicy {
+    /// Read the password policy from environmental variables
+    pub fn from_env() -> Result<Self, Box<dyn Error>> {
+        Ok(PasswordPolicy{
+            min_length: settings::get_value("MIN_PASSWORD_LENGTH", DEFAULT_MIN_PASSWORD_LENGTH)?,
+            require_complex: settings::is_enabled("REQUIRE_COMPLEX_PASSWORDS"),
+        })
+    }
+
+    /// Check that a password follows the policy, describing why if it doesn't
+    pub fn check(&self, password: &str) -> Result<(), Box<dyn Error>> {
+        if password.chars().count() < self.min_length {
+            return Err(Box::new(ioErr::new(ioErrKind::InvalidInput, format!("Password must be at least {} characters", self.min_length))));
+        }
+
+        let is_complex = password.chars().any(|c| c.is_lowercase())
+            && password.chars().any(|c| c.is_uppercase())
+            && password.chars().any(|c| c.is_numeric());
+
+        if self.require_complex && !is_complex {
+            return Err(Box::new(ioErr::new(ioErrKind::InvalidInput, "Password must contain lowercase and uppercase letters and numbers")));
+        }
+
+        Ok(())
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::auth::Password;
+    use crate::auth::{generate_salt, KeyedRateLimit, Lockout, Login, Password, PasswordPolicy, RateLimit, DUMMY_PASSWORD};
+    use std::str;
+    use chrono::TimeZone;
+    use std::io::Error as ioErr;
+    use std::io::ErrorKind as ioErrKind;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_login() {
+        let mut login = Login::new();
+        assert!(!login.is_authenticated());
+        let error = login.email().unwrap_err();
+        assert_eq!(error.downcast_ref::<ioErr>().unwrap().kind(), ioErrKind::PermissionDenied);
+
+        login.authenticate(String::from("me@example.com")).unwrap();
+        assert!(login.is_authenticated());
+        assert_eq!(login.email().unwrap(), "me@example.com");
+
+        login.logout();
+        assert!(!login.is_authenticated());
+        assert!(login.email().is_err());
+
+        // An empty email is refused, so the login stays unauthenticated and agrees with email()
+        let error = login.authenticate(String::new()).unwrap_err();
+        assert_eq!(error.downcast_ref::<ioErr>().unwrap().kind(), ioErrKind::InvalidInput);
+        assert!(!login.is_authenticated());
+        assert!(login.email().is_err());
+    }
 
     #[test]
     fn test_hash() {
         let passwords = vec!["8nLpNaeJ", "9poyvjJN", "L3Chj2ne"];
         let salt = b"samplesalt";
+
+        let hashes: Vec<Password> = passwords
+            .iter()
+            .map(|x| Password::hash(x, Some(salt)).unwrap())
+            .collect();
+
+        // New hashes use Argon2id, with the salt and parameters written into the hash
+        for (password, hash) in passwords.iter().zip(&hashes) {
+            let encoded = str::from_utf8(&hash.hash).unwrap();
+            assert!(encoded.starts_with("$argon2id$v=19$m=4096,t=3,p=1$c2FtcGxlc2FsdA$"), "{}", encoded);
+            assert_eq!(hash.is_valid(password).unwrap(), true);
+            assert_eq!(hash.hash, Password::hash(password, Some(salt)).unwrap().hash);
+        }
+        assert_ne!(hashes[0].hash, hashes[1].hash);
+
+        // Hashes stored before the switch to Argon2id still verify
         let test_hashes = vec![
             "$argon2i$v=19$m=4096,t=3,p=1$c2FtcGxlc2FsdA$75kN1JTjZ+AwNg3f5PvLU4Dp+4biUIo2BOqo9dYdXVE".to_string().into_bytes(),
             "$argon2i$v=19$m=4096,t=3,p=1$c2FtcGxlc2FsdA$yU0Lgj66mhc2a7HT6z9RTP6myZgssy99snipJyrAku4".to_string().into_bytes(),
             "$argon2i$v=19$m=4096,t=3,p=1$c2FtcGxlc2FsdA$d9UXA+y9LsGj89WB/3DNV6JpDwDr4fyo2rbjo02vilk".to_string().into_bytes(),
         ];
 
-        let hashes: Vec<Password> = passwords
-            .iter()
-            .map(|x| Password::hash(x, Some(salt)).unwrap())
-            .collect();
+        for (password, hash) in passwords.iter().zip(test_hashes) {
+            let stored = Password{
+                hash,
+                salt: salt.to_vec(),
+            };
+            assert_eq!(stored.is_valid(password).unwrap(), true);
+            assert_eq!(stored.is_valid("k2uEa77H").unwrap(), false);
+        }
+    }
+
+    #[test]
+    fn test_phc_round_trip() {
+        // A hash checks out from its PHC string alone, whatever length of salt it was made with
+        for length in [8, 16, 32, 64].iter() {
+            let salt = generate_salt(*length).unwrap();
+            assert_eq!(salt.len(), *length);
+
+            let encoded = String::from_utf8(Password::hash("k2uEa77H", Some(&salt)).unwrap().hash).unwrap();
+            let stored = Password{
+                hash: encoded.clone().into_bytes(),
+                salt: Vec::new(),
+            };
+            assert_eq!(stored.is_valid("k2uEa77H").unwrap(), true, "{}", encoded);
+            assert_eq!(stored.is_valid("9poyvjJN").unwrap(), false, "{}", encoded);
 
-        assert_eq!(hashes[0].hash, test_hashes[0]);
-        assert_eq!(hashes[1].hash, test_hashes[1]);
-        assert_eq!(hashes[2].hash, test_hashes[2]);
+            // The salt can be read back out of the hash
+            let fields: Vec<&str> = encoded.split('$').collect();
+            assert_eq!(fields[1], "argon2id");
+            assert_eq!(base64::decode_config(fields[4], base64::STANDARD_NO_PAD).unwrap(), salt);
+        }
+
+        // Salts have to be long enough for Argon2, and can't be configured to be unreasonably long
+        assert!(generate_salt(7).is_err());
+        assert!(generate_salt(65).is_err());
+        assert_ne!(generate_salt(32).unwrap(), generate_salt(32).unwrap());
+
+        // Hashes that aren't PHC strings can't be checked
+        let garbled = Password{
+            hash: b"$argon2id$v=19$m=4096".to_vec(),
+            salt: Vec::new(),
+        };
+        assert!(garbled.is_valid("k2uEa77H").is_err());
     }
 
     #[test]
@@ -91,4 +415,103 @@ mod tests {
 
         assert_eq!(hash.is_valid(password).unwrap(), true);
     }
-}
+
+    #[test]
+    fn test_check_dummy() {
+        // The dummy is a real hash, so checking it costs as much as checking a user's password
+        let dummy = DUMMY_PASSWORD.as_ref().unwrap();
+        assert!(str::from_utf8(&dummy.hash).unwrap().starts_with("$argon2"));
+        Password::check_dummy("k2uEa77H");
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let start = Instant::now();
+        let mut limit = RateLimit::new(2, Duration::from_secs(60));
+
+        assert_eq!(limit.check(start), true);
+        assert_eq!(limit.check(start + Duration::from_secs(1)), true);
+        assert_eq!(limit.check(start + Duration::from_secs(2)), false);
+
+        // The first attempt leaves the window
+        assert_eq!(limit.check(start + Duration::from_secs(60)), true);
+        assert_eq!(limit.check(start + Duration::from_secs(61)), true);
+        assert_eq!(limit.check(start + Duration::from_secs(62)), false);
+    }
+
+    #[test]
+    fn test_keyed_rate_limit() {
+        let start = Instant::now();
+        let limit = KeyedRateLimit::new(2, Duration::from_secs(60));
+
+        // Each key has its own limit
+        assert_eq!(limit.check("me@example.com", start), true);
+        assert_eq!(limit.check("me@example.com", start), true);
+        assert_eq!(limit.check("me@example.com", start), false);
+        assert_eq!(limit.check("you@example.com", start), true);
+
+        // Keys are forgotten once their attempts leave the window, which frees up their limit
+        assert_eq!(limit.limits.lock().unwrap().len(), 2);
+        assert_eq!(limit.check("you@example.com", start + Duration::from_secs(60)), true);
+        assert_eq!(limit.limits.lock().unwrap().len(), 1);
+        assert_eq!(limit.check("me@example.com", start + Duration::from_secs(60)), true);
+    }
+
+    #[test]
+    fn test_lockout() {
+        let lockout = Lockout{
+            max_attempts: 3,
+            duration: chrono::Duration::minutes(15),
+        };
+        let start = chrono::Utc.ymd(2021, 1, 1).and_hms(12, 0, 0);
+
+        // The third failure in a row locks the account
+        let (attempts, locked_until) = lockout.fail(0, start);
+        assert_eq!((attempts, locked_until), (1, None));
+        let (attempts, locked_until) = lockout.fail(attempts, start);
+        assert_eq!((attempts, locked_until), (2, None));
+        let (attempts, locked_until) = lockout.fail(attempts, start);
+        assert_eq!(attempts, 0);
+        assert_eq!(locked_until, Some(start + chrono::Duration::minutes(15)));
+
+        // Logins are rejected until the lockout runs out
+        assert!(!lockout.is_locked(None, start));
+        assert!(lockout.is_locked(locked_until, start));
+        assert!(lockout.is_locked(locked_until, start + chrono::Duration::minutes(14)));
+        assert!(!lockout.is_locked(locked_until, start + chrono::Duration::minutes(15)));
+
+        // Accounts are never locked if no limit is set
+        let disabled = Lockout{
+            max_attempts: 0,
+            duration: chrono::Duration::minutes(15),
+        };
+        assert_eq!(disabled.fail(100, start), (101, None));
+    }
+
+    #[test]
+    fn test_password_policy() {
+        let policy = PasswordPolicy{
+            min_length: 8,
+            require_complex: false,
+        };
+
+        assert!(policy.check("short").is_err());
+        assert!(policy.check("longenough").is_ok());
+
+        let policy = PasswordPolicy{
+            min_length: 8,
+            require_complex: true,
+        };
+
+        assert!(policy.check("longenough").is_err());
+        assert!(policy.check("k2uEa77H").is_ok());
+
+        // Deployments can relax the policy entirely
+        let policy = PasswordPolicy{
+            min_length: 0,
+            require_complex: false,
+        };
+
+        assert!(policy.check("").is_ok());
+    }
+}
\ No newline at end of file
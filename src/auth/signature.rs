@@ -0,0 +1,101 @@
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fmt;
+use ed25519_dalek::{PublicKey, Signature};
+
+/// A message's signature doesn't match its sender's public key
+#[derive(Debug)]
+pub struct InvalidSignature;
+
+impl fmt::Display for InvalidSignature {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Invalid 'signature' field for 'message'")
+    }
+}
+
+impl Error for InvalidSignature {}
+
+/// Encode the parts of a message that are signed
+///
+/// The data, media type and timestamp are each prefixed with their length as a big-endian u32,
+/// followed by the conversation id as a big-endian i32.
+pub fn signed_bytes(data: &[u8], media_type: &[u8], timestamp: &[u8], conversation_id: i32) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(data.len() + media_type.len() + timestamp.len() + 16);
+
+    for part in [data, media_type, timestamp].iter() {
+        bytes.extend_from_slice(&(part.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(part);
+    }
+
+    bytes.extend_from_slice(&conversation_id.to_be_bytes());
+    bytes
+}
+
+/// Check that a raw public key is a valid ed25519 key
+pub fn is_public_key(public_key: &[u8]) -> bool {
+    PublicKey::from_bytes(public_key).is_ok()
+}
+
+/// Check an ed25519 signature over a message, given the signer's raw 32-byte public key
+pub fn verify(public_key: &[u8], signature: &[u8], message: &[u8]) -> Result<(), InvalidSignature> {
+    let public_key = PublicKey::from_bytes(public_key).map_err(|_| InvalidSignature)?;
+    let signature = Signature::try_from(signature).map_err(|_| InvalidSignature)?;
+
+    public_key.verify_strict(message, &signature).map_err(|_| InvalidSignature)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::auth::signature::{is_public_key, signed_bytes, verify};
+
+    fn from_hex(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    const PUBLIC_KEY: &str = "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a";
+
+    #[test]
+    fn test_signed_bytes() {
+        let bytes = signed_bytes(b"hello", b"text/plain", b"2021-01-01T00:00:00Z", 7);
+
+        assert_eq!(bytes, from_hex("0000000568656c6c6f0000000a746578742f706c61696e00000014323032312d30312d30315430303a30303a30305a00000007"));
+
+        // Moving bytes between fields changes the encoding
+        assert_ne!(bytes, signed_bytes(b"hellot", b"ext/plain", b"2021-01-01T00:00:00Z", 7));
+    }
+
+    #[test]
+    fn test_verify() {
+        let public_key = from_hex(PUBLIC_KEY);
+
+        // RFC 8032 test 1
+        let signature = from_hex("e5564300c360ac729086e2cc806e828a84877f1eb8e5d974d873e065224901555fb8821590a33bacc61e39701cf9b46bd25bf5f0595bbe24655141438e7a100b");
+        assert!(verify(&public_key, &signature, b"").is_ok());
+
+        // A message signed with the same key
+        let message = signed_bytes(b"hello", b"text/plain", b"2021-01-01T00:00:00Z", 7);
+        let signature = from_hex("90f8747c83073077dd99674ffb10ca47f3768cee94b1e79b05e7c2f3f4d07034623d8f3d70ce902b96619ff569f488718cdd61300d86fc5c7113be9f85c3170c");
+        assert!(verify(&public_key, &signature, &message).is_ok());
+
+        // The same signature in another conversation
+        let moved = signed_bytes(b"hello", b"text/plain", b"2021-01-01T00:00:00Z", 8);
+        assert!(verify(&public_key, &signature, &moved).is_err());
+
+        // Malformed keys and signatures
+        assert!(verify(&public_key[..31], &signature, &message).is_err());
+        assert!(verify(&public_key, &signature[..63], &message).is_err());
+        assert!(verify(&public_key, &[0; 64], &message).is_err());
+    }
+
+    #[test]
+    fn test_is_public_key() {
+        let public_key = from_hex(PUBLIC_KEY);
+
+        assert!(is_public_key(&public_key));
+        assert!(!is_public_key(&public_key[..31]));
+        assert!(!is_public_key(&[]));
+    }
+}
@@ -1,30 +1,205 @@
 use crate::settings;
+use crate::settings::DatabaseConfig;
 
-use std::env;
 use std::error::Error;
+use std::future::Future;
+use std::io::Error as ioErr;
+use std::io::ErrorKind as ioErrKind;
+use async_std::task;
+use chrono::{DateTime, Duration, Utc};
 use log::info;
-use sqlx::{Pool, Postgres, postgres::PgPoolOptions};
+use sqlx::{PgPool, Pool, Postgres, migrate::MigrateError, postgres::PgPoolOptions};
+#[cfg(feature = "sqlite")]
+use sqlx::{SqlitePool, sqlite::{SqliteConnectOptions, SqlitePoolOptions}};
+
+/// The number of seconds an unfinished upload is kept for after its last chunk, unless configured otherwise
+const DEFAULT_UPLOAD_TTL: i32 = 3600;
+/// The most times a query is tried before its error is given up on
+const MAX_ATTEMPTS: u32 = 3;
+/// How long to wait before retrying a query the first time, doubling for each retry after that
+const RETRY_DELAY: std::time::Duration = std::time::Duration::from_millis(50);
+
+/// Postgres error codes for failures that can go away if the query is tried again
+const TRANSIENT_CODES: &[&str] = &[
+    "40001", // serialization_failure
+    "40P01", // deadlock_detected
+    "57P01", // admin_shutdown
+    "57P03", // cannot_connect_now
+    "08000", // connection_exception
+    "08003", // connection_does_not_exist
+    "08006", // connection_failure
+];
+
+/// The databases that requests are sent to, with reads going to a replica if there is one
+#[derive(Clone)]
+pub struct DbRouter {
+    primary: PgPool,
+    replica: Option<PgPool>,
+    read_your_writes: std::time::Duration,
+}
+
+impl DbRouter {
+    pub fn new(primary: PgPool, replica: Option<PgPool>, read_your_writes: std::time::Duration) -> Self {
+        DbRouter{
+            primary,
+            replica,
+            read_your_writes,
+        }
+    }
+
+    /// Send every request to the same database
+    pub fn single(pool: PgPool) -> Self {
+        Self::new(pool, None, std::time::Duration::from_secs(0))
+    }
+
+    /// Get the primary database, which takes every write
+    pub fn primary(&self) -> &PgPool {
+        &self.primary
+    }
+
+    /// Check whether a read can go to the replica, given when its connection last wrote (if it has)
+    ///
+    /// Replicas can lag behind, so reads stay on the primary for a while after a write to make sure they see it.
+    pub fn reads_from_replica(&self, last_write: Option<std::time::Instant>, now: std::time::Instant) -> bool {
+        self.replica.is_some() && last_write.map_or(true, |w| now.saturating_duration_since(w) >= self.read_your_writes)
+    }
+
+    /// Choose the database for a request, sending reads to the replica when they can go there
+    pub fn pool_for(&self, is_read: bool, last_write: Option<std::time::Instant>, now: std::time::Instant) -> &PgPool {
+        match &self.replica {
+            Some(replica) if is_read && self.reads_from_replica(last_write, now) => replica,
+            _ => &self.primary,
+        }
+    }
+}
+
+/// The database the server keeps its data in
+#[derive(Clone)]
+pub enum Database {
+    /// Postgres, with reads going to a replica if there is one
+    Postgres(DbRouter),
+    /// A SQLite file, which only the handlers that take a storage can use
+    #[cfg(feature = "sqlite")]
+    Sqlite(SqlitePool),
+}
+
+impl Database {
+    /// Get the primary Postgres database, which background work (push, cleanup and retention) runs on, unless the
+    /// database is SQLite
+    pub fn postgres(&self) -> Option<&PgPool> {
+        match self {
+            Database::Postgres(router) => Some(router.primary()),
+            #[cfg(feature = "sqlite")]
+            Database::Sqlite(_) => None,
+        }
+    }
+}
+
+impl From<DbRouter> for Database {
+    fn from(router: DbRouter) -> Self {
+        Database::Postgres(router)
+    }
+}
+
+/// Set up whichever database `DATABASE_URL` names, connecting to a replica too if Postgres has one
+pub async fn init(config: &DatabaseConfig) -> Result<Database, Box<dyn Error>> {
+    // Without the sqlite feature, a SQLite URL was already refused when the settings were read
+    #[cfg(feature = "sqlite")]
+    {
+        if config.is_sqlite() {
+            return Ok(Database::Sqlite(init_sqlite(config).await?));
+        }
+    }
+
+    let pool = init_db(config).await?;
+    let replica = init_replica(config).await?;
+    Ok(Database::Postgres(DbRouter::new(pool, replica, config.read_your_writes)))
+}
+
+/// Get the options for a database pool
+fn pool_options(config: &DatabaseConfig) -> PgPoolOptions {
+    PgPoolOptions::new()
+        .max_connections(config.max_connections)
+        .min_connections(config.min_connections)
+        .connect_timeout(config.acquire_timeout)
+        .max_lifetime(config.max_lifetime)
+        .idle_timeout(config.idle_timeout)
+}
 
 /// Set up a database to accept connections
-pub async fn init_db() -> Result<Pool<Postgres>, Box<dyn Error>> {
+pub async fn init_db(config: &DatabaseConfig) -> Result<Pool<Postgres>, Box<dyn Error>> {
     // Connect to database
-    let max_conns: u32 = env::var("MAX_DB_CONNECTIONS")
-        .unwrap_or(String::from("150"))
-        .parse()?;
-
-    let pool = PgPoolOptions::new()
-        .max_connections(max_conns)
-        .connect(&env::var("DATABASE_URL")?)
+    let pool = pool_options(config)
+        .connect(&config.url)
         .await?;
 
+    info!("Database pool of {}-{} connections (acquire timeout {}s, max lifetime {}s, idle timeout {}s)",
+        config.min_connections,
+        config.max_connections,
+        config.acquire_timeout.as_secs(),
+        config.max_lifetime.as_secs(),
+        config.idle_timeout.as_secs());
+
     // Drop existing tables if requested
     if settings::is_enabled("DROP_DATABASE") {
         drop_tables(&pool).await?;
     }
 
-    // Create tables if requested
-    if settings::is_enabled("CREATE_DATABASE") {
-        create_tables(&pool).await?;
+    // Create or update tables if requested
+    if settings::is_enabled("RUN_MIGRATIONS") {
+        run_migrations(&pool).await?;
+    }
+
+    Ok(pool)
+}
+
+/// Connect to the read-only replica, if one is configured
+///
+/// The replica is expected to follow the primary, so its tables are never created, dropped or migrated.
+pub async fn init_replica(config: &DatabaseConfig) -> Result<Option<Pool<Postgres>>, Box<dyn Error>> {
+    let url = match &config.replica_url {
+        Some(url) => url,
+        None => return Ok(None),
+    };
+
+    let pool = pool_options(config)
+        .connect(url)
+        .await?;
+
+    info!("Replica pool of {}-{} connections (reads stay on the primary for {}s after a write)",
+        config.min_connections,
+        config.max_connections,
+        config.read_your_writes.as_secs());
+
+    Ok(Some(pool))
+}
+
+/// Set up a SQLite database to accept connections, creating its file if it doesn't exist yet
+#[cfg(feature = "sqlite")]
+pub async fn init_sqlite(config: &DatabaseConfig) -> Result<SqlitePool, Box<dyn Error>> {
+    // The tables are only ever dropped from Postgres, and a SQLite database can be emptied by deleting its file
+    if settings::is_enabled("DROP_DATABASE") {
+        return Err(Box::new(ioErr::new(ioErrKind::InvalidInput, "DROP_DATABASE can't be used with SQLite (delete the database file instead)")));
+    }
+
+    let options = config.url.parse::<SqliteConnectOptions>()?
+        .create_if_missing(true);
+    let pool = SqlitePoolOptions::new()
+        .max_connections(config.max_connections)
+        .min_connections(config.min_connections)
+        .connect_timeout(config.acquire_timeout)
+        .max_lifetime(config.max_lifetime)
+        .idle_timeout(config.idle_timeout)
+        .connect_with(options)
+        .await?;
+
+    info!("SQLite pool of {}-{} connections (only the storage handlers are available)",
+        config.min_connections,
+        config.max_connections);
+
+    // Create or update tables if requested
+    if settings::is_enabled("RUN_MIGRATIONS") {
+        run_sqlite_migrations(&pool).await?;
     }
 
     Ok(pool)
@@ -32,7 +207,7 @@ pub async fn init_db() -> Result<Pool<Postgres>, Box<dyn Error>> {
 
 /// Drop all existing tables in a database
 async fn drop_tables(pool: &Pool<Postgres>) -> Result<(), Box<dyn Error>> {
-    sqlx::query_file!("src/sql/tables/drop.sql")
+    sqlx::query_file!("src/sql/drop-tables.sql")
         .execute(pool)
         .await?;
 
@@ -40,24 +215,1752 @@ async fn drop_tables(pool: &Pool<Postgres>) -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
-/// Create all necessary tables in a database
-async fn create_tables(pool: &Pool<Postgres>) -> Result<(), Box<dyn Error>> {
-    sqlx::query_file!("src/sql/tables/users.sql")
-        .execute(pool)
+/// Apply any migrations that a database hasn't had yet
+pub async fn run_migrations(pool: &Pool<Postgres>) -> Result<(), Box<dyn Error>> {
+    match sqlx::migrate!().run(pool).await {
+        Ok(()) => (),
+        // A database migrated by a newer version of the server can't be used safely
+        Err(MigrateError::VersionMissing(version)) => {
+            return Err(Box::new(ioErr::new(ioErrKind::InvalidData, format!("Database is on unknown migration version {} (was it migrated by a newer server?)", version))));
+        },
+        Err(e) => return Err(Box::new(e)),
+    }
+
+    info!("Migrations applied");
+    Ok(())
+}
+
+/// Apply any SQLite migrations that a database hasn't had yet
+///
+/// SQLite has its own migrations, since its tables keep times as milliseconds and leave out what only the Postgres
+/// handlers use (uploads, revisions and key history).
+#[cfg(feature = "sqlite")]
+pub async fn run_sqlite_migrations(pool: &SqlitePool) -> Result<(), Box<dyn Error>> {
+    match sqlx::migrate!("migrations/sqlite").run(pool).await {
+        Ok(()) => (),
+        Err(MigrateError::VersionMissing(version)) => {
+            return Err(Box::new(ioErr::new(ioErrKind::InvalidData, format!("Database is on unknown migration version {} (was it migrated by a newer server?)", version))));
+        },
+        Err(e) => return Err(Box::new(e)),
+    }
+
+    info!("Migrations applied");
+    Ok(())
+}
+
+/// Check whether a database error is likely to go away if the query is tried again
+///
+/// Constraint violations and other errors caused by the query itself are never transient. Neither is a pool that
+/// timed out, which has already waited `DB_ACQUIRE_TIMEOUT` and is answered as busy rather than waited on again.
+pub fn is_transient(error: &sqlx::Error) -> bool {
+    match error {
+        sqlx::Error::Io(_) => true,
+        sqlx::Error::Database(e) => e.code().map_or(false, |code| TRANSIENT_CODES.contains(&code.as_ref())),
+        _ => false,
+    }
+}
+
+/// Get how long to wait before retrying a query, given which attempt just failed and a random jitter between 0 and 1
+fn backoff(attempt: u32, jitter: f64) -> std::time::Duration {
+    let delay = RETRY_DELAY * 2u32.pow(attempt.saturating_sub(1));
+    delay + delay.mul_f64(jitter.max(0.0).min(1.0))
+}
+
+/// Get a random jitter between 0 and 1, so clients retrying at the same time spread out
+fn jitter() -> f64 {
+    let mut bytes = [0; 4];
+    match getrandom::getrandom(&mut bytes) {
+        Ok(()) => f64::from(u32::from_be_bytes(bytes)) / f64::from(u32::MAX),
+        Err(_) => 0.5,
+    }
+}
+
+/// Run a database operation, trying it again with backoff if it fails for a transient reason
+///
+/// Only operations that are safe to repeat should be retried: reads, idempotent writes, or a whole transaction
+/// (which is rolled back when it fails).
+pub async fn retry<T, F, Fut>(operation: F) -> Result<T, sqlx::Error>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, sqlx::Error>>,
+{
+    retry_if(is_transient, operation).await
+}
+
+/// Run an operation that returns any error, trying it again with backoff if it fails for a transient database reason
+///
+/// The same rules apply as for `retry`, for operations (like storage) that don't return database errors directly.
+pub async fn retry_boxed<T, F, Fut>(operation: F) -> Result<T, Box<dyn Error>>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, Box<dyn Error>>>,
+{
+    retry_if(|e| e.downcast_ref::<sqlx::Error>().map_or(false, is_transient), operation).await
+}
+
+/// Run an operation, trying it again with backoff while it fails with errors that `should_retry` accepts
+async fn retry_if<T, E, F, Fut>(should_retry: impl Fn(&E) -> bool, mut operation: F) -> Result<T, E>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    let mut attempt = 1;
+
+    loop {
+        match operation().await {
+            Err(e) if attempt < MAX_ATTEMPTS && should_retry(&e) => (),
+            result => return result,
+        }
+
+        task::sleep(backoff(attempt, jitter())).await;
+        attempt += 1;
+    }
+}
+
+/// Check if a user is a participant in a conversation
+pub async fn is_member(email: &str, conversation_id: i32, db_pool: &PgPool) -> Result<bool, Box<dyn Error>> {
+    let stream = retry(|| sqlx::query_file!("src/sql/is-member.sql", email, conversation_id)
+            .fetch_one(db_pool))
         .await?;
 
-    sqlx::query_file!("src/sql/tables/conversations.sql")
-        .execute(pool)
+    Ok(stream.is_member)
+}
+
+/// Find the conversation a message belongs to, if the message exists
+pub async fn message_conversation(message_id: i32, db_pool: &PgPool) -> Result<Option<i32>, Box<dyn Error>> {
+    let stream = retry(|| sqlx::query_file!("src/sql/read-message-conversation.sql", message_id)
+            .fetch_optional(db_pool))
         .await?;
 
-    sqlx::query_file!("src/sql/tables/participants.sql")
-        .execute(pool)
+    Ok(stream.map(|m| m.conversation))
+}
+
+/// Delete chunked uploads that haven't been added to within `UPLOAD_TTL` seconds
+pub async fn delete_expired_uploads(db_pool: &PgPool) -> Result<u64, Box<dyn Error>> {
+    let ttl = settings::get_value("UPLOAD_TTL", DEFAULT_UPLOAD_TTL)?;
+
+    // Expired uploads stay locked until they're gone, so a chunk can't be added to one that's losing its chunks
+    let mut tx = db_pool.begin().await?;
+
+    sqlx::query_file!("src/sql/delete-expired-upload-chunks.sql", ttl)
+        .execute(&mut tx)
         .await?;
 
-    sqlx::query_file!("src/sql/tables/messages.sql")
-        .execute(pool)
+    let deleted = sqlx::query_file!("src/sql/delete-expired-uploads.sql", ttl)
+        .execute(&mut tx)
+        .await?
+        .rows_affected();
+
+    tx.commit().await?;
+
+    Ok(deleted)
+}
+
+/// Find the time before which messages in a conversation with a retention window have expired
+pub fn retention_cutoff(now: DateTime<Utc>, retention_seconds: i32) -> DateTime<Utc> {
+    now - Duration::seconds(retention_seconds.into())
+}
+
+/// Delete messages (and their reactions and revisions) that are older than their conversation's retention window
+pub async fn delete_expired_messages(now: DateTime<Utc>, db_pool: &PgPool) -> Result<u64, Box<dyn Error>> {
+    let conversations = sqlx::query_file!("src/sql/read-retention.sql")
+        .fetch_all(db_pool)
         .await?;
 
-    info!("New tables created");
-    Ok(())
-}
\ No newline at end of file
+    let mut deleted = 0;
+
+    for conversation in conversations {
+        let cutoff = retention_cutoff(now, conversation.retention_seconds);
+        let mut tx = db_pool.begin().await?;
+
+        sqlx::query_file!("src/sql/delete-expired-reactions.sql", conversation.id, cutoff)
+            .execute(&mut tx)
+            .await?;
+
+        sqlx::query_file!("src/sql/delete-expired-revisions.sql", conversation.id, cutoff)
+            .execute(&mut tx)
+            .await?;
+
+        deleted += sqlx::query_file!("src/sql/delete-expired-messages.sql", conversation.id, cutoff)
+            .execute(&mut tx)
+            .await?
+            .rows_affected();
+
+        tx.commit().await?;
+    }
+
+    Ok(deleted)
+}
+
+/// Check if a user sent a message
+pub async fn is_sender(email: &str, message_id: i32, db_pool: &PgPool) -> Result<bool, Box<dyn Error>> {
+    let stream = retry(|| sqlx::query_file!("src/sql/is-sender.sql", email, message_id)
+            .fetch_one(db_pool))
+        .await?;
+
+    Ok(stream.is_sender)
+}
+
+/// Check if a user is an admin of a conversation
+pub async fn is_admin(email: &str, conversation_id: i32, db_pool: &PgPool) -> Result<bool, Box<dyn Error>> {
+    let stream = retry(|| sqlx::query_file!("src/sql/is-admin.sql", email, conversation_id)
+            .fetch_one(db_pool))
+        .await?;
+
+    Ok(stream.is_admin)
+}
+
+/// A pool that only connects once it's used, for tests whose requests are answered without reaching the database
+#[cfg(test)]
+pub fn lazy_pool() -> PgPool {
+    PgPool::connect_lazy("postgres://localhost/echo").unwrap()
+}
+
+/// The scratch database named by `TEST_DATABASE_URL`, which the ignored tests are free to empty
+#[cfg(test)]
+pub fn test_database_url() -> String {
+    std::env::var("TEST_DATABASE_URL").expect("TEST_DATABASE_URL must name a scratch database to run ignored tests")
+}
+
+/// An empty, migrated SQLite database that lasts as long as its pool
+///
+/// Every connection to `sqlite::memory:` opens a database of its own, so the pool keeps exactly one.
+#[cfg(all(test, feature = "sqlite"))]
+pub async fn sqlite_memory_pool() -> SqlitePool {
+    let pool = SqlitePoolOptions::new()
+        .max_connections(1)
+        .min_connections(1)
+        .max_lifetime(None)
+        .idle_timeout(None)
+        .connect("sqlite::memory:")
+        .await
+        .unwrap();
+    run_sqlite_migrations(&pool).await.unwrap();
+    pool
+}
+
+#[cfg(test)]
+static SCRATCH_DATABASE: once_cell::sync::Lazy<async_std::sync::Mutex<()>> = once_cell::sync::Lazy::new(Default::default);
+
+/// Empty and migrate the scratch database, holding it until the returned guard is dropped so tests don't share it
+#[cfg(test)]
+pub async fn scratch_database() -> (async_std::sync::MutexGuard<'static, ()>, PgPool) {
+    let turn = SCRATCH_DATABASE.lock().await;
+    let db_pool = PgPool::connect(&test_database_url()).await.unwrap();
+    drop_tables(&db_pool).await.unwrap();
+    run_migrations(&db_pool).await.unwrap();
+    (turn, db_pool)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::api::{Attachment, Conversation, Cursor, Invitation, Message, Reaction, Upload, User};
+    use crate::api::request::{Operation, Request, Target};
+    use crate::api::response::{Response, STATUS_BUSY, STATUS_CONFLICT, STATUS_FAILURE, STATUS_INVALID_INPUT, STATUS_NOT_FOUND, STATUS_PERMISSION_DENIED, STATUS_SUCCESS};
+    use crate::auth::{signature, Login};
+    use crate::database::{backoff, Database, DbRouter, delete_expired_messages, drop_tables, init_db, is_transient, lazy_pool, retention_cutoff, retry_if, run_migrations, scratch_database, test_database_url};
+    use crate::settings::{DatabaseConfig, MessageConfig, Timeouts};
+    use crate::{framing, handle_connection, push};
+    use async_std::io::prelude::*;
+    use async_std::net::{TcpListener, TcpStream};
+    use async_std::task;
+    use chrono::{Duration, SecondsFormat, TimeZone, Utc};
+    use ed25519_dalek::{Keypair, PublicKey, SecretKey, Signer};
+    use serde_json::json;
+    use sha2::{Digest, Sha256};
+    use sqlx::PgPool;
+    use std::cell::Cell;
+    use std::collections::HashMap;
+    use zeroize::Zeroizing;
+
+    #[test]
+    fn test_retention_cutoff() {
+        // A message sent at noon in a conversation that keeps messages for an hour
+        let sent = Utc.ymd(2021, 1, 1).and_hms(12, 0, 0);
+        let retention = 3600;
+
+        // Messages are purged when they are older than the cutoff
+        let expired = |now| sent < retention_cutoff(now, retention);
+
+        assert!(!expired(sent));
+        assert!(!expired(sent + Duration::minutes(59)));
+        assert!(!expired(sent + Duration::hours(1)));
+        assert!(expired(sent + Duration::hours(1) + Duration::seconds(1)));
+        assert!(expired(sent + Duration::days(1)));
+
+        assert_eq!(retention_cutoff(sent, 0), sent);
+    }
+
+    #[test]
+    fn test_is_transient() {
+        assert!(!is_transient(&sqlx::Error::PoolTimedOut));
+        assert!(is_transient(&sqlx::Error::Io(std::io::Error::new(std::io::ErrorKind::ConnectionReset, "reset"))));
+        assert!(!is_transient(&sqlx::Error::RowNotFound));
+        assert!(!is_transient(&sqlx::Error::PoolClosed));
+    }
+
+    #[test]
+    fn test_backoff() {
+        let delay = std::time::Duration::from_millis(50);
+
+        // Each retry waits twice as long as the last, plus up to the same again in jitter
+        assert_eq!(backoff(1, 0.0), delay);
+        assert_eq!(backoff(2, 0.0), delay * 2);
+        assert_eq!(backoff(3, 1.0), delay * 8);
+        assert_eq!(backoff(1, 0.5), delay.mul_f64(1.5));
+    }
+
+    #[async_std::test]
+    async fn test_retry() {
+        // An operation that fails twice before succeeding is retried until it works
+        let attempts = Cell::new(0);
+        let result = retry_if(|_| true, || {
+            attempts.set(attempts.get() + 1);
+            let attempt = attempts.get();
+            async move {
+                match attempt {
+                    1 | 2 => Err(sqlx::Error::PoolTimedOut),
+                    _ => Ok(attempt),
+                }
+            }
+        }).await;
+        assert_eq!(result.unwrap(), 3);
+
+        // Attempts are limited
+        let attempts = Cell::new(0);
+        let result: Result<(), _> = retry_if(|_| true, || {
+            attempts.set(attempts.get() + 1);
+            async { Err(sqlx::Error::PoolTimedOut) }
+        }).await;
+        assert!(result.is_err());
+        assert_eq!(attempts.get(), 3);
+
+        // Errors that aren't transient are returned straight away
+        let attempts = Cell::new(0);
+        let result: Result<(), _> = retry_if(is_transient, || {
+            attempts.set(attempts.get() + 1);
+            async { Err(sqlx::Error::RowNotFound) }
+        }).await;
+        assert!(matches!(result, Err(sqlx::Error::RowNotFound)));
+        assert_eq!(attempts.get(), 1);
+    }
+
+    /// A user with the password every test logs in with
+    fn user(email: &str) -> User {
+        User{
+            email: Some(String::from(email)),
+            password: Some(Zeroizing::new(String::from("correct horse"))),
+            public_key: Some(vec![0; 32]),
+            ..Default::default()
+        }
+    }
+
+    /// Register users with the password every test logs in with
+    async fn register(db_pool: &PgPool, emails: &[&str]) {
+        let request = Request::builder(Operation::Create, Target::Users)
+            .users(emails.iter().map(|email| user(email)).collect::<Vec<User>>())
+            .build();
+        request.handle(&mut Login::new(), db_pool).await.unwrap();
+    }
+
+    /// Log in as a registered user
+    async fn log_in(db_pool: &PgPool, email: &str) -> Login {
+        let mut login = Login::new();
+        let request = Request::builder(Operation::Verify, Target::Users)
+            .users(vec![user(email)])
+            .build();
+        request.handle(&mut login, db_pool).await.unwrap();
+        login
+    }
+
+    /// Accept the invitation to a conversation
+    async fn join(login: &mut Login, db_pool: &PgPool, conversation: Option<i32>) {
+        let response = Request::builder(Operation::Read, Target::Invitations).build().handle(login, db_pool).await.unwrap();
+        let invitation = response.invitations.unwrap().into_iter().find(|i| i.conversation == conversation).unwrap();
+        let request = Request::builder(Operation::Update, Target::Invitations)
+            .invitations(vec![Invitation{
+                status: Some(String::from("accepted")),
+                ..invitation
+            }])
+            .build();
+        request.handle(login, db_pool).await.unwrap();
+    }
+
+    /// Read a framed response from the server
+    async fn read_frame(stream: &mut TcpStream) -> serde_json::Value {
+        let mut length = [0; 4];
+        stream.read_exact(&mut length).await.unwrap();
+        let mut buffer = vec![0; u32::from_be_bytes(length) as usize];
+        stream.read_exact(&mut buffer).await.unwrap();
+        serde_json::from_slice(&buffer).unwrap()
+    }
+
+    /// Send a framed request to the server and read its response
+    async fn exchange(stream: &mut TcpStream, request: serde_json::Value) -> serde_json::Value {
+        stream.write_all(&framing::encode(request.to_string().as_bytes())).await.unwrap();
+        read_frame(stream).await
+    }
+
+    /// Serve a single connection on a local port, returning the address to connect to
+    async fn serve_one(db_pool: &PgPool) -> (std::net::SocketAddr, task::JoinHandle<Result<(), Box<dyn std::error::Error>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let db = Database::from(DbRouter::single(db_pool.clone()));
+        let server = task::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            handle_connection(stream, None, Timeouts::default(), None, 1024 * 1024, &db).await
+        });
+        (address, server)
+    }
+
+    /// Create a conversation, inviting another user to it
+    async fn start_conversation(login: &mut Login, db_pool: &PgPool, name: &str, invitee: &str) -> Option<i32> {
+        let request = Request::builder(Operation::Create, Target::Conversations)
+            .users(vec![User::from_email(String::from(invitee))])
+            .conversations(vec![Conversation{
+                name: Some(String::from(name)),
+                ..Default::default()
+            }])
+            .build();
+        request.handle(login, db_pool).await.unwrap().conversations.unwrap()[0].id
+    }
+
+    /// A text message, ready to send
+    fn text(text: &str) -> Message {
+        Message{
+            data: Some(text.as_bytes().to_vec()),
+            media_type: Some(b"text/plain".to_vec()),
+            timestamp: Some(Utc::now().timestamp_millis()),
+            signature: Some(vec![0; 64]),
+            ..Default::default()
+        }
+    }
+
+    /// Send a text message to a conversation, returning its id
+    async fn send(login: &mut Login, db_pool: &PgPool, conversation: Option<i32>, data: &str) -> Option<i32> {
+        let request = Request::builder(Operation::Create, Target::Messages)
+            .conversations(vec![Conversation{
+                id: conversation,
+                ..Default::default()
+            }])
+            .messages(vec![text(data)])
+            .build();
+        request.handle(login, db_pool).await.unwrap().messages.unwrap()[0].id
+    }
+
+    /// Read a conversation's newest messages, newest first
+    async fn read_messages(login: &mut Login, db_pool: &PgPool, conversation: Option<i32>) -> Vec<Message> {
+        let request = Request::builder(Operation::Read, Target::Messages)
+            .conversations(vec![Conversation{
+                id: conversation,
+                ..Default::default()
+            }])
+            .build();
+        request.handle(login, db_pool).await.unwrap().messages.unwrap()
+    }
+
+    /// Read how many unread messages each of a user's conversations has
+    async fn unread(login: &mut Login, db_pool: &PgPool) -> HashMap<i32, i64> {
+        let request = Request::builder(Operation::Read, Target::Unread).build();
+        request.handle(login, db_pool).await.unwrap().unread.unwrap()
+    }
+
+    #[async_std::test]
+    #[ignore]
+    async fn test_migrations() {
+        let (_turn, db_pool) = scratch_database().await;
+
+        // Migrations that have already been applied are skipped
+        run_migrations(&db_pool).await.unwrap();
+
+        // Dropping the tables forgets the migrations too, so they're applied again from the start
+        drop_tables(&db_pool).await.unwrap();
+        run_migrations(&db_pool).await.unwrap();
+
+        let users: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM users")
+            .fetch_one(&db_pool)
+            .await
+            .unwrap();
+        assert_eq!(users, 0);
+    }
+
+    #[async_std::test]
+    #[ignore]
+    async fn test_create_users() {
+        let (_turn, db_pool) = scratch_database().await;
+        let mut login = Login::new();
+
+        // A duplicate email is reported without stopping the rest of the batch
+        let request = Request::builder(Operation::Create, Target::Users)
+            .users(vec![user("alice@example.com"), user("bob@example.com"), user("alice@example.com")])
+            .build();
+        let response = request.handle(&mut login, &db_pool).await.unwrap();
+        let statuses: Vec<Option<u8>> = response.users.unwrap().iter().map(|u| u.status).collect();
+        assert_eq!(statuses, vec![Some(STATUS_SUCCESS), Some(STATUS_SUCCESS), Some(STATUS_CONFLICT)]);
+
+        let request = Request::builder(Operation::Create, Target::Users)
+            .users(vec![user("bob@example.com"), user("carol@example.com")])
+            .build();
+        let response = request.handle(&mut login, &db_pool).await.unwrap();
+        let statuses: Vec<Option<u8>> = response.users.unwrap().iter().map(|u| u.status).collect();
+        assert_eq!(statuses, vec![Some(STATUS_CONFLICT), Some(STATUS_SUCCESS)]);
+
+        let registered: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM users")
+            .fetch_one(&db_pool)
+            .await
+            .unwrap();
+        assert_eq!(registered, 3);
+
+        let request = Request::builder(Operation::Verify, Target::Users)
+            .users(vec![user("alice@example.com")])
+            .build();
+        request.handle(&mut login, &db_pool).await.unwrap();
+        assert!(login.is_authenticated());
+    }
+
+    #[async_std::test]
+    #[ignore]
+    async fn test_create_users_in_one_statement() {
+        let (_turn, db_pool) = scratch_database().await;
+        register(&db_pool, &["alice@example.com"]).await;
+
+        // A batch of users is stored by a single statement, which leaves out emails that are already registered
+        let mut batch: Vec<User> = (0..99).map(|i| user(&format!("bulk-{}@example.com", i))).collect();
+        batch.insert(50, user("alice@example.com"));
+        let request = Request::builder(Operation::Create, Target::Users)
+            .users(batch)
+            .build();
+        let response = request.handle(&mut Login::new(), &db_pool).await.unwrap();
+        let statuses: Vec<Option<u8>> = response.users.unwrap().iter().map(|u| u.status).collect();
+        assert_eq!(statuses.len(), 100);
+        assert_eq!(statuses[50], Some(STATUS_CONFLICT));
+        assert_eq!(statuses.iter().filter(|&&s| s == Some(STATUS_SUCCESS)).count(), 99);
+
+        // Rows written by the same statement share both the transaction and the command that wrote them
+        let written: (i64, i64, i64) = sqlx::query_as("SELECT COUNT(*), COUNT(DISTINCT xmin::TEXT), COUNT(DISTINCT cmin::TEXT) FROM users WHERE email LIKE 'bulk-%'")
+            .fetch_one(&db_pool)
+            .await
+            .unwrap();
+        assert_eq!(written, (99, 1, 1));
+    }
+
+    #[async_std::test]
+    #[ignore]
+    async fn test_read_own_profile() {
+        let (_turn, db_pool) = scratch_database().await;
+        register(&db_pool, &["alice@example.com"]).await;
+        let mut alice = log_in(&db_pool, "alice@example.com").await;
+
+        // Users can read their own profile, which never includes their password
+        let response = Request::builder(Operation::Read, Target::Users).build().handle(&mut alice, &db_pool).await.unwrap();
+        let json: serde_json::Value = serde_json::from_str(&response.to_json()).unwrap();
+        let profile = response.users.unwrap().remove(0);
+        assert_eq!(profile.email.as_deref(), Some("alice@example.com"));
+        assert_eq!(profile.public_key, Some(vec![0; 32]));
+        assert!(profile.password.is_none());
+        assert!(json["users"][0].get("password").is_none());
+        assert!(json["users"][0].get("salt").is_none());
+    }
+
+    #[async_std::test]
+    #[ignore]
+    async fn test_audit_log() {
+        let (_turn, db_pool) = scratch_database().await;
+        register(&db_pool, &["alice@example.com"]).await;
+        let mut alice = log_in(&db_pool, "alice@example.com").await;
+
+        // Logins and password changes are audited, without recording either password
+        let request = Request::builder(Operation::Update, Target::Users)
+            .users(vec![User{
+                new_password: Some(Zeroizing::new(String::from("battery staple"))),
+                ..user("alice@example.com")
+            }])
+            .build();
+        request.handle(&mut alice, &db_pool).await.unwrap();
+
+        let entries: Vec<(String, String, Option<String>, String)> = sqlx::query_as("SELECT actor, action, target, outcome FROM audit_log ORDER BY id")
+            .fetch_all(&db_pool)
+            .await
+            .unwrap();
+        assert_eq!(entries, vec![
+            (String::from("alice@example.com"), String::from("login"), Some(String::from("alice@example.com")), String::from("success")),
+            (String::from("alice@example.com"), String::from("change_password"), Some(String::from("alice@example.com")), String::from("success")),
+        ]);
+    }
+
+    #[async_std::test]
+    #[ignore]
+    async fn test_archive_conversations() {
+        let (_turn, db_pool) = scratch_database().await;
+        register(&db_pool, &["alice@example.com", "bob@example.com"]).await;
+        let mut alice = log_in(&db_pool, "alice@example.com").await;
+
+        let created = start_conversation(&mut alice, &db_pool, "Migrated", "bob@example.com").await;
+
+        let request = Request::builder(Operation::Read, Target::Conversations).build();
+        let response = request.handle(&mut alice, &db_pool).await.unwrap();
+        let conversations = response.conversations.unwrap();
+        assert_eq!(conversations.len(), 1);
+        assert_eq!(conversations[0].id, created);
+        assert_eq!(conversations[0].name.as_deref(), Some("Migrated"));
+        assert_eq!(conversations[0].archived, Some(false));
+
+        // Archived conversations are left out of the list unless asked for
+        let archive = |archived: bool| Request::builder(Operation::Update, Target::Conversations)
+            .conversations(vec![Conversation{
+                id: created,
+                archived: Some(archived),
+                ..Default::default()
+            }])
+            .build();
+        let response = archive(true).handle(&mut alice, &db_pool).await.unwrap();
+        assert_eq!(response.affected, Some(1));
+
+        let request = Request::builder(Operation::Read, Target::Conversations).build();
+        let response = request.handle(&mut alice, &db_pool).await.unwrap();
+        assert!(response.conversations.unwrap().is_empty());
+
+        let request = Request::builder(Operation::Read, Target::Conversations)
+            .include_archived(true)
+            .build();
+        let response = request.handle(&mut alice, &db_pool).await.unwrap();
+        let conversations = response.conversations.unwrap();
+        assert_eq!(conversations.len(), 1);
+        assert_eq!(conversations[0].archived, Some(true));
+
+        archive(false).handle(&mut alice, &db_pool).await.unwrap();
+        let request = Request::builder(Operation::Read, Target::Conversations).build();
+        let response = request.handle(&mut alice, &db_pool).await.unwrap();
+        assert_eq!(response.conversations.unwrap().len(), 1);
+
+        // Archiving only touches the user's own membership
+        let request = Request::builder(Operation::Update, Target::Conversations)
+            .conversations(vec![Conversation{
+                id: Some(0),
+                archived: Some(true),
+                ..Default::default()
+            }])
+            .build();
+        let error = request.handle(&mut alice, &db_pool).await.unwrap_err();
+        assert_eq!(error.to_string(), "Not a member of conversation");
+    }
+
+    #[async_std::test]
+    #[ignore]
+    async fn test_stream_messages() {
+        let (_turn, db_pool) = scratch_database().await;
+        register(&db_pool, &["alice@example.com", "bob@example.com"]).await;
+        let mut alice = log_in(&db_pool, "alice@example.com").await;
+        let created = start_conversation(&mut alice, &db_pool, "Streamed", "bob@example.com").await;
+
+        // Streaming a conversation sends every message in chunks of up to a page, in the same order as a single read
+        let request = Request::builder(Operation::Create, Target::Messages)
+            .conversations(vec![Conversation{
+                id: created,
+                ..Default::default()
+            }])
+            .messages((0..7).map(|i| text(&format!("Message {}", i))).collect::<Vec<Message>>())
+            .build();
+        request.handle(&mut alice, &db_pool).await.unwrap();
+
+        let read = |limit: i64, stream: bool| Request::builder(Operation::Read, Target::Messages)
+            .conversations(vec![Conversation{
+                id: created,
+                ..Default::default()
+            }])
+            .limit(limit)
+            .stream(stream)
+            .build();
+
+        let (sender, received) = async_std::channel::unbounded();
+        read(3, true).handle_streamed(&alice, &db_pool, &db_pool, &sender).await.unwrap();
+        let chunks: Vec<Response> = std::iter::from_fn(|| received.try_recv().ok()).collect();
+        let sizes: Vec<usize> = chunks.iter().map(|c| c.messages.as_ref().unwrap().len()).collect();
+        let more: Vec<Option<bool>> = chunks.iter().map(|c| c.has_more).collect();
+        assert_eq!(sizes, vec![3, 3, 1]);
+        assert_eq!(more, vec![Some(true), Some(true), Some(false)]);
+
+        let streamed: Vec<Option<i32>> = chunks.into_iter().flat_map(|c| c.messages.unwrap()).map(|m| m.id).collect();
+        let response = read(10, false).handle(&mut alice, &db_pool).await.unwrap();
+        let single: Vec<Option<i32>> = response.messages.unwrap().into_iter().map(|m| m.id).collect();
+        assert_eq!(response.has_more, Some(false));
+        assert_eq!(streamed, single);
+    }
+
+    #[async_std::test]
+    #[ignore]
+    async fn test_read_conversations_has_more() {
+        let (_turn, db_pool) = scratch_database().await;
+        register(&db_pool, &["alice@example.com", "bob@example.com", "carol@example.com"]).await;
+        let mut alice = log_in(&db_pool, "alice@example.com").await;
+        start_conversation(&mut alice, &db_pool, "Migrated", "bob@example.com").await;
+        start_conversation(&mut alice, &db_pool, "Paged", "carol@example.com").await;
+
+        // Reads say whether they were cut short at the limit
+        let page = |cursor: Option<Cursor>| Request::builder(Operation::Read, Target::Conversations)
+            .limit(1)
+            .cursor(cursor)
+            .build();
+        let response = page(None).handle(&mut alice, &db_pool).await.unwrap();
+        assert_eq!(response.conversations.unwrap().len(), 1);
+        assert_eq!(response.has_more, Some(true));
+        assert!(response.cursor.is_some());
+
+        let response = page(response.cursor).handle(&mut alice, &db_pool).await.unwrap();
+        assert_eq!(response.conversations.unwrap().len(), 1);
+        assert_eq!(response.has_more, Some(false));
+        assert_eq!(response.cursor, None);
+    }
+
+    #[async_std::test]
+    #[ignore]
+    async fn test_rotate_public_key() {
+        let (_turn, db_pool) = scratch_database().await;
+        register(&db_pool, &["alice@example.com", "bob@example.com"]).await;
+        let mut alice = log_in(&db_pool, "alice@example.com").await;
+        let created = start_conversation(&mut alice, &db_pool, "Signed", "bob@example.com").await;
+
+        // Rotated keys are kept, so a message can still be checked against the key that was current when it was sent
+        let keypair = |seed: u8| {
+            let secret = SecretKey::from_bytes(&[seed; 32]).unwrap();
+            let public = PublicKey::from(&secret);
+            Keypair{ secret, public }
+        };
+        let (first, second) = (keypair(1), keypair(2));
+        let rotate = |public_key: &PublicKey, password: &str| Request::builder(Operation::Update, Target::Users)
+            .users(vec![User{
+                password: Some(Zeroizing::new(String::from(password))),
+                new_public_key: Some(public_key.to_bytes().to_vec()),
+                ..Default::default()
+            }])
+            .build();
+        rotate(&first.public, "correct horse").handle(&mut alice, &db_pool).await.unwrap();
+
+        let timestamp = Utc::now();
+        let signed = signature::signed_bytes(b"Signed", b"text/plain", timestamp.to_rfc3339_opts(SecondsFormat::Millis, true).as_bytes(), created.unwrap());
+        let sent = first.sign(&signed).to_bytes().to_vec();
+        rotate(&second.public, "correct horse").handle(&mut alice, &db_pool).await.unwrap();
+
+        let error = rotate(&first.public, "battery staple").handle(&mut alice, &db_pool).await.unwrap_err();
+        assert_eq!(error.to_string(), "Invalid password");
+
+        let request = Request::builder(Operation::Read, Target::Users)
+            .users(vec![User::from_email(String::from("alice@example.com"))])
+            .build();
+        let response = request.handle(&mut alice, &db_pool).await.unwrap();
+        let profile = response.users.unwrap().remove(0);
+        let previous = profile.previous_keys.unwrap();
+        assert_eq!(profile.public_key, Some(second.public.to_bytes().to_vec()));
+        assert_eq!(previous.iter().map(|k| k.public_key.clone()).collect::<Vec<Vec<u8>>>(), vec![vec![0; 32], first.public.to_bytes().to_vec()]);
+
+        let current_then = previous.iter()
+            .find(|k| k.replaced_at > timestamp)
+            .map(|k| k.public_key.clone())
+            .unwrap();
+        assert_eq!(current_then, first.public.to_bytes().to_vec());
+        assert!(signature::verify(&current_then, &sent, &signed).is_ok());
+        assert!(signature::verify(profile.public_key.as_ref().unwrap(), &sent, &signed).is_err());
+    }
+
+    #[async_std::test]
+    #[ignore]
+    async fn test_unread() {
+        let (_turn, db_pool) = scratch_database().await;
+        register(&db_pool, &["alice@example.com", "bob@example.com", "carol@example.com"]).await;
+        let mut alice = log_in(&db_pool, "alice@example.com").await;
+        let mut bob = log_in(&db_pool, "bob@example.com").await;
+
+        let created = start_conversation(&mut alice, &db_pool, "Counted", "bob@example.com").await;
+        start_conversation(&mut alice, &db_pool, "Quiet", "carol@example.com").await;
+        join(&mut bob, &db_pool, created).await;
+
+        let request = Request::builder(Operation::Create, Target::Messages)
+            .conversations(vec![Conversation{
+                id: created,
+                ..Default::default()
+            }])
+            .messages((0..7).map(|i| text(&format!("Message {}", i))).collect::<Vec<Message>>())
+            .build();
+        request.handle(&mut alice, &db_pool).await.unwrap();
+        let newest: Vec<Option<i32>> = read_messages(&mut alice, &db_pool, created).await.into_iter().map(|m| m.id).collect();
+
+        // Unread counts cover every conversation, leaving out the user's own messages and those before their read pointer
+        let counts = unread(&mut alice, &db_pool).await;
+        assert_eq!(counts.len(), 2);
+        assert_eq!(counts[&created.unwrap()], 0);
+        assert_eq!(unread(&mut bob, &db_pool).await, vec![(created.unwrap(), 7)].into_iter().collect());
+
+        let request = Request::builder(Operation::Update, Target::Conversations)
+            .conversations(vec![Conversation{
+                id: created,
+                last_read_message_id: newest[3],
+                ..Default::default()
+            }])
+            .build();
+        request.handle(&mut bob, &db_pool).await.unwrap();
+        assert_eq!(unread(&mut bob, &db_pool).await, vec![(created.unwrap(), 3)].into_iter().collect());
+
+        // Purged messages no longer count as unread
+        let response = Request::builder(Operation::Delete, Target::Messages)
+            .messages(vec![Message{
+                id: newest[0],
+                ..Default::default()
+            }])
+            .purge(true)
+            .build()
+            .handle(&mut alice, &db_pool)
+            .await
+            .unwrap();
+        assert_eq!(response.affected, Some(1));
+        assert_eq!(unread(&mut bob, &db_pool).await, vec![(created.unwrap(), 2)].into_iter().collect());
+    }
+
+    #[async_std::test]
+    #[ignore]
+    async fn test_delete_conversations() {
+        let (_turn, db_pool) = scratch_database().await;
+        register(&db_pool, &["alice@example.com", "bob@example.com"]).await;
+        let mut alice = log_in(&db_pool, "alice@example.com").await;
+        let mut bob = log_in(&db_pool, "bob@example.com").await;
+
+        let created = start_conversation(&mut alice, &db_pool, "Deleted", "bob@example.com").await;
+        join(&mut bob, &db_pool, created).await;
+        send(&mut alice, &db_pool, created, "From the admin").await;
+        send(&mut bob, &db_pool, created, "From a member").await;
+
+        // Only an admin can delete a conversation, which takes everything in it along
+        let delete = || Request::builder(Operation::Delete, Target::Conversations)
+            .conversations(vec![Conversation{
+                id: created,
+                ..Default::default()
+            }])
+            .build();
+        let error = delete().handle(&mut bob, &db_pool).await.unwrap_err();
+        let response = Response::from_error(error.as_ref());
+        assert_eq!(response.status, STATUS_PERMISSION_DENIED);
+        assert_eq!(response.error.as_deref(), Some("Not an admin of conversation"));
+        assert_eq!(read_messages(&mut bob, &db_pool, created).await.len(), 2);
+
+        let response = delete().handle(&mut alice, &db_pool).await.unwrap();
+        assert_eq!(response.affected, Some(1));
+
+        let remaining: (i64, i64, i64) = sqlx::query_as("SELECT (SELECT COUNT(*) FROM conversations WHERE id = $1), (SELECT COUNT(*) FROM participants WHERE conversation = $1), (SELECT COUNT(*) FROM messages WHERE conversation = $1)")
+            .bind(created)
+            .fetch_one(&db_pool)
+            .await
+            .unwrap();
+        assert_eq!(remaining, (0, 0, 0));
+    }
+
+    #[async_std::test]
+    #[ignore]
+    async fn test_blocks() {
+        let (_turn, db_pool) = scratch_database().await;
+        register(&db_pool, &["alice@example.com", "bob@example.com"]).await;
+        let mut alice = log_in(&db_pool, "alice@example.com").await;
+        let mut bob = log_in(&db_pool, "bob@example.com").await;
+
+        let created = start_conversation(&mut alice, &db_pool, "Blocked", "bob@example.com").await;
+        join(&mut bob, &db_pool, created).await;
+
+        let blocks = |operation: Operation, email: &str| Request::builder(operation, Target::Blocks)
+            .users(vec![User::from_email(String::from(email))])
+            .build();
+        let message = || Request::builder(Operation::Create, Target::Messages)
+            .conversations(vec![Conversation{
+                id: created,
+                ..Default::default()
+            }])
+            .messages(vec![text("Hello")])
+            .build();
+
+        // Blocking someone who isn't registered is reported rather than failing on the missing user
+        let error = blocks(Operation::Create, "nobody@example.com").handle(&mut bob, &db_pool).await.unwrap_err();
+        let response = Response::from_error(error.as_ref());
+        assert_eq!(response.status, STATUS_NOT_FOUND);
+        assert_eq!(response.error.as_deref(), Some("User does not exist"));
+
+        // Blocking someone twice keeps one block, and their messages are refused in any conversation with the blocker
+        blocks(Operation::Create, "alice@example.com").handle(&mut bob, &db_pool).await.unwrap();
+        blocks(Operation::Create, "alice@example.com").handle(&mut bob, &db_pool).await.unwrap();
+
+        let error = message().handle(&mut alice, &db_pool).await.unwrap_err();
+        let response = Response::from_error(error.as_ref());
+        assert_eq!(response.status, STATUS_PERMISSION_DENIED);
+        assert_eq!(response.error.as_deref(), Some("Blocked by a member of conversation"));
+        assert!(read_messages(&mut bob, &db_pool, created).await.is_empty());
+
+        // Unblocking lets their messages through again
+        let response = blocks(Operation::Delete, "alice@example.com").handle(&mut bob, &db_pool).await.unwrap();
+        assert_eq!(response.affected, Some(1));
+
+        let sent = message().handle(&mut alice, &db_pool).await.unwrap().messages.unwrap()[0].id;
+        let received: Vec<Option<i32>> = read_messages(&mut bob, &db_pool, created).await.into_iter().map(|m| m.id).collect();
+        assert_eq!(received, vec![sent]);
+    }
+
+    #[async_std::test]
+    #[ignore]
+    async fn test_inbox() {
+        let (_turn, db_pool) = scratch_database().await;
+        register(&db_pool, &["alice@example.com", "bob@example.com", "carol@example.com"]).await;
+        let mut alice = log_in(&db_pool, "alice@example.com").await;
+        let mut bob = log_in(&db_pool, "bob@example.com").await;
+
+        let created = start_conversation(&mut alice, &db_pool, "Busy", "bob@example.com").await;
+        let paged = start_conversation(&mut alice, &db_pool, "Paged", "carol@example.com").await;
+        join(&mut bob, &db_pool, created).await;
+
+        let request = Request::builder(Operation::Create, Target::Messages)
+            .conversations(vec![Conversation{
+                id: created,
+                ..Default::default()
+            }])
+            .messages((0..7).map(|i| text(&format!("Message {}", i))).collect::<Vec<Message>>())
+            .build();
+        request.handle(&mut alice, &db_pool).await.unwrap();
+        let single: Vec<Option<i32>> = read_messages(&mut alice, &db_pool, created).await.into_iter().map(|m| m.id).collect();
+
+        // The inbox merges the newest messages from all of a user's conversations, and only theirs
+        let latest = send(&mut alice, &db_pool, paged, "Latest").await;
+
+        let inbox = |limit: i64, before_id: Option<i32>| Request::builder(Operation::Read, Target::Messages)
+            .limit(limit)
+            .before_id(before_id)
+            .build();
+        let response = inbox(10, None).handle(&mut alice, &db_pool).await.unwrap();
+        let merged: Vec<(Option<i32>, Option<i32>)> = response.messages.unwrap().into_iter().map(|m| (m.id, m.conversation)).collect();
+        let mut expected = vec![(latest, paged)];
+        expected.extend(single.iter().map(|id| (*id, created)));
+        assert_eq!(merged, expected);
+        assert_eq!(response.has_more, Some(false));
+
+        let response = inbox(10, None).handle(&mut bob, &db_pool).await.unwrap();
+        let messages = response.messages.unwrap();
+        assert_eq!(messages.len(), 7);
+        assert!(messages.iter().all(|m| m.conversation == created));
+
+        // Older pages continue from the last message of the one before
+        let response = inbox(3, None).handle(&mut alice, &db_pool).await.unwrap();
+        assert_eq!(response.has_more, Some(true));
+        assert_eq!(response.next_id, expected[2].0);
+        let response = inbox(3, response.next_id).handle(&mut alice, &db_pool).await.unwrap();
+        let ids: Vec<Option<i32>> = response.messages.unwrap().into_iter().map(|m| m.id).collect();
+        assert_eq!(ids, expected[3..6].iter().map(|(id, _)| *id).collect::<Vec<Option<i32>>>());
+    }
+
+    #[async_std::test]
+    #[ignore]
+    async fn test_compress_message_data() {
+        let (_turn, db_pool) = scratch_database().await;
+        register(&db_pool, &["alice@example.com", "bob@example.com"]).await;
+        let mut alice = log_in(&db_pool, "alice@example.com").await;
+        let paged = start_conversation(&mut alice, &db_pool, "Compressed", "bob@example.com").await;
+
+        // Large data is stored compressed when that's turned on, and read back as it was sent
+        let large = "hello world ".repeat(1000).into_bytes();
+        let request = Request::builder(Operation::Create, Target::Messages)
+            .conversations(vec![Conversation{
+                id: paged,
+                ..Default::default()
+            }])
+            .messages(vec![Message{
+                data: Some(large.clone()),
+                ..text("")
+            }])
+            .message_config(MessageConfig{
+                compression_threshold: Some(4096),
+                ..MessageConfig::default()
+            })
+            .build();
+        let compressed = request.handle(&mut alice, &db_pool).await.unwrap().messages.unwrap()[0].id;
+
+        let (size, data_encoding): (i32, Option<String>) = sqlx::query_as("SELECT octet_length(data), data_encoding FROM messages WHERE id = $1")
+            .bind(compressed)
+            .fetch_one(&db_pool)
+            .await
+            .unwrap();
+        assert!((size as usize) < large.len());
+        assert_eq!(data_encoding.as_deref(), Some("zstd"));
+
+        let response = Request::builder(Operation::Read, Target::Messages)
+            .messages(vec![Message{
+                id: compressed,
+                ..Default::default()
+            }])
+            .build()
+            .handle(&mut alice, &db_pool)
+            .await
+            .unwrap();
+        assert_eq!(response.messages.unwrap()[0].data, Some(large));
+
+        // Compressed messages are previewed from what was stored with them, rather than being read back in full
+        let response = Request::builder(Operation::Read, Target::Conversations).build().handle(&mut alice, &db_pool).await.unwrap();
+        let preview = response.conversations.unwrap().into_iter().find(|c| c.id == paged).unwrap().last_preview;
+        assert_eq!(preview, Some("hello world ".repeat(9)[..100].to_string()));
+    }
+
+    #[async_std::test]
+    #[ignore]
+    async fn test_chunked_upload() {
+        let (_turn, db_pool) = scratch_database().await;
+        register(&db_pool, &["alice@example.com", "bob@example.com"]).await;
+        let mut alice = log_in(&db_pool, "alice@example.com").await;
+        let paged = start_conversation(&mut alice, &db_pool, "Uploads", "bob@example.com").await;
+
+        // Attachments can be uploaded in chunks, resumed from wherever the server got to, and are only kept if they match
+        let upload = Request::builder(Operation::Create, Target::Uploads)
+            .uploads(vec![Upload{
+                conversation: paged,
+                media_type: Some(b"text/plain".to_vec()),
+                size: Some(10),
+                ..Default::default()
+            }])
+            .build()
+            .handle(&mut alice, &db_pool)
+            .await
+            .unwrap()
+            .uploads
+            .unwrap()[0]
+            .id;
+        let chunk = |index: i32, data: &[u8]| Request::builder(Operation::Update, Target::Uploads)
+            .uploads(vec![Upload{
+                id: upload,
+                index: Some(index),
+                data: Some(data.to_vec()),
+                ..Default::default()
+            }])
+            .build();
+        let finish = |sha256: Vec<u8>| Request::builder(Operation::Create, Target::Attachments)
+            .attachments(vec![Attachment{
+                upload,
+                sha256: Some(sha256),
+                ..Default::default()
+            }])
+            .build();
+
+        chunk(0, b"hello").handle(&mut alice, &db_pool).await.unwrap();
+        let error = chunk(0, b"hello").handle(&mut alice, &db_pool).await.unwrap_err();
+        assert_eq!(error.to_string(), "Expected chunk 1 of upload");
+
+        let response = Request::builder(Operation::Read, Target::Uploads)
+            .uploads(vec![Upload{
+                id: upload,
+                ..Default::default()
+            }])
+            .build()
+            .handle(&mut alice, &db_pool)
+            .await
+            .unwrap();
+        let progress = &response.uploads.unwrap()[0];
+        assert_eq!((progress.received, progress.index), (Some(5), Some(1)));
+
+        chunk(1, b"world").handle(&mut alice, &db_pool).await.unwrap();
+        let error = finish(Sha256::digest(b"hello").to_vec()).handle(&mut alice, &db_pool).await.unwrap_err();
+        assert_eq!(error.to_string(), "Invalid 'sha256' field for 'attachment'");
+
+        let attachment = finish(Sha256::digest(b"helloworld").to_vec()).handle(&mut alice, &db_pool).await.unwrap().attachments.unwrap()[0].id;
+        let response = Request::builder(Operation::Read, Target::Attachments)
+            .attachments(vec![Attachment{
+                id: attachment,
+                ..Default::default()
+            }])
+            .build()
+            .handle(&mut alice, &db_pool)
+            .await
+            .unwrap();
+        assert_eq!(response.attachments.unwrap()[0].data.as_deref(), Some(&b"helloworld"[..]));
+
+        let chunks: (i64,) = sqlx::query_as("SELECT COUNT(*) FROM upload_chunks")
+            .fetch_one(&db_pool)
+            .await
+            .unwrap();
+        assert_eq!(chunks, (0,));
+    }
+
+    #[async_std::test]
+    #[ignore]
+    async fn test_message_sequence() {
+        let (_turn, db_pool) = scratch_database().await;
+        register(&db_pool, &["alice@example.com", "carol@example.com"]).await;
+        let mut alice = log_in(&db_pool, "alice@example.com").await;
+        let synced = start_conversation(&mut alice, &db_pool, "Synced", "carol@example.com").await;
+
+        // A large batch is stored in request order, and only what's actually stored takes up sequence numbers
+        let synced_message = |key: String| Message{
+            idempotency_key: Some(key.clone()),
+            ..text(&key)
+        };
+        let sync = |messages: Vec<Message>| Request::builder(Operation::Create, Target::Messages)
+            .conversations(vec![Conversation{
+                id: synced,
+                ..Default::default()
+            }])
+            .messages(messages)
+            .build();
+        sync(vec![synced_message(String::from("sync-0"))]).handle(&mut alice, &db_pool).await.unwrap();
+
+        // The first message was already stored, one is rejected and one is repeated
+        let mut messages: Vec<Message> = (0..500).map(|i| synced_message(format!("sync-{}", i))).collect();
+        messages.insert(250, Message{
+            signature: None,
+            ..synced_message(String::from("unsigned"))
+        });
+        messages.push(synced_message(String::from("sync-1")));
+        let response = sync(messages).handle(&mut alice, &db_pool).await.unwrap();
+        let results = response.messages.unwrap();
+        assert_eq!(results.len(), 502);
+        assert_eq!(results[250].status, Some(STATUS_INVALID_INPUT));
+        assert_eq!(response.already_stored, Some(vec![String::from("sync-0"), String::from("sync-1")]));
+
+        let stored: HashMap<String, (i32, i32)> = sqlx::query_as("SELECT idempotency_key, id, seq FROM messages WHERE conversation = $1")
+            .bind(synced)
+            .fetch_all(&db_pool)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|(key, id, seq): (String, i32, i32)| (key, (id, seq)))
+            .collect();
+        assert_eq!(stored.len(), 500);
+
+        for result in results.iter().filter(|m| m.status == Some(STATUS_SUCCESS)) {
+            let key = result.idempotency_key.clone().unwrap();
+            assert_eq!((result.id.unwrap(), result.seq.unwrap()), stored[&key]);
+        }
+
+        let seqs: Vec<i32> = results.iter().take(501).filter_map(|m| m.seq).collect();
+        assert_eq!(seqs, (1..=500).collect::<Vec<i32>>());
+        assert_eq!(results[501].seq, Some(2));
+
+        let last_seq: i32 = sqlx::query_scalar("SELECT last_seq FROM conversations WHERE id = $1")
+            .bind(synced)
+            .fetch_one(&db_pool)
+            .await
+            .unwrap();
+        assert_eq!(last_seq, 500);
+    }
+
+    #[async_std::test]
+    #[ignore]
+    async fn test_connection() {
+        let (_turn, db_pool) = scratch_database().await;
+        register(&db_pool, &["alice@example.com", "bob@example.com"]).await;
+
+        // One connection can send any number of requests, staying logged in between them
+        let (address, server) = serve_one(&db_pool).await;
+        let mut stream = TcpStream::connect(address).await.unwrap();
+
+        let response = exchange(&mut stream, json!({
+            "function": "VERIFY USERS",
+            "users": [{"email": "alice@example.com", "password": "correct horse"}],
+        })).await;
+        assert_eq!(response["status"], 1);
+
+        let response = exchange(&mut stream, json!({
+            "function": "CREATE CONVERSATIONS",
+            "users": [{"email": "bob@example.com"}],
+            "conversations": [{"name": "Sequential"}],
+        })).await;
+        assert_eq!(response["status"], 1);
+        let conversation = response["conversations"][0]["id"].as_i64().unwrap();
+
+        // A malformed request is answered with an error, without closing the connection
+        stream.write_all(&framing::encode(b"{not json")).await.unwrap();
+        let response = read_frame(&mut stream).await;
+        assert_eq!(response["status"], 4);
+
+        let response = exchange(&mut stream, json!({
+            "function": "CREATE MESSAGES",
+            "conversations": [{"id": conversation}],
+            "messages": [{
+                "data": base64::encode("Hello"),
+                "mediaType": base64::encode("text/plain"),
+                "timestamp": Utc::now().timestamp_millis(),
+                "signature": base64::encode([0; 64]),
+            }],
+        })).await;
+        assert_eq!(response["status"], 1);
+
+        let response = exchange(&mut stream, json!({
+            "function": "READ MESSAGES",
+            "conversations": [{"id": conversation}],
+        })).await;
+        assert_eq!(response["status"], 1);
+        assert_eq!(response["messages"][0]["data"], base64::encode("Hello"));
+        assert_eq!(response["messages"][0]["sender"], "alice@example.com");
+
+        // Users can be found by email, which gives their key but nothing secret
+        let response = exchange(&mut stream, json!({
+            "function": "READ USERS",
+            "users": [{"email": "bob@example.com"}],
+        })).await;
+        assert_eq!(response["status"], 1);
+        assert_eq!(response["users"][0]["email"], "bob@example.com");
+        assert!(response["users"][0]["id"].is_i64());
+        assert!(response["users"][0]["publicKey"].is_string());
+        assert!(["password", "pass", "salt"].iter().all(|field| response["users"][0].get(field).is_none()));
+
+        let response = exchange(&mut stream, json!({
+            "function": "READ USERS",
+            "users": [{"email": "nobody@example.com"}],
+        })).await;
+        assert_eq!(response["status"], 3);
+        assert_eq!(response["error"], "User does not exist");
+
+        stream.close().await.unwrap();
+        server.await.unwrap();
+    }
+
+    #[async_std::test]
+    #[ignore]
+    async fn test_push() {
+        let (_turn, db_pool) = scratch_database().await;
+        register(&db_pool, &["alice@example.com", "bob@example.com"]).await;
+        let mut alice = log_in(&db_pool, "alice@example.com").await;
+        let conversation = start_conversation(&mut alice, &db_pool, "Pushed", "bob@example.com").await;
+
+        // Members connected elsewhere are pushed new messages without asking for them
+        let listen_pool = db_pool.clone();
+        task::spawn(async move { push::listen(listen_pool).await.unwrap() });
+
+        let (address, server) = serve_one(&db_pool).await;
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        let (bob_address, bob_server) = serve_one(&db_pool).await;
+        let mut bob_stream = TcpStream::connect(bob_address).await.unwrap();
+
+        for (stream, email) in vec![(&mut stream, "alice@example.com"), (&mut bob_stream, "bob@example.com")] {
+            let response = exchange(stream, json!({
+                "function": "VERIFY USERS",
+                "users": [{"email": email, "password": "correct horse"}],
+            })).await;
+            assert_eq!(response["status"], 1);
+        }
+
+        // Messages sent before the listener has started aren't pushed, so it's given time to
+        task::sleep(std::time::Duration::from_millis(500)).await;
+
+        let response = exchange(&mut stream, json!({
+            "function": "CREATE MESSAGES",
+            "conversations": [{"id": conversation}],
+            "messages": [{
+                "data": base64::encode("Pushed"),
+                "mediaType": base64::encode("text/plain"),
+                "timestamp": Utc::now().timestamp_millis(),
+                "signature": base64::encode([0; 64]),
+            }],
+        })).await;
+        assert_eq!(response["status"], 1);
+        let pushed_id = response["messages"][0]["id"].clone();
+
+        let pushed = async_std::future::timeout(std::time::Duration::from_secs(5), read_frame(&mut bob_stream))
+            .await
+            .unwrap();
+        assert_eq!(pushed["event"], "message");
+        assert_eq!(pushed["conversation"], conversation.unwrap());
+        assert_eq!(pushed["messages"][0]["id"], pushed_id);
+        assert_eq!(pushed["messages"][0]["data"], base64::encode("Pushed"));
+        assert_eq!(pushed["messages"][0]["sender"], "alice@example.com");
+
+        // The sender only gets the response, not a push of their own message
+        let response = exchange(&mut stream, json!({"function": "READ UNREAD"})).await;
+        assert_eq!(response["status"], 1);
+        assert!(response.get("event").is_none());
+
+        bob_stream.close().await.unwrap();
+        bob_server.await.unwrap();
+
+        stream.close().await.unwrap();
+        server.await.unwrap();
+    }
+
+    #[async_std::test]
+    #[ignore]
+    async fn test_constraint_errors() {
+        let (_turn, db_pool) = scratch_database().await;
+        register(&db_pool, &["alice@example.com"]).await;
+
+        // Broken constraints are described to clients without giving away any SQL
+        let duplicate = sqlx::query("INSERT INTO users (email, public_key, pass, salt) VALUES ('alice@example.com', '', '', '')")
+            .execute(&db_pool)
+            .await
+            .unwrap_err();
+        let response = Response::from_error(&duplicate);
+        assert_eq!(response.status, STATUS_CONFLICT);
+        assert_eq!(response.error.as_deref(), Some("Email already registered"));
+        assert!(!response.to_json().contains("users_email_key"));
+
+        let orphan = sqlx::query("INSERT INTO participants (identity, conversation) VALUES (1, 0)")
+            .execute(&db_pool)
+            .await
+            .unwrap_err();
+        let response = Response::from_error(&orphan);
+        assert_eq!(response.status, STATUS_NOT_FOUND);
+        assert_eq!(response.error.as_deref(), Some("Conversation does not exist"));
+
+        let invalid = sqlx::query("SELECT missing FROM users")
+            .execute(&db_pool)
+            .await
+            .unwrap_err();
+        let response = Response::from_error(&invalid);
+        assert_eq!(response.status, STATUS_FAILURE);
+        assert_eq!(response.error.as_deref(), Some("Internal error"));
+    }
+
+    #[async_std::test]
+    #[ignore]
+    async fn test_conversation_order() {
+        let (_turn, db_pool) = scratch_database().await;
+        register(&db_pool, &["dave@example.com", "erin@example.com"]).await;
+        let mut dave = log_in(&db_pool, "dave@example.com").await;
+
+        // Conversations are listed by their latest message, with those that have none last, and each page carries on
+        // from the one before
+        let first = start_conversation(&mut dave, &db_pool, "First", "erin@example.com").await;
+        let second = start_conversation(&mut dave, &db_pool, "Second", "erin@example.com").await;
+        let quiet = start_conversation(&mut dave, &db_pool, "Quiet", "erin@example.com").await;
+        send(&mut dave, &db_pool, second, "Earlier").await;
+        send(&mut dave, &db_pool, first, "Later").await;
+
+        let page = |cursor: Option<Cursor>| Request::builder(Operation::Read, Target::Conversations)
+            .limit(2)
+            .cursor(cursor)
+            .build();
+        let response = page(None).handle(&mut dave, &db_pool).await.unwrap();
+        let ids: Vec<Option<i32>> = response.conversations.unwrap().into_iter().map(|c| c.id).collect();
+        assert_eq!(ids, vec![first, second]);
+        assert_eq!(response.has_more, Some(true));
+
+        let response = page(response.cursor).handle(&mut dave, &db_pool).await.unwrap();
+        let ids: Vec<Option<i32>> = response.conversations.unwrap().into_iter().map(|c| c.id).collect();
+        assert_eq!(ids, vec![quiet]);
+        assert_eq!(response.has_more, Some(false));
+    }
+
+    #[async_std::test]
+    #[ignore]
+    async fn test_idempotency_keys() {
+        let (_turn, db_pool) = scratch_database().await;
+        register(&db_pool, &["dave@example.com", "erin@example.com"]).await;
+        let mut dave = log_in(&db_pool, "dave@example.com").await;
+        let quiet = start_conversation(&mut dave, &db_pool, "Quiet", "erin@example.com").await;
+        let empty = start_conversation(&mut dave, &db_pool, "Empty", "erin@example.com").await;
+
+        // A message resent with the same idempotency key is only stored once
+        let keyed = |conversation: Option<i32>, key: &str| Request::builder(Operation::Create, Target::Messages)
+            .conversations(vec![Conversation{
+                id: conversation,
+                ..Default::default()
+            }])
+            .messages(vec![Message{
+                idempotency_key: Some(String::from(key)),
+                ..text("Sent twice")
+            }])
+            .build();
+        keyed(quiet, "retry-1").handle(&mut dave, &db_pool).await.unwrap();
+        keyed(quiet, "retry-1").handle(&mut dave, &db_pool).await.unwrap();
+
+        let rows: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM messages WHERE conversation = $1 AND idempotency_key = 'retry-1'")
+            .bind(quiet)
+            .fetch_one(&db_pool)
+            .await
+            .unwrap();
+        assert_eq!(rows, 1);
+
+        // A resent message gets the id it was first stored with, and the response says it was already stored
+        let original = keyed(quiet, "retry-1").handle(&mut dave, &db_pool).await.unwrap();
+        let json: serde_json::Value = serde_json::from_str(&original.to_json()).unwrap();
+        assert_eq!(json["alreadyStored"], json!(["retry-1"]));
+        assert_eq!(original.messages.unwrap()[0].status, Some(STATUS_SUCCESS));
+
+        let first_try = keyed(empty, "retry-2").handle(&mut dave, &db_pool).await.unwrap();
+        let second_try = keyed(empty, "retry-2").handle(&mut dave, &db_pool).await.unwrap();
+        assert_eq!(first_try.already_stored, Some(Vec::new()));
+        assert_eq!(second_try.already_stored, Some(vec![String::from("retry-2")]));
+        assert_eq!(first_try.messages.unwrap()[0].id, second_try.messages.unwrap()[0].id);
+
+        let rows: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM messages WHERE conversation = $1")
+            .bind(empty)
+            .fetch_one(&db_pool)
+            .await
+            .unwrap();
+        assert_eq!(rows, 1);
+    }
+
+    #[async_std::test]
+    #[ignore]
+    async fn test_out_of_order_timestamps() {
+        let (_turn, db_pool) = scratch_database().await;
+        register(&db_pool, &["dave@example.com", "erin@example.com"]).await;
+        let mut dave = log_in(&db_pool, "dave@example.com").await;
+        let second = start_conversation(&mut dave, &db_pool, "Second", "erin@example.com").await;
+        send(&mut dave, &db_pool, second, "Earlier").await;
+
+        // Messages are read in the order they were stored, whatever their senders' clocks said
+        let now = Utc::now().timestamp_millis();
+        let timestamps = vec![now, now - 60000, now - 30000];
+        let request = Request::builder(Operation::Create, Target::Messages)
+            .conversations(vec![Conversation{
+                id: second,
+                ..Default::default()
+            }])
+            .messages(timestamps.iter().map(|&timestamp| Message{
+                timestamp: Some(timestamp),
+                ..text("Out of order")
+            }).collect::<Vec<Message>>())
+            .build();
+        let sent: Vec<Option<i32>> = request.handle(&mut dave, &db_pool).await.unwrap().messages.unwrap().into_iter().map(|m| m.id).collect();
+
+        let read: Vec<(Option<i32>, Option<i32>, Option<i64>)> = read_messages(&mut dave, &db_pool, second).await
+            .into_iter()
+            .take(3)
+            .map(|m| (m.id, m.seq, m.timestamp))
+            .collect();
+        let seqs: Vec<Option<i32>> = read.iter().map(|(_, seq, _)| *seq).collect();
+        assert_eq!(read.iter().map(|(id, _, _)| *id).collect::<Vec<Option<i32>>>(), sent.into_iter().rev().collect::<Vec<Option<i32>>>());
+        assert_eq!(seqs, vec![Some(4), Some(3), Some(2)]);
+        assert_eq!(read.iter().map(|(_, _, timestamp)| *timestamp).collect::<Vec<Option<i64>>>(), timestamps.into_iter().rev().map(Some).collect::<Vec<Option<i64>>>());
+    }
+
+    #[async_std::test]
+    #[ignore]
+    async fn test_read_pointers() {
+        let (_turn, db_pool) = scratch_database().await;
+        register(&db_pool, &["dave@example.com", "erin@example.com"]).await;
+        let mut dave = log_in(&db_pool, "dave@example.com").await;
+        let mut erin = log_in(&db_pool, "erin@example.com").await;
+
+        let first = start_conversation(&mut dave, &db_pool, "First", "erin@example.com").await;
+        let second = start_conversation(&mut dave, &db_pool, "Second", "erin@example.com").await;
+        let elsewhere = send(&mut dave, &db_pool, first, "Elsewhere").await;
+        let older = send(&mut dave, &db_pool, second, "Older").await;
+        let newer = send(&mut dave, &db_pool, second, "Newer").await;
+        join(&mut erin, &db_pool, second).await;
+
+        // Members move their own read pointer forward only, and can see everyone else's
+        let read_up_to = |message_id: Option<i32>| Request::builder(Operation::Update, Target::Conversations)
+            .conversations(vec![Conversation{
+                id: second,
+                last_read_message_id: message_id,
+                ..Default::default()
+            }])
+            .build();
+        let response = read_up_to(newer).handle(&mut erin, &db_pool).await.unwrap();
+        assert_eq!(response.affected, Some(1));
+
+        let error = read_up_to(older).handle(&mut erin, &db_pool).await.unwrap_err();
+        assert_eq!(error.to_string(), "Read pointer can't move backward");
+
+        let error = read_up_to(elsewhere).handle(&mut erin, &db_pool).await.unwrap_err();
+        assert_eq!(error.to_string(), "Invalid 'last_read_message_id' field for 'conversation'");
+
+        let request = Request::builder(Operation::Read, Target::Users)
+            .conversations(vec![Conversation{
+                id: second,
+                ..Default::default()
+            }])
+            .build();
+        let pointers: HashMap<String, Option<i32>> = request.handle(&mut dave, &db_pool).await.unwrap().users.unwrap()
+            .into_iter()
+            .map(|u| (u.email.unwrap(), u.last_read_message_id))
+            .collect();
+        assert_eq!(pointers["erin@example.com"], newer);
+        assert_eq!(pointers["dave@example.com"], None);
+    }
+
+    #[async_std::test]
+    #[ignore]
+    async fn test_conversation_unread_counts() {
+        let (_turn, db_pool) = scratch_database().await;
+        register(&db_pool, &["dave@example.com", "erin@example.com"]).await;
+        let mut dave = log_in(&db_pool, "dave@example.com").await;
+        let mut erin = log_in(&db_pool, "erin@example.com").await;
+
+        let first = start_conversation(&mut dave, &db_pool, "First", "erin@example.com").await;
+        let second = start_conversation(&mut dave, &db_pool, "Second", "erin@example.com").await;
+        send(&mut dave, &db_pool, first, "Later").await;
+        let read = send(&mut dave, &db_pool, second, "Read").await;
+        send(&mut dave, &db_pool, second, "Unread").await;
+        join(&mut erin, &db_pool, first).await;
+        join(&mut erin, &db_pool, second).await;
+
+        let request = Request::builder(Operation::Update, Target::Conversations)
+            .conversations(vec![Conversation{
+                id: second,
+                last_read_message_id: read,
+                ..Default::default()
+            }])
+            .build();
+        request.handle(&mut erin, &db_pool).await.unwrap();
+
+        // Conversations list how many messages from others are past the user's read pointer, counting all of them
+        // until the pointer is first set
+        let unread_counts = |response: Response| response.conversations.unwrap()
+            .into_iter()
+            .map(|c| (c.id, c.unread_count))
+            .collect::<Vec<(Option<i32>, Option<i64>)>>();
+        let response = Request::builder(Operation::Read, Target::Conversations).build().handle(&mut erin, &db_pool).await.unwrap();
+        assert_eq!(unread_counts(response), vec![(second, Some(1)), (first, Some(1))]);
+
+        let response = Request::builder(Operation::Read, Target::Conversations).build().handle(&mut dave, &db_pool).await.unwrap();
+        assert!(unread_counts(response).iter().all(|(_, count)| *count == Some(0)));
+    }
+
+    #[async_std::test]
+    #[ignore]
+    async fn test_conversation_previews() {
+        let (_turn, db_pool) = scratch_database().await;
+        register(&db_pool, &["dave@example.com", "erin@example.com"]).await;
+        let mut dave = log_in(&db_pool, "dave@example.com").await;
+
+        let first = start_conversation(&mut dave, &db_pool, "First", "erin@example.com").await;
+        let quiet = start_conversation(&mut dave, &db_pool, "Quiet", "erin@example.com").await;
+        let second = start_conversation(&mut dave, &db_pool, "Second", "erin@example.com").await;
+        let empty = start_conversation(&mut dave, &db_pool, "Empty", "erin@example.com").await;
+        send(&mut dave, &db_pool, first, "Later").await;
+        send(&mut dave, &db_pool, quiet, "Sent twice").await;
+        send(&mut dave, &db_pool, second, "Out of order").await;
+
+        // Each conversation shows its latest message, and those without any come last with nothing to show
+        let response = Request::builder(Operation::Read, Target::Conversations).build().handle(&mut dave, &db_pool).await.unwrap();
+        let latest: Vec<(Option<i32>, Option<String>, Option<Vec<u8>>, Option<String>, bool)> = response.conversations.unwrap()
+            .into_iter()
+            .map(|c| (c.id, c.last_sender, c.last_media_type, c.last_preview, c.timestamp.is_some()))
+            .collect();
+        let shown = |id: Option<i32>, preview: &str| (id, Some(String::from("dave@example.com")), Some(b"text/plain".to_vec()), Some(String::from(preview)), true);
+        assert_eq!(latest, vec![
+            shown(second, "Out of order"),
+            shown(quiet, "Sent twice"),
+            shown(first, "Later"),
+            (empty, None, None, None, false),
+        ]);
+
+        // Previews are cut to the configured length, each conversation showing its own latest message
+        let request = Request::builder(Operation::Read, Target::Conversations)
+            .message_config(MessageConfig{
+                preview_length: 5,
+                ..MessageConfig::default()
+            })
+            .build();
+        let previews: Vec<(Option<i32>, Option<String>)> = request.handle(&mut dave, &db_pool).await.unwrap().conversations.unwrap()
+            .into_iter()
+            .map(|c| (c.id, c.last_preview))
+            .collect();
+        assert_eq!(previews, vec![
+            (second, Some(String::from("Out o"))),
+            (quiet, Some(String::from("Sent "))),
+            (first, Some(String::from("Later"))),
+            (empty, None),
+        ]);
+    }
+
+    #[async_std::test]
+    #[ignore]
+    async fn test_affected_counts() {
+        let (_turn, db_pool) = scratch_database().await;
+        register(&db_pool, &["dave@example.com", "erin@example.com"]).await;
+        let mut dave = log_in(&db_pool, "dave@example.com").await;
+        let mut erin = log_in(&db_pool, "erin@example.com").await;
+        let first = start_conversation(&mut dave, &db_pool, "First", "erin@example.com").await;
+        join(&mut erin, &db_pool, first).await;
+
+        // Updates and deletes say how many rows they changed, and one that matches nothing is reported as missing
+        let request = Request::builder(Operation::Update, Target::Conversations)
+            .conversations(vec![Conversation{
+                id: first,
+                name: Some(String::from("Renamed")),
+                ..Default::default()
+            }])
+            .build();
+        let response = request.handle(&mut dave, &db_pool).await.unwrap();
+        assert_eq!(response.affected, Some(1));
+
+        let reaction = |message: Option<i32>| vec![Reaction{
+            message,
+            emoji: Some(String::from("👍")),
+            sender: None,
+        }];
+        let latest_in_first = send(&mut dave, &db_pool, first, "React to this").await;
+        Request::builder(Operation::Create, Target::Reactions)
+            .reactions(reaction(latest_in_first))
+            .build()
+            .handle(&mut erin, &db_pool)
+            .await
+            .unwrap();
+
+        let unreact = || Request::builder(Operation::Delete, Target::Reactions)
+            .reactions(reaction(latest_in_first))
+            .build();
+        let response = unreact().handle(&mut erin, &db_pool).await.unwrap();
+        assert_eq!(response.affected, Some(1));
+
+        let error = unreact().handle(&mut erin, &db_pool).await.unwrap_err();
+        let response = Response::from_error(error.as_ref());
+        assert_eq!(response.status, STATUS_NOT_FOUND);
+        assert_eq!(response.error.as_deref(), Some("Reaction does not exist"));
+    }
+
+    #[async_std::test]
+    #[ignore]
+    async fn test_read_message_by_id() {
+        let (_turn, db_pool) = scratch_database().await;
+        register(&db_pool, &["alice@example.com", "dave@example.com", "erin@example.com"]).await;
+        let mut alice = log_in(&db_pool, "alice@example.com").await;
+        let mut dave = log_in(&db_pool, "dave@example.com").await;
+        let mut erin = log_in(&db_pool, "erin@example.com").await;
+        let first = start_conversation(&mut dave, &db_pool, "First", "erin@example.com").await;
+        join(&mut erin, &db_pool, first).await;
+        let latest_in_first = send(&mut dave, &db_pool, first, "React to this").await;
+
+        // A single message can be read by its id, but only by members of its conversation
+        let by_id = |id: Option<i32>| Request::builder(Operation::Read, Target::Messages)
+            .messages(vec![Message{
+                id,
+                ..Default::default()
+            }])
+            .build();
+        let message = by_id(latest_in_first).handle(&mut erin, &db_pool).await.unwrap().messages.unwrap().remove(0);
+        assert_eq!(message.id, latest_in_first);
+        assert_eq!(message.conversation, first);
+        assert_eq!(message.data, Some(b"React to this".to_vec()));
+        assert_eq!(message.sender.as_deref(), Some("dave@example.com"));
+
+        for (login, id) in vec![(&mut alice, latest_in_first), (&mut erin, Some(i32::MAX))] {
+            let error = by_id(id).handle(login, &db_pool).await.unwrap_err();
+            let response = Response::from_error(error.as_ref());
+            assert_eq!(response.status, STATUS_NOT_FOUND);
+            assert_eq!(response.error.as_deref(), Some("Message does not exist"));
+        }
+    }
+
+    #[async_std::test]
+    #[ignore]
+    async fn test_retention() {
+        let (_turn, db_pool) = scratch_database().await;
+        register(&db_pool, &["dave@example.com", "erin@example.com"]).await;
+        let mut dave = log_in(&db_pool, "dave@example.com").await;
+        let quiet = start_conversation(&mut dave, &db_pool, "Quiet", "erin@example.com").await;
+        send(&mut dave, &db_pool, quiet, "Sent twice").await;
+
+        // Messages past their conversation's retention window are hidden straight away, and purged once the clock
+        // reaches the end of the window
+        let request = Request::builder(Operation::Update, Target::Conversations)
+            .conversations(vec![Conversation{
+                id: quiet,
+                retention_seconds: Some(3600),
+                ..Default::default()
+            }])
+            .build();
+        request.handle(&mut dave, &db_pool).await.unwrap();
+
+        let sent_at = Utc::now() - Duration::hours(2);
+        sqlx::query("UPDATE messages SET created_at = $2 WHERE conversation = $1")
+            .bind(quiet)
+            .bind(sent_at)
+            .execute(&db_pool)
+            .await
+            .unwrap();
+
+        assert!(read_messages(&mut dave, &db_pool, quiet).await.is_empty());
+
+        assert_eq!(delete_expired_messages(sent_at + Duration::minutes(59), &db_pool).await.unwrap(), 0);
+        assert_eq!(delete_expired_messages(sent_at + Duration::hours(1) + Duration::seconds(1), &db_pool).await.unwrap(), 1);
+
+        let remaining: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM messages WHERE conversation = $1")
+            .bind(quiet)
+            .fetch_one(&db_pool)
+            .await
+            .unwrap();
+        assert_eq!(remaining, 0);
+    }
+
+    #[async_std::test]
+    #[ignore]
+    async fn test_read_conversations_by_role() {
+        let (_turn, db_pool) = scratch_database().await;
+        register(&db_pool, &["dave@example.com", "erin@example.com"]).await;
+        let mut dave = log_in(&db_pool, "dave@example.com").await;
+        let mut erin = log_in(&db_pool, "erin@example.com").await;
+        let first = start_conversation(&mut dave, &db_pool, "First", "erin@example.com").await;
+        let second = start_conversation(&mut dave, &db_pool, "Second", "erin@example.com").await;
+
+        // Conversations can be listed by the role the user holds in them
+        let hosted = start_conversation(&mut erin, &db_pool, "Hosted", "dave@example.com").await;
+        join(&mut dave, &db_pool, hosted).await;
+
+        let with_role = |role: Option<&str>| Request::builder(Operation::Read, Target::Conversations)
+            .role(role.map(String::from))
+            .build();
+        let ids = |response: Response| {
+            let mut ids: Vec<Option<i32>> = response.conversations.unwrap().into_iter().map(|c| c.id).collect();
+            ids.sort();
+            ids
+        };
+
+        let response = with_role(Some("admin")).handle(&mut dave, &db_pool).await.unwrap();
+        assert_eq!(ids(response), vec![first, second]);
+
+        let response = with_role(Some("member")).handle(&mut dave, &db_pool).await.unwrap();
+        assert_eq!(ids(response), vec![hosted]);
+
+        let response = with_role(None).handle(&mut dave, &db_pool).await.unwrap();
+        assert_eq!(ids(response).len(), 3);
+    }
+
+    #[test]
+    fn test_db_router() {
+        // Only the routing decisions are checked
+        let primary = lazy_pool();
+        let replica = lazy_pool();
+        let router = DbRouter::new(primary, Some(replica), std::time::Duration::from_secs(5));
+        let now = std::time::Instant::now();
+
+        let to_replica = |is_read: bool, last_write: Option<std::time::Instant>| {
+            !std::ptr::eq(router.pool_for(is_read, last_write, now), router.primary())
+        };
+
+        assert!(to_replica(true, None));
+        assert!(!to_replica(false, None));
+
+        // Reads go to the primary for a while after a write, so they see it
+        assert!(!to_replica(true, Some(now - std::time::Duration::from_secs(1))));
+        assert!(to_replica(true, Some(now - std::time::Duration::from_secs(5))));
+        assert!(!to_replica(false, Some(now - std::time::Duration::from_secs(60))));
+
+        // Without a replica, everything goes to the primary
+        let single = DbRouter::single(lazy_pool());
+        assert!(!single.reads_from_replica(None, now));
+        assert!(std::ptr::eq(single.pool_for(true, None, now), single.primary()));
+    }
+
+    #[async_std::test]
+    #[ignore]
+    async fn test_pool_busy() {
+        // Needs a database to connect to, but doesn't change anything in it
+        let config = DatabaseConfig{
+            url: test_database_url(),
+            max_connections: 1,
+            min_connections: 0,
+            acquire_timeout: std::time::Duration::from_millis(200),
+            max_lifetime: std::time::Duration::from_secs(60),
+            idle_timeout: std::time::Duration::from_secs(60),
+            replica_url: None,
+            read_your_writes: std::time::Duration::from_secs(0),
+        };
+        let db_pool = init_db(&config).await.unwrap();
+
+        // With the only connection in use elsewhere, a request gives up and reports that the server is busy
+        let held = db_pool.acquire().await.unwrap();
+        let mut login = Login::new();
+        login.authenticate(String::from("alice@example.com")).unwrap();
+
+        let started = std::time::Instant::now();
+        let request = Request::builder(Operation::Read, Target::Conversations).build();
+        let error = request.handle(&mut login, &db_pool).await.unwrap_err();
+        let response = Response::from_error(error.as_ref());
+        assert_eq!(response.status, STATUS_BUSY);
+        assert_eq!(response.error.as_deref(), Some("Server busy"));
+        assert!(started.elapsed() < std::time::Duration::from_secs(5));
+
+        drop(held);
+    }
+}
@@ -0,0 +1,253 @@
+use crate::api::ScrubbedValue;
+use crate::settings;
+
+use std::convert::TryFrom;
+use std::error::Error;
+use std::io::Read;
+use std::io::Error as ioErr;
+use std::io::ErrorKind as ioErrKind;
+use base64;
+use serde_json::{Map, Value, json};
+use zeroize::Zeroizing;
+use zstd;
+
+/// The smallest response (in bytes) that is compressed, unless configured otherwise
+const DEFAULT_COMPRESSION_THRESHOLD: usize = 1024;
+/// The zstd compression level used for responses and stored message data
+const COMPRESSION_LEVEL: i32 = 3;
+
+/// A way of compressing requests and responses on the wire
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Encoding {
+    Zstd,
+}
+
+impl Encoding {
+    /// Look up an encoding by name, returning None for encodings the server doesn't know
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "zstd" => Some(Encoding::Zstd),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Encoding::Zstd => "zstd",
+        }
+    }
+}
+
+/// Unwrap a request, decompressing it if it was sent as `{"encoding": ..., "data": ...}`
+///
+/// Returns the request without its `acceptEncoding` list, along with the known encodings in that list. A compressed
+/// request is held to the same `max_size` as a frame once it's decompressed, so it can't grow past what the connection
+/// would accept uncompressed. Every copy of the request is scrubbed of passwords once it's done with.
+pub fn decode_request(data: &str, max_size: usize) -> Result<(Zeroizing<String>, Vec<Encoding>), Box<dyn Error>> {
+    let parse = |data: &[u8]| -> Result<ScrubbedValue, Box<dyn Error>> {
+        let request: Map<String, Value> = serde_json::from_slice(data)
+            .map_err(|e| ioErr::new(ioErrKind::InvalidInput, format!("Malformed request: {}", e)))?;
+        Ok(ScrubbedValue(Value::Object(request)))
+    };
+    let mut request = parse(data.as_bytes())?;
+
+    // Compressed requests are wrapped in an envelope naming their encoding
+    if let Some(name) = request.0.get("encoding").and_then(|e| e.as_str()) {
+        let encoding = Encoding::from_name(name)
+            .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, format!("Unknown encoding '{}'", name)))?;
+        let body = request.0.get("data")
+            .and_then(|d| d.as_str())
+            .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'data' field for encoded request"))?;
+        let body = Zeroizing::new(decompress(encoding, &base64::decode(body)?, max_size)?);
+
+        request = parse(&body)?;
+    }
+
+    // Encodings the server doesn't know are left out, so responses fall back to identity
+    let accepted = match request.0.as_object_mut().and_then(|r| r.remove("acceptEncoding")) {
+        Some(Value::Array(names)) => names
+            .iter()
+            .filter_map(|n| n.as_str())
+            .filter_map(Encoding::from_name)
+            .collect(),
+        _ => Vec::new(),
+    };
+
+    Ok((Zeroizing::new(request.0.to_string()), accepted))
+}
+
+/// Compress a response with the first accepted encoding if it's over the size threshold
+pub fn encode_response(response: String, accepted: &[Encoding]) -> Result<String, Box<dyn Error>> {
+    let threshold = settings::get_value("COMPRESSION_THRESHOLD", DEFAULT_COMPRESSION_THRESHOLD)?;
+    encode_with_threshold(response, accepted, threshold)
+}
+
+fn encode_with_threshold(response: String, accepted: &[Encoding], threshold: usize) -> Result<String, Box<dyn Error>> {
+    let encoding = match accepted.first() {
+        Some(e) if response.len() >= threshold => e,
+        _ => return Ok(response),
+    };
+
+    let body = compress(*encoding, response.as_bytes())?;
+
+    Ok(json!({
+        "encoding": encoding.name(),
+        "data": base64::encode(body),
+    }).to_string())
+}
+
+/// Compress a message's `data` before it's stored, if stored data is compressed and it's at least `threshold` bytes
+///
+/// Returns the data to store along with the name of the encoding it was compressed with, if it was.
+pub fn compress_data(data: Vec<u8>, threshold: Option<usize>) -> Result<(Vec<u8>, Option<&'static str>), Box<dyn Error>> {
+    match threshold {
+        Some(threshold) if data.len() >= threshold => (),
+        _ => return Ok((data, None)),
+    }
+
+    let compressed = compress(Encoding::Zstd, &data)?;
+
+    // Data that doesn't shrink (e.g. encrypted or already compressed media) is stored as it was sent
+    match compressed.len() < data.len() {
+        true => Ok((compressed, Some(Encoding::Zstd.name()))),
+        false => Ok((data, None)),
+    }
+}
+
+/// Undo `compress_data`, given the encoding a message's `data` was stored with (if any)
+pub fn decompress_data(data: Vec<u8>, encoding: Option<&str>) -> Result<Vec<u8>, Box<dyn Error>> {
+    let encoding = match encoding {
+        Some(name) => Encoding::from_name(name)
+            .ok_or_else(|| ioErr::new(ioErrKind::InvalidData, format!("Unknown encoding '{}' for stored data", name)))?,
+        None => return Ok(data),
+    };
+
+    // The server compressed this itself, so it isn't held to a frame's size limit
+    match encoding {
+        Encoding::Zstd => Ok(zstd::decode_all(&data[..])
+            .map_err(|_| ioErr::new(ioErrKind::InvalidData, "Stored data could not be decompressed"))?),
+    }
+}
+
+fn compress(encoding: Encoding, data: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    match encoding {
+        Encoding::Zstd => Ok(zstd::encode_all(data, COMPRESSION_LEVEL)?),
+    }
+}
+
+/// Decompress data, giving up as soon as it's larger than `max_size` bytes rather than decompressing the rest
+fn decompress(encoding: Encoding, data: &[u8], max_size: usize) -> Result<Vec<u8>, Box<dyn Error>> {
+    let invalid = |_| ioErr::new(ioErrKind::InvalidInput, "Invalid compressed request");
+    let mut body = Vec::new();
+
+    // Read one byte past the limit to tell whether there's more
+    match encoding {
+        Encoding::Zstd => zstd::stream::read::Decoder::new(data)
+            .map_err(invalid)?
+            .take(u64::try_from(max_size)?.saturating_add(1))
+            .read_to_end(&mut body)
+            .map_err(invalid)?,
+    };
+
+    match body.len() > max_size {
+        true => Err(Box::new(ioErr::new(ioErrKind::InvalidInput, format!("Request too large (more than {} bytes decompressed)", max_size)))),
+        false => Ok(body),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::encoding::*;
+
+    #[test]
+    fn test_encode_response() {
+        let small = json!({"status": 1}).to_string();
+        let large = json!({"status": 1, "messages": vec!["hello world"; 200]}).to_string();
+
+        // Small responses and clients that don't accept compression get plain JSON
+        assert_eq!(encode_with_threshold(small.clone(), &[Encoding::Zstd], 1024).unwrap(), small);
+        assert_eq!(encode_with_threshold(large.clone(), &[], 1024).unwrap(), large);
+
+        // Large responses are compressed and can be decompressed back
+        let encoded = encode_with_threshold(large.clone(), &[Encoding::Zstd], 1024).unwrap();
+        assert!(encoded.len() < large.len());
+
+        let envelope: Value = serde_json::from_str(&encoded).unwrap();
+        assert_eq!(envelope["encoding"], "zstd");
+
+        let body = base64::decode(envelope["data"].as_str().unwrap()).unwrap();
+        assert_eq!(decompress(Encoding::Zstd, &body, large.len()).unwrap(), large.into_bytes());
+    }
+
+    #[test]
+    fn test_compress_data() {
+        let large = b"hello world ".repeat(1000);
+
+        // Compressible data over the threshold is compressed, and decompresses back to what was sent
+        let (stored, encoding) = compress_data(large.clone(), Some(4096)).unwrap();
+        assert_eq!(encoding, Some("zstd"));
+        assert!(stored.len() < large.len());
+        assert_eq!(decompress_data(stored, encoding).unwrap(), large);
+
+        // Small data is stored as sent
+        let (stored, encoding) = compress_data(b"hello".to_vec(), Some(4096)).unwrap();
+        assert_eq!((stored.as_slice(), encoding), (&b"hello"[..], None));
+        assert_eq!(decompress_data(stored, encoding).unwrap(), b"hello");
+
+        // So is data that compression wouldn't shrink
+        let mut random = vec![0; 8192];
+        getrandom::getrandom(&mut random).unwrap();
+        let (stored, encoding) = compress_data(random.clone(), Some(4096)).unwrap();
+        assert_eq!(encoding, None);
+        assert_eq!(stored, random);
+
+        // Nothing is compressed unless stored data is compressed at all
+        let (stored, encoding) = compress_data(large.clone(), None).unwrap();
+        assert_eq!((stored, encoding), (large, None));
+
+        // Data stored in an unknown encoding, or corrupted, can't be read
+        assert!(decompress_data(b"data".to_vec(), Some("br")).is_err());
+        assert!(decompress_data(b"data".to_vec(), Some("zstd")).is_err());
+    }
+
+    #[test]
+    fn test_decode_request() {
+        let plain = json!({"function": "READ MESSAGES", "acceptEncoding": ["br", "zstd"]}).to_string();
+        let (request, accepted) = decode_request(&plain, 1024).unwrap();
+
+        // Unknown encodings are ignored and the list is removed from the request
+        assert_eq!(accepted, vec![Encoding::Zstd]);
+        assert_eq!(*request, json!({"function": "READ MESSAGES"}).to_string());
+
+        let (_, accepted) = decode_request(&json!({"function": "READ MESSAGES", "acceptEncoding": ["br"]}).to_string(), 1024).unwrap();
+        assert!(accepted.is_empty());
+
+        // Compressed requests are unwrapped
+        let inner = json!({"function": "READ MESSAGES", "limit": 10}).to_string();
+        let compressed = json!({
+            "encoding": "zstd",
+            "data": base64::encode(compress(Encoding::Zstd, inner.as_bytes()).unwrap()),
+        }).to_string();
+        let (request, _) = decode_request(&compressed, 1024).unwrap();
+        assert_eq!(serde_json::from_str::<Value>(&request).unwrap(), serde_json::from_str::<Value>(&inner).unwrap());
+
+        // Requests in an unknown encoding can't be read
+        let unknown = json!({"encoding": "br", "data": "ZGF0YQ=="}).to_string();
+        assert!(decode_request(&unknown, 1024).is_err());
+        let corrupt = json!({"encoding": "zstd", "data": "ZGF0YQ=="}).to_string();
+        assert!(decode_request(&corrupt, 1024).is_err());
+
+        // A request that's small compressed but too large once decompressed is refused without decompressing it all
+        let bomb = json!({
+            "encoding": "zstd",
+            "data": base64::encode(compress(Encoding::Zstd, &vec![b' '; 16 * 1024 * 1024]).unwrap()),
+        }).to_string();
+        assert!(bomb.len() < 64 * 1024);
+        let error = decode_request(&bomb, 1024).err().unwrap();
+        assert_eq!(error.to_string(), "Request too large (more than 1024 bytes decompressed)");
+
+        let (request, _) = decode_request(&compressed, inner.len()).unwrap();
+        assert_eq!(serde_json::from_str::<Value>(&request).unwrap(), serde_json::from_str::<Value>(&inner).unwrap());
+        assert!(decode_request(&compressed, inner.len() - 1).is_err());
+    }
+}
@@ -0,0 +1,218 @@
+use std::convert::TryFrom;
+use std::error::Error;
+use std::io::Error as ioErr;
+use std::io::ErrorKind as ioErrKind;
+use zeroize::Zeroize;
+
+/// The number of bytes in a frame's length prefix
+const PREFIX_LENGTH: usize = 4;
+
+/// Put a payload in a frame: its length as a big-endian u32, followed by the payload itself
+pub fn encode(payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(PREFIX_LENGTH + payload.len());
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// Collects bytes read from a connection and splits them back into frames
+///
+/// A read can hold part of a frame or several frames at once, so bytes are kept until a whole frame has arrived.
+pub struct FrameDecoder {
+    buffer: Vec<u8>,
+    max_size: usize,
+}
+
+impl FrameDecoder {
+    /// Create a decoder that rejects frames with payloads larger than `max_size` bytes
+    pub fn new(max_size: usize) -> Self {
+        FrameDecoder{
+            buffer: Vec::new(),
+            max_size,
+        }
+    }
+
+    /// Add bytes read from the connection
+    pub fn extend(&mut self, data: &[u8]) {
+        self.buffer.extend_from_slice(data);
+    }
+
+    /// Take the next whole frame's payload, if one has arrived
+    ///
+    /// A frame that's too large is rejected as soon as its length is known, without waiting for its payload.
+    pub fn next_frame(&mut self) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        if self.buffer.len() < PREFIX_LENGTH {
+            return Ok(None);
+        }
+
+        let mut prefix = [0; PREFIX_LENGTH];
+        prefix.copy_from_slice(&self.buffer[..PREFIX_LENGTH]);
+        let length = usize::try_from(u32::from_be_bytes(prefix))?;
+
+        if length > self.max_size {
+            return Err(Box::new(ioErr::new(ioErrKind::InvalidInput, format!("Request too large ({} bytes, at most {})", length, self.max_size))));
+        }
+
+        let end = PREFIX_LENGTH + length;
+        if self.buffer.len() < end {
+            return Ok(None);
+        }
+
+        let payload = self.buffer[PREFIX_LENGTH..end].to_vec();
+
+        // Move the rest to the front, scrubbing what's left behind since requests may contain passwords
+        let remaining = self.buffer.len() - end;
+        self.buffer.copy_within(end.., 0);
+        self.buffer[remaining..].zeroize();
+        self.buffer.truncate(remaining);
+
+        Ok(Some(payload))
+    }
+}
+
+impl Drop for FrameDecoder {
+    fn drop(&mut self) {
+        self.buffer.zeroize();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::framing::{encode, FrameDecoder, PREFIX_LENGTH};
+    use std::io;
+
+    #[test]
+    fn test_encode() {
+        assert_eq!(encode(b"{}"), vec![0, 0, 0, 2, b'{', b'}']);
+        assert_eq!(encode(b""), vec![0, 0, 0, 0]);
+        assert_eq!(&encode(&[7; 300])[..4], &[0, 0, 1, 44]);
+    }
+
+    #[test]
+    fn test_whole_frame() {
+        let mut decoder = FrameDecoder::new(1024);
+        decoder.extend(&encode(br#"{"function": "READ USERS"}"#));
+
+        assert_eq!(decoder.next_frame().unwrap(), Some(br#"{"function": "READ USERS"}"#.to_vec()));
+        assert_eq!(decoder.next_frame().unwrap(), None);
+    }
+
+    #[test]
+    fn test_partial_reads() {
+        let frame = encode(br#"{"function": "READ USERS"}"#);
+
+        // Split at every point, including within the length prefix
+        for split in 0..frame.len() {
+            let mut decoder = FrameDecoder::new(1024);
+
+            decoder.extend(&frame[..split]);
+            assert_eq!(decoder.next_frame().unwrap(), None, "split at {}", split);
+
+            decoder.extend(&frame[split..]);
+            assert_eq!(decoder.next_frame().unwrap(), Some(frame[4..].to_vec()), "split at {}", split);
+        }
+
+        // A byte at a time
+        let mut decoder = FrameDecoder::new(1024);
+        for (i, byte) in frame.iter().enumerate() {
+            decoder.extend(&[*byte]);
+
+            match i == frame.len() - 1 {
+                true => assert_eq!(decoder.next_frame().unwrap(), Some(frame[4..].to_vec())),
+                false => assert_eq!(decoder.next_frame().unwrap(), None),
+            }
+        }
+    }
+
+    #[test]
+    fn test_multiple_frames() {
+        let payloads: Vec<&[u8]> = vec![b"first", b"", b"third frame", b"{\n}"];
+        let mut data: Vec<u8> = payloads.iter().flat_map(|p| encode(p)).collect();
+
+        // The start of another frame arrives along with the rest
+        data.extend_from_slice(&encode(b"unfinished")[..6]);
+
+        let mut decoder = FrameDecoder::new(1024);
+        decoder.extend(&data);
+
+        for payload in payloads {
+            assert_eq!(decoder.next_frame().unwrap(), Some(payload.to_vec()));
+        }
+        assert_eq!(decoder.next_frame().unwrap(), None);
+
+        decoder.extend(&encode(b"unfinished")[6..]);
+        assert_eq!(decoder.next_frame().unwrap(), Some(b"unfinished".to_vec()));
+    }
+
+    #[test]
+    fn test_zero_length_frame() {
+        let mut decoder = FrameDecoder::new(0);
+        decoder.extend(&[0, 0, 0, 0]);
+
+        assert_eq!(decoder.next_frame().unwrap(), Some(Vec::new()));
+        assert_eq!(decoder.next_frame().unwrap(), None);
+    }
+
+    #[test]
+    fn test_oversized_frame() {
+        // Frames at the limit are fine
+        let mut decoder = FrameDecoder::new(8);
+        decoder.extend(&encode(&[1; 8]));
+        assert_eq!(decoder.next_frame().unwrap(), Some(vec![1; 8]));
+
+        // Larger ones are rejected from their length alone
+        decoder.extend(&[0, 0, 0, 9]);
+        let error = decoder.next_frame().unwrap_err();
+        assert_eq!(error.downcast_ref::<io::Error>().unwrap().kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(error.to_string(), "Request too large (9 bytes, at most 8)");
+
+        let mut decoder = FrameDecoder::new(1024);
+        decoder.extend(&[0xff, 0xff, 0xff, 0xff]);
+        assert!(decoder.next_frame().is_err());
+    }
+
+    #[test]
+    fn test_adversarial_prefixes() {
+        let max_size = 4096;
+
+        // Lengths around the limit and at the extremes, along with some arbitrary ones
+        let mut lengths = vec![0, 1, max_size - 1, max_size, max_size + 1, u32::MAX as usize - 1, u32::MAX as usize];
+        let mut seed: u32 = 0x9e37_79b9;
+        for _ in 0..64 {
+            seed = seed.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            lengths.push(seed as usize);
+        }
+
+        for length in lengths {
+            let mut decoder = FrameDecoder::new(max_size);
+            let prefix = (length as u32).to_be_bytes();
+
+            // Send the length, then (up to a point) the payload it promises, a read at a time
+            let payload = vec![b'x'; length.min(2 * max_size)];
+            let reads = std::iter::once(&prefix[..]).chain(payload.chunks(1024));
+
+            let mut frames = Vec::new();
+            let mut rejected = false;
+            for read in reads {
+                decoder.extend(read);
+
+                match decoder.next_frame() {
+                    Ok(Some(frame)) => frames.push(frame),
+                    Ok(None) => (),
+                    Err(_) => rejected = true,
+                }
+
+                // Nothing more is read once a frame is rejected, and what's kept never outgrows one frame and a read
+                if rejected {
+                    break;
+                }
+                assert!(decoder.buffer.len() <= PREFIX_LENGTH + max_size + 1024, "length {}", length);
+            }
+
+            match length > max_size {
+                true => assert!(rejected && frames.is_empty(), "length {}", length),
+                false => assert_eq!(frames, vec![vec![b'x'; length]], "length {}", length),
+            }
+        }
+    }
+}
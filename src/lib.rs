@@ -1,118 +1,969 @@
 pub mod database;
+pub mod push;
+pub mod settings;
 pub mod tls;
+pub mod unix;
 mod api;
+mod audit;
 mod auth;
-mod settings;
+mod encoding;
+mod storage;
+mod framing;
 
-use crate::api::request::{Request, Operation, Target};
+use crate::api::request::Request;
 use crate::api::response::Response;
+use crate::database::Database;
+use crate::encoding::Encoding;
+use crate::framing::FrameDecoder;
+use crate::settings::{Heartbeat, ListenerConfig, Timeouts};
 //use crate::auth;
 
 use std::error::Error;
+use std::future::Future;
 use std::io::Error as ioErr;
 use std::io::ErrorKind as ioErrKind;
 use std::str;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::time;
+use async_std::channel;
+use async_std::future;
 use async_std::task;
 use async_std::prelude::*;
-use async_std::net::TcpStream;
+use async_std::io::{Read, Write};
+use async_std::net::{TcpListener, TcpStream};
+use async_std::stream::Stream;
+use async_std::os::unix::net::UnixStream;
 use async_tls::TlsAcceptor;
-use log::{error, info};
-use sqlx::PgPool;
+use log::{error, info, warn};
+use zeroize::Zeroize;
 
-/// Handle incoming connections from clients
-pub async fn handle_connection(stream: TcpStream, acceptor: &TlsAcceptor, db_pool: &PgPool) -> Result<(), Box<dyn Error>> {
-    let mut buffer = [0; 1024];
-    let interval = time::Duration::from_millis(500);
+/// What the server sends to check that an idle connection is still there
+const PING: &[u8] = br#"{"function": "PING"}"#;
+/// What the server sends before closing a connection that's been idle for too long
+const IDLE_NOTICE: &[u8] = br#"{"function": "CLOSE", "reason": "Idle timeout"}"#;
+
+/// A cap on the number of connections handled at once
+#[derive(Clone)]
+pub struct ConnectionLimit {
+    max: usize,
+    active: Arc<AtomicUsize>,
+}
+
+/// A connection's place within a `ConnectionLimit`, which is given up when dropped
+pub struct ConnectionPermit {
+    active: Arc<AtomicUsize>,
+}
+
+impl ConnectionLimit {
+    pub fn new(max: usize) -> Self {
+        ConnectionLimit{
+            max,
+            active: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Take a place for a new connection, unless the limit has been reached
+    pub fn try_acquire(&self) -> Option<ConnectionPermit> {
+        self.active
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| match n < self.max {
+                true => Some(n + 1),
+                false => None,
+            })
+            .ok()
+            .map(|_| ConnectionPermit{
+                active: Arc::clone(&self.active),
+            })
+    }
+
+    /// Get the number of connections currently being handled
+    pub fn active(&self) -> usize {
+        self.active.load(Ordering::Acquire)
+    }
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        self.active.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Bind each configured listener, leaving out (and logging) any that can't be bound
+///
+/// The server can still start as long as one of them binds, e.g. when `[::]` already covers IPv4 as well as IPv6.
+pub async fn bind_listeners(listeners: &[ListenerConfig]) -> Vec<(TcpListener, ListenerConfig)> {
+    let mut bound = Vec::new();
+
+    for config in listeners {
+        match TcpListener::bind(config.address).await {
+            Ok(listener) => {
+                // The port is only known once bound if 0 was asked for
+                let address = listener.local_addr().unwrap_or(config.address);
+                info!("Listening on {} ({})", address, if config.tls { "TLS" } else { "plaintext" });
+                bound.push((listener, *config));
+            },
+            Err(e) => warn!("Could not listen on {}: {}", config.address, e),
+        }
+    }
+
+    bound
+}
+
+/// Accept connections from a listener until it stops, handling each one in its own task
+///
+/// Connections from every listener share the same limit and database pools.
+pub async fn accept_connections(listener: TcpListener, acceptor: Option<TlsAcceptor>, timeouts: Timeouts, heartbeat: Option<Heartbeat>, max_frame_size: usize, limit: ConnectionLimit, db: Database) -> std::io::Result<()> {
+    let peer = |stream: &TcpStream| stream.peer_addr().map(|address| address.to_string());
+
+    serve(listener.incoming(), limit, peer, move |stream: TcpStream| {
+        let acceptor = acceptor.clone();
+        let db = db.clone();
+
+        async move { handle_connection(stream, acceptor.as_ref(), timeouts, heartbeat, max_frame_size, &db).await }
+    }).await;
+
+    Ok(())
+}
+
+/// Accept connections until `incoming` runs out, handing each one within the limit to `handle` in its own task
+///
+/// A connection that fails before it can be handled (e.g. because the client hung up straight away) is logged and
+/// skipped, so it doesn't stop the server from accepting anyone else.
+pub async fn serve<S, F, H>(mut incoming: impl Stream<Item = std::io::Result<S>> + Unpin, limit: ConnectionLimit, peer: impl Fn(&S) -> std::io::Result<String>, handle: F)
+where
+    F: Fn(S) -> H,
+    H: Future<Output = Result<(), Box<dyn Error>>> + Send + 'static,
+{
+    while let Some(stream) = incoming.next().await {
+        let (stream, peer) = match stream.and_then(|s| peer(&s).map(|p| (s, p))) {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                error!("Could not accept connection: {}", e);
+                continue;
+            },
+        };
+
+        // Turn away connections beyond the limit by closing them straight away
+        let permit = match limit.try_acquire() {
+            Some(p) => p,
+            None => {
+                warn!("Rejected connection from {} (limit of {} reached)", peer, limit.max);
+                continue;
+            },
+        };
+
+        info!("Successful connection from {}", peer);
+        let handled = handle(stream);
+
+        task::spawn(async move {
+            if let Err(e) = handled.await {
+                error!("{}", e);
+            }
+
+            drop(permit);
+        });
+    }
+}
+
+/// Handle incoming connections from clients, performing a TLS handshake if an acceptor is provided
+///
+/// Connections that don't finish the handshake, send nothing or don't authenticate within `timeouts` of connecting are
+/// closed, as are connections that go idle for too long, don't answer a heartbeat in time or send a request larger than
+/// `max_frame_size` bytes.
+pub async fn handle_connection(stream: TcpStream, acceptor: Option<&TlsAcceptor>, timeouts: Timeouts, heartbeat: Option<Heartbeat>, max_frame_size: usize, db: &Database) -> Result<(), Box<dyn Error>> {
     let address = stream.peer_addr()?;
-    let mut user = auth::Login{
-        email: None,
-        is_authenticated: false,
-    };
 
-    // Perform TLS handshake
-    let handshake = acceptor.accept(stream);
-    let mut stream = handshake.await?;
-    info!("Handshake successful");
+    match acceptor {
+        Some(acceptor) => {
+            // Perform TLS handshake, giving up on just this connection if it fails or the client stays silent (the
+            // handshake is the first thing it has to send)
+            let handshake = future::timeout(timeouts.first_byte, acceptor.accept(stream));
+            let stream = handshake.await
+                .map_err(|_| ioErr::new(ioErrKind::TimedOut, format!("TLS handshake with {} did not finish in time", address)))?
+                .map_err(|e| ioErr::new(e.kind(), format!("TLS handshake with {} failed: {}", address, e)))?;
+            info!("Handshake successful");
+
+            handle_stream(stream, timeouts, heartbeat, max_frame_size, db).await?;
+        },
+        None => handle_stream(stream, timeouts, heartbeat, max_frame_size, db).await?,
+    }
+
+    info!("Disconnected {}", address);
+    Ok(())
+}
+
+/// Handle a connection from a local client over the Unix socket
+///
+/// These use the same protocol as TCP connections, without TLS since they never leave the host.
+pub async fn handle_unix_connection(stream: UnixStream, timeouts: Timeouts, heartbeat: Option<Heartbeat>, max_frame_size: usize, db: &Database) -> Result<(), Box<dyn Error>> {
+    handle_stream(stream, timeouts, heartbeat, max_frame_size, db).await?;
+
+    info!("Disconnected local client");
+    Ok(())
+}
+
+/// Handle requests sent over an established connection
+///
+/// Requests and responses are each sent as a frame: a big-endian u32 length followed by that many bytes.
+async fn handle_stream<S: Read + Write + Unpin>(mut stream: S, timeouts: Timeouts, heartbeat: Option<Heartbeat>, max_frame_size: usize, db: &Database) -> Result<(), Box<dyn Error>> {
+    let mut buffer = [0; 1024];
+    let mut frames = FrameDecoder::new(max_frame_size);
+    let interval = time::Duration::from_millis(500);
+    let mut user = auth::Login::new();
+    let mut last_write = None;
+    let connected = time::Instant::now();
+    let first_byte_deadline = connected + timeouts.first_byte;
+    let auth_deadline = connected + timeouts.auth;
+    let mut received_any = false;
+    let mut last_frame = connected;
+    let mut pinged = false;
+    let mut subscription: Option<push::Subscription<'static>> = None;
 
     // Polling connection
     loop {
-        match stream.read(&mut buffer).await {
+        // Wait for the next heartbeat, or for an answer to the last one
+        let wait = heartbeat.map(|h| match pinged {
+            true => h.timeout,
+            false => h.interval,
+        });
+
+        // Reads also have to finish before whichever deadline the connection is still subject to comes first
+        let now = time::Instant::now();
+        let deadlines = [
+            Some(first_byte_deadline).filter(|_| !received_any),
+            Some(auth_deadline).filter(|_| !user.is_authenticated()),
+            timeouts.idle.map(|idle| last_frame + idle),
+        ];
+        let wait = deadlines.iter()
+            .flatten()
+            .map(|d| d.saturating_duration_since(now))
+            .chain(wait)
+            .min();
+
+        let event = match wait {
+            Some(wait) => match future::timeout(wait, next_event(&mut stream, &mut buffer, subscription.as_ref())).await {
+                Ok(event) => event,
+                Err(_) => {
+                    let now = time::Instant::now();
+
+                    if !received_any && now >= first_byte_deadline {
+                        return Err(Box::new(ioErr::new(ioErrKind::TimedOut, "Connection sent nothing in time")));
+                    }
+
+                    if !user.is_authenticated() && now >= auth_deadline {
+                        return Err(Box::new(ioErr::new(ioErrKind::TimedOut, "Connection did not authenticate in time")));
+                    }
+
+                    // Let the client know why it's being disconnected, in case it's still there
+                    if timeouts.idle.map_or(false, |idle| now >= last_frame + idle) {
+                        stream.write_all(&framing::encode(IDLE_NOTICE)).await?;
+                        stream.flush().await?;
+                        return Err(Box::new(ioErr::new(ioErrKind::TimedOut, "Connection was idle for too long")));
+                    }
+
+                    // Without heartbeats, there's nothing else to wake up for
+                    if heartbeat.is_none() {
+                        continue;
+                    }
+
+                    if pinged {
+                        return Err(Box::new(ioErr::new(ioErrKind::TimedOut, "Connection did not answer heartbeat in time")));
+                    }
+
+                    // Check that the client is still there
+                    stream.write_all(&framing::encode(PING)).await?;
+                    stream.flush().await?;
+                    pinged = true;
+                    continue;
+                },
+            },
+            None => next_event(&mut stream, &mut buffer, subscription.as_ref()).await,
+        };
+
+        // Pushes are passed straight on, and don't count as the client doing anything
+        let read = match event {
+            Event::Read(read) => read,
+            Event::Push(frame) => {
+                stream.write_all(&framing::encode(frame.as_bytes())).await?;
+                stream.flush().await?;
+                continue;
+            },
+        };
+
+        match read {
             Ok(0) => break,
             Ok(n) => {
-                let result = handle_request(&buffer[..n], &mut user, db_pool).await;
-                
-                if let Err(e) = &result {
-                    error!("{}", e);
+                // Anything the client sends shows it's still there
+                pinged = false;
+                received_any = true;
+
+                // Scrub what was read once it's been copied, since requests may contain passwords
+                frames.extend(&buffer[..n]);
+                buffer[..n].zeroize();
+
+                loop {
+                    // Answer an oversized request, then close the connection rather than read the rest of it
+                    let mut frame = match frames.next_frame() {
+                        Ok(Some(frame)) => frame,
+                        Ok(None) => break,
+                        Err(e) => {
+                            let response = format_response(Err(e));
+                            stream.write_all(&framing::encode(response.as_bytes())).await?;
+                            stream.flush().await?;
+                            return Err(Box::new(ioErr::new(ioErrKind::InvalidData, "Connection sent a request that was too large")));
+                        },
+                    };
+
+                    // Any complete frame counts as activity, including answers to heartbeats
+                    last_frame = time::Instant::now();
+
+                    // Empty frames and answers to heartbeats don't need a response
+                    if frame.is_empty() || is_pong(&frame) {
+                        continue;
+                    }
+
+                    // Streamed responses are written as they're produced
+                    let result = handle_request(&frame, max_frame_size, &mut user, db, &mut last_write, &mut stream).await;
+
+                    // Scrub the request, which may have contained a password
+                    frame.zeroize();
+
+                    if let Err(e) = &result {
+                        error!("{}", e);
+                    }
+
+                    let response = match result {
+                        Ok(None) => continue,
+                        Ok(Some(r)) => format_response(Ok(r)),
+                        Err(e) => format_response(Err(e)),
+                    };
+                    stream.write_all(&framing::encode(response.as_bytes())).await?;
+                    stream.flush().await?;
                 }
 
-                let response = format_response(result.ok());
-                task::block_on(stream.write_all(response.as_bytes()))?;
-                stream.flush();
+                // Pushes follow whoever is logged in, stopping on logout
+                let email = user.email().ok();
+
+                if subscription.as_ref().map(|s| s.email()) != email {
+                    subscription = email.map(|email| push::subscribe(email, user.connection()));
+                }
             },
-            Err(_) => task::sleep(interval).await,
+            // Reads that were only cut short can be retried, but anything else means the connection is gone
+            Err(e) if matches!(e.kind(), ioErrKind::Interrupted | ioErrKind::WouldBlock) => task::sleep(interval).await,
+            Err(e) => return Err(Box::new(e)),
         }
     }
 
-    info!("Disconnected {}", address);
     Ok(())
 }
 
-/// Handle a request from a client
-async fn handle_request(data: &[u8], user: &mut auth::Login, db_pool: &PgPool) -> Result<Response, Box<dyn Error>> {
+/// Something that happened on a connection while waiting for the client
+enum Event {
+    Read(std::io::Result<usize>),
+    Push(String),
+}
+
+/// Wait for the client to send something, or for new messages to be pushed to it
+async fn next_event<S: Read + Unpin>(stream: &mut S, buffer: &mut [u8], subscription: Option<&push::Subscription<'_>>) -> Event {
+    let read = async {
+        Event::Read(stream.read(buffer).await)
+    };
+
+    let pushed = async {
+        match subscription {
+            Some(s) => Event::Push(s.next().await),
+            None => future::pending().await,
+        }
+    };
+
+    // A read that loses is dropped before it takes anything, so nothing the client sent is lost
+    futures_lite::future::or(read, pushed).await
+}
+
+/// Check whether a client sent an answer to a heartbeat, which doesn't need a response
+fn is_pong(data: &[u8]) -> bool {
+    serde_json::from_slice::<serde_json::Value>(data)
+        .map(|v| v["function"] == "PONG")
+        .unwrap_or(false)
+}
+
+/// Handle a request (or a batch of requests) from a client, returning the response as JSON and the encodings the client
+/// accepts, or nothing if the request's responses were streamed to `stream` as they were produced
+///
+/// `last_write` is when the connection last sent a write (if it has), which keeps its reads on the primary database
+/// until a replica is likely to have caught up. Compressed requests can't be larger than `max_size` once decompressed.
+async fn handle_request<S: Write + Unpin>(data: &[u8], max_size: usize, user: &mut auth::Login, db: &Database, last_write: &mut Option<time::Instant>, stream: &mut S) -> Result<Option<(String, Vec<Encoding>)>, Box<dyn Error>> {
     // Prepare data
     let data = str::from_utf8(data)?;
-    let request = Request::from_json(data)?;
-
-    // Identify type of request
-    let response = match request.operation {
-        Operation::Verify => {
-            match request.target {
-                Target::Users => request.verify_users(user, db_pool).await?,
-                _ => return Err(Box::new(ioErr::new(ioErrKind::InvalidInput, "Invalid operation"))),
-            }
+
+    // Answer a batch with a response for each of its requests (batches aren't compressed)
+    if is_batch(data) {
+        let requests = Request::batch_from_json(data)?;
+        let is_write = requests.iter().any(Request::is_write);
+
+        let responses = match db {
+            Database::Postgres(router) => {
+                // A batch only goes to the replica if every request in it is a read
+                let db_pool = router.pool_for(requests.iter().all(Request::is_read), *last_write, time::Instant::now());
+                Request::handle_batch(requests, user, db_pool, router.primary()).await
+            },
+            #[cfg(feature = "sqlite")]
+            Database::Sqlite(db_pool) => Request::handle_sqlite_batch(requests, user, db_pool).await,
+        };
+
+        if is_write {
+            *last_write = Some(time::Instant::now());
         }
-        Operation::Create => {
-            match request.target {
-                Target::Conversations => request.create_conversations(user, db_pool).await?,
-                Target::Messages => request.create_messages(user, db_pool).await?,
-                Target::Users => request.create_users(db_pool).await?,
+
+        return Ok(Some((Response::batch_to_json(&responses), Vec::new())));
+    }
+
+    let (data, accepted) = encoding::decode_request(data, max_size)?;
+    let request = Request::from_json(&data)?;
+    let is_read = request.is_read();
+    let since_write = *last_write;
+
+    // Send each chunk of a streamed request as its own response, writing each one while the next is read (at most one
+    // chunk waits to be written, so a client that reads slowly slows the request down rather than filling memory)
+    if request.is_streamed() {
+        let (sender, chunks) = channel::bounded(1);
+
+        // The sender is dropped once the request is finished, which ends the writes
+        let handled = async move {
+            match db {
+                Database::Postgres(router) => {
+                    let db_pool = router.pool_for(is_read, since_write, time::Instant::now());
+                    request.handle_streamed(user, db_pool, router.primary(), &sender).await
+                },
+                #[cfg(feature = "sqlite")]
+                Database::Sqlite(db_pool) => request.handle_sqlite_streamed(user, db_pool, &sender).await,
             }
-        }
-        Operation::Read => {
-            match request.target {
-                Target::Conversations => request.read_conversations(user, db_pool).await?,
-                Target::Messages => request.read_messages(user, db_pool).await?,
-                Target::Users => request.read_users(user, db_pool).await?,
+        };
+
+        // The chunks are dropped if a write fails, which ends the request
+        let written = async move {
+            while let Ok(chunk) = chunks.recv().await {
+                let response = format_response(Ok((chunk.to_json(), accepted.clone())));
+                stream.write_all(&framing::encode(response.as_bytes())).await?;
             }
+            stream.flush().await
+        };
+
+        let (handled, written) = futures_lite::future::zip(handled, written).await;
+        written?;
+        handled?;
+        return Ok(None);
+    }
+
+    // Handle request
+    let is_write = request.is_write();
+    let response = match db {
+        Database::Postgres(router) => {
+            // Audit entries are always written to the primary, since a replica can't take them
+            let db_pool = router.pool_for(is_read, since_write, time::Instant::now());
+            request.handle_routed(user, db_pool, router.primary()).await
+        },
+        #[cfg(feature = "sqlite")]
+        Database::Sqlite(db_pool) => request.handle_sqlite(user, db_pool).await,
+    };
+
+    if is_write {
+        *last_write = Some(time::Instant::now());
+    }
+
+    Ok(Some((response?.to_json(), accepted)))
+}
+
+/// Check whether a client sent a batch of requests (a JSON array) rather than a single request
+fn is_batch(data: &str) -> bool {
+    data.trim_start().starts_with('[')
+}
+
+/// Format a response (compressing it if the client accepts that) or use a failure response if the request failed
+fn format_response(result: Result<(String, Vec<Encoding>), Box<dyn Error>>) -> String {
+    match result {
+        Ok((json, accepted)) => encoding::encode_response(json.clone(), &accepted).unwrap_or(json),
+        // If the request failed, use a failure response describing the error
+        Err(e) => Response::from_error(e.as_ref()).to_json(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{accept_connections, bind_listeners, framing, handle_connection, handle_stream, handle_unix_connection, is_batch, is_pong, serve, ConnectionLimit, PING};
+    use crate::database::{lazy_pool, test_database_url, Database, DbRouter};
+    use crate::settings::{ListenerConfig, TlsConfig, UnixSocketConfig};
+    use crate::tls::get_acceptor;
+    use crate::unix::UnixSocket;
+    use crate::settings::{Heartbeat, Timeouts};
+    use std::io;
+    use std::pin::Pin;
+    use std::sync::{Arc, Mutex};
+    use std::task::{Context, Poll};
+    use std::time::Duration;
+    use std::env;
+    use std::fs;
+    use async_std::io::{Read, Write};
+    use async_std::net::{TcpListener, TcpStream};
+    use async_std::os::unix::net::UnixStream;
+    use async_std::prelude::*;
+    use async_tls::TlsConnector;
+    use rustls::{Certificate, ClientConfig};
+    use serde_json::json;
+    use sqlx::PgPool;
+
+    /// A client that connects but never sends anything, keeping track of what it's sent
+    #[derive(Clone, Default)]
+    struct IdleStream {
+        received: Arc<Mutex<Vec<u8>>>,
+    }
+
+    impl Read for IdleStream {
+        fn poll_read(self: Pin<&mut Self>, _: &mut Context<'_>, _: &mut [u8]) -> Poll<io::Result<usize>> {
+            Poll::Pending
         }
-        Operation::Update => {
-            match request.target {
-                _ => return Err(Box::new(ioErr::new(ioErrKind::InvalidInput, "Invalid operation"))),
-            }
+    }
+
+    impl Write for IdleStream {
+        fn poll_write(self: Pin<&mut Self>, _: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+            self.received.lock().unwrap().extend_from_slice(buf);
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    /// A client that sends some bytes and then disconnects, keeping track of what it's sent
+    #[derive(Clone, Default)]
+    struct ScriptedStream {
+        incoming: Arc<Mutex<Vec<u8>>>,
+        received: Arc<Mutex<Vec<u8>>>,
+    }
+
+    impl Read for ScriptedStream {
+        fn poll_read(self: Pin<&mut Self>, _: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+            let mut incoming = self.incoming.lock().unwrap();
+            let n = incoming.len().min(buf.len());
+            buf[..n].copy_from_slice(&incoming[..n]);
+            incoming.drain(..n);
+            Poll::Ready(Ok(n))
+        }
+    }
+
+    impl Write for ScriptedStream {
+        fn poll_write(self: Pin<&mut Self>, _: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+            self.received.lock().unwrap().extend_from_slice(buf);
+            Poll::Ready(Ok(buf.len()))
         }
-        Operation::Delete => {
-            match request.target {
-                _ => return Err(Box::new(ioErr::new(ioErrKind::InvalidInput, "Invalid operation"))),
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[async_std::test]
+    async fn test_auth_timeout() {
+        let db = Database::from(DbRouter::single(lazy_pool()));
+
+        let result = handle_stream(IdleStream::default(), Timeouts{auth: Duration::from_millis(50), ..Timeouts::default()}, None, 1024, &db).await;
+        let error = result.err().unwrap();
+
+        assert_eq!(error.downcast_ref::<io::Error>().unwrap().kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[async_std::test]
+    async fn test_heartbeat_timeout() {
+        let db = Database::from(DbRouter::single(lazy_pool()));
+        let stream = IdleStream::default();
+        let heartbeat = Heartbeat{
+            interval: Duration::from_millis(50),
+            timeout: Duration::from_millis(50),
+        };
+
+        // The client is pinged well before it would have to authenticate, and is closed when it doesn't answer
+        let started = std::time::Instant::now();
+        let result = handle_stream(stream.clone(), Timeouts::default(), Some(heartbeat), 1024, &db).await;
+        let error = result.err().unwrap();
+
+        assert_eq!(error.downcast_ref::<io::Error>().unwrap().kind(), io::ErrorKind::TimedOut);
+        assert_eq!(error.to_string(), "Connection did not answer heartbeat in time");
+        assert!(started.elapsed() < Duration::from_secs(10));
+        assert_eq!(stream.received.lock().unwrap()[..], framing::encode(PING)[..]);
+    }
+
+    #[async_std::test]
+    async fn test_first_byte_timeout() {
+        let db = Database::from(DbRouter::single(lazy_pool()));
+        let timeouts = Timeouts{
+            first_byte: Duration::from_millis(50),
+            ..Timeouts::default()
+        };
+
+        // A client that connects and never sends anything is closed long before it would have to authenticate
+        let result = handle_stream(IdleStream::default(), timeouts, None, 1024, &db).await;
+        let error = result.err().unwrap();
+
+        assert_eq!(error.downcast_ref::<io::Error>().unwrap().kind(), io::ErrorKind::TimedOut);
+        assert_eq!(error.to_string(), "Connection sent nothing in time");
+    }
+
+    #[async_std::test]
+    async fn test_handshake_timeout() {
+        let db = Database::from(DbRouter::single(lazy_pool()));
+        let timeouts = Timeouts{
+            first_byte: Duration::from_millis(50),
+            ..Timeouts::default()
+        };
+
+        let cert = rcgen::generate_simple_self_signed(vec![String::from("localhost")]).unwrap();
+        let dir = env::temp_dir();
+        let tls = TlsConfig{
+            cert_path: dir.join("echo-handshake-cert.pem").to_string_lossy().into_owned(),
+            key_path: dir.join("echo-handshake-key.pem").to_string_lossy().into_owned(),
+        };
+        fs::write(&tls.cert_path, cert.serialize_pem().unwrap()).unwrap();
+        fs::write(&tls.key_path, cert.serialize_private_key_pem()).unwrap();
+        let acceptor = get_acceptor(&tls).await.unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = async_std::task::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            handle_connection(stream, Some(&acceptor), timeouts, None, 1024, &db).await
+        });
+
+        // A client that connects and never starts the handshake is closed like one that never sends a request
+        let _stream = TcpStream::connect(address).await.unwrap();
+        let error = server.await.unwrap_err();
+        assert_eq!(error.downcast_ref::<io::Error>().unwrap().kind(), io::ErrorKind::TimedOut);
+        assert!(error.to_string().contains("did not finish in time"));
+    }
+
+    #[async_std::test]
+    async fn test_idle_timeout() {
+        let db = Database::from(DbRouter::single(lazy_pool()));
+        let timeouts = Timeouts{
+            idle: Some(Duration::from_millis(200)),
+            ..Timeouts::default()
+        };
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = async_std::task::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            handle_connection(stream, None, timeouts, None, 1024, &db).await
+        });
+
+        // Each frame resets the timer, so a client that keeps answering outlives the idle period
+        let started = std::time::Instant::now();
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        for _ in 0..4 {
+            stream.write_all(&framing::encode(br#"{"function": "PONG"}"#)).await.unwrap();
+            async_std::task::sleep(Duration::from_millis(100)).await;
+        }
+
+        // Once the client pauses, it's told why before it's disconnected
+        let mut length = [0; 4];
+        stream.read_exact(&mut length).await.unwrap();
+        let mut buffer = vec![0; u32::from_be_bytes(length) as usize];
+        stream.read_exact(&mut buffer).await.unwrap();
+        let notice: serde_json::Value = serde_json::from_slice(&buffer).unwrap();
+        assert_eq!(notice["function"], "CLOSE");
+        assert_eq!(notice["reason"], "Idle timeout");
+        assert!(started.elapsed() >= Duration::from_millis(500));
+
+        let error = server.await.unwrap_err();
+        assert_eq!(error.to_string(), "Connection was idle for too long");
+        assert_eq!(stream.read(&mut length).await.unwrap(), 0);
+    }
+
+    #[async_std::test]
+    async fn test_frames() {
+        let db = Database::from(DbRouter::single(lazy_pool()));
+
+        // Heartbeat answers and empty frames get no response, while other requests are answered in their own frames
+        let mut incoming = framing::encode(br#"{"function": "PONG"}"#);
+        incoming.extend(framing::encode(b""));
+        incoming.extend(framing::encode(br#"{"function": "READ USERS"}"#));
+        let stream = ScriptedStream::default();
+        *stream.incoming.lock().unwrap() = incoming;
+
+        handle_stream(stream.clone(), Timeouts::default(), None, 1024, &db).await.unwrap();
+
+        let received = stream.received.lock().unwrap().clone();
+        let length = u32::from_be_bytes([received[0], received[1], received[2], received[3]]) as usize;
+        assert_eq!(received.len(), 4 + length);
+        let response: serde_json::Value = serde_json::from_slice(&received[4..]).unwrap();
+        assert_eq!(response["error"], "Not authenticated");
+
+        // An oversized request is answered with an error before the connection is closed
+        let mut incoming = framing::encode(&[b' '; 1025]);
+        incoming.extend(framing::encode(br#"{"function": "READ USERS"}"#));
+        let stream = ScriptedStream::default();
+        *stream.incoming.lock().unwrap() = incoming;
+
+        let error = handle_stream(stream.clone(), Timeouts::default(), None, 1024, &db).await.unwrap_err();
+        assert_eq!(error.to_string(), "Connection sent a request that was too large");
+
+        let received = stream.received.lock().unwrap().clone();
+        let response: serde_json::Value = serde_json::from_slice(&received[4..]).unwrap();
+        assert_eq!(response["status"], 4);
+        assert_eq!(response["error"], "Request too large (1025 bytes, at most 1024)");
+    }
+
+    #[async_std::test]
+    #[ignore]
+    async fn test_verify_over_tls() {
+        // Needs a database to check the login against, but doesn't change anything in it
+        let db = Database::from(DbRouter::single(PgPool::connect(&test_database_url()).await.unwrap()));
+
+        // Serve a single connection with a self-signed certificate
+        let cert = rcgen::generate_simple_self_signed(vec![String::from("localhost")]).unwrap();
+        let dir = env::temp_dir();
+        let tls = TlsConfig{
+            cert_path: dir.join("echo-connection-cert.pem").to_string_lossy().into_owned(),
+            key_path: dir.join("echo-connection-key.pem").to_string_lossy().into_owned(),
+        };
+        fs::write(&tls.cert_path, cert.serialize_pem().unwrap()).unwrap();
+        fs::write(&tls.key_path, cert.serialize_private_key_pem()).unwrap();
+        let acceptor = get_acceptor(&tls).await.unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = async_std::task::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            handle_connection(stream, Some(&acceptor), Timeouts::default(), None, 1024, &db).await
+        });
+
+        // Connect as a client that only trusts that certificate
+        let mut config = ClientConfig::new();
+        config.root_store.add(&Certificate(cert.serialize_der().unwrap())).unwrap();
+        let connector = TlsConnector::from(Arc::new(config));
+        let stream = TcpStream::connect(address).await.unwrap();
+        let mut stream = connector.connect("localhost", stream).await.unwrap();
+
+        let request = json!({
+            "function": "VERIFY USERS",
+            "users": [{"email": "nobody@example.com", "password": "correct horse"}],
+        });
+        stream.write_all(&framing::encode(request.to_string().as_bytes())).await.unwrap();
+
+        let mut length = [0; 4];
+        stream.read_exact(&mut length).await.unwrap();
+        let mut buffer = vec![0; u32::from_be_bytes(length) as usize];
+        stream.read_exact(&mut buffer).await.unwrap();
+        let response: serde_json::Value = serde_json::from_slice(&buffer).unwrap();
+        assert_eq!(response["status"], 2);
+        assert_eq!(response["error"], "Invalid password");
+
+        // Closing the connection ends the server's side cleanly
+        stream.close().await.unwrap();
+        server.await.unwrap();
+    }
+
+    #[async_std::test]
+    #[ignore]
+    async fn test_verify_over_unix_socket() {
+        // Needs a database to check the login against, but doesn't change anything in it
+        let db = Database::from(DbRouter::single(PgPool::connect(&test_database_url()).await.unwrap()));
+
+        // Serve a single connection over a socket in the temporary directory
+        let config = UnixSocketConfig{
+            path: env::temp_dir().join("echo-connection.sock").to_string_lossy().into_owned(),
+            mode: 0o600,
+        };
+        let socket = UnixSocket::bind(&config).await.unwrap();
+        let server = async_std::task::spawn(async move {
+            let (stream, _) = socket.listener().accept().await.unwrap();
+            handle_unix_connection(stream, Timeouts::default(), None, 1024, &db).await.unwrap();
+        });
+
+        let mut stream = UnixStream::connect(&config.path).await.unwrap();
+        let request = json!({
+            "function": "VERIFY USERS",
+            "users": [{"email": "nobody@example.com", "password": "correct horse"}],
+        });
+        stream.write_all(&framing::encode(request.to_string().as_bytes())).await.unwrap();
+
+        let mut length = [0; 4];
+        stream.read_exact(&mut length).await.unwrap();
+        let mut buffer = vec![0; u32::from_be_bytes(length) as usize];
+        stream.read_exact(&mut buffer).await.unwrap();
+        let response: serde_json::Value = serde_json::from_slice(&buffer).unwrap();
+        assert_eq!(response["status"], 2);
+        assert_eq!(response["error"], "Invalid password");
+
+        // Once the server is done with the socket, its file is removed
+        drop(stream);
+        server.await;
+        assert!(fs::symlink_metadata(&config.path).is_err());
+    }
+
+    /// Send a framed request and read the framed response
+    async fn exchange<S: Read + Write + Unpin>(stream: &mut S, request: &serde_json::Value) -> serde_json::Value {
+        stream.write_all(&framing::encode(request.to_string().as_bytes())).await.unwrap();
+
+        let mut length = [0; 4];
+        stream.read_exact(&mut length).await.unwrap();
+        let mut buffer = vec![0; u32::from_be_bytes(length) as usize];
+        stream.read_exact(&mut buffer).await.unwrap();
+        serde_json::from_slice(&buffer).unwrap()
+    }
+
+    #[async_std::test]
+    async fn test_multiple_listeners() {
+        let db = Database::from(DbRouter::single(lazy_pool()));
+        let limit = ConnectionLimit::new(4);
+
+        let cert = rcgen::generate_simple_self_signed(vec![String::from("localhost")]).unwrap();
+        let dir = env::temp_dir();
+        let tls = TlsConfig{
+            cert_path: dir.join("echo-listeners-cert.pem").to_string_lossy().into_owned(),
+            key_path: dir.join("echo-listeners-key.pem").to_string_lossy().into_owned(),
+        };
+        fs::write(&tls.cert_path, cert.serialize_pem().unwrap()).unwrap();
+        fs::write(&tls.key_path, cert.serialize_private_key_pem()).unwrap();
+        let acceptor = get_acceptor(&tls).await.unwrap();
+
+        // Two loopback ports, one with TLS and one without (and a smaller frame size limit), along with one that can't
+        // be bound
+        let listeners = bind_listeners(&[
+            ListenerConfig{
+                address: "127.0.0.1:0".parse().unwrap(),
+                tls: true,
+                max_frame_size: 1024,
+            },
+            ListenerConfig{
+                address: "127.0.0.1:0".parse().unwrap(),
+                tls: false,
+                max_frame_size: 64,
+            },
+            ListenerConfig{
+                address: "192.0.2.1:0".parse().unwrap(),
+                tls: false,
+                max_frame_size: 1024,
+            },
+        ]).await;
+        assert_eq!(listeners.len(), 2);
+
+        let mut addresses = Vec::new();
+        for (listener, config) in listeners {
+            addresses.push(listener.local_addr().unwrap());
+            let acceptor = match config.tls {
+                true => Some(acceptor.clone()),
+                false => None,
+            };
+            async_std::task::spawn(accept_connections(listener, acceptor, Timeouts::default(), None, config.max_frame_size, limit.clone(), db.clone()));
+        }
+        assert_ne!(addresses[0], addresses[1]);
+
+        let request = json!({"function": "VERIFY USERS"});
+
+        let mut config = ClientConfig::new();
+        config.root_store.add(&Certificate(cert.serialize_der().unwrap())).unwrap();
+        let stream = TcpStream::connect(addresses[0]).await.unwrap();
+        let mut encrypted = TlsConnector::from(Arc::new(config)).connect("localhost", stream).await.unwrap();
+        let response = exchange(&mut encrypted, &request).await;
+        assert_eq!(response["error"], "Missing 'users' list");
+
+        let mut plaintext = TcpStream::connect(addresses[1]).await.unwrap();
+        let response = exchange(&mut plaintext, &request).await;
+        assert_eq!(response["error"], "Missing 'users' list");
+
+        // Both connections count towards the same limit
+        assert_eq!(limit.active(), 2);
+
+        // Each listener holds requests to its own limit
+        let large = json!({"function": "VERIFY USERS", "users": [{"email": "someone-with-a-long-address@example.com"}]});
+        let too_large = format!("Request too large ({} bytes, at most 64)", large.to_string().len());
+        assert_ne!(exchange(&mut encrypted, &large).await["error"], too_large.as_str());
+        assert_eq!(exchange(&mut plaintext, &large).await["error"], too_large.as_str());
+    }
+
+    #[test]
+    fn test_is_pong() {
+        assert!(is_pong(br#"{"function": "PONG"}"#));
+        assert!(!is_pong(br#"{"function": "READ USERS"}"#));
+        assert!(!is_pong(b"PONG"));
+    }
+
+    #[test]
+    fn test_is_batch() {
+        assert!(is_batch(r#"[{"function": "READ USERS"}]"#));
+        assert!(is_batch(" \n[]"));
+        assert!(!is_batch(r#"{"function": "READ USERS"}"#));
+    }
+
+    #[async_std::test]
+    async fn test_connection_limit() {
+        let limit = ConnectionLimit::new(3);
+
+        // Start more connections than the limit, holding each one open for a while
+        let tasks: Vec<_> = (0..10)
+            .map(|_| {
+                let limit = limit.clone();
+                async_std::task::spawn(async move {
+                    match limit.try_acquire() {
+                        Some(_permit) => {
+                            assert!(limit.active() <= 3);
+                            async_std::task::sleep(Duration::from_millis(50)).await;
+                            true
+                        },
+                        None => false,
+                    }
+                })
+            })
+            .collect();
+
+        let mut accepted = 0;
+        for task in tasks {
+            if task.await {
+                accepted += 1;
             }
         }
-    };
 
-    Ok(response)
-}
+        assert!(accepted >= 3);
+        assert!(accepted < 10);
 
-/// Format an response as JSON or use a default if none is provided
-fn format_response(response: Option<Response>) -> String {
-    let response = match response {
-        Some(r) => r,
-        // If no input provided, use a default failure response
-        None => Response{
-            status: 0,
-            conversations: None,
-            messages: None,
-            users: None,
-        },
-    };
+        // Places are given back when connections finish
+        assert_eq!(limit.active(), 0);
+        let permits: Vec<_> = (0..3).map(|_| limit.try_acquire().unwrap()).collect();
+        assert!(limit.try_acquire().is_none());
+        drop(permits);
+        assert!(limit.try_acquire().is_some());
+    }
+
+    #[async_std::test]
+    async fn test_serve() {
+        // Connections that fail while being accepted, or whose peer can't be found, are skipped without stopping
+        let incoming = async_std::stream::from_iter(vec![
+            Ok(1),
+            Err(io::Error::new(io::ErrorKind::ConnectionAborted, "aborted")),
+            Ok(2),
+            Ok(3),
+        ]);
+        let peer = |&n: &i32| match n {
+            2 => Err(io::Error::new(io::ErrorKind::NotConnected, "hung up")),
+            n => Ok(n.to_string()),
+        };
 
-    response.to_json()
-}
\ No newline at end of file
+        let handled = Arc::new(Mutex::new(Vec::new()));
+        let recorded = Arc::clone(&handled);
+        serve(incoming, ConnectionLimit::new(10), peer, move |n| {
+            recorded.lock().unwrap().push(n);
+            async { Ok::<(), Box<dyn std::error::Error>>(()) }
+        }).await;
+
+        assert_eq!(*handled.lock().unwrap(), vec![1, 3]);
+    }
+}
@@ -1,54 +1,181 @@
-use std::env;
-use std::net::SocketAddr;
 use async_std::prelude::*;
-use async_std::net::TcpListener;
 use async_std::task;
+use std::time::Duration;
 use dotenv;
-use log::{error, info};
+use log::{error, info, warn};
 
 use echo_server;
 
+/// How often abandoned uploads are looked for
+const UPLOAD_CLEANUP_INTERVAL: Duration = Duration::from_secs(60);
+/// How long to wait before listening for new messages again after losing the database
+const PUSH_RESTART_INTERVAL: Duration = Duration::from_secs(5);
+/// How often (in seconds) expired messages are looked for, unless configured otherwise
+const DEFAULT_RETENTION_INTERVAL: u64 = 60;
+
 #[async_std::main]
 async fn main() -> std::io::Result<()> {
     dotenv::dotenv().ok();
     env_logger::init();
 
-    // Prepare database
-    let pool = echo_server::database::init_db().await
+    // Read database settings before doing anything else, so a missing URL fails fast
+    let db_config = match echo_server::settings::DatabaseConfig::from_env() {
+        Ok(c) => c,
+        Err(e) => {
+            error!("Could not read database configuration: {}", e);
+            std::process::exit(1);
+        },
+    };
+
+    // Check the accepted media types before taking any connections
+    if let Err(e) = echo_server::settings::load_media_allowlist() {
+        error!("Could not read media allowlist: {}", e);
+        std::process::exit(1);
+    }
+
+    // Prepare database: Postgres (sending reads to a replica if one is configured) or a SQLite file
+    let db = echo_server::database::init(&db_config).await
         .expect("Could not initialize database");
 
-    // Choose a socket address
-    let socket_addr: SocketAddr = format!("{}:{}",
-            env::var("IP_ADDRESS").unwrap_or(String::from("[::]")),
-            env::var("PORT_NUMBER").unwrap_or(String::from("63100")))
-        .parse()
-        .expect("Could not parse socket address");
+    // Read server settings
+    let config = echo_server::settings::ServerConfig::from_env()
+        .expect("Could not read server configuration");
+    let timeouts = config.timeouts;
+    let heartbeat = config.heartbeat;
+    let max_frame_size = config.max_frame_size;
+    let limit = echo_server::ConnectionLimit::new(config.max_connections);
 
-    // Set up TLS
-    let acceptor = echo_server::tls::get_acceptor().await
-        .expect("Could not accept TLS handshake");
+    // Set up TLS, refusing to start with a certificate or key that can't be used (the configuration already refused to
+    // go without one unless TLS was turned off)
+    let acceptor = match &config.tls {
+        Some(tls) => match echo_server::tls::get_acceptor(tls).await {
+            Ok(a) => Some(a),
+            Err(e) => {
+                error!("Could not set up TLS: {}", e);
+                std::process::exit(1);
+            },
+        },
+        None => {
+            warn!("TLS is disabled, so connections are plaintext");
+            None
+        },
+    };
 
-    // Listen for incoming connections
-    let listener = TcpListener::bind(socket_addr).await?;
-    let mut incoming = listener.incoming();
+    // Listen for local clients too if a Unix socket is configured
+    let unix_socket = match &config.unix_socket {
+        Some(unix) => match echo_server::unix::UnixSocket::bind(unix).await {
+            Ok(s) => Some(s),
+            Err(e) => {
+                error!("Could not set up Unix socket: {}", e);
+                std::process::exit(1);
+            },
+        },
+        None => None,
+    };
 
-    info!("Listening on port {}", socket_addr.port());
+    // Remove the Unix socket when stopped, since nothing else would
+    let socket_path = unix_socket.as_ref().map(|s| s.path().to_path_buf());
+    ctrlc::set_handler(move || {
+        info!("Shutting down");
 
-    while let Some(stream) = incoming.next().await {
-        let stream = stream?;
-        let acceptor = acceptor.clone();
-        let pool = pool.clone();
+        if let Some(path) = &socket_path {
+            echo_server::unix::remove(path);
+        }
+
+        std::process::exit(0);
+    }).expect("Could not set up shutdown handler");
+
+    // Background work only applies to Postgres, since SQLite has no uploads, no other servers to hear about new
+    // messages from and no retention settings
+    if let Some(pool) = db.postgres() {
+        spawn_background_tasks(pool.clone());
+    }
 
-        info!("Successful connection from {}", stream.peer_addr()?);
+    // Handle local connections alongside TCP ones, within the same limit
+    if let Some(unix_socket) = unix_socket {
+        let limit = limit.clone();
+        let db = db.clone();
+
+        info!("Listening on {}", unix_socket.path().display());
 
         task::spawn(async move {
-            let result = echo_server::handle_connection(stream, &acceptor, &pool).await;
+            // Local clients don't have an address of their own
+            let incoming = unix_socket.listener().incoming();
+            echo_server::serve(incoming, limit, |_| Ok(String::from("local client")), move |stream| {
+                let db = db.clone();
 
-            if let Err(e) = result {
-                error!("{}", e);
-            }
+                async move { echo_server::handle_unix_connection(stream, timeouts, heartbeat, max_frame_size, &db).await }
+            }).await;
         });
     }
 
+    // Listen for incoming connections on every address that can be bound
+    let listeners = echo_server::bind_listeners(&config.listeners).await;
+    if listeners.is_empty() {
+        error!("Could not listen on any address");
+        std::process::exit(1);
+    }
+
+    let handles: Vec<_> = listeners
+        .into_iter()
+        .map(|(listener, listener_config)| {
+            let acceptor = match listener_config.tls {
+                true => acceptor.clone(),
+                false => None,
+            };
+
+            task::spawn(echo_server::accept_connections(listener, acceptor, timeouts, heartbeat, listener_config.max_frame_size, limit.clone(), db.clone()))
+        })
+        .collect();
+
+    for handle in handles {
+        handle.await?;
+    }
+
     Ok(())
 }
+
+/// Clean up abandoned uploads, push new messages and delete expired ones in the background
+fn spawn_background_tasks(pool: sqlx::PgPool) {
+    // Clean up abandoned uploads in the background
+    let cleanup_pool = pool.clone();
+    task::spawn(async move {
+        loop {
+            match echo_server::database::delete_expired_uploads(&cleanup_pool).await {
+                Ok(0) => (),
+                Ok(n) => info!("Deleted {} abandoned uploads", n),
+                Err(e) => error!("{}", e),
+            }
+
+            task::sleep(UPLOAD_CLEANUP_INTERVAL).await;
+        }
+    });
+
+    // Push new messages to the members connected here, wherever they were sent from
+    let push_pool = pool.clone();
+    task::spawn(async move {
+        loop {
+            if let Err(e) = echo_server::push::listen(push_pool.clone()).await {
+                error!("Stopped pushing new messages: {}", e);
+            }
+
+            task::sleep(PUSH_RESTART_INTERVAL).await;
+        }
+    });
+
+    // Delete messages past their conversation's retention window in the background
+    let retention_interval = echo_server::settings::get_value("RETENTION_INTERVAL", DEFAULT_RETENTION_INTERVAL)
+        .expect("Could not read retention interval");
+    let retention_pool = pool;
+    task::spawn(async move {
+        loop {
+            match echo_server::database::delete_expired_messages(chrono::Utc::now(), &retention_pool).await {
+                Ok(0) => (),
+                Ok(n) => info!("Deleted {} expired messages", n),
+                Err(e) => error!("{}", e),
+            }
+
+            task::sleep(Duration::from_secs(retention_interval.max(1))).await;
+        }
+    });
+}
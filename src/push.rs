@@ -0,0 +1,311 @@
+use crate::api::{Attachment, Message};
+use crate::api::response::Response;
+use crate::database;
+use crate::encoding;
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::Mutex;
+use async_std::channel::{self, Receiver, Sender};
+use async_std::future;
+use log::warn;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use sqlx::{PgPool, Postgres, Transaction};
+use sqlx::postgres::PgListener;
+
+/// The Postgres channel new messages are announced on
+const CHANNEL: &str = "new_messages";
+/// The most message ids announced in one notification, which keeps it well under Postgres' 8000 byte payload limit
+const MAX_NOTIFIED_MESSAGES: usize = 500;
+/// The most pushes held for a connection that isn't reading them, after which more are dropped
+const MAX_QUEUED_PUSHES: usize = 64;
+
+/// The connections on this server that users are logged in on
+static CONNECTIONS: Lazy<Registry> = Lazy::new(Registry::default);
+/// An id for this server, so a connection's id (which is only unique on its own server) can be told apart from others'
+static SERVER: Lazy<u64> = Lazy::new(|| {
+    let mut id = [0; 8];
+    getrandom::getrandom(&mut id).map_or(u64::from(std::process::id()), |_| u64::from_le_bytes(id))
+});
+
+/// An announcement of new messages in a conversation, sent from whichever server stored them
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+struct Notice {
+    conversation: i32,
+    sender: String,
+    /// The server and connection the messages were sent on, which already has them in its response
+    server: u64,
+    connection: u64,
+    messages: Vec<i32>,
+}
+
+/// Connections waiting for pushes, by the email of the user logged in on them
+#[derive(Default)]
+pub struct Registry {
+    connections: Mutex<HashMap<String, Vec<(u64, Sender<String>)>>>,
+}
+
+impl Registry {
+    /// Start sending pushes for a user to a connection (identified by `id`), until the subscription is dropped
+    pub fn subscribe(&self, email: &str, id: u64) -> Subscription<'_> {
+        let (sender, receiver) = channel::bounded(MAX_QUEUED_PUSHES);
+
+        self.connections.lock().unwrap()
+            .entry(String::from(email))
+            .or_default()
+            .push((id, sender));
+
+        Subscription{
+            registry: self,
+            email: String::from(email),
+            id,
+            receiver,
+        }
+    }
+
+    /// Check whether a user is logged in on any connection
+    pub fn is_connected(&self, email: &str) -> bool {
+        self.connections.lock().unwrap().contains_key(email)
+    }
+
+    /// Push a frame to every connection a user is logged in on (besides the one with the id `except`), returning how
+    /// many it was queued for
+    ///
+    /// Connections that have fallen too far behind miss the push rather than hold up everyone else.
+    pub fn push(&self, email: &str, frame: &str, except: Option<u64>) -> usize {
+        match self.connections.lock().unwrap().get(email) {
+            Some(senders) => senders.iter()
+                .filter(|(id, _)| Some(*id) != except)
+                .filter(|(_, sender)| sender.try_send(String::from(frame)).is_ok())
+                .count(),
+            None => 0,
+        }
+    }
+
+    /// Stop sending pushes to a connection
+    fn unsubscribe(&self, email: &str, id: u64) {
+        let mut connections = self.connections.lock().unwrap();
+
+        if let Some(senders) = connections.get_mut(email) {
+            senders.retain(|(i, _)| *i != id);
+
+            if senders.is_empty() {
+                connections.remove(email);
+            }
+        }
+    }
+}
+
+/// A connection's place in a registry, which it gives up when dropped
+pub struct Subscription<'a> {
+    registry: &'a Registry,
+    email: String,
+    id: u64,
+    receiver: Receiver<String>,
+}
+
+impl Subscription<'_> {
+    /// The user pushes are being sent for
+    pub fn email(&self) -> &str {
+        &self.email
+    }
+
+    /// Wait for the next push
+    pub async fn next(&self) -> String {
+        match self.receiver.recv().await {
+            Ok(frame) => frame,
+            // The sender is only dropped along with the subscription, so this can't happen while it's in use
+            Err(_) => future::pending().await,
+        }
+    }
+}
+
+impl Drop for Subscription<'_> {
+    fn drop(&mut self) {
+        self.registry.unsubscribe(&self.email, self.id);
+    }
+}
+
+/// Start sending pushes for a user to the current connection
+pub fn subscribe(email: &str, connection: u64) -> Subscription<'static> {
+    CONNECTIONS.subscribe(email, connection)
+}
+
+/// Check whether a user is logged in on any connection to this server
+#[cfg(feature = "sqlite")]
+pub fn is_connected(email: &str) -> bool {
+    CONNECTIONS.is_connected(email)
+}
+
+/// Push messages stored by this server straight to the connections of their conversation's members, returning how many
+/// connections they were queued for
+///
+/// This is for a SQLite database, which only this server uses, so there's nobody elsewhere to announce them to. The
+/// connection the messages were sent on isn't pushed them, since it was already answered with them.
+#[cfg(feature = "sqlite")]
+pub fn push_local(conversation: i32, recipients: &[String], messages: Vec<Message>, connection: u64) -> usize {
+    push_to(&CONNECTIONS, conversation, recipients, messages, Some(connection))
+}
+
+/// Announce new messages sent on a connection, which Postgres only passes on once the transaction commits
+pub async fn notify(tx: &mut Transaction<'_, Postgres>, conversation: i32, sender: &str, connection: u64, messages: &[i32]) -> Result<(), Box<dyn Error>> {
+    for chunk in messages.chunks(MAX_NOTIFIED_MESSAGES) {
+        let notice = serde_json::to_string(&Notice{
+            conversation,
+            sender: String::from(sender),
+            server: *SERVER,
+            connection,
+            messages: chunk.to_vec(),
+        })?;
+
+        // pg_notify returns void, which can't be checked at compile time
+        sqlx::query(include_str!("sql/notify-messages.sql"))
+            .bind(CHANNEL)
+            .bind(notice)
+            .execute(&mut *tx)
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// Push announced messages to the connections of their conversation's members, until the database can't be reached
+///
+/// Every server listens, so members are reached whichever server they're connected to.
+pub async fn listen(db_pool: PgPool) -> Result<(), Box<dyn Error>> {
+    let mut listener = PgListener::connect_with(&db_pool).await?;
+    listener.listen(CHANNEL).await?;
+
+    loop {
+        let notification = listener.recv().await?;
+
+        // A push that can't be sent is only missed, since the messages can still be read
+        if let Err(e) = deliver(notification.payload(), &CONNECTIONS, &db_pool).await {
+            warn!("Could not push new messages: {}", e);
+        }
+    }
+}
+
+/// Push the messages in an announcement to the members of their conversation that are connected, returning how many
+/// connections they were queued for
+///
+/// The connection the messages were sent on isn't pushed them, since it was already answered with them, but the
+/// sender's other connections are.
+async fn deliver(payload: &str, registry: &Registry, db_pool: &PgPool) -> Result<usize, Box<dyn Error>> {
+    let notice: Notice = serde_json::from_str(payload)?;
+
+    let recipients: Vec<String> = database::retry(|| sqlx::query_file!("src/sql/read-member-emails.sql", notice.conversation)
+            .fetch_all(db_pool))
+        .await?
+        .into_iter()
+        .map(|m| m.email)
+        .filter(|email| registry.is_connected(email))
+        .collect();
+
+    // Nobody here to push to, so there's no need to read the messages
+    if recipients.is_empty() {
+        return Ok(0);
+    }
+
+    let messages = database::retry(|| sqlx::query_file!("src/sql/read-pushed-messages.sql", notice.conversation, &notice.messages)
+            .fetch_all(db_pool))
+        .await?
+        .into_iter()
+        .map(|m| -> Result<Message, Box<dyn Error>> {
+            Ok(Message{
+                id: Some(m.id),
+                seq: Some(m.seq),
+                conversation: Some(notice.conversation),
+                data: Some(encoding::decompress_data(m.data, m.data_encoding.as_deref())?),
+                media_type: m.media_type,
+                timestamp: m.timestamp,
+                created_at: Some(m.created_at),
+                signature: m.signature,
+                sender: Some(m.email),
+                parent_id: m.parent_id,
+                attachment: m.attachment_id.map(|id| Attachment{
+                    id: Some(id),
+                    conversation: Some(notice.conversation),
+                    media_type: m.attachment_media_type,
+                    size: m.attachment_size,
+                    ..Default::default()
+                }),
+                ..Default::default()
+            })
+        })
+        .collect::<Result<Vec<Message>, _>>()?;
+
+    // Connection ids are only unique on their own server
+    let except = Some(notice.connection).filter(|_| notice.server == *SERVER);
+
+    Ok(push_to(registry, notice.conversation, &recipients, messages, except))
+}
+
+/// Push messages to the connections of some of their conversation's members (besides the one with the id `except`),
+/// returning how many connections they were queued for
+fn push_to(registry: &Registry, conversation: i32, recipients: &[String], messages: Vec<Message>, except: Option<u64>) -> usize {
+    let frame = Response::message_event_to_json(conversation, messages);
+
+    recipients.iter().map(|email| registry.push(email, &frame, except)).sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::push::{Notice, Registry, MAX_QUEUED_PUSHES};
+
+    #[async_std::test]
+    async fn test_registry() {
+        let registry = Registry::default();
+        let phone = registry.subscribe("me@example.com", 1);
+        let laptop = registry.subscribe("me@example.com", 2);
+        assert!(registry.is_connected("me@example.com"));
+        assert!(!registry.is_connected("you@example.com"));
+
+        // Every connection a user is logged in on gets the push
+        assert_eq!(registry.push("me@example.com", "hello", None), 2);
+        assert_eq!(phone.next().await, "hello");
+        assert_eq!(laptop.next().await, "hello");
+        assert_eq!(registry.push("you@example.com", "hello", None), 0);
+
+        // Apart from the connection a message was sent on
+        assert_eq!(registry.push("me@example.com", "sent", Some(1)), 1);
+        assert_eq!(laptop.next().await, "sent");
+
+        // Connections stop getting pushes once they're gone
+        drop(phone);
+        assert_eq!(registry.push("me@example.com", "again", None), 1);
+        assert_eq!(laptop.next().await, "again");
+        drop(laptop);
+        assert!(!registry.is_connected("me@example.com"));
+    }
+
+    #[async_std::test]
+    async fn test_registry_backlog() {
+        let registry = Registry::default();
+        let slow = registry.subscribe("me@example.com", 1);
+
+        // A connection that stops reading misses pushes instead of holding them forever
+        for i in 0..MAX_QUEUED_PUSHES {
+            assert_eq!(registry.push("me@example.com", &i.to_string(), None), 1);
+        }
+        assert_eq!(registry.push("me@example.com", "dropped", None), 0);
+
+        assert_eq!(slow.next().await, "0");
+        assert_eq!(registry.push("me@example.com", "queued", None), 1);
+    }
+
+    #[test]
+    fn test_notice() {
+        let notice = Notice{
+            conversation: 3,
+            sender: String::from("me@example.com"),
+            server: 12,
+            connection: 5,
+            messages: vec![7, 8],
+        };
+
+        let payload = serde_json::to_string(&notice).unwrap();
+        assert_eq!(serde_json::from_str::<Notice>(&payload).unwrap(), notice);
+    }
+}
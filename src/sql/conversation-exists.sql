@@ -0,0 +1,3 @@
+SELECT EXISTS (
+    SELECT 1 FROM conversations WHERE id = $1
+) AS "exists!"
\ No newline at end of file
@@ -0,0 +1,6 @@
+INSERT INTO attachments (owner, conversation, media_type, size, data)
+VALUES (
+    (SELECT id FROM users WHERE email = $1),
+    $2, $3, $4, $5
+)
+RETURNING id
\ No newline at end of file
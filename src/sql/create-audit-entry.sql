@@ -0,0 +1,2 @@
+INSERT INTO audit_log (actor, action, target, outcome)
+VALUES ($1, $2, $3, $4)
\ No newline at end of file
@@ -0,0 +1,11 @@
+WITH blocked AS (
+    SELECT id FROM users WHERE email = $2
+), inserted AS (
+    INSERT INTO blocks (blocker, blocked)
+    SELECT (SELECT id FROM users WHERE email = $1), blocked.id
+    FROM blocked
+    ON CONFLICT (blocker, blocked) DO NOTHING
+)
+SELECT EXISTS (
+    SELECT 1 FROM blocked
+) AS "exists!"
\ No newline at end of file
@@ -1,2 +1,3 @@
-INSERT INTO conversations (name)
-VALUES ($1)
\ No newline at end of file
+INSERT INTO conversations (name, direct_key, public)
+VALUES ($1, $2, $3)
+RETURNING id
\ No newline at end of file
@@ -1,5 +1,6 @@
-INSERT INTO participants (identity, conversation)
+INSERT INTO participants (identity, conversation, role)
 VALUES (
-    (SELECT id FROM users WHERE email = $1),
-    (SELECT id FROM conversations WHERE name = $2)
-)
\ No newline at end of file
+    (SELECT id FROM users WHERE LOWER(email) = LOWER($1)),
+    $2, $3
+)
+ON CONFLICT (identity, conversation) DO NOTHING
\ No newline at end of file
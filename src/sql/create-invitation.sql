@@ -0,0 +1,7 @@
+INSERT INTO invitations (conversation, inviter, invitee)
+VALUES (
+    $1,
+    (SELECT id FROM users WHERE email = $2),
+    (SELECT id FROM users WHERE LOWER(email) = LOWER($3))
+)
+ON CONFLICT (conversation, invitee) DO NOTHING
\ No newline at end of file
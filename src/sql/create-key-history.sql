@@ -0,0 +1,2 @@
+INSERT INTO public_key_history (identity, public_key)
+SELECT id, public_key FROM users WHERE email = $1
\ No newline at end of file
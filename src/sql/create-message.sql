@@ -1,10 +1,12 @@
-INSERT INTO messages (sender, data, media_type, timestamp, signature)
-VALUES (
-    (SELECT participants.id
-    FROM participants
-    JOIN users ON users.id = participants.identity
-    JOIN conversations ON conversations.id = participants.conversation
-    WHERE users.email = $1
-    AND conversations.id = $2),
-    $3, $4, $5, $6
-)
\ No newline at end of file
+INSERT INTO messages (sender, conversation, seq, data, media_type, timestamp, signature, idempotency_key, search, parent_id, attachment_id, data_encoding, preview)
+SELECT participants.id, $2, $3 + batch.position::INT, batch.data, batch.media_type, batch.timestamp, batch.signature,
+    NULLIF(batch.idempotency_key, ''), to_tsvector('simple', NULLIF(batch.search, '')), NULLIF(batch.parent_id, 0), NULLIF(batch.attachment_id, 0),
+    NULLIF(batch.data_encoding, ''), NULLIF(batch.preview, '')
+FROM participants
+JOIN users ON users.id = participants.identity
+CROSS JOIN UNNEST($4::BYTEA[], $5::BYTEA[], $6::BIGINT[], $7::BYTEA[], $8::VARCHAR[], $9::TEXT[], $10::INT[], $11::INT[], $12::VARCHAR[], $13::TEXT[])
+    WITH ORDINALITY AS batch (data, media_type, timestamp, signature, idempotency_key, search, parent_id, attachment_id, data_encoding, preview, position)
+WHERE users.email = $1
+AND participants.conversation = $2
+ON CONFLICT (sender, idempotency_key) DO NOTHING
+RETURNING id, seq
\ No newline at end of file
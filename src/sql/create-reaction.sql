@@ -0,0 +1,6 @@
+INSERT INTO reactions (identity, message, emoji)
+VALUES (
+    (SELECT id FROM users WHERE email = $1),
+    $2, $3
+)
+ON CONFLICT (message, identity, emoji) DO NOTHING
\ No newline at end of file
@@ -0,0 +1,4 @@
+INSERT INTO message_revisions (message, data, data_encoding, media_type, timestamp, signature, edited_at)
+SELECT id, data, data_encoding, media_type, timestamp, signature, COALESCE(edited_at, created_at)
+FROM messages
+WHERE id = $1
\ No newline at end of file
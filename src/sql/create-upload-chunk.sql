@@ -0,0 +1,2 @@
+INSERT INTO upload_chunks (upload, position, data)
+VALUES ($1, $2, $3)
\ No newline at end of file
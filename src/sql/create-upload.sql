@@ -0,0 +1,6 @@
+INSERT INTO uploads (owner, conversation, media_type, size)
+VALUES (
+    (SELECT id FROM users WHERE email = $1),
+    $2, $3, $4
+)
+RETURNING id
\ No newline at end of file
@@ -1,2 +1,6 @@
-INSERT INTO users (email, public_key, pass, salt)
-VALUES ($1, $2, $3, $4)
\ No newline at end of file
+INSERT INTO users (email, public_key, pass, salt, display_name, avatar_url)
+SELECT email, public_key, pass, salt, NULLIF(display_name, ''), NULLIF(avatar_url, '')
+FROM UNNEST($1::VARCHAR[], $2::BYTEA[], $3::BYTEA[], $4::BYTEA[], $5::VARCHAR[], $6::VARCHAR[])
+    AS new_users (email, public_key, pass, salt, display_name, avatar_url)
+ON CONFLICT (email) DO NOTHING
+RETURNING id, email
\ No newline at end of file
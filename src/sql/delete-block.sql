@@ -0,0 +1,3 @@
+DELETE FROM blocks
+WHERE blocker = (SELECT id FROM users WHERE email = $1)
+AND blocked = (SELECT id FROM users WHERE email = $2)
\ No newline at end of file
@@ -0,0 +1,2 @@
+DELETE FROM attachments
+WHERE conversation = $1
\ No newline at end of file
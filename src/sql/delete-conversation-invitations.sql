@@ -0,0 +1,2 @@
+DELETE FROM invitations
+WHERE conversation = $1
\ No newline at end of file
@@ -0,0 +1,2 @@
+DELETE FROM messages
+WHERE conversation = $1
\ No newline at end of file
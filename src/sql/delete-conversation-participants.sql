@@ -0,0 +1,2 @@
+DELETE FROM participants
+WHERE conversation = $1
\ No newline at end of file
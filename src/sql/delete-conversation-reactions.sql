@@ -0,0 +1,4 @@
+DELETE FROM reactions
+USING messages
+WHERE messages.id = reactions.message
+AND messages.conversation = $1
\ No newline at end of file
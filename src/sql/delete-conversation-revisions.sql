@@ -0,0 +1,4 @@
+DELETE FROM message_revisions
+USING messages
+WHERE messages.id = message_revisions.message
+AND messages.conversation = $1
\ No newline at end of file
@@ -0,0 +1,4 @@
+DELETE FROM upload_chunks
+WHERE upload IN (
+    SELECT id FROM uploads WHERE conversation = $1
+)
\ No newline at end of file
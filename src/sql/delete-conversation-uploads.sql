@@ -0,0 +1,2 @@
+DELETE FROM uploads
+WHERE conversation = $1
\ No newline at end of file
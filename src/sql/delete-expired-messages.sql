@@ -0,0 +1,3 @@
+DELETE FROM messages
+WHERE conversation = $1
+AND created_at < $2
\ No newline at end of file
@@ -0,0 +1,5 @@
+DELETE FROM reactions
+USING messages
+WHERE messages.id = reactions.message
+AND messages.conversation = $1
+AND messages.created_at < $2
\ No newline at end of file
@@ -0,0 +1,5 @@
+DELETE FROM message_revisions
+USING messages
+WHERE messages.id = message_revisions.message
+AND messages.conversation = $1
+AND messages.created_at < $2
\ No newline at end of file
@@ -0,0 +1,6 @@
+DELETE FROM upload_chunks
+WHERE upload IN (
+    SELECT id FROM uploads
+    WHERE updated_at < NOW() - make_interval(secs => $1::INT)
+    FOR UPDATE
+)
\ No newline at end of file
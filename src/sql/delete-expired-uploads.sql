@@ -0,0 +1,2 @@
+DELETE FROM uploads
+WHERE updated_at < NOW() - make_interval(secs => $1::INT)
\ No newline at end of file
@@ -0,0 +1,21 @@
+UPDATE messages
+SET deleted_at = NOW(),
+    deleted_by = (SELECT id FROM users WHERE email = $1),
+    search = NULL
+WHERE id = $2
+AND deleted_at IS NULL
+AND (
+    sender IN (
+        SELECT participants.id
+        FROM participants
+        JOIN users ON users.id = participants.identity
+        WHERE users.email = $1
+    )
+    OR conversation IN (
+        SELECT participants.conversation
+        FROM participants
+        JOIN users ON users.id = participants.identity
+        WHERE users.email = $1
+        AND participants.role = 'admin'
+    )
+)
\ No newline at end of file
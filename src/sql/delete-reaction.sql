@@ -0,0 +1,4 @@
+DELETE FROM reactions
+WHERE identity = (SELECT id FROM users WHERE email = $1)
+AND message = $2
+AND emoji = $3
\ No newline at end of file
@@ -0,0 +1,2 @@
+DELETE FROM message_revisions
+WHERE message = $1
\ No newline at end of file
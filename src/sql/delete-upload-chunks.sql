@@ -0,0 +1 @@
+DELETE FROM upload_chunks WHERE upload = $1
\ No newline at end of file
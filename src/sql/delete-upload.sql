@@ -0,0 +1 @@
+DELETE FROM uploads WHERE id = $1
\ No newline at end of file
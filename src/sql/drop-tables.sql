@@ -0,0 +1 @@
+DROP TABLE IF EXISTS public_key_history, audit_log, invitations, reactions, blocks, message_revisions, messages, attachments, upload_chunks, uploads, participants, conversations, users, _sqlx_migrations CASCADE
\ No newline at end of file
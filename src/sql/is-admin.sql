@@ -0,0 +1,8 @@
+SELECT EXISTS (
+    SELECT 1
+    FROM participants
+    JOIN users ON users.id = participants.identity
+    WHERE users.email = $1
+    AND participants.conversation = $2
+    AND participants.role = 'admin'
+) AS "is_admin!"
\ No newline at end of file
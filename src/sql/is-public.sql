@@ -0,0 +1 @@
+SELECT public FROM conversations WHERE id = $1
\ No newline at end of file
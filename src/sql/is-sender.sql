@@ -0,0 +1,8 @@
+SELECT EXISTS (
+    SELECT 1
+    FROM messages
+    JOIN participants ON participants.id = messages.sender
+    JOIN users ON users.id = participants.identity
+    WHERE users.email = $1
+    AND messages.id = $2
+) AS "is_sender!"
\ No newline at end of file
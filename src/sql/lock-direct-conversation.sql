@@ -0,0 +1 @@
+SELECT 1 AS locked FROM pg_advisory_xact_lock(hashtext($1))
\ No newline at end of file
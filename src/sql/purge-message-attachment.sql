@@ -0,0 +1,6 @@
+UPDATE attachments
+SET data = '',
+    size = 0
+WHERE id = (
+    SELECT attachment_id FROM messages WHERE id = $1 AND deleted_at IS NOT NULL
+)
\ No newline at end of file
@@ -0,0 +1,10 @@
+UPDATE messages
+SET data = '',
+    data_encoding = NULL,
+    preview = NULL,
+    media_type = NULL,
+    signature = NULL,
+    search = NULL,
+    attachment_id = NULL
+WHERE id = $1
+AND deleted_at IS NOT NULL
\ No newline at end of file
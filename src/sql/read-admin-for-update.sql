@@ -0,0 +1,7 @@
+SELECT participants.id
+FROM participants
+JOIN users ON users.id = participants.identity
+WHERE users.email = $1
+AND participants.conversation = $2
+AND participants.role = 'admin'
+FOR UPDATE OF participants
\ No newline at end of file
@@ -0,0 +1,9 @@
+SELECT attachments.id, attachments.conversation, attachments.media_type, attachments.size, attachments.data
+FROM attachments
+WHERE attachments.id = $2
+AND attachments.conversation IN (
+    SELECT participants.conversation
+    FROM participants
+    JOIN users ON users.id = participants.identity
+    WHERE users.email = $1
+)
\ No newline at end of file
@@ -1,6 +1,46 @@
-SELECT conversations.id, conversations.name
+SELECT conversations.id, conversations.name, latest.created_at AS "timestamp?",
+    conversations.direct_key IS NOT NULL AS "direct!", conversations.public,
+    conversations.retention_seconds,
+    COALESCE(latest.id, 0) AS "activity!",
+    participants.role, participants.archived, participants.last_read_message_id, unread.count AS "unread_count!",
+    latest.email AS "last_sender?", latest.media_type AS "last_media_type?", latest.preview AS "last_preview?",
+    latest.data AS "last_data?"
 FROM conversations
 JOIN participants ON participants.conversation = conversations.id
+LEFT JOIN LATERAL (
+    SELECT messages.id, messages.created_at, messages.media_type, messages.preview,
+        CASE WHEN messages.preview IS NULL AND messages.data_encoding IS NULL THEN substring(messages.data FROM 1 FOR $5) END AS data,
+        users.email
+    FROM messages
+    JOIN participants AS senders ON senders.id = messages.sender
+    JOIN users ON users.id = senders.identity
+    WHERE messages.conversation = conversations.id
+    AND messages.deleted_at IS NULL
+    AND (conversations.retention_seconds IS NULL
+        OR messages.created_at >= NOW() - make_interval(secs => conversations.retention_seconds))
+    ORDER BY messages.id DESC
+    LIMIT 1
+) AS latest ON TRUE
+LEFT JOIN LATERAL (
+    SELECT COUNT(*) AS count
+    FROM messages
+    WHERE messages.conversation = conversations.id
+    AND messages.sender <> participants.id
+    AND (conversations.retention_seconds IS NULL
+        OR messages.created_at >= NOW() - make_interval(secs => conversations.retention_seconds))
+    AND messages.seq > COALESCE((
+        SELECT seq FROM messages AS read WHERE read.id = participants.last_read_message_id
+    ), 0)
+) AS unread ON TRUE
 WHERE participants.identity = (
     SELECT id FROM users WHERE email = $1
-)
\ No newline at end of file
+)
+AND ($6::VARCHAR IS NULL OR participants.role = $6)
+AND ($7::BOOLEAN OR NOT participants.archived)
+AND (
+    $2::INT IS NULL
+    OR COALESCE(latest.id, 0) < $2
+    OR (COALESCE(latest.id, 0) = $2 AND conversations.id < $3)
+)
+ORDER BY COALESCE(latest.id, 0) DESC, conversations.id DESC
+LIMIT $4
\ No newline at end of file
@@ -0,0 +1,10 @@
+SELECT conversations.id, conversations.name
+FROM conversations
+JOIN participants ON participants.conversation = conversations.id
+JOIN users ON users.id = participants.identity
+WHERE conversations.direct_key = $1
+AND LOWER(users.email) = ANY($2)
+GROUP BY conversations.id
+HAVING COUNT(*) = 2
+ORDER BY conversations.id
+LIMIT 1
\ No newline at end of file
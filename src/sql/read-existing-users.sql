@@ -0,0 +1 @@
+SELECT LOWER(email) AS "email!" FROM users WHERE LOWER(email) = ANY($1)
\ No newline at end of file
@@ -0,0 +1,14 @@
+SELECT messages.id, messages.seq, messages.conversation, messages.data, messages.data_encoding, messages.media_type, messages.timestamp, messages.created_at, messages.edited_at, messages.signature, messages.parent_id, users.email, attachments.id AS "attachment_id?", attachments.media_type AS "attachment_media_type?", attachments.size AS "attachment_size?"
+FROM messages
+JOIN participants AS members ON members.conversation = messages.conversation
+JOIN users AS members_users ON members_users.id = members.identity AND members_users.email = $1
+JOIN participants AS senders ON senders.id = messages.sender
+JOIN users ON users.id = senders.identity
+JOIN conversations ON conversations.id = messages.conversation
+LEFT JOIN attachments ON attachments.id = messages.attachment_id
+WHERE messages.deleted_at IS NULL
+AND (conversations.retention_seconds IS NULL
+    OR messages.created_at >= NOW() - make_interval(secs => conversations.retention_seconds))
+AND ($3::INT IS NULL OR (messages.created_at, messages.id) < (SELECT created_at, id FROM messages WHERE id = $3))
+ORDER BY messages.created_at DESC, messages.id DESC
+LIMIT $2 OFFSET $4
\ No newline at end of file
@@ -0,0 +1,7 @@
+SELECT invitations.id, invitations.conversation, invitations.status, inviters.email AS inviter
+FROM invitations
+JOIN users AS inviters ON inviters.id = invitations.inviter
+JOIN users AS invitees ON invitees.id = invitations.invitee
+WHERE invitees.email = $1
+AND invitations.status = 'pending'
+ORDER BY invitations.id
\ No newline at end of file
@@ -0,0 +1,5 @@
+SELECT public_key_history.public_key, public_key_history.replaced_at
+FROM public_key_history
+JOIN users ON users.id = public_key_history.identity
+WHERE users.email = $1
+ORDER BY public_key_history.replaced_at, public_key_history.id
\ No newline at end of file
@@ -0,0 +1,4 @@
+SELECT users.email
+FROM participants
+JOIN users ON users.id = participants.identity
+WHERE participants.conversation = $1
\ No newline at end of file
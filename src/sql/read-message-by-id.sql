@@ -0,0 +1,16 @@
+SELECT messages.id, messages.seq, messages.conversation, messages.data, messages.data_encoding, messages.media_type, messages.timestamp, messages.created_at, messages.edited_at, messages.signature, messages.parent_id, users.email, attachments.id AS "attachment_id?", attachments.media_type AS "attachment_media_type?", attachments.size AS "attachment_size?", messages.deleted_at IS NOT NULL AS "deleted!"
+FROM messages
+JOIN participants AS senders ON senders.id = messages.sender
+JOIN users ON users.id = senders.identity
+JOIN conversations ON conversations.id = messages.conversation
+LEFT JOIN attachments ON attachments.id = messages.attachment_id
+WHERE messages.id = $2
+AND ($3::BOOLEAN OR messages.deleted_at IS NULL)
+AND (conversations.retention_seconds IS NULL
+    OR messages.created_at >= NOW() - make_interval(secs => conversations.retention_seconds))
+AND messages.conversation IN (
+    SELECT participants.conversation
+    FROM participants
+    JOIN users ON users.id = participants.identity
+    WHERE users.email = $1
+)
\ No newline at end of file
@@ -0,0 +1,7 @@
+SELECT messages.id, messages.seq
+FROM messages
+JOIN participants ON participants.id = messages.sender
+JOIN users ON users.id = participants.identity
+WHERE users.email = $1
+AND messages.conversation = $2
+AND messages.idempotency_key = $3
\ No newline at end of file
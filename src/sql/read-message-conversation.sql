@@ -0,0 +1 @@
+SELECT conversation FROM messages WHERE id = $1
\ No newline at end of file
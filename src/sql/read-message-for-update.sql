@@ -0,0 +1,8 @@
+SELECT messages.id, messages.conversation
+FROM messages
+JOIN participants ON participants.id = messages.sender
+JOIN users ON users.id = participants.identity
+WHERE users.email = $1
+AND messages.id = $2
+AND messages.deleted_at IS NULL
+FOR UPDATE OF messages
\ No newline at end of file
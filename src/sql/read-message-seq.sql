@@ -0,0 +1 @@
+SELECT seq FROM messages WHERE id = $1 AND conversation = $2
\ No newline at end of file
@@ -1,12 +1,28 @@
-SELECT messages.data, messages.media_type, messages.timestamp, messages.signature, users.email
+SELECT messages.id, messages.seq, messages.data, messages.data_encoding, messages.media_type, messages.timestamp, messages.created_at, messages.edited_at, messages.signature, messages.parent_id, users.email, attachments.id AS "attachment_id?", attachments.media_type AS "attachment_media_type?", attachments.size AS "attachment_size?", messages.deleted_at IS NOT NULL AS "deleted!",
+    ARRAY(SELECT reactions.emoji FROM reactions WHERE reactions.message = messages.id ORDER BY reactions.id) AS "reaction_emojis!",
+    ARRAY(SELECT reacted.email FROM reactions JOIN users AS reacted ON reacted.id = reactions.identity WHERE reactions.message = messages.id ORDER BY reactions.id) AS "reaction_senders!"
 FROM messages
 JOIN participants ON participants.id = messages.sender
 JOIN users ON users.id = participants.identity
-JOIN conversations ON conversations.id = participants.conversation
-WHERE (conversations.id = $2)
+JOIN conversations ON conversations.id = messages.conversation
+LEFT JOIN attachments ON attachments.id = messages.attachment_id
+WHERE (messages.conversation = $2)
 AND ($2 IN (
     SELECT conversation
     FROM participants
     JOIN users ON users.id = participants.identity
     WHERE users.email = $1
-))
\ No newline at end of file
+))
+AND ($5::INT IS NULL OR messages.seq > (SELECT seq FROM messages WHERE id = $5))
+AND ($6::INT IS NULL OR messages.seq < (SELECT seq FROM messages WHERE id = $6))
+AND ($7::TIMESTAMPTZ IS NULL OR messages.created_at >= $7)
+AND ($8::TIMESTAMPTZ IS NULL OR messages.created_at < $8)
+AND ($9::INT IS NULL OR messages.parent_id = $9)
+AND (NOT $10::BOOLEAN OR users.email <> $1)
+AND ($11::BOOLEAN OR messages.deleted_at IS NULL)
+AND (conversations.retention_seconds IS NULL
+    OR messages.created_at >= NOW() - make_interval(secs => conversations.retention_seconds))
+ORDER BY
+    CASE WHEN $5::INT IS NULL THEN messages.seq END DESC,
+    messages.seq ASC
+LIMIT $3 OFFSET $4
\ No newline at end of file
@@ -0,0 +1,7 @@
+SELECT id, name
+FROM conversations
+WHERE public
+AND name ILIKE '%' || $1 || '%'
+AND ($2::INT IS NULL OR id < $2)
+ORDER BY id DESC
+LIMIT $3
\ No newline at end of file
@@ -0,0 +1,9 @@
+SELECT messages.id, messages.seq, messages.data, messages.data_encoding, messages.media_type, messages.timestamp, messages.created_at, messages.signature, messages.parent_id, users.email, attachments.id AS "attachment_id?", attachments.media_type AS "attachment_media_type?", attachments.size AS "attachment_size?"
+FROM messages
+JOIN participants AS senders ON senders.id = messages.sender
+JOIN users ON users.id = senders.identity
+LEFT JOIN attachments ON attachments.id = messages.attachment_id
+WHERE messages.conversation = $1
+AND messages.id = ANY($2)
+AND messages.deleted_at IS NULL
+ORDER BY messages.seq
\ No newline at end of file
@@ -0,0 +1,7 @@
+SELECT participants.id, messages.seq AS "seq?"
+FROM participants
+JOIN users ON users.id = participants.identity
+LEFT JOIN messages ON messages.id = participants.last_read_message_id
+WHERE users.email = $1
+AND participants.conversation = $2
+FOR UPDATE OF participants
\ No newline at end of file
@@ -0,0 +1,3 @@
+SELECT id, retention_seconds AS "retention_seconds!"
+FROM conversations
+WHERE retention_seconds IS NOT NULL
\ No newline at end of file
@@ -0,0 +1,5 @@
+SELECT message_revisions.data, message_revisions.data_encoding, message_revisions.media_type, message_revisions.timestamp, message_revisions.signature, message_revisions.edited_at, messages.conversation
+FROM message_revisions
+JOIN messages ON messages.id = message_revisions.message
+WHERE message_revisions.message = $1
+ORDER BY message_revisions.id ASC
\ No newline at end of file
@@ -0,0 +1 @@
+SELECT id, email, public_key, display_name, avatar_url FROM users WHERE email = $1
\ No newline at end of file
@@ -0,0 +1,14 @@
+SELECT participants.conversation AS id, COUNT(messages.id) AS "unread!"
+FROM participants
+JOIN conversations ON conversations.id = participants.conversation
+LEFT JOIN messages AS read ON read.id = participants.last_read_message_id
+LEFT JOIN messages ON messages.conversation = participants.conversation
+    AND messages.sender <> participants.id
+    AND messages.deleted_at IS NULL
+    AND (conversations.retention_seconds IS NULL
+        OR messages.created_at >= NOW() - make_interval(secs => conversations.retention_seconds))
+    AND messages.seq > COALESCE(read.seq, 0)
+WHERE participants.identity = (
+    SELECT id FROM users WHERE email = $1
+)
+GROUP BY participants.conversation
\ No newline at end of file
@@ -0,0 +1 @@
+SELECT data FROM upload_chunks WHERE upload = $1 ORDER BY position
\ No newline at end of file
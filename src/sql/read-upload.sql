@@ -0,0 +1,6 @@
+SELECT uploads.id, uploads.conversation, uploads.media_type, uploads.size, uploads.received, uploads.next_index
+FROM uploads
+JOIN users ON users.id = uploads.owner
+WHERE users.email = $1
+AND uploads.id = $2
+FOR UPDATE OF uploads
\ No newline at end of file
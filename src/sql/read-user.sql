@@ -1,4 +1,4 @@
-SELECT users.email, participants.display_name, users.public_key
+SELECT users.email, participants.display_name AS name, users.public_key, users.display_name, users.avatar_url, participants.last_read_message_id
 FROM users
 JOIN participants ON participants.identity = users.id
 JOIN conversations ON conversations.id = participants.conversation
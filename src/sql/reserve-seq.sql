@@ -0,0 +1,4 @@
+UPDATE conversations
+SET last_seq = last_seq + $2
+WHERE id = $1
+RETURNING last_seq
\ No newline at end of file
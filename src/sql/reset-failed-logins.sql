@@ -0,0 +1,4 @@
+UPDATE users
+SET failed_attempts = 0,
+    locked_until = NULL
+WHERE email = $1
\ No newline at end of file
@@ -0,0 +1,19 @@
+SELECT messages.id, messages.seq, messages.conversation, messages.data, messages.data_encoding, messages.media_type, messages.timestamp, messages.created_at, messages.signature, messages.parent_id, users.email
+FROM messages
+JOIN participants AS senders ON senders.id = messages.sender
+JOIN users ON users.id = senders.identity
+JOIN conversations ON conversations.id = messages.conversation
+CROSS JOIN plainto_tsquery('simple', $2) AS query
+WHERE messages.search @@ query
+AND messages.deleted_at IS NULL
+AND (conversations.retention_seconds IS NULL
+    OR messages.created_at >= NOW() - make_interval(secs => conversations.retention_seconds))
+AND messages.conversation IN (
+    SELECT participants.conversation
+    FROM participants
+    JOIN users ON users.id = participants.identity
+    WHERE users.email = $1
+)
+AND ($3::INT IS NULL OR messages.conversation = $3)
+ORDER BY ts_rank(messages.search, query) DESC, messages.id DESC
+LIMIT $4 OFFSET $5
\ No newline at end of file
@@ -0,0 +1,2 @@
+INSERT INTO audit_log (actor, action, target, outcome, created_at)
+VALUES (?1, ?2, ?3, ?4, ?5)
\ No newline at end of file
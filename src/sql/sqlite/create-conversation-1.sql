@@ -0,0 +1,2 @@
+INSERT INTO conversations (name, direct_key, public)
+VALUES (?1, ?2, ?3)
\ No newline at end of file
@@ -0,0 +1,8 @@
+INSERT INTO invitations (conversation, inviter, invitee, created_at)
+VALUES (
+    ?1,
+    (SELECT id FROM users WHERE email = ?2),
+    (SELECT id FROM users WHERE LOWER(email) = LOWER(?3)),
+    ?4
+)
+ON CONFLICT (conversation, invitee) DO NOTHING
\ No newline at end of file
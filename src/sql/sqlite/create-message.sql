@@ -0,0 +1,7 @@
+INSERT INTO messages (sender, conversation, seq, data, media_type, timestamp, signature, idempotency_key, parent_id, attachment_id, data_encoding, preview, created_at)
+SELECT participants.id, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13
+FROM participants
+JOIN users ON users.id = participants.identity
+WHERE users.email = ?1
+AND participants.conversation = ?2
+ON CONFLICT (sender, idempotency_key) DO NOTHING
\ No newline at end of file
@@ -0,0 +1,3 @@
+INSERT INTO users (email, public_key, pass, salt, display_name, avatar_url)
+VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+ON CONFLICT (email) DO NOTHING
\ No newline at end of file
@@ -0,0 +1,7 @@
+SELECT EXISTS (
+    SELECT 1
+    FROM blocks
+    JOIN participants ON participants.identity = blocks.blocker
+    WHERE participants.conversation = ?2
+    AND blocks.blocked = (SELECT id FROM users WHERE email = ?1)
+) AS is_blocked
\ No newline at end of file
@@ -0,0 +1,7 @@
+SELECT EXISTS (
+    SELECT 1
+    FROM participants
+    JOIN users ON users.id = participants.identity
+    WHERE users.email = ?1
+    AND participants.conversation = ?2
+) AS is_member
\ No newline at end of file
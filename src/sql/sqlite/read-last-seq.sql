@@ -0,0 +1 @@
+SELECT last_seq FROM conversations WHERE id = ?1
\ No newline at end of file
@@ -0,0 +1,27 @@
+SELECT messages.id, messages.seq, messages.data, messages.data_encoding, messages.media_type, messages.timestamp, messages.created_at, messages.edited_at, messages.signature, messages.parent_id, users.email, attachments.id AS attachment_id, attachments.media_type AS attachment_media_type, attachments.size AS attachment_size, messages.deleted_at IS NOT NULL AS deleted
+FROM messages
+JOIN participants ON participants.id = messages.sender
+JOIN users ON users.id = participants.identity
+JOIN conversations ON conversations.id = messages.conversation
+LEFT JOIN attachments ON attachments.id = messages.attachment_id
+WHERE (messages.conversation = ?2)
+AND (?2 IN (
+    SELECT conversation
+    FROM participants
+    JOIN users ON users.id = participants.identity
+    WHERE users.email = ?1
+))
+AND (?5 IS NULL OR messages.seq > (SELECT seq FROM messages WHERE id = ?5))
+AND (?6 IS NULL OR messages.seq < (SELECT seq FROM messages WHERE id = ?6))
+AND (?13 IS NULL OR CASE WHEN ?5 IS NULL THEN messages.seq < ?13 ELSE messages.seq > ?13 END)
+AND (?7 IS NULL OR messages.created_at >= ?7)
+AND (?8 IS NULL OR messages.created_at < ?8)
+AND (?9 IS NULL OR messages.parent_id = ?9)
+AND (NOT ?10 OR users.email <> ?1)
+AND (?11 OR messages.deleted_at IS NULL)
+AND (conversations.retention_seconds IS NULL
+    OR messages.created_at >= ?12 - conversations.retention_seconds * 1000)
+ORDER BY
+    CASE WHEN ?5 IS NULL THEN messages.seq END DESC,
+    messages.seq ASC
+LIMIT ?3 OFFSET ?4
\ No newline at end of file
@@ -0,0 +1 @@
+SELECT public_key FROM users WHERE email = ?1
\ No newline at end of file
@@ -0,0 +1,5 @@
+SELECT reactions.emoji, users.email
+FROM reactions
+JOIN users ON users.id = reactions.identity
+WHERE reactions.message = ?1
+ORDER BY reactions.id
\ No newline at end of file
@@ -0,0 +1,3 @@
+UPDATE conversations
+SET last_seq = last_seq + ?2
+WHERE id = ?1
\ No newline at end of file
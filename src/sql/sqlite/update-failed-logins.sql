@@ -0,0 +1,4 @@
+UPDATE users
+SET failed_attempts = ?2,
+    locked_until = ?3
+WHERE email = ?1
\ No newline at end of file
@@ -1,5 +0,0 @@
-CREATE TABLE conversations (
-    id SERIAL PRIMARY KEY,
-    name VARCHAR(50) UNIQUE NOT NULL,
-    timestamp BYTEA
-)
\ No newline at end of file
@@ -1 +0,0 @@
-DROP TABLE IF EXISTS messages, participants, conversations, users CASCADE
\ No newline at end of file
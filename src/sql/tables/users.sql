@@ -1,7 +0,0 @@
-CREATE TABLE users (
-    id SERIAL PRIMARY KEY,
-    email VARCHAR(50) UNIQUE NOT NULL,
-    public_key BYTEA NOT NULL,
-    pass BYTEA NOT NULL,
-    salt BYTEA NOT NULL
-)
\ No newline at end of file
@@ -0,0 +1,9 @@
+DELETE FROM message_revisions
+WHERE message = $1
+AND id NOT IN (
+    SELECT id
+    FROM message_revisions
+    WHERE message = $1
+    ORDER BY id DESC
+    LIMIT $2
+)
\ No newline at end of file
@@ -0,0 +1,4 @@
+UPDATE participants
+SET archived = $3
+WHERE conversation = $2
+AND identity = (SELECT id FROM users WHERE email = $1)
\ No newline at end of file
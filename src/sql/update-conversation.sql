@@ -0,0 +1,5 @@
+UPDATE conversations
+SET name = COALESCE($2, name),
+    public = COALESCE($3, public) AND direct_key IS NULL,
+    retention_seconds = CASE WHEN $4::INT IS NULL THEN retention_seconds ELSE NULLIF($4, 0) END
+WHERE id = $1
\ No newline at end of file
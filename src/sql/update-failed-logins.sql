@@ -0,0 +1,5 @@
+UPDATE users
+SET failed_attempts = CASE WHEN $2::INT > 0 AND failed_attempts + 1 >= $2::INT THEN 0 ELSE failed_attempts + 1 END,
+    locked_until = CASE WHEN $2::INT > 0 AND failed_attempts + 1 >= $2::INT THEN NOW() + make_interval(secs => $3::INT) ELSE locked_until END
+WHERE email = $1
+RETURNING failed_attempts
\ No newline at end of file
@@ -0,0 +1,6 @@
+UPDATE invitations
+SET status = $3
+WHERE id = $2
+AND status = 'pending'
+AND invitee = (SELECT id FROM users WHERE email = $1)
+RETURNING conversation
\ No newline at end of file
@@ -0,0 +1,10 @@
+UPDATE messages
+SET data = $2,
+    media_type = $3,
+    timestamp = $4,
+    signature = $5,
+    search = to_tsvector('simple', $6),
+    data_encoding = $7,
+    preview = $8,
+    edited_at = NOW()
+WHERE id = $1
\ No newline at end of file
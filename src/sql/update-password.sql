@@ -0,0 +1,4 @@
+UPDATE users
+SET pass = $2,
+    salt = $3
+WHERE email = $1
\ No newline at end of file
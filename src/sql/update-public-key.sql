@@ -0,0 +1,3 @@
+UPDATE users
+SET public_key = $2
+WHERE email = $1
\ No newline at end of file
@@ -0,0 +1,3 @@
+UPDATE participants
+SET last_read_message_id = $2
+WHERE id = $1
\ No newline at end of file
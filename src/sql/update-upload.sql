@@ -0,0 +1,5 @@
+UPDATE uploads
+SET received = received + $2,
+    next_index = next_index + 1,
+    updated_at = NOW()
+WHERE id = $1
\ No newline at end of file
@@ -0,0 +1,4 @@
+UPDATE users
+SET display_name = COALESCE($2, display_name),
+    avatar_url = COALESCE($3, avatar_url)
+WHERE email = $1
\ No newline at end of file
@@ -1 +1 @@
-SELECT pass, salt FROM users WHERE email = $1
\ No newline at end of file
+SELECT pass, salt, failed_attempts, locked_until FROM users WHERE email = $1
\ No newline at end of file
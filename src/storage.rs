@@ -0,0 +1,1267 @@
+use crate::api::request::{ROLE_ADMIN, ROLE_MEMBER};
+use crate::auth::{Lockout, Password};
+use crate::database;
+use crate::push;
+
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::error::Error;
+use std::io::Error as ioErr;
+use std::io::ErrorKind as ioErrKind;
+use std::pin::Pin;
+use async_std::stream::{Stream, StreamExt};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use sqlx::PgPool;
+
+#[cfg(feature = "sqlite")]
+mod sqlite;
+
+#[cfg(feature = "sqlite")]
+pub use sqlite::SqliteStorage;
+
+/// What's stored about a user for checking their login
+pub struct StoredLogin {
+    pub password: Password,
+    pub failed_attempts: i32,
+    pub locked_until: Option<DateTime<Utc>>,
+}
+
+/// A conversation to be created, with the users who start in it and those who are invited to it
+pub struct NewConversation {
+    pub name: String,
+    pub direct_key: Option<String>,
+    pub public: bool,
+    pub creator: String,
+    pub members: Vec<String>,
+    pub invitees: Vec<String>,
+}
+
+/// A conversation after it's been stored
+#[derive(Debug, PartialEq)]
+pub struct StoredConversation {
+    pub id: i32,
+    pub name: String,
+    /// Whether the conversation is new, rather than an existing direct conversation both users are in
+    pub created: bool,
+}
+
+/// A user to be registered, with their password already hashed
+pub struct NewUser {
+    pub email: String,
+    pub public_key: Vec<u8>,
+    pub password: Password,
+    pub display_name: Option<String>,
+    pub avatar_url: Option<String>,
+}
+
+/// Messages to be stored together in a conversation
+pub struct NewMessages {
+    pub sender: String,
+    pub conversation_id: i32,
+    /// The sender's connection, which isn't pushed its own messages
+    pub connection: u64,
+    pub messages: Vec<NewMessage>,
+}
+
+/// A message that has passed validation, with its data prepared for storage
+pub struct NewMessage {
+    pub data: Vec<u8>,
+    /// How the data was compressed, if it was
+    pub data_encoding: Option<String>,
+    pub preview: Option<String>,
+    pub media_type: Vec<u8>,
+    pub timestamp: i64,
+    pub signature: Vec<u8>,
+    pub idempotency_key: Option<String>,
+    pub search: Option<String>,
+    pub parent_id: Option<i32>,
+    pub attachment_id: Option<i32>,
+}
+
+/// Where a message ended up once it was stored
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StoredMessageId {
+    pub id: i32,
+    pub seq: i32,
+    /// Whether the message had already been stored with the same idempotency key, rather than being new
+    pub duplicate: bool,
+}
+
+/// Which of a conversation's messages to read, and how many
+#[derive(Clone, Debug, Default)]
+pub struct MessageQuery {
+    pub limit: i64,
+    pub offset: i64,
+    /// Only read messages after this one, oldest first (otherwise messages are read newest first)
+    pub after_id: Option<i32>,
+    pub before_id: Option<i32>,
+    /// Only read messages the server received from this time (inclusive)
+    pub since: Option<DateTime<Utc>>,
+    /// Only read messages the server received before this time
+    pub until: Option<DateTime<Utc>>,
+    pub parent_id: Option<i32>,
+    /// Leave out the reader's own messages
+    pub exclude_self: bool,
+    /// Keep deleted messages (as tombstones) rather than leaving them out
+    pub include_deleted: bool,
+}
+
+/// A message as it's stored, with who sent it and what's attached and reacted to it
+pub struct StoredMessage {
+    pub id: i32,
+    pub seq: i32,
+    pub data: Vec<u8>,
+    pub data_encoding: Option<String>,
+    pub media_type: Option<Vec<u8>>,
+    pub timestamp: Option<i64>,
+    pub created_at: DateTime<Utc>,
+    pub edited_at: Option<DateTime<Utc>>,
+    pub signature: Option<Vec<u8>>,
+    pub sender: String,
+    pub parent_id: Option<i32>,
+    pub attachment_id: Option<i32>,
+    pub attachment_media_type: Option<Vec<u8>>,
+    pub attachment_size: Option<i32>,
+    /// Each reaction's emoji and the email of whoever sent it, in the order they were added
+    pub reactions: Vec<(String, String)>,
+    pub deleted: bool,
+}
+
+/// Messages read one at a time, so a long conversation never has to be held in memory all at once
+pub type MessageStream<'a> = Pin<Box<dyn Stream<Item = Result<StoredMessage, sqlx::Error>> + Send + 'a>>;
+
+/// Put messages stored in one batch back into the order they were sent, given the first sequence number of the batch
+///
+/// Sequence numbers follow the order of the batch, so each message's position is its offset from the first one.
+/// Positions left empty were already stored by a retried request.
+fn order_by_seq(rows: Vec<(i32, i32)>, first_seq: i32, count: usize) -> Vec<Option<(i32, i32)>> {
+    let mut ordered = vec![None; count];
+
+    for (id, seq) in rows {
+        if let Some(slot) = usize::try_from(seq - first_seq).ok().and_then(|i| ordered.get_mut(i)) {
+            *slot = Some((id, seq));
+        }
+    }
+
+    ordered
+}
+
+/// Where handlers keep and find their data
+///
+/// Handlers that take a storage rather than a database pool can be tested without Postgres, and are the ones a SQLite
+/// database can serve. So far VERIFY USERS, CREATE USERS, CREATE CONVERSATIONS, CREATE PARTICIPANTS, CREATE MESSAGES
+/// and reading a conversation's messages (and the password checks behind re-authentication) take one; the other
+/// handlers still query the Postgres pool directly.
+#[async_trait]
+pub trait Storage: Send + Sync {
+    /// Get a user's password and failed logins, if the user exists
+    async fn get_user_by_email(&self, email: &str) -> Result<Option<StoredLogin>, Box<dyn Error>>;
+    /// Count a failed login for a user in one step (so concurrent failures are all counted), locking their account
+    /// if the lockout rules say so, and returning the new number of failures in a row if the user exists
+    async fn add_failed_login(&self, email: &str, lockout: &Lockout) -> Result<Option<i32>, Box<dyn Error>>;
+    /// Start counting a user's failed logins again and unlock their account
+    async fn reset_failed_logins(&self, email: &str) -> Result<(), Box<dyn Error>>;
+    /// Find which of some (lowercase) emails belong to users
+    async fn existing_users(&self, emails: &[String]) -> Result<Vec<String>, Box<dyn Error>>;
+    /// Add users in one step, skipping emails that are already registered (or repeated), and returning the id and
+    /// email of each user that was added
+    async fn insert_users(&self, users: &[NewUser]) -> Result<Vec<(i32, String)>, Box<dyn Error>>;
+    /// Get a user's public key, if the user exists
+    async fn get_public_key(&self, email: &str) -> Result<Option<Vec<u8>>, Box<dyn Error>>;
+    /// Add a conversation along with its participants and invitations, unless a direct conversation that both users
+    /// are already participants of exists (in which case that one is returned)
+    async fn insert_conversation(&self, conversation: &NewConversation) -> Result<StoredConversation, Box<dyn Error>>;
+    /// Check if a conversation exists
+    async fn conversation_exists(&self, conversation_id: i32) -> Result<bool, Box<dyn Error>>;
+    /// Check if a conversation is public, if the conversation exists
+    async fn is_public(&self, conversation_id: i32) -> Result<Option<bool>, Box<dyn Error>>;
+    /// Check if a user is a participant of a conversation
+    async fn is_member(&self, email: &str, conversation_id: i32) -> Result<bool, Box<dyn Error>>;
+    /// Check if any participant in a conversation has blocked a user
+    async fn is_blocked(&self, email: &str, conversation_id: i32) -> Result<bool, Box<dyn Error>>;
+    /// Add a user to conversations with a role, all or none of them at once, leaving any they're already in as they
+    /// were
+    async fn add_member(&self, email: &str, conversation_ids: &[i32], role: &str) -> Result<(), Box<dyn Error>>;
+    /// Find the conversation a message belongs to, if the message exists
+    async fn message_conversation(&self, message_id: i32) -> Result<Option<i32>, Box<dyn Error>>;
+    /// Find the conversation an attachment belongs to, if the attachment exists
+    async fn attachment_conversation(&self, attachment_id: i32) -> Result<Option<i32>, Box<dyn Error>>;
+    /// Store messages in one step, in the order they're given, and let the conversation's other connected members
+    /// know about them
+    ///
+    /// Messages already stored with the same idempotency key (by an earlier request, or earlier in the same batch)
+    /// aren't stored again and don't take up a sequence number; they're given the id they were first stored with.
+    async fn insert_messages(&self, messages: &NewMessages) -> Result<Vec<StoredMessageId>, Box<dyn Error>>;
+    /// Read messages from a conversation a user is in, in sequence order
+    fn list_messages<'a>(&'a self, email: &str, conversation_id: i32, query: &MessageQuery) -> MessageStream<'a>;
+}
+
+/// Storage in the Postgres database
+pub struct PgStorage<'a> {
+    db_pool: &'a PgPool,
+}
+
+impl<'a> PgStorage<'a> {
+    /// Use a database pool for storage
+    pub fn new(db_pool: &'a PgPool) -> Self {
+        PgStorage{ db_pool }
+    }
+}
+
+#[async_trait]
+impl<'a> Storage for PgStorage<'a> {
+    async fn get_user_by_email(&self, email: &str) -> Result<Option<StoredLogin>, Box<dyn Error>> {
+        let user = sqlx::query_file!("src/sql/verify-user.sql", email)
+            .fetch_optional(self.db_pool)
+            .await?;
+
+        Ok(user.map(|u| StoredLogin{
+            password: Password{
+                hash: u.pass,
+                salt: u.salt,
+            },
+            failed_attempts: u.failed_attempts,
+            locked_until: u.locked_until,
+        }))
+    }
+
+    async fn add_failed_login(&self, email: &str, lockout: &Lockout) -> Result<Option<i32>, Box<dyn Error>> {
+        let user = sqlx::query_file!("src/sql/update-failed-logins.sql", email, lockout.max_attempts, lockout.duration.num_seconds() as i32)
+            .fetch_optional(self.db_pool)
+            .await?;
+
+        Ok(user.map(|u| u.failed_attempts))
+    }
+
+    async fn reset_failed_logins(&self, email: &str) -> Result<(), Box<dyn Error>> {
+        sqlx::query_file!("src/sql/reset-failed-logins.sql", email)
+            .execute(self.db_pool)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn existing_users(&self, emails: &[String]) -> Result<Vec<String>, Box<dyn Error>> {
+        Ok(sqlx::query_file!("src/sql/read-existing-users.sql", emails)
+            .fetch_all(self.db_pool)
+            .await?
+            .into_iter()
+            .map(|u| u.email)
+            .collect())
+    }
+
+    async fn insert_users(&self, users: &[NewUser]) -> Result<Vec<(i32, String)>, Box<dyn Error>> {
+        let emails: Vec<String> = users.iter().map(|u| u.email.clone()).collect();
+        let public_keys: Vec<Vec<u8>> = users.iter().map(|u| u.public_key.clone()).collect();
+        let hashes: Vec<Vec<u8>> = users.iter().map(|u| u.password.hash.clone()).collect();
+        let salts: Vec<Vec<u8>> = users.iter().map(|u| u.password.salt.clone()).collect();
+        // Missing profile fields are sent as empty values, which the statement turns back into NULL
+        let display_names: Vec<String> = users.iter().map(|u| u.display_name.clone().unwrap_or_default()).collect();
+        let avatar_urls: Vec<String> = users.iter().map(|u| u.avatar_url.clone().unwrap_or_default()).collect();
+
+        Ok(sqlx::query_file!("src/sql/create-user.sql",
+                &emails,
+                &public_keys,
+                &hashes,
+                &salts,
+                &display_names,
+                &avatar_urls)
+            .fetch_all(self.db_pool)
+            .await?
+            .into_iter()
+            .map(|u| (u.id, u.email))
+            .collect())
+    }
+
+    async fn get_public_key(&self, email: &str) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        let user = sqlx::query_file!("src/sql/read-public-key.sql", email)
+            .fetch_optional(self.db_pool)
+            .await?;
+
+        Ok(user.map(|u| u.public_key))
+    }
+
+    async fn insert_conversation(&self, conversation: &NewConversation) -> Result<StoredConversation, Box<dyn Error>> {
+        // Run all inserts in a transaction so a failure leaves no partial conversation
+        let mut tx = self.db_pool.begin().await?;
+
+        // A direct conversation is only reused if both users are already in it, and creating one is locked by its key
+        // so two can't be made at once
+        if let Some(direct_key) = &conversation.direct_key {
+            sqlx::query_file!("src/sql/lock-direct-conversation.sql", direct_key)
+                .execute(&mut tx)
+                .await?;
+
+            let emails: Vec<String> = std::iter::once(conversation.creator.to_lowercase())
+                .chain(conversation.members.iter().cloned())
+                .chain(conversation.invitees.iter().cloned())
+                .collect();
+            let existing = sqlx::query_file!("src/sql/read-direct-conversation.sql", direct_key, &emails)
+                .fetch_optional(&mut tx)
+                .await?;
+
+            if let Some(existing) = existing {
+                return Ok(StoredConversation{
+                    id: existing.id,
+                    name: existing.name,
+                    created: false,
+                });
+            }
+        }
+
+        let id = sqlx::query_file!("src/sql/create-conversation-1.sql", conversation.name, conversation.direct_key, conversation.public)
+            .fetch_one(&mut tx)
+            .await?
+            .id;
+
+        // Add creator user
+        sqlx::query_file!("src/sql/create-conversation-2.sql", conversation.creator, id, ROLE_ADMIN)
+            .execute(&mut tx)
+            .await?;
+
+        // Add remaining users, and invite those who must accept first
+        for email in &conversation.members {
+            sqlx::query_file!("src/sql/create-conversation-2.sql", email, id, ROLE_MEMBER)
+                .execute(&mut tx)
+                .await?;
+        }
+
+        for email in &conversation.invitees {
+            sqlx::query_file!("src/sql/create-invitation.sql", id, conversation.creator, email)
+                .execute(&mut tx)
+                .await?;
+        }
+
+        tx.commit().await?;
+
+        Ok(StoredConversation{
+            id,
+            name: conversation.name.clone(),
+            created: true,
+        })
+    }
+
+    async fn conversation_exists(&self, conversation_id: i32) -> Result<bool, Box<dyn Error>> {
+        Ok(sqlx::query_file!("src/sql/conversation-exists.sql", conversation_id)
+            .fetch_one(self.db_pool)
+            .await?
+            .exists)
+    }
+
+    async fn is_public(&self, conversation_id: i32) -> Result<Option<bool>, Box<dyn Error>> {
+        let conversation = sqlx::query_file!("src/sql/is-public.sql", conversation_id)
+            .fetch_optional(self.db_pool)
+            .await?;
+
+        Ok(conversation.map(|c| c.public))
+    }
+
+    async fn is_member(&self, email: &str, conversation_id: i32) -> Result<bool, Box<dyn Error>> {
+        Ok(sqlx::query_file!("src/sql/is-member.sql", email, conversation_id)
+            .fetch_one(self.db_pool)
+            .await?
+            .is_member)
+    }
+
+    async fn is_blocked(&self, email: &str, conversation_id: i32) -> Result<bool, Box<dyn Error>> {
+        Ok(sqlx::query_file!("src/sql/is-blocked.sql", email, conversation_id)
+            .fetch_one(self.db_pool)
+            .await?
+            .is_blocked)
+    }
+
+    async fn add_member(&self, email: &str, conversation_ids: &[i32], role: &str) -> Result<(), Box<dyn Error>> {
+        let mut tx = self.db_pool.begin().await?;
+
+        for conversation_id in conversation_ids {
+            sqlx::query_file!("src/sql/create-conversation-2.sql", email, conversation_id, role)
+                .execute(&mut tx)
+                .await?;
+        }
+
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    async fn message_conversation(&self, message_id: i32) -> Result<Option<i32>, Box<dyn Error>> {
+        let message = sqlx::query_file!("src/sql/read-message-conversation.sql", message_id)
+            .fetch_optional(self.db_pool)
+            .await?;
+
+        Ok(message.map(|m| m.conversation))
+    }
+
+    async fn attachment_conversation(&self, attachment_id: i32) -> Result<Option<i32>, Box<dyn Error>> {
+        let attachment = sqlx::query_file!("src/sql/read-attachment-conversation.sql", attachment_id)
+            .fetch_optional(self.db_pool)
+            .await?;
+
+        Ok(attachment.map(|a| a.conversation))
+    }
+
+    async fn insert_messages(&self, messages: &NewMessages) -> Result<Vec<StoredMessageId>, Box<dyn Error>> {
+        let NewMessages{ sender, conversation_id, connection, messages } = messages;
+        let mut tx = self.db_pool.begin().await?;
+
+        // Messages already stored by a retried request, or repeated within this one, are left out before any sequence
+        // numbers are reserved, so they don't leave gaps
+        let keys: Vec<String> = messages.iter()
+            .filter_map(|m| m.idempotency_key.clone())
+            .collect();
+        let mut by_key: HashMap<String, (i32, i32)> = match keys.is_empty() {
+            true => HashMap::new(),
+            false => sqlx::query_file!("src/sql/read-messages-by-keys.sql", sender, conversation_id, &keys)
+                .fetch_all(&mut tx)
+                .await?
+                .into_iter()
+                .map(|r| (r.idempotency_key, (r.id, r.seq)))
+                .collect(),
+        };
+
+        let mut seen: HashSet<&str> = by_key.keys().map(String::as_str).collect();
+        let is_new: Vec<bool> = messages.iter()
+            .map(|m| m.idempotency_key.as_deref().map_or(true, |k| seen.insert(k)))
+            .collect();
+
+        let new_messages: Vec<&NewMessage> = messages.iter()
+            .zip(&is_new)
+            .filter(|(_, &is_new)| is_new)
+            .map(|(m, _)| m)
+            .collect();
+
+        let inserted = match new_messages.is_empty() {
+            true => Vec::new(),
+            false => {
+                let count = new_messages.len() as i32;
+                let last_seq = sqlx::query_file!("src/sql/reserve-seq.sql", conversation_id, count)
+                    .fetch_one(&mut tx)
+                    .await?
+                    .last_seq;
+                let first_seq = last_seq - count + 1;
+
+                // Optional fields are sent as empty values, which the statement turns back into NULL
+                let data: Vec<Vec<u8>> = new_messages.iter().map(|m| m.data.clone()).collect();
+                let data_encodings: Vec<String> = new_messages.iter().map(|m| m.data_encoding.clone().unwrap_or_default()).collect();
+                let previews: Vec<String> = new_messages.iter().map(|m| m.preview.clone().unwrap_or_default()).collect();
+                let media_types: Vec<Vec<u8>> = new_messages.iter().map(|m| m.media_type.clone()).collect();
+                let timestamps: Vec<i64> = new_messages.iter().map(|m| m.timestamp).collect();
+                let signatures: Vec<Vec<u8>> = new_messages.iter().map(|m| m.signature.clone()).collect();
+                let idempotency_keys: Vec<String> = new_messages.iter().map(|m| m.idempotency_key.clone().unwrap_or_default()).collect();
+                let searches: Vec<String> = new_messages.iter().map(|m| m.search.clone().unwrap_or_default()).collect();
+                let parent_ids: Vec<i32> = new_messages.iter().map(|m| m.parent_id.unwrap_or(0)).collect();
+                let attachment_ids: Vec<i32> = new_messages.iter().map(|m| m.attachment_id.unwrap_or(0)).collect();
+
+                // Messages stored by a concurrent request since they were looked up are skipped
+                let rows = sqlx::query_file!("src/sql/create-message.sql",
+                        sender,
+                        conversation_id,
+                        first_seq - 1,
+                        &data,
+                        &media_types,
+                        &timestamps,
+                        &signatures,
+                        &idempotency_keys,
+                        &searches,
+                        &parent_ids,
+                        &attachment_ids,
+                        &data_encodings,
+                        &previews)
+                    .fetch_all(&mut tx)
+                    .await?;
+
+                let rows = rows.into_iter()
+                    .map(|r| (r.id, r.seq))
+                    .collect();
+
+                order_by_seq(rows, first_seq, new_messages.len())
+            },
+        };
+
+        let mut inserted = inserted.into_iter();
+        let mut stored = Vec::new();
+
+        for (message, is_new) in messages.iter().zip(is_new) {
+            let key = message.idempotency_key.as_deref().unwrap_or_default();
+
+            let (id, seq, duplicate) = match is_new {
+                true => match inserted.next().flatten() {
+                    Some((id, seq)) => (id, seq, false),
+                    // A concurrent request with the same key got there first
+                    None => {
+                        let existing = sqlx::query_file!("src/sql/read-message-by-key.sql",
+                                sender,
+                                conversation_id,
+                                key)
+                            .fetch_one(&mut tx)
+                            .await?;
+
+                        (existing.id, existing.seq, true)
+                    },
+                },
+                false => {
+                    let (id, seq) = by_key.get(key)
+                        .copied()
+                        .ok_or_else(|| ioErr::new(ioErrKind::Other, "Message was not stored"))?;
+
+                    (id, seq, true)
+                },
+            };
+
+            if is_new && !key.is_empty() {
+                by_key.insert(key.to_string(), (id, seq));
+            }
+
+            stored.push(StoredMessageId{ id, seq, duplicate });
+        }
+
+        // Let connected members (and the sender's other connections) know about the new messages once they're stored
+        // (a retried request has nothing new)
+        let created: Vec<i32> = stored.iter()
+            .filter(|m| !m.duplicate)
+            .map(|m| m.id)
+            .collect();
+        push::notify(&mut tx, *conversation_id, sender, *connection, &created).await?;
+
+        tx.commit().await?;
+
+        Ok(stored)
+    }
+
+    fn list_messages<'a>(&'a self, email: &str, conversation_id: i32, query: &MessageQuery) -> MessageStream<'a> {
+        // Newest first, or oldest first when syncing messages after a given id
+        let rows = sqlx::query_file!("src/sql/read-message.sql",
+                email,
+                conversation_id,
+                query.limit,
+                query.offset,
+                query.after_id,
+                query.before_id,
+                query.since,
+                query.until,
+                query.parent_id,
+                query.exclude_self,
+                query.include_deleted)
+            .fetch(self.db_pool)
+            .map(|row| row.map(|m| StoredMessage{
+                id: m.id,
+                seq: m.seq,
+                data: m.data,
+                data_encoding: m.data_encoding,
+                media_type: m.media_type,
+                timestamp: m.timestamp,
+                created_at: m.created_at,
+                edited_at: m.edited_at,
+                signature: m.signature,
+                sender: m.email,
+                parent_id: m.parent_id,
+                attachment_id: m.attachment_id,
+                attachment_media_type: m.attachment_media_type,
+                attachment_size: m.attachment_size,
+                reactions: m.reaction_emojis.into_iter().zip(m.reaction_senders).collect(),
+                deleted: m.deleted,
+            }));
+
+        Box::pin(rows)
+    }
+}
+
+/// Storage that tries operations again when they fail for a transient reason (e.g. a dropped connection)
+///
+/// Reads, resetting a user's failed logins and adding a member are safe to repeat, as is storing a direct conversation
+/// that both users join straight away, which is found again if the first attempt went through. Counting a failed login
+/// could count it twice, a repeat of adding users would report the ones it added as already registered, and messages
+/// and other conversations (including direct ones the other user is only invited to) have nothing to tell a repeat
+/// apart from a new one, so they're never retried. Messages are read as a stream, which isn't retried either.
+pub struct RetryStorage<S> {
+    inner: S,
+}
+
+impl<S: Storage> RetryStorage<S> {
+    /// Retry operations on another storage
+    pub fn new(inner: S) -> Self {
+        RetryStorage{ inner }
+    }
+}
+
+#[async_trait]
+impl<S: Storage> Storage for RetryStorage<S> {
+    async fn get_user_by_email(&self, email: &str) -> Result<Option<StoredLogin>, Box<dyn Error>> {
+        database::retry_boxed(|| self.inner.get_user_by_email(email)).await
+    }
+
+    async fn add_failed_login(&self, email: &str, lockout: &Lockout) -> Result<Option<i32>, Box<dyn Error>> {
+        self.inner.add_failed_login(email, lockout).await
+    }
+
+    async fn reset_failed_logins(&self, email: &str) -> Result<(), Box<dyn Error>> {
+        database::retry_boxed(|| self.inner.reset_failed_logins(email)).await
+    }
+
+    async fn existing_users(&self, emails: &[String]) -> Result<Vec<String>, Box<dyn Error>> {
+        database::retry_boxed(|| self.inner.existing_users(emails)).await
+    }
+
+    async fn insert_users(&self, users: &[NewUser]) -> Result<Vec<(i32, String)>, Box<dyn Error>> {
+        self.inner.insert_users(users).await
+    }
+
+    async fn get_public_key(&self, email: &str) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        database::retry_boxed(|| self.inner.get_public_key(email)).await
+    }
+
+    async fn insert_conversation(&self, conversation: &NewConversation) -> Result<StoredConversation, Box<dyn Error>> {
+        match conversation.direct_key.is_some() && conversation.invitees.is_empty() {
+            true => database::retry_boxed(|| self.inner.insert_conversation(conversation)).await,
+            false => self.inner.insert_conversation(conversation).await,
+        }
+    }
+
+    async fn conversation_exists(&self, conversation_id: i32) -> Result<bool, Box<dyn Error>> {
+        database::retry_boxed(|| self.inner.conversation_exists(conversation_id)).await
+    }
+
+    async fn is_public(&self, conversation_id: i32) -> Result<Option<bool>, Box<dyn Error>> {
+        database::retry_boxed(|| self.inner.is_public(conversation_id)).await
+    }
+
+    async fn is_member(&self, email: &str, conversation_id: i32) -> Result<bool, Box<dyn Error>> {
+        database::retry_boxed(|| self.inner.is_member(email, conversation_id)).await
+    }
+
+    async fn is_blocked(&self, email: &str, conversation_id: i32) -> Result<bool, Box<dyn Error>> {
+        database::retry_boxed(|| self.inner.is_blocked(email, conversation_id)).await
+    }
+
+    async fn add_member(&self, email: &str, conversation_ids: &[i32], role: &str) -> Result<(), Box<dyn Error>> {
+        database::retry_boxed(|| self.inner.add_member(email, conversation_ids, role)).await
+    }
+
+    async fn message_conversation(&self, message_id: i32) -> Result<Option<i32>, Box<dyn Error>> {
+        database::retry_boxed(|| self.inner.message_conversation(message_id)).await
+    }
+
+    async fn attachment_conversation(&self, attachment_id: i32) -> Result<Option<i32>, Box<dyn Error>> {
+        database::retry_boxed(|| self.inner.attachment_conversation(attachment_id)).await
+    }
+
+    async fn insert_messages(&self, messages: &NewMessages) -> Result<Vec<StoredMessageId>, Box<dyn Error>> {
+        self.inner.insert_messages(messages).await
+    }
+
+    fn list_messages<'a>(&'a self, email: &str, conversation_id: i32, query: &MessageQuery) -> MessageStream<'a> {
+        self.inner.list_messages(email, conversation_id, query)
+    }
+}
+
+#[cfg(test)]
+pub use memory::MemoryStorage;
+
+#[cfg(test)]
+mod memory {
+    use crate::api::request::{ROLE_ADMIN, ROLE_MEMBER};
+    use crate::auth::{Lockout, Password};
+    use crate::storage::{MessageQuery, MessageStream, NewConversation, NewMessages, NewUser, Storage, StoredConversation, StoredLogin, StoredMessage, StoredMessageId};
+
+    use std::collections::HashMap;
+    use std::error::Error;
+    use std::io::Error as ioErr;
+    use std::io::ErrorKind as ioErrKind;
+    use std::sync::Mutex;
+    use async_trait::async_trait;
+    use chrono::{DateTime, Utc};
+
+    /// A user as kept in memory
+    pub struct MemoryUser {
+        pub id: i32,
+        pub password: Password,
+        pub public_key: Vec<u8>,
+        pub failed_attempts: i32,
+        pub locked_until: Option<DateTime<Utc>>,
+    }
+
+    /// A conversation as kept in memory
+    pub struct MemoryConversation {
+        pub id: i32,
+        pub name: String,
+        pub direct_key: Option<String>,
+        pub public: bool,
+        /// Each participant's email and role
+        pub participants: Vec<(String, &'static str)>,
+        pub invitees: Vec<String>,
+    }
+
+    /// A message as kept in memory
+    pub struct MemoryMessage {
+        pub id: i32,
+        pub conversation: i32,
+        pub seq: i32,
+        pub sender: String,
+        pub data: Vec<u8>,
+        pub data_encoding: Option<String>,
+        pub preview: Option<String>,
+        pub media_type: Vec<u8>,
+        pub timestamp: i64,
+        pub signature: Vec<u8>,
+        pub idempotency_key: Option<String>,
+        pub parent_id: Option<i32>,
+        pub attachment_id: Option<i32>,
+        pub created_at: DateTime<Utc>,
+        pub deleted: bool,
+    }
+
+    /// Storage kept in memory, for testing handlers without a database
+    #[derive(Default)]
+    pub struct MemoryStorage {
+        pub users: Mutex<HashMap<String, MemoryUser>>,
+        pub conversations: Mutex<Vec<MemoryConversation>>,
+        pub messages: Mutex<Vec<MemoryMessage>>,
+        /// Each block's blocker and the user they blocked, by email
+        pub blocks: Mutex<Vec<(String, String)>>,
+    }
+
+    impl MemoryStorage {
+        /// Add a user with a (plaintext) password
+        pub fn add_user(&self, email: &str, password: &str) {
+            let mut users = self.users.lock().unwrap();
+            let id = users.len() as i32 + 1;
+
+            users.insert(String::from(email), MemoryUser{
+                id,
+                password: Password::hash(password, None).unwrap(),
+                public_key: vec![0; 32],
+                failed_attempts: 0,
+                locked_until: None,
+            });
+        }
+    }
+
+    #[async_trait]
+    impl Storage for MemoryStorage {
+        async fn get_user_by_email(&self, email: &str) -> Result<Option<StoredLogin>, Box<dyn Error>> {
+            let users = self.users.lock().unwrap();
+
+            Ok(users.get(email).map(|user| StoredLogin{
+                password: Password{
+                    hash: user.password.hash.clone(),
+                    salt: user.password.salt.clone(),
+                },
+                failed_attempts: user.failed_attempts,
+                locked_until: user.locked_until,
+            }))
+        }
+
+        async fn add_failed_login(&self, email: &str, lockout: &Lockout) -> Result<Option<i32>, Box<dyn Error>> {
+            let mut users = self.users.lock().unwrap();
+
+            Ok(users.get_mut(email).map(|user| {
+                let (failed_attempts, locked_until) = lockout.fail(user.failed_attempts, Utc::now());
+                user.failed_attempts = failed_attempts;
+                user.locked_until = locked_until.or(user.locked_until);
+                failed_attempts
+            }))
+        }
+
+        async fn reset_failed_logins(&self, email: &str) -> Result<(), Box<dyn Error>> {
+            if let Some(user) = self.users.lock().unwrap().get_mut(email) {
+                user.failed_attempts = 0;
+                user.locked_until = None;
+            }
+
+            Ok(())
+        }
+
+        async fn existing_users(&self, emails: &[String]) -> Result<Vec<String>, Box<dyn Error>> {
+            let users = self.users.lock().unwrap();
+
+            Ok(users
+                .keys()
+                .map(|email| email.to_lowercase())
+                .filter(|email| emails.contains(email))
+                .collect())
+        }
+
+        async fn insert_users(&self, new_users: &[NewUser]) -> Result<Vec<(i32, String)>, Box<dyn Error>> {
+            let mut users = self.users.lock().unwrap();
+            let mut created = Vec::new();
+
+            for user in new_users {
+                if users.contains_key(&user.email) {
+                    continue;
+                }
+
+                let id = users.len() as i32 + 1;
+                users.insert(user.email.clone(), MemoryUser{
+                    id,
+                    password: Password{
+                        hash: user.password.hash.clone(),
+                        salt: user.password.salt.clone(),
+                    },
+                    public_key: user.public_key.clone(),
+                    failed_attempts: 0,
+                    locked_until: None,
+                });
+                created.push((id, user.email.clone()));
+            }
+
+            Ok(created)
+        }
+
+        async fn get_public_key(&self, email: &str) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+            Ok(self.users.lock().unwrap().get(email).map(|user| user.public_key.clone()))
+        }
+
+        async fn insert_conversation(&self, conversation: &NewConversation) -> Result<StoredConversation, Box<dyn Error>> {
+            let mut conversations = self.conversations.lock().unwrap();
+
+            let users: Vec<String> = std::iter::once(conversation.creator.to_lowercase())
+                .chain(conversation.members.iter().cloned())
+                .chain(conversation.invitees.iter().cloned())
+                .collect();
+            let existing = conversations
+                .iter()
+                .find(|c| c.direct_key.is_some()
+                    && c.direct_key == conversation.direct_key
+                    && users.iter().all(|email| c.participants.iter().any(|(p, _)| p.eq_ignore_ascii_case(email))));
+
+            if let Some(existing) = existing {
+                return Ok(StoredConversation{
+                    id: existing.id,
+                    name: existing.name.clone(),
+                    created: false,
+                });
+            }
+
+            let id = conversations.len() as i32 + 1;
+            let mut participants = vec![(conversation.creator.clone(), ROLE_ADMIN)];
+            participants.extend(conversation.members.iter().map(|email| (email.clone(), ROLE_MEMBER)));
+
+            conversations.push(MemoryConversation{
+                id,
+                name: conversation.name.clone(),
+                direct_key: conversation.direct_key.clone(),
+                public: conversation.public,
+                participants,
+                invitees: conversation.invitees.clone(),
+            });
+
+            Ok(StoredConversation{
+                id,
+                name: conversation.name.clone(),
+                created: true,
+            })
+        }
+
+        async fn conversation_exists(&self, conversation_id: i32) -> Result<bool, Box<dyn Error>> {
+            Ok(self.conversations.lock().unwrap().iter().any(|c| c.id == conversation_id))
+        }
+
+        async fn is_public(&self, conversation_id: i32) -> Result<Option<bool>, Box<dyn Error>> {
+            Ok(self.conversations.lock().unwrap().iter().find(|c| c.id == conversation_id).map(|c| c.public))
+        }
+
+        async fn is_member(&self, email: &str, conversation_id: i32) -> Result<bool, Box<dyn Error>> {
+            Ok(self.conversations.lock().unwrap()
+                .iter()
+                .find(|c| c.id == conversation_id)
+                .map_or(false, |c| c.participants.iter().any(|(p, _)| p.eq_ignore_ascii_case(email))))
+        }
+
+        async fn is_blocked(&self, email: &str, conversation_id: i32) -> Result<bool, Box<dyn Error>> {
+            let blocks = self.blocks.lock().unwrap();
+            let mut blockers = blocks.iter()
+                .filter(|(_, blocked)| blocked.eq_ignore_ascii_case(email))
+                .map(|(blocker, _)| blocker);
+
+            Ok(self.conversations.lock().unwrap()
+                .iter()
+                .find(|c| c.id == conversation_id)
+                .map_or(false, |c| blockers.any(|blocker| c.participants.iter().any(|(p, _)| p.eq_ignore_ascii_case(blocker)))))
+        }
+
+        async fn add_member(&self, email: &str, conversation_ids: &[i32], role: &str) -> Result<(), Box<dyn Error>> {
+            let mut conversations = self.conversations.lock().unwrap();
+            let role = match role == ROLE_ADMIN {
+                true => ROLE_ADMIN,
+                false => ROLE_MEMBER,
+            };
+
+            if !conversation_ids.iter().all(|id| conversations.iter().any(|c| c.id == *id)) {
+                return Err(Box::new(ioErr::new(ioErrKind::NotFound, "Conversation does not exist")));
+            }
+
+            for conversation in conversations.iter_mut().filter(|c| conversation_ids.contains(&c.id)) {
+                if !conversation.participants.iter().any(|(p, _)| p.eq_ignore_ascii_case(email)) {
+                    conversation.participants.push((String::from(email), role));
+                }
+            }
+
+            Ok(())
+        }
+
+        async fn message_conversation(&self, message_id: i32) -> Result<Option<i32>, Box<dyn Error>> {
+            Ok(self.messages.lock().unwrap().iter().find(|m| m.id == message_id).map(|m| m.conversation))
+        }
+
+        async fn attachment_conversation(&self, _attachment_id: i32) -> Result<Option<i32>, Box<dyn Error>> {
+            // Attachments aren't kept in memory, so none of them exist
+            Ok(None)
+        }
+
+        async fn insert_messages(&self, new_messages: &NewMessages) -> Result<Vec<StoredMessageId>, Box<dyn Error>> {
+            let mut messages = self.messages.lock().unwrap();
+            let mut stored = Vec::new();
+
+            for message in &new_messages.messages {
+                let existing = messages.iter().find(|m| m.idempotency_key.is_some()
+                    && m.idempotency_key == message.idempotency_key
+                    && m.conversation == new_messages.conversation_id
+                    && m.sender == new_messages.sender);
+
+                if let Some(existing) = existing {
+                    stored.push(StoredMessageId{ id: existing.id, seq: existing.seq, duplicate: true });
+                    continue;
+                }
+
+                let id = messages.len() as i32 + 1;
+                let seq = messages.iter()
+                    .filter(|m| m.conversation == new_messages.conversation_id)
+                    .map(|m| m.seq)
+                    .max()
+                    .unwrap_or(0) + 1;
+
+                messages.push(MemoryMessage{
+                    id,
+                    conversation: new_messages.conversation_id,
+                    seq,
+                    sender: new_messages.sender.clone(),
+                    data: message.data.clone(),
+                    data_encoding: message.data_encoding.clone(),
+                    preview: message.preview.clone(),
+                    media_type: message.media_type.clone(),
+                    timestamp: message.timestamp,
+                    signature: message.signature.clone(),
+                    idempotency_key: message.idempotency_key.clone(),
+                    parent_id: message.parent_id,
+                    attachment_id: message.attachment_id,
+                    created_at: Utc::now(),
+                    deleted: false,
+                });
+                stored.push(StoredMessageId{ id, seq, duplicate: false });
+            }
+
+            Ok(stored)
+        }
+
+        fn list_messages<'a>(&'a self, email: &str, conversation_id: i32, query: &MessageQuery) -> MessageStream<'a> {
+            let messages = self.messages.lock().unwrap();
+            let seq_of = |id: Option<i32>| id.map(|id| messages.iter().find(|m| m.id == id).map(|m| m.seq));
+
+            // Messages after or before one that doesn't exist match nothing, as they would in the database
+            let (after, before) = (seq_of(query.after_id), seq_of(query.before_id));
+            let is_member = self.conversations.lock().unwrap()
+                .iter()
+                .any(|c| c.id == conversation_id && c.participants.iter().any(|(p, _)| p.eq_ignore_ascii_case(email)));
+
+            let mut rows: Vec<&MemoryMessage> = messages.iter()
+                .filter(|m| is_member && m.conversation == conversation_id)
+                .filter(|m| after.map_or(true, |seq| seq.map_or(false, |seq| m.seq > seq)))
+                .filter(|m| before.map_or(true, |seq| seq.map_or(false, |seq| m.seq < seq)))
+                .filter(|m| query.since.map_or(true, |since| m.created_at >= since))
+                .filter(|m| query.until.map_or(true, |until| m.created_at < until))
+                .filter(|m| query.parent_id.map_or(true, |parent_id| m.parent_id == Some(parent_id)))
+                .filter(|m| !query.exclude_self || !m.sender.eq_ignore_ascii_case(email))
+                .filter(|m| query.include_deleted || !m.deleted)
+                .collect();
+
+            match query.after_id {
+                Some(_) => rows.sort_by_key(|m| m.seq),
+                None => rows.sort_by_key(|m| -m.seq),
+            }
+
+            let rows: Vec<Result<StoredMessage, sqlx::Error>> = rows.into_iter()
+                .skip(query.offset.max(0) as usize)
+                .take(query.limit.max(0) as usize)
+                .map(|m| Ok(StoredMessage{
+                    id: m.id,
+                    seq: m.seq,
+                    data: m.data.clone(),
+                    data_encoding: m.data_encoding.clone(),
+                    media_type: Some(m.media_type.clone()),
+                    timestamp: Some(m.timestamp),
+                    created_at: m.created_at,
+                    edited_at: None,
+                    signature: Some(m.signature.clone()),
+                    sender: m.sender.clone(),
+                    parent_id: m.parent_id,
+                    attachment_id: m.attachment_id,
+                    attachment_media_type: None,
+                    attachment_size: None,
+                    reactions: Vec::new(),
+                    deleted: m.deleted,
+                }))
+                .collect();
+
+            Box::pin(async_std::stream::from_iter(rows))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::api::request::ROLE_MEMBER;
+    use crate::auth::{Lockout, Password};
+    use crate::storage::{order_by_seq, MemoryStorage, MessageQuery, MessageStream, NewConversation, NewMessage, NewMessages, NewUser, RetryStorage, Storage, StoredConversation, StoredLogin, StoredMessageId};
+
+    use std::error::Error;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use async_trait::async_trait;
+
+    /// Storage that fails with `error` the first `failures` times it's used, like a database connection that drops
+    struct FlakyStorage {
+        inner: MemoryStorage,
+        failures: usize,
+        error: fn() -> sqlx::Error,
+        attempts: AtomicUsize,
+    }
+
+    impl FlakyStorage {
+        fn new(failures: usize, error: fn() -> sqlx::Error) -> Self {
+            FlakyStorage{
+                inner: MemoryStorage::default(),
+                failures,
+                error,
+                attempts: AtomicUsize::new(0),
+            }
+        }
+
+        /// Count an attempt, failing it if there are failures left
+        fn attempt(&self) -> Result<(), Box<dyn Error>> {
+            match self.attempts.fetch_add(1, Ordering::SeqCst) < self.failures {
+                true => Err(Box::new((self.error)())),
+                false => Ok(()),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl Storage for FlakyStorage {
+        async fn get_user_by_email(&self, email: &str) -> Result<Option<StoredLogin>, Box<dyn Error>> {
+            self.attempt()?;
+            self.inner.get_user_by_email(email).await
+        }
+
+        async fn add_failed_login(&self, email: &str, lockout: &Lockout) -> Result<Option<i32>, Box<dyn Error>> {
+            self.attempt()?;
+            self.inner.add_failed_login(email, lockout).await
+        }
+
+        async fn reset_failed_logins(&self, email: &str) -> Result<(), Box<dyn Error>> {
+            self.attempt()?;
+            self.inner.reset_failed_logins(email).await
+        }
+
+        async fn existing_users(&self, emails: &[String]) -> Result<Vec<String>, Box<dyn Error>> {
+            self.attempt()?;
+            self.inner.existing_users(emails).await
+        }
+
+        async fn insert_users(&self, users: &[NewUser]) -> Result<Vec<(i32, String)>, Box<dyn Error>> {
+            self.attempt()?;
+            self.inner.insert_users(users).await
+        }
+
+        async fn get_public_key(&self, email: &str) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+            self.attempt()?;
+            self.inner.get_public_key(email).await
+        }
+
+        async fn insert_conversation(&self, conversation: &NewConversation) -> Result<StoredConversation, Box<dyn Error>> {
+            self.attempt()?;
+            self.inner.insert_conversation(conversation).await
+        }
+
+        async fn conversation_exists(&self, conversation_id: i32) -> Result<bool, Box<dyn Error>> {
+            self.attempt()?;
+            self.inner.conversation_exists(conversation_id).await
+        }
+
+        async fn is_public(&self, conversation_id: i32) -> Result<Option<bool>, Box<dyn Error>> {
+            self.attempt()?;
+            self.inner.is_public(conversation_id).await
+        }
+
+        async fn is_member(&self, email: &str, conversation_id: i32) -> Result<bool, Box<dyn Error>> {
+            self.attempt()?;
+            self.inner.is_member(email, conversation_id).await
+        }
+
+        async fn is_blocked(&self, email: &str, conversation_id: i32) -> Result<bool, Box<dyn Error>> {
+            self.attempt()?;
+            self.inner.is_blocked(email, conversation_id).await
+        }
+
+        async fn add_member(&self, email: &str, conversation_ids: &[i32], role: &str) -> Result<(), Box<dyn Error>> {
+            self.attempt()?;
+            self.inner.add_member(email, conversation_ids, role).await
+        }
+
+        async fn message_conversation(&self, message_id: i32) -> Result<Option<i32>, Box<dyn Error>> {
+            self.attempt()?;
+            self.inner.message_conversation(message_id).await
+        }
+
+        async fn attachment_conversation(&self, attachment_id: i32) -> Result<Option<i32>, Box<dyn Error>> {
+            self.attempt()?;
+            self.inner.attachment_conversation(attachment_id).await
+        }
+
+        async fn insert_messages(&self, messages: &NewMessages) -> Result<Vec<StoredMessageId>, Box<dyn Error>> {
+            self.attempt()?;
+            self.inner.insert_messages(messages).await
+        }
+
+        fn list_messages<'a>(&'a self, email: &str, conversation_id: i32, query: &MessageQuery) -> MessageStream<'a> {
+            self.inner.list_messages(email, conversation_id, query)
+        }
+    }
+
+    /// A database connection that dropped, which is worth trying again
+    fn dropped() -> sqlx::Error {
+        sqlx::Error::Io(std::io::Error::new(std::io::ErrorKind::ConnectionReset, "reset"))
+    }
+
+    fn conversation(direct_key: Option<&str>) -> NewConversation {
+        NewConversation{
+            name: String::from("Conversation"),
+            direct_key: direct_key.map(String::from),
+            public: false,
+            creator: String::from("me@example.com"),
+            members: vec![String::from("you@example.com")],
+            invitees: Vec::new(),
+        }
+    }
+
+    #[async_std::test]
+    async fn test_retry_storage() {
+        // Storage that fails twice for a transient reason recovers on the third attempt
+        let flaky = FlakyStorage::new(2, dropped);
+        flaky.inner.add_user("me@example.com", "k2uEa77H");
+        let storage = RetryStorage::new(flaky);
+
+        let user = storage.get_user_by_email("me@example.com").await.unwrap();
+        assert!(user.is_some());
+        assert_eq!(storage.inner.attempts.load(Ordering::SeqCst), 3);
+
+        // Attempts are limited
+        let storage = RetryStorage::new(FlakyStorage::new(5, dropped));
+        assert!(storage.existing_users(&[String::from("me@example.com")]).await.is_err());
+        assert_eq!(storage.inner.attempts.load(Ordering::SeqCst), 3);
+
+        // Errors that aren't transient are returned straight away
+        let storage = RetryStorage::new(FlakyStorage::new(2, || sqlx::Error::RowNotFound));
+        assert!(storage.reset_failed_logins("me@example.com").await.is_err());
+        assert_eq!(storage.inner.attempts.load(Ordering::SeqCst), 1);
+
+        // Failed logins are only counted once, even if counting one fails
+        let storage = RetryStorage::new(FlakyStorage::new(1, dropped));
+        storage.inner.inner.add_user("me@example.com", "k2uEa77H");
+        let lockout = Lockout{
+            max_attempts: 0,
+            duration: chrono::Duration::minutes(15),
+        };
+        assert!(storage.add_failed_login("me@example.com", &lockout).await.is_err());
+        assert_eq!(storage.inner.attempts.load(Ordering::SeqCst), 1);
+        assert_eq!(storage.add_failed_login("me@example.com", &lockout).await.unwrap(), Some(1));
+        assert_eq!(storage.add_failed_login("you@example.com", &lockout).await.unwrap(), None);
+
+        // Direct conversations both users join straight away can be stored again safely, but other conversations are
+        // only tried once
+        let storage = RetryStorage::new(FlakyStorage::new(1, dropped));
+        let stored = storage.insert_conversation(&conversation(Some("me@example.com,you@example.com"))).await.unwrap();
+        assert!(stored.created);
+        assert_eq!(storage.inner.attempts.load(Ordering::SeqCst), 2);
+
+        let storage = RetryStorage::new(FlakyStorage::new(1, dropped));
+        let invited = NewConversation{
+            members: Vec::new(),
+            invitees: vec![String::from("you@example.com")],
+            ..conversation(Some("me@example.com,you@example.com"))
+        };
+        assert!(storage.insert_conversation(&invited).await.is_err());
+        assert_eq!(storage.inner.attempts.load(Ordering::SeqCst), 1);
+
+        let storage = RetryStorage::new(FlakyStorage::new(1, dropped));
+        assert!(storage.insert_conversation(&conversation(None)).await.is_err());
+        assert_eq!(storage.inner.attempts.load(Ordering::SeqCst), 1);
+        assert!(storage.inner.inner.conversations.lock().unwrap().is_empty());
+
+        // Adding a member can be repeated, but adding users and storing messages are only tried once
+        let storage = RetryStorage::new(FlakyStorage::new(0, dropped));
+        storage.inner.inner.add_user("me@example.com", "k2uEa77H");
+        storage.inner.inner.add_user("you@example.com", "9poyvjJN");
+        storage.inner.inner.add_user("them@example.com", "uZmS2oMK");
+        let stored = storage.insert_conversation(&conversation(None)).await.unwrap();
+
+        let storage = RetryStorage::new(FlakyStorage{ failures: 1, attempts: AtomicUsize::new(0), ..storage.inner });
+        storage.add_member("them@example.com", &[stored.id], ROLE_MEMBER).await.unwrap();
+        assert_eq!(storage.inner.attempts.load(Ordering::SeqCst), 2);
+        assert!(storage.is_member("them@example.com", stored.id).await.unwrap());
+
+        let storage = RetryStorage::new(FlakyStorage{ failures: 2, attempts: AtomicUsize::new(0), ..storage.inner });
+        let user = NewUser{
+            email: String::from("new@example.com"),
+            public_key: vec![0; 32],
+            password: Password::hash("uZmS2oMK", None).unwrap(),
+            display_name: None,
+            avatar_url: None,
+        };
+        assert!(storage.insert_users(&[user]).await.is_err());
+        assert_eq!(storage.inner.attempts.load(Ordering::SeqCst), 1);
+
+        let messages = NewMessages{
+            sender: String::from("me@example.com"),
+            conversation_id: stored.id,
+            connection: 0,
+            messages: vec![NewMessage{
+                data: b"Hello".to_vec(),
+                data_encoding: None,
+                preview: None,
+                media_type: b"text/plain".to_vec(),
+                timestamp: 0,
+                signature: Vec::new(),
+                idempotency_key: None,
+                search: None,
+                parent_id: None,
+                attachment_id: None,
+            }],
+        };
+        assert!(storage.insert_messages(&messages).await.is_err());
+        assert_eq!(storage.inner.attempts.load(Ordering::SeqCst), 2);
+        assert!(storage.inner.inner.messages.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_order_by_seq() {
+        // Rows can come back from the database in any order
+        let first_seq = 41;
+        let mut rows: Vec<(i32, i32)> = (0..500).map(|i| (1000 + i, first_seq + i)).collect();
+        rows.sort_by_key(|&(id, _)| (id * 7919) % 500);
+
+        let ordered = order_by_seq(rows, first_seq, 500);
+        assert_eq!(ordered.len(), 500);
+
+        for (i, stored) in ordered.into_iter().enumerate() {
+            assert_eq!(stored, Some((1000 + i as i32, first_seq + i as i32)));
+        }
+
+        // Messages that were already stored leave a gap
+        let ordered = order_by_seq(vec![(5, 12), (7, 10)], 10, 3);
+        assert_eq!(ordered, vec![Some((7, 10)), None, Some((5, 12))]);
+
+        // Rows outside the batch are ignored
+        let ordered = order_by_seq(vec![(1, 9), (2, 13)], 10, 3);
+        assert_eq!(ordered, vec![None, None, None]);
+    }
+}
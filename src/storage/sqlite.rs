@@ -0,0 +1,605 @@
+use crate::api::{Attachment, Message};
+use crate::api::request::{ROLE_ADMIN, ROLE_MEMBER};
+use crate::auth::{Lockout, Password};
+use crate::encoding;
+use crate::push;
+use crate::storage::{MessageQuery, MessageStream, NewConversation, NewMessages, NewUser, Storage, StoredConversation, StoredLogin, StoredMessage, StoredMessageId};
+
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::error::Error;
+use std::io::Error as ioErr;
+use std::io::ErrorKind as ioErrKind;
+use async_std::sync::Mutex;
+use async_trait::async_trait;
+use chrono::{DateTime, TimeZone, Utc};
+use log::warn;
+use once_cell::sync::Lazy;
+use sqlx::{Row, SqlitePool};
+use sqlx::sqlite::SqliteRow;
+
+/// How many messages are read from the database at a time while they're streamed
+const PAGE_SIZE: i64 = 100;
+
+/// Held while writing, since SQLite only takes one write at a time and a transaction that reads before it writes fails
+/// (rather than waiting) if another one wrote first
+static WRITES: Lazy<Mutex<()>> = Lazy::new(Default::default);
+
+/// Storage in a SQLite database, for a single server that doesn't need Postgres
+///
+/// Times are kept as milliseconds since the Unix epoch, and batches are stored a row at a time inside a transaction,
+/// since SQLite can't unpack arrays the way Postgres does.
+pub struct SqliteStorage<'a> {
+    db_pool: &'a SqlitePool,
+}
+
+impl<'a> SqliteStorage<'a> {
+    /// Use a database pool for storage
+    pub fn new(db_pool: &'a SqlitePool) -> Self {
+        SqliteStorage{ db_pool }
+    }
+
+    /// Add a user with a (plaintext) password
+    #[cfg(test)]
+    pub async fn add_user(&self, email: &str, password: &str) {
+        self.insert_users(&[NewUser{
+            email: String::from(email),
+            public_key: vec![0; 32],
+            password: Password::hash(password, None).unwrap(),
+            display_name: None,
+            avatar_url: None,
+        }]).await.unwrap();
+    }
+
+    /// Push newly stored messages to the members of their conversation connected to this server, returning how many
+    /// connections they were queued for
+    async fn push(&self, conversation_id: i32, connection: u64, created: &[i32]) -> Result<usize, Box<dyn Error>> {
+        let recipients: Vec<String> = sqlx::query(include_str!("../sql/sqlite/read-member-emails.sql"))
+            .bind(conversation_id)
+            .fetch_all(self.db_pool)
+            .await?
+            .iter()
+            .map(|m| m.try_get("email"))
+            .collect::<Result<Vec<String>, _>>()?
+            .into_iter()
+            .filter(|email| push::is_connected(email))
+            .collect();
+
+        // Nobody here to push to, so there's no need to read the messages
+        if recipients.is_empty() || created.is_empty() {
+            return Ok(0);
+        }
+
+        let mut messages = Vec::new();
+
+        for id in created {
+            let m = match sqlx::query(include_str!("../sql/sqlite/read-pushed-message.sql"))
+                .bind(conversation_id)
+                .bind(*id)
+                .fetch_optional(self.db_pool)
+                .await?
+            {
+                Some(m) => m,
+                None => continue,
+            };
+
+            let data_encoding: Option<String> = m.try_get("data_encoding")?;
+            messages.push(Message{
+                id: Some(m.try_get("id")?),
+                seq: Some(m.try_get("seq")?),
+                conversation: Some(conversation_id),
+                data: Some(encoding::decompress_data(m.try_get("data")?, data_encoding.as_deref())?),
+                media_type: m.try_get("media_type")?,
+                timestamp: m.try_get("timestamp")?,
+                created_at: Some(from_millis(m.try_get("created_at")?)),
+                signature: m.try_get("signature")?,
+                sender: Some(m.try_get("email")?),
+                parent_id: m.try_get("parent_id")?,
+                attachment: m.try_get::<Option<i32>, _>("attachment_id")?.map(|id| -> Result<Attachment, sqlx::Error> {
+                    Ok(Attachment{
+                        id: Some(id),
+                        conversation: Some(conversation_id),
+                        media_type: m.try_get("attachment_media_type")?,
+                        size: m.try_get("attachment_size")?,
+                        ..Default::default()
+                    })
+                }).transpose()?,
+                ..Default::default()
+            });
+        }
+
+        Ok(push::push_local(conversation_id, &recipients, messages, connection))
+    }
+}
+
+/// Turn a time kept in milliseconds since the Unix epoch back into a date
+fn from_millis(millis: i64) -> DateTime<Utc> {
+    Utc.timestamp_millis(millis)
+}
+
+/// Turn a row id into a 32-bit id, like the ones Postgres gives out
+fn to_id(rowid: i64) -> Result<i32, Box<dyn Error>> {
+    Ok(i32::try_from(rowid)?)
+}
+
+#[async_trait]
+impl<'a> Storage for SqliteStorage<'a> {
+    async fn get_user_by_email(&self, email: &str) -> Result<Option<StoredLogin>, Box<dyn Error>> {
+        let user = sqlx::query(include_str!("../sql/sqlite/verify-user.sql"))
+            .bind(email)
+            .fetch_optional(self.db_pool)
+            .await?;
+
+        Ok(match user {
+            Some(u) => Some(StoredLogin{
+                password: Password{
+                    hash: u.try_get("pass")?,
+                    salt: u.try_get("salt")?,
+                },
+                failed_attempts: u.try_get("failed_attempts")?,
+                locked_until: u.try_get::<Option<i64>, _>("locked_until")?.map(from_millis),
+            }),
+            None => None,
+        })
+    }
+
+    async fn add_failed_login(&self, email: &str, lockout: &Lockout) -> Result<Option<i32>, Box<dyn Error>> {
+        // The count is read and written while holding the write lock, so concurrent failures are all counted
+        let _writing = WRITES.lock().await;
+        let mut tx = self.db_pool.begin().await?;
+
+        let user = match sqlx::query(include_str!("../sql/sqlite/verify-user.sql"))
+            .bind(email)
+            .fetch_optional(&mut tx)
+            .await?
+        {
+            Some(u) => u,
+            None => return Ok(None),
+        };
+
+        let locked_until: Option<i64> = user.try_get("locked_until")?;
+        let (failed_attempts, locked) = lockout.fail(user.try_get("failed_attempts")?, Utc::now());
+
+        sqlx::query(include_str!("../sql/sqlite/update-failed-logins.sql"))
+            .bind(email)
+            .bind(failed_attempts)
+            .bind(locked.map(|t| t.timestamp_millis()).or(locked_until))
+            .execute(&mut tx)
+            .await?;
+
+        tx.commit().await?;
+
+        Ok(Some(failed_attempts))
+    }
+
+    async fn reset_failed_logins(&self, email: &str) -> Result<(), Box<dyn Error>> {
+        let _writing = WRITES.lock().await;
+
+        sqlx::query(include_str!("../sql/sqlite/reset-failed-logins.sql"))
+            .bind(email)
+            .execute(self.db_pool)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn existing_users(&self, emails: &[String]) -> Result<Vec<String>, Box<dyn Error>> {
+        let mut existing = Vec::new();
+
+        for email in emails {
+            let user = sqlx::query(include_str!("../sql/sqlite/read-existing-user.sql"))
+                .bind(email)
+                .fetch_optional(self.db_pool)
+                .await?;
+
+            if let Some(u) = user {
+                existing.push(u.try_get("email")?);
+            }
+        }
+
+        Ok(existing)
+    }
+
+    async fn insert_users(&self, users: &[NewUser]) -> Result<Vec<(i32, String)>, Box<dyn Error>> {
+        let _writing = WRITES.lock().await;
+        let mut tx = self.db_pool.begin().await?;
+        let mut created = Vec::new();
+
+        // Emails that are already registered (or repeated) add nothing
+        for user in users {
+            let done = sqlx::query(include_str!("../sql/sqlite/create-user.sql"))
+                .bind(&user.email)
+                .bind(&user.public_key)
+                .bind(&user.password.hash)
+                .bind(&user.password.salt)
+                .bind(&user.display_name)
+                .bind(&user.avatar_url)
+                .execute(&mut tx)
+                .await?;
+
+            if done.rows_affected() == 1 {
+                created.push((to_id(done.last_insert_rowid())?, user.email.clone()));
+            }
+        }
+
+        tx.commit().await?;
+
+        Ok(created)
+    }
+
+    async fn get_public_key(&self, email: &str) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        let user = sqlx::query(include_str!("../sql/sqlite/read-public-key.sql"))
+            .bind(email)
+            .fetch_optional(self.db_pool)
+            .await?;
+
+        Ok(match user {
+            Some(u) => Some(u.try_get("public_key")?),
+            None => None,
+        })
+    }
+
+    async fn insert_conversation(&self, conversation: &NewConversation) -> Result<StoredConversation, Box<dyn Error>> {
+        // Run all inserts in a transaction so a failure leaves no partial conversation, and hold the write lock so two
+        // direct conversations can't be made at once
+        let _writing = WRITES.lock().await;
+        let mut tx = self.db_pool.begin().await?;
+
+        // A direct conversation is only reused if both users are already in it
+        if let Some(direct_key) = &conversation.direct_key {
+            let other = conversation.members.iter()
+                .chain(&conversation.invitees)
+                .next()
+                .cloned()
+                .unwrap_or_default();
+            let existing = sqlx::query(include_str!("../sql/sqlite/read-direct-conversation.sql"))
+                .bind(direct_key)
+                .bind(conversation.creator.to_lowercase())
+                .bind(other)
+                .fetch_optional(&mut tx)
+                .await?;
+
+            if let Some(existing) = existing {
+                return Ok(StoredConversation{
+                    id: existing.try_get("id")?,
+                    name: existing.try_get("name")?,
+                    created: false,
+                });
+            }
+        }
+
+        let id = sqlx::query(include_str!("../sql/sqlite/create-conversation-1.sql"))
+            .bind(&conversation.name)
+            .bind(&conversation.direct_key)
+            .bind(conversation.public)
+            .execute(&mut tx)
+            .await?
+            .last_insert_rowid();
+        let id = to_id(id)?;
+
+        // Add creator user
+        sqlx::query(include_str!("../sql/sqlite/create-conversation-2.sql"))
+            .bind(&conversation.creator)
+            .bind(id)
+            .bind(ROLE_ADMIN)
+            .execute(&mut tx)
+            .await?;
+
+        // Add remaining users, and invite those who must accept first
+        for email in &conversation.members {
+            sqlx::query(include_str!("../sql/sqlite/create-conversation-2.sql"))
+                .bind(email)
+                .bind(id)
+                .bind(ROLE_MEMBER)
+                .execute(&mut tx)
+                .await?;
+        }
+
+        for email in &conversation.invitees {
+            sqlx::query(include_str!("../sql/sqlite/create-invitation.sql"))
+                .bind(id)
+                .bind(&conversation.creator)
+                .bind(email)
+                .bind(Utc::now().timestamp_millis())
+                .execute(&mut tx)
+                .await?;
+        }
+
+        tx.commit().await?;
+
+        Ok(StoredConversation{
+            id,
+            name: conversation.name.clone(),
+            created: true,
+        })
+    }
+
+    async fn conversation_exists(&self, conversation_id: i32) -> Result<bool, Box<dyn Error>> {
+        Ok(sqlx::query(include_str!("../sql/sqlite/conversation-exists.sql"))
+            .bind(conversation_id)
+            .fetch_one(self.db_pool)
+            .await?
+            .try_get("exists")?)
+    }
+
+    async fn is_public(&self, conversation_id: i32) -> Result<Option<bool>, Box<dyn Error>> {
+        let conversation = sqlx::query(include_str!("../sql/sqlite/is-public.sql"))
+            .bind(conversation_id)
+            .fetch_optional(self.db_pool)
+            .await?;
+
+        Ok(match conversation {
+            Some(c) => Some(c.try_get("public")?),
+            None => None,
+        })
+    }
+
+    async fn is_member(&self, email: &str, conversation_id: i32) -> Result<bool, Box<dyn Error>> {
+        Ok(sqlx::query(include_str!("../sql/sqlite/is-member.sql"))
+            .bind(email)
+            .bind(conversation_id)
+            .fetch_one(self.db_pool)
+            .await?
+            .try_get("is_member")?)
+    }
+
+    async fn is_blocked(&self, email: &str, conversation_id: i32) -> Result<bool, Box<dyn Error>> {
+        Ok(sqlx::query(include_str!("../sql/sqlite/is-blocked.sql"))
+            .bind(email)
+            .bind(conversation_id)
+            .fetch_one(self.db_pool)
+            .await?
+            .try_get("is_blocked")?)
+    }
+
+    async fn add_member(&self, email: &str, conversation_ids: &[i32], role: &str) -> Result<(), Box<dyn Error>> {
+        let _writing = WRITES.lock().await;
+        let mut tx = self.db_pool.begin().await?;
+
+        for conversation_id in conversation_ids {
+            sqlx::query(include_str!("../sql/sqlite/create-conversation-2.sql"))
+                .bind(email)
+                .bind(*conversation_id)
+                .bind(role)
+                .execute(&mut tx)
+                .await?;
+        }
+
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    async fn message_conversation(&self, message_id: i32) -> Result<Option<i32>, Box<dyn Error>> {
+        let message = sqlx::query(include_str!("../sql/sqlite/read-message-conversation.sql"))
+            .bind(message_id)
+            .fetch_optional(self.db_pool)
+            .await?;
+
+        Ok(match message {
+            Some(m) => Some(m.try_get("conversation")?),
+            None => None,
+        })
+    }
+
+    async fn attachment_conversation(&self, attachment_id: i32) -> Result<Option<i32>, Box<dyn Error>> {
+        let attachment = sqlx::query(include_str!("../sql/sqlite/read-attachment-conversation.sql"))
+            .bind(attachment_id)
+            .fetch_optional(self.db_pool)
+            .await?;
+
+        Ok(match attachment {
+            Some(a) => Some(a.try_get("conversation")?),
+            None => None,
+        })
+    }
+
+    async fn insert_messages(&self, messages: &NewMessages) -> Result<Vec<StoredMessageId>, Box<dyn Error>> {
+        let NewMessages{ sender, conversation_id, connection, messages } = messages;
+        let writing = WRITES.lock().await;
+        let mut tx = self.db_pool.begin().await?;
+        let now = Utc::now().timestamp_millis();
+        let mut stored = Vec::new();
+
+        // Nothing else writes while the lock is held, so a message already stored with the same key (by a retried
+        // request, or earlier in this one) is found here and doesn't take up a sequence number
+        for message in messages {
+            let existing = match &message.idempotency_key {
+                Some(key) => sqlx::query(include_str!("../sql/sqlite/read-message-by-key.sql"))
+                    .bind(sender)
+                    .bind(*conversation_id)
+                    .bind(key)
+                    .fetch_optional(&mut tx)
+                    .await?,
+                None => None,
+            };
+
+            if let Some(existing) = existing {
+                stored.push(StoredMessageId{
+                    id: existing.try_get("id")?,
+                    seq: existing.try_get("seq")?,
+                    duplicate: true,
+                });
+                continue;
+            }
+
+            sqlx::query(include_str!("../sql/sqlite/reserve-seq.sql"))
+                .bind(*conversation_id)
+                .bind(1)
+                .execute(&mut tx)
+                .await?;
+            let seq: i32 = sqlx::query(include_str!("../sql/sqlite/read-last-seq.sql"))
+                .bind(*conversation_id)
+                .fetch_one(&mut tx)
+                .await?
+                .try_get("last_seq")?;
+
+            let done = sqlx::query(include_str!("../sql/sqlite/create-message.sql"))
+                .bind(sender)
+                .bind(*conversation_id)
+                .bind(seq)
+                .bind(&message.data)
+                .bind(&message.media_type)
+                .bind(message.timestamp)
+                .bind(&message.signature)
+                .bind(&message.idempotency_key)
+                .bind(message.parent_id)
+                .bind(message.attachment_id)
+                .bind(&message.data_encoding)
+                .bind(&message.preview)
+                .bind(now)
+                .execute(&mut tx)
+                .await?;
+
+            // The sender isn't a participant, so the whole batch is rolled back
+            if done.rows_affected() == 0 {
+                return Err(Box::new(ioErr::new(ioErrKind::Other, "Message was not stored")));
+            }
+
+            stored.push(StoredMessageId{
+                id: to_id(done.last_insert_rowid())?,
+                seq,
+                duplicate: false,
+            });
+        }
+
+        tx.commit().await?;
+        drop(writing);
+
+        // Let connected members (and the sender's other connections) know about the new messages once they're stored,
+        // without failing the request if they can't be told, since the messages can still be read
+        let created: Vec<i32> = stored.iter()
+            .filter(|m| !m.duplicate)
+            .map(|m| m.id)
+            .collect();
+
+        if let Err(e) = self.push(*conversation_id, *connection, &created).await {
+            warn!("Could not push new messages: {}", e);
+        }
+
+        Ok(stored)
+    }
+
+    fn list_messages<'a>(&'a self, email: &str, conversation_id: i32, query: &MessageQuery) -> MessageStream<'a> {
+        let pages = MessagePages{
+            db_pool: self.db_pool,
+            email: String::from(email),
+            conversation_id,
+            query: query.clone(),
+            now: Utc::now().timestamp_millis(),
+            read: 0,
+            last_seq: None,
+            rows: VecDeque::new(),
+            done: false,
+        };
+
+        Box::pin(futures_lite::stream::unfold(pages, |mut pages| async move {
+            pages.next().await.map(|row| (row, pages))
+        }))
+    }
+}
+
+/// Messages read from a conversation a page at a time, so a long conversation never has to be held in memory at once
+struct MessagePages<'a> {
+    db_pool: &'a SqlitePool,
+    email: String,
+    conversation_id: i32,
+    query: MessageQuery,
+    /// When the read started, which messages past their conversation's retention window are measured from
+    now: i64,
+    /// How many messages have been read so far
+    read: i64,
+    /// The sequence number of the last message read, which the next page carries on from
+    last_seq: Option<i32>,
+    rows: VecDeque<StoredMessage>,
+    done: bool,
+}
+
+impl MessagePages<'_> {
+    /// Get the next message, reading another page once the last one runs out
+    async fn next(&mut self) -> Option<Result<StoredMessage, sqlx::Error>> {
+        if self.rows.is_empty() && !self.done {
+            if let Err(e) = self.read_page().await {
+                self.done = true;
+                return Some(Err(e));
+            }
+        }
+
+        self.rows.pop_front().map(Ok)
+    }
+
+    /// Read the next page of messages, newest first (or oldest first when syncing messages after a given id)
+    ///
+    /// Pages after the first carry on from the last message's sequence number rather than an offset, so messages sent
+    /// in the meantime don't shift them.
+    async fn read_page(&mut self) -> Result<(), sqlx::Error> {
+        let limit = PAGE_SIZE.min(self.query.limit - self.read);
+
+        if limit <= 0 {
+            self.done = true;
+            return Ok(());
+        }
+
+        let rows = sqlx::query(include_str!("../sql/sqlite/read-message.sql"))
+            .bind(&self.email)
+            .bind(self.conversation_id)
+            .bind(limit)
+            .bind(match self.last_seq {
+                Some(_) => 0,
+                None => self.query.offset,
+            })
+            .bind(self.query.after_id)
+            .bind(self.query.before_id)
+            .bind(self.query.since.map(|t| t.timestamp_millis()))
+            .bind(self.query.until.map(|t| t.timestamp_millis()))
+            .bind(self.query.parent_id)
+            .bind(self.query.exclude_self)
+            .bind(self.query.include_deleted)
+            .bind(self.now)
+            .bind(self.last_seq)
+            .fetch_all(self.db_pool)
+            .await?;
+
+        self.done = (rows.len() as i64) < limit;
+        self.read += rows.len() as i64;
+
+        for row in rows {
+            let message = self.message(row).await?;
+            self.last_seq = Some(message.seq);
+            self.rows.push_back(message);
+        }
+
+        Ok(())
+    }
+
+    /// Turn a row into a message, along with its reactions in the order they were added
+    async fn message(&self, m: SqliteRow) -> Result<StoredMessage, sqlx::Error> {
+        let id: i32 = m.try_get("id")?;
+        let reactions = sqlx::query(include_str!("../sql/sqlite/read-reactions.sql"))
+            .bind(id)
+            .fetch_all(self.db_pool)
+            .await?
+            .iter()
+            .map(|r| Ok((r.try_get("emoji")?, r.try_get("email")?)))
+            .collect::<Result<Vec<(String, String)>, sqlx::Error>>()?;
+
+        Ok(StoredMessage{
+            id,
+            seq: m.try_get("seq")?,
+            data: m.try_get("data")?,
+            data_encoding: m.try_get("data_encoding")?,
+            media_type: m.try_get("media_type")?,
+            timestamp: m.try_get("timestamp")?,
+            created_at: from_millis(m.try_get("created_at")?),
+            edited_at: m.try_get::<Option<i64>, _>("edited_at")?.map(from_millis),
+            signature: m.try_get("signature")?,
+            sender: m.try_get("email")?,
+            parent_id: m.try_get("parent_id")?,
+            attachment_id: m.try_get("attachment_id")?,
+            attachment_media_type: m.try_get("attachment_media_type")?,
+            attachment_size: m.try_get("attachment_size")?,
+            reactions,
+            deleted: m.try_get("deleted")?,
+        })
+    }
+}
@@ -1,4 +1,5 @@
-use std::env;
+use crate::settings::TlsConfig;
+
 use std::error::Error;
 use std::fs::File;
 use std::io::BufReader;
@@ -11,16 +12,17 @@ use rustls::{NoClientAuth, ServerConfig};
 use rustls_pemfile;
 
 /// Create a TLS acceptor using a locally-stored key and certificate
-pub async fn get_acceptor() -> Result<TlsAcceptor, Box<dyn Error>> {
-    let cert = get_cert(
-        &env::var("TLS_CERT_PATH")?
-    )?;
-    let key = get_key(
-        &env::var("TLS_KEY_PATH")?
-    )?;
+///
+/// This is done at startup, so a certificate or key that can't be used stops the server before it takes connections.
+pub async fn get_acceptor(tls: &TlsConfig) -> Result<TlsAcceptor, Box<dyn Error>> {
+    let cert = get_cert(&tls.cert_path)
+        .map_err(|e| ioErr::new(ioErrKind::InvalidData, format!("Could not read TLS certificate '{}': {}", tls.cert_path, e)))?;
+    let key = get_key(&tls.key_path)
+        .map_err(|e| ioErr::new(ioErrKind::InvalidData, format!("Could not read TLS key '{}': {}", tls.key_path, e)))?;
 
     let mut config = ServerConfig::new(NoClientAuth::new());
-    config.set_single_cert(cert, key)?;
+    config.set_single_cert(cert, key)
+        .map_err(|e| ioErr::new(ioErrKind::InvalidData, format!("Invalid TLS certificate or key: {}", e)))?;
 
     let acceptor = TlsAcceptor::from(Arc::new(config));
     Ok(acceptor)
@@ -30,11 +32,15 @@ pub async fn get_acceptor() -> Result<TlsAcceptor, Box<dyn Error>> {
 fn get_cert(path: &str) -> Result<Vec<Certificate>, Box<dyn Error>> {
     let file = File::open(path)?;
     let mut reader = BufReader::new(file);
-    let cert = rustls_pemfile::certs(&mut reader)?
+    let cert: Vec<Certificate> = rustls_pemfile::certs(&mut reader)?
         .iter()
         .map(|v| rustls::Certificate(v.clone()))
         .collect();
-    
+
+    if cert.is_empty() {
+        return Err(Box::new(ioErr::new(ioErrKind::InvalidData, "No certificates found")));
+    }
+
     Ok(cert)
 }
 
@@ -52,4 +58,48 @@ fn get_key(path: &str) -> Result<PrivateKey, Box<dyn Error>> {
             _ => { return Err(Box::new(ioErr::new(ioErrKind::InvalidData, "Invalid key"))) },
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::settings::TlsConfig;
+    use crate::tls::get_acceptor;
+    use std::env;
+    use std::fs;
+
+    /// Write a self-signed certificate for localhost and its key to temporary files
+    fn self_signed(name: &str) -> TlsConfig {
+        let cert = rcgen::generate_simple_self_signed(vec![String::from("localhost")]).unwrap();
+        let dir = env::temp_dir();
+        let tls = TlsConfig{
+            cert_path: dir.join(format!("echo-{}-cert.pem", name)).to_string_lossy().into_owned(),
+            key_path: dir.join(format!("echo-{}-key.pem", name)).to_string_lossy().into_owned(),
+        };
+
+        fs::write(&tls.cert_path, cert.serialize_pem().unwrap()).unwrap();
+        fs::write(&tls.key_path, cert.serialize_private_key_pem()).unwrap();
+
+        tls
+    }
+
+    #[async_std::test]
+    async fn test_get_acceptor() {
+        let tls = self_signed("acceptor");
+        assert!(get_acceptor(&tls).await.is_ok());
+
+        // Anything that can't be used is reported along with its path, so the server doesn't start
+        let missing = TlsConfig{
+            cert_path: String::from("/nonexistent/cert.pem"),
+            ..tls.clone()
+        };
+        let error = get_acceptor(&missing).await.err().unwrap();
+        assert!(error.to_string().starts_with("Could not read TLS certificate '/nonexistent/cert.pem'"));
+
+        let swapped = TlsConfig{
+            cert_path: tls.key_path.clone(),
+            key_path: tls.cert_path.clone(),
+        };
+        let error = get_acceptor(&swapped).await.err().unwrap();
+        assert!(error.to_string().contains("No certificates found"));
+    }
+}
@@ -0,0 +1,124 @@
+use crate::settings::UnixSocketConfig;
+
+use std::error::Error;
+use std::fs;
+use std::io::Error as ioErr;
+use std::io::ErrorKind as ioErrKind;
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use async_std::os::unix::net::UnixListener;
+
+/// A Unix socket the server listens on, whose file is removed once it's no longer needed
+pub struct UnixSocket {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+impl UnixSocket {
+    /// Listen on the configured path, replacing a socket left behind by a server that didn't shut down cleanly
+    ///
+    /// Anything at the path that isn't a socket is left alone, and stops the server from starting.
+    pub async fn bind(config: &UnixSocketConfig) -> Result<Self, Box<dyn Error>> {
+        let path = PathBuf::from(&config.path);
+
+        match fs::symlink_metadata(&path) {
+            Ok(metadata) if metadata.file_type().is_socket() => fs::remove_file(&path)?,
+            Ok(_) => return Err(Box::new(ioErr::new(ioErrKind::AlreadyExists, format!("'{}' exists and is not a socket", config.path)))),
+            Err(e) if e.kind() == ioErrKind::NotFound => (),
+            Err(e) => return Err(Box::new(e)),
+        }
+
+        // Nobody else can reach into a private directory, so the socket is bound there and given its permissions
+        // before it's moved into place, rather than being open to anyone allowed by the umask in the meantime
+        let name = path.file_name()
+            .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, format!("'{}' is not a file path", config.path)))?;
+        let private = path.with_file_name(format!(".{}.{}", name.to_string_lossy(), std::process::id()));
+        let _ = fs::remove_dir_all(&private);
+        fs::DirBuilder::new().mode(0o700).create(&private)?;
+
+        let bound = bind_privately(&private.join(name), &path, config.mode).await;
+        let _ = fs::remove_dir_all(&private);
+
+        Ok(UnixSocket{
+            listener: bound.map_err(|e| ioErr::new(e.kind(), format!("Could not listen on '{}': {}", config.path, e)))?,
+            path,
+        })
+    }
+
+    pub fn listener(&self) -> &UnixListener {
+        &self.listener
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for UnixSocket {
+    fn drop(&mut self) {
+        remove(&self.path);
+    }
+}
+
+/// Listen at a path inside a private directory, then give the socket its permissions and move it to `path`
+async fn bind_privately(private: &Path, path: &Path, mode: u32) -> Result<UnixListener, ioErr> {
+    let listener = UnixListener::bind(private).await?;
+
+    // Only clients allowed by the socket's permissions can connect
+    fs::set_permissions(private, fs::Permissions::from_mode(mode))?;
+    fs::rename(private, path)?;
+
+    Ok(listener)
+}
+
+/// Remove a socket's file, e.g. when the server is shutting down
+pub fn remove(path: &Path) {
+    let _ = fs::remove_file(path);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::settings::UnixSocketConfig;
+    use crate::unix::UnixSocket;
+    use async_std::os::unix::net::UnixStream;
+    use std::env;
+    use std::fs;
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+    #[async_std::test]
+    async fn test_bind() {
+        let config = UnixSocketConfig{
+            path: env::temp_dir().join("echo-bind.sock").to_string_lossy().into_owned(),
+            mode: 0o600,
+        };
+        let _ = fs::remove_file(&config.path);
+
+        let socket = UnixSocket::bind(&config).await.unwrap();
+        let metadata = fs::metadata(&config.path).unwrap();
+        assert!(metadata.file_type().is_socket());
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+        assert!(UnixStream::connect(&config.path).await.is_ok());
+
+        // The private directory it was bound in is gone once it's in place
+        let private = format!("{}.{}", env::temp_dir().join(".echo-bind.sock").to_string_lossy(), std::process::id());
+        assert!(fs::symlink_metadata(&private).is_err());
+
+        // The socket is removed when the server is done with it
+        drop(socket);
+        assert!(fs::symlink_metadata(&config.path).is_err());
+
+        // A socket left behind by a server that stopped without removing it is replaced
+        drop(std::os::unix::net::UnixListener::bind(&config.path).unwrap());
+        assert!(UnixStream::connect(&config.path).await.is_err());
+        let socket = UnixSocket::bind(&config).await.unwrap();
+        assert!(UnixStream::connect(socket.path()).await.is_ok());
+        drop(socket);
+
+        // Anything else at the path is kept
+        fs::write(&config.path, "not a socket").unwrap();
+        let error = UnixSocket::bind(&config).await.err().unwrap();
+        assert!(error.to_string().ends_with("exists and is not a socket"));
+        assert_eq!(fs::read_to_string(&config.path).unwrap(), "not a socket");
+        fs::remove_file(&config.path).unwrap();
+    }
+}